@@ -91,6 +91,14 @@ pub struct Shell {
     /// When set, will limit the how many block heights in the past can the
     /// storage be queried for reading values.
     pub storage_read_past_height_limit: Option<u64>,
+    /// When set, a query handler call that takes longer than this many
+    /// milliseconds is logged as slow, with the `metrics` feature of
+    /// `namada`. When not set, defaults to 1 second.
+    pub query_slow_threshold_ms: Option<u64>,
+    /// When set to `true`, a panic of a query handler is caught and returned
+    /// as an error of the query instead of unwinding through the shell.
+    /// When not set, defaults to `false`.
+    pub query_catch_handler_panics: Option<bool>,
     /// Use the [`Ledger::db_dir()`] method to read the value.
     db_dir: PathBuf,
     /// Use the [`Ledger::tendermint_dir()`] method to read the value.
@@ -140,6 +148,8 @@ impl Ledger {
                 tx_wasm_compilation_cache_bytes: None,
                 // Default corresponds to 1 hour of past blocks at 1 block/sec
                 storage_read_past_height_limit: Some(3600),
+                query_slow_threshold_ms: None,
+                query_catch_handler_panics: None,
                 db_dir: DB_DIR.into(),
                 tendermint_dir: TENDERMINT_DIR.into(),
             },
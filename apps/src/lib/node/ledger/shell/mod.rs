@@ -19,6 +19,7 @@ use std::mem;
 use std::path::{Path, PathBuf};
 #[allow(unused_imports)]
 use std::rc::Rc;
+use std::time::Duration;
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use namada::ledger::events::log::EventLog;
@@ -28,6 +29,7 @@ use namada::ledger::pos::namada_proof_of_stake::types::{
     ActiveValidator, ValidatorSetUpdate,
 };
 use namada::ledger::pos::namada_proof_of_stake::PosBase;
+use namada::ledger::queries::HandlerConfig;
 use namada::ledger::storage::write_log::WriteLog;
 use namada::ledger::storage::{
     DBIter, Sha256Hasher, Storage, StorageHasher, DB,
@@ -220,6 +222,9 @@ where
     /// limit the how many block heights in the past can the storage be
     /// queried for reading values.
     storage_read_past_height_limit: Option<u64>,
+    /// Taken from the config's query settings, e.g.
+    /// `query_slow_threshold_ms` and `query_catch_handler_panics`.
+    query_handler_config: HandlerConfig,
     /// Proposal execution tracking
     pub proposal_data: HashSet<u64>,
     /// Log of events emitted by `FinalizeBlock` ABCI calls.
@@ -248,6 +253,14 @@ where
         let mode = config.tendermint.tendermint_mode;
         let storage_read_past_height_limit =
             config.shell.storage_read_past_height_limit;
+        let mut query_handler_config = HandlerConfig::default();
+        if let Some(millis) = config.shell.query_slow_threshold_ms {
+            query_handler_config.slow_query_threshold =
+                Duration::from_millis(millis);
+        }
+        if let Some(catch_panics) = config.shell.query_catch_handler_panics {
+            query_handler_config.catch_panics = catch_panics;
+        }
         if !Path::new(&base_dir).is_dir() {
             std::fs::create_dir(&base_dir)
                 .expect("Creating directory for Namada should not fail");
@@ -333,6 +346,7 @@ where
                 tx_wasm_compilation_cache as usize,
             ),
             storage_read_past_height_limit,
+            query_handler_config,
             proposal_data: HashSet::new(),
             // TODO: config event log params
             event_log: EventLog::default(),
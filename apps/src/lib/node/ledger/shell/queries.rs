@@ -28,6 +28,11 @@ where
             vp_wasm_cache: self.vp_wasm_cache.read_only(),
             tx_wasm_cache: self.tx_wasm_cache.read_only(),
             storage_read_past_height_limit: self.storage_read_past_height_limit,
+            version: None,
+            prefix_args: Default::default(),
+            capabilities: Default::default(),
+            finalized_height: None,
+            handler_config: self.query_handler_config,
         };
 
         // Convert request to domain-type
@@ -48,12 +53,30 @@ where
         // Invoke the root RPC handler - returns borsh-encoded data on success
         let result = namada::ledger::queries::handle_path(ctx, &request);
         match result {
-            Ok(ResponseQuery { data, info, proof }) => response::Query {
-                value: data,
-                info,
-                proof_ops: proof.map(Into::into),
-                ..Default::default()
-            },
+            Ok(response) => {
+                // The metadata that has no field in the ABCI response is sent
+                // in its `key`
+                let key = response.metadata().encode();
+                let ResponseQuery {
+                    data,
+                    info,
+                    log,
+                    code,
+                    codespace,
+                    proof,
+                    ..
+                } = response;
+                response::Query {
+                    value: data,
+                    info,
+                    log,
+                    code,
+                    codespace,
+                    key,
+                    proof_ops: proof.map(Into::into),
+                    ..Default::default()
+                }
+            }
             Err(err) => response::Query {
                 code: 1,
                 info: format!("RPC error: {}", err),
@@ -894,6 +894,26 @@ impl<'iter> DBIter<'iter> for RocksDB {
         iter_prefix(self, prefix)
     }
 
+    fn iter_prefix_after(
+        &'iter self,
+        prefix: &Key,
+        after: &str,
+    ) -> PersistentPrefixIterator<'iter> {
+        let db_prefix = "subspace/".to_owned();
+        // The smallest key after the `after` key
+        let start = format!("{}{}\0", db_prefix, after);
+        iter_db_prefix_from(self, db_prefix, prefix, start)
+    }
+
+    fn iter_old_diffs(
+        &'iter self,
+        height: BlockHeight,
+        prefix: &Key,
+    ) -> PersistentPrefixIterator<'iter> {
+        let db_prefix = format!("{}/diffs/old/", height.raw());
+        iter_db_prefix(self, db_prefix, prefix)
+    }
+
     fn iter_results(&'iter self) -> PersistentPrefixIterator<'iter> {
         let db_prefix = "results/".to_owned();
         let prefix = "results".to_owned();
@@ -919,20 +939,43 @@ fn iter_prefix<'iter>(
     db: &'iter RocksDB,
     prefix: &Key,
 ) -> PersistentPrefixIterator<'iter> {
-    let db_prefix = "subspace/".to_owned();
+    iter_db_prefix(db, "subspace/".to_owned(), prefix)
+}
+
+/// Iterate the key-vals with the given prefix under the `db_prefix`, which is
+/// stripped from the iterated keys.
+fn iter_db_prefix<'iter>(
+    db: &'iter RocksDB,
+    db_prefix: String,
+    prefix: &Key,
+) -> PersistentPrefixIterator<'iter> {
+    let start = format!("{}{}", db_prefix, prefix);
+    iter_db_prefix_from(db, db_prefix, prefix, start)
+}
+
+/// Iterate the key-vals with the given prefix under the `db_prefix`, which is
+/// stripped from the iterated keys, seeking directly to the `start` key, or to
+/// the prefix if the `start` key is before it.
+fn iter_db_prefix_from<'iter>(
+    db: &'iter RocksDB,
+    db_prefix: String,
+    prefix: &Key,
+    start: String,
+) -> PersistentPrefixIterator<'iter> {
     let prefix = format!("{}{}", db_prefix, prefix);
+    let start = start.max(prefix.clone());
 
     let mut read_opts = ReadOptions::default();
     // don't use the prefix bloom filter
     read_opts.set_total_order_seek(true);
-    let mut upper_prefix = prefix.clone().into_bytes();
+    let mut upper_prefix = prefix.into_bytes();
     if let Some(last) = upper_prefix.pop() {
         upper_prefix.push(last + 1);
     }
     read_opts.set_iterate_upper_bound(upper_prefix);
 
     let iter = db.0.iterator_opt(
-        IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+        IteratorMode::From(start.as_bytes(), Direction::Forward),
         read_opts,
     );
     PersistentPrefixIterator(PrefixIterator::new(iter, db_prefix))
@@ -433,6 +433,7 @@ impl<H: StorageHasher + Default> MerkleTree<H> {
 }
 
 /// The root hash of the merkle tree as bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MerkleRoot(pub Vec<u8>);
 
 impl From<H256> for MerkleRoot {
@@ -144,6 +144,9 @@ impl DB for MockDB {
                             types::decode(bytes).map_err(Error::CodingError)?,
                         );
                     }
+                    "diffs" => {
+                        // ignore the diffs
+                    }
                     _ => unknown_key_error(path)?,
                 },
                 None => unknown_key_error(path)?,
@@ -352,45 +355,61 @@ impl DB for MockDB {
 
     fn read_subspace_val_with_height(
         &self,
-        _key: &Key,
-        _height: BlockHeight,
-        _last_height: BlockHeight,
+        key: &Key,
+        height: BlockHeight,
+        last_height: BlockHeight,
     ) -> Result<Option<Vec<u8>>> {
-        // Mock DB can read only the latest value for now
-        unimplemented!()
+        // Check if the value changed at this height
+        let (old_val_key, new_val_key) = Self::subspace_diff_keys(height, key)?;
+        // If it has a "new" val, it was written at this height
+        if let Some(new_val) = self.0.borrow().get(&new_val_key) {
+            return Ok(Some(new_val.clone()));
+        }
+        // If it has an "old" val, it was deleted at this height
+        if self.0.borrow().contains_key(&old_val_key) {
+            return Ok(None);
+        }
+
+        // If the value didn't change at the given height, we try to look for it
+        // at successor heights, up to the `last_height`
+        let mut raw_height = height.0 + 1;
+        loop {
+            // Try to find the next diff on this key
+            let (old_val_key, new_val_key) =
+                Self::subspace_diff_keys(BlockHeight(raw_height), key)?;
+            // If it has an "old" val, it's the one we're looking for
+            if let Some(old_val) = self.0.borrow().get(&old_val_key) {
+                return Ok(Some(old_val.clone()));
+            }
+            // Check if the value was created at this height instead, which
+            // would mean that it wasn't present before
+            if self.0.borrow().contains_key(&new_val_key) {
+                return Ok(None);
+            }
+            if raw_height >= last_height.0 {
+                // Read from latest height
+                return self.read_subspace_val(key);
+            } else {
+                raw_height += 1
+            }
+        }
     }
 
     fn write_subspace_val(
         &mut self,
-        _height: BlockHeight,
+        height: BlockHeight,
         key: &Key,
         value: impl AsRef<[u8]>,
     ) -> Result<i64> {
-        let value = value.as_ref();
-        let key = Key::parse("subspace").map_err(Error::KeyError)?.join(key);
-        let current_len = value.len() as i64;
-        Ok(
-            match self
-                .0
-                .borrow_mut()
-                .insert(key.to_string(), value.to_owned())
-            {
-                Some(prev_value) => current_len - prev_value.len() as i64,
-                None => current_len,
-            },
-        )
+        self.write_value(height, key, value.as_ref())
     }
 
     fn delete_subspace_val(
         &mut self,
-        _height: BlockHeight,
+        height: BlockHeight,
         key: &Key,
     ) -> Result<i64> {
-        let key = Key::parse("subspace").map_err(Error::KeyError)?.join(key);
-        Ok(match self.0.borrow_mut().remove(&key.to_string()) {
-            Some(value) => value.len() as i64,
-            None => 0,
-        })
+        self.delete_value(height, key)
     }
 
     fn batch() -> Self::WriteBatch {
@@ -406,34 +425,87 @@ impl DB for MockDB {
     fn batch_write_subspace_val(
         &self,
         _batch: &mut Self::WriteBatch,
-        _height: BlockHeight,
+        height: BlockHeight,
         key: &Key,
         value: impl AsRef<[u8]>,
     ) -> Result<i64> {
-        let value = value.as_ref();
-        let key = Key::parse("subspace").map_err(Error::KeyError)?.join(key);
-        let current_len = value.len() as i64;
-        Ok(
-            match self
-                .0
-                .borrow_mut()
-                .insert(key.to_string(), value.to_owned())
-            {
-                Some(prev_value) => current_len - prev_value.len() as i64,
-                None => current_len,
-            },
-        )
+        self.write_value(height, key, value.as_ref())
     }
 
     fn batch_delete_subspace_val(
         &self,
         _batch: &mut Self::WriteBatch,
-        _height: BlockHeight,
+        height: BlockHeight,
         key: &Key,
     ) -> Result<i64> {
-        let key = Key::parse("subspace").map_err(Error::KeyError)?.join(key);
-        Ok(match self.0.borrow_mut().remove(&key.to_string()) {
-            Some(value) => value.len() as i64,
+        self.delete_value(height, key)
+    }
+}
+
+impl MockDB {
+    /// Get the keys of the "old" and "new" diffs of an account subspace key
+    /// changed at the given height, like in the `PersistentStorage`.
+    fn subspace_diff_keys(
+        height: BlockHeight,
+        key: &Key,
+    ) -> Result<(String, String)> {
+        let key_prefix = Key::from(height.to_db_key())
+            .push(&"diffs".to_owned())
+            .map_err(Error::KeyError)?;
+        let old_val_key = key_prefix
+            .push(&"old".to_owned())
+            .map_err(Error::KeyError)?
+            .join(key)
+            .to_string();
+        let new_val_key = key_prefix
+            .push(&"new".to_owned())
+            .map_err(Error::KeyError)?
+            .join(key)
+            .to_string();
+        Ok((old_val_key, new_val_key))
+    }
+
+    /// Write the value with the given height and account subspace key,
+    /// together with its diff. Returns the size difference from previous
+    /// value, if any, or the size of the value otherwise.
+    fn write_value(
+        &self,
+        height: BlockHeight,
+        key: &Key,
+        value: &[u8],
+    ) -> Result<i64> {
+        let (old_val_key, new_val_key) = Self::subspace_diff_keys(height, key)?;
+        let subspace_key =
+            Key::parse("subspace").map_err(Error::KeyError)?.join(key);
+        let current_len = value.len() as i64;
+        let mut db = self.0.borrow_mut();
+        let prev_value = db.insert(subspace_key.to_string(), value.to_owned());
+        db.insert(new_val_key, value.to_owned());
+        Ok(match prev_value {
+            Some(prev_value) => {
+                let size_diff = current_len - prev_value.len() as i64;
+                db.insert(old_val_key, prev_value);
+                size_diff
+            }
+            None => current_len,
+        })
+    }
+
+    /// Delete the value with the given height and account subspace key,
+    /// recording its diff. Returns the size of the removed value, if any, 0
+    /// if no previous value was found.
+    fn delete_value(&self, height: BlockHeight, key: &Key) -> Result<i64> {
+        let (old_val_key, _new_val_key) =
+            Self::subspace_diff_keys(height, key)?;
+        let subspace_key =
+            Key::parse("subspace").map_err(Error::KeyError)?.join(key);
+        let mut db = self.0.borrow_mut();
+        Ok(match db.remove(&subspace_key.to_string()) {
+            Some(value) => {
+                let len = value.len() as i64;
+                db.insert(old_val_key, value);
+                len
+            }
             None => 0,
         })
     }
@@ -449,6 +521,30 @@ impl<'iter> DBIter<'iter> for MockDB {
         MockPrefixIterator::new(MockIterator { prefix, iter }, db_prefix)
     }
 
+    fn iter_prefix_after(
+        &'iter self,
+        prefix: &Key,
+        after: &str,
+    ) -> MockPrefixIterator {
+        let db_prefix = "subspace/".to_owned();
+        let prefix = format!("{}{}", db_prefix, prefix);
+        // The smallest key after the `after` key
+        let start = format!("{}{}\0", db_prefix, after);
+        let iter = self.0.borrow().clone().split_off(&start).into_iter();
+        MockPrefixIterator::new(MockIterator { prefix, iter }, db_prefix)
+    }
+
+    fn iter_old_diffs(
+        &'iter self,
+        height: BlockHeight,
+        prefix: &Key,
+    ) -> MockPrefixIterator {
+        let db_prefix = format!("{}/diffs/old/", height.raw());
+        let prefix = format!("{}{}", db_prefix, prefix);
+        let iter = self.0.borrow().clone().into_iter();
+        MockPrefixIterator::new(MockIterator { prefix, iter }, db_prefix)
+    }
+
     fn iter_results(&'iter self) -> MockPrefixIterator {
         let db_prefix = "results/".to_owned();
         let prefix = "results".to_owned();
@@ -305,6 +305,24 @@ pub trait DBIter<'iter> {
     /// ordered by the storage keys.
     fn iter_prefix(&'iter self, prefix: &Key) -> Self::PrefixIter;
 
+    /// Read account subspace key value pairs with the given prefix from the DB,
+    /// ordered by the storage keys, starting right after the key whose string
+    /// is `after`, to which the iterator seeks directly.
+    fn iter_prefix_after(
+        &'iter self,
+        prefix: &Key,
+        after: &str,
+    ) -> Self::PrefixIter;
+
+    /// Read the old values of the account subspace keys with the given prefix
+    /// that were changed or deleted at the given height from the DB's diffs,
+    /// ordered by the storage keys.
+    fn iter_old_diffs(
+        &'iter self,
+        height: BlockHeight,
+        prefix: &Key,
+    ) -> Self::PrefixIter;
+
     /// Read results subspace key value pairs from the DB
     fn iter_results(&'iter self) -> Self::PrefixIter;
 }
@@ -511,6 +529,27 @@ where
         (self.db.iter_prefix(prefix), prefix.len() as _)
     }
 
+    /// Returns a prefix iterator, ordered by storage keys, starting right after
+    /// the key whose string is `after`, and the gas cost
+    pub fn iter_prefix_after(
+        &self,
+        prefix: &Key,
+        after: &str,
+    ) -> (<D as DBIter<'_>>::PrefixIter, u64) {
+        (self.db.iter_prefix_after(prefix, after), prefix.len() as _)
+    }
+
+    /// Returns a prefix iterator of the old values of the keys that were
+    /// changed or deleted at the given height, ordered by storage keys, and
+    /// the gas cost
+    pub fn iter_old_diffs(
+        &self,
+        height: BlockHeight,
+        prefix: &Key,
+    ) -> (<D as DBIter<'_>>::PrefixIter, u64) {
+        (self.db.iter_old_diffs(height, prefix), prefix.len() as _)
+    }
+
     /// Returns a prefix iterator and the gas cost
     pub fn iter_results(&self) -> (<D as DBIter<'_>>::PrefixIter, u64) {
         (self.db.iter_results(), 0)
@@ -647,6 +686,19 @@ where
         }
     }
 
+    /// Get the root of the merkle tree at the given height, against which
+    /// the existence proofs at the height can be verified
+    pub fn get_merkle_root(&self, height: BlockHeight) -> Result<MerkleRoot> {
+        if height >= self.get_block_height().0 {
+            Ok(self.merkle_root())
+        } else {
+            match self.db.read_merkle_tree_stores(height)? {
+                Some(stores) => Ok(MerkleTree::<H>::new(stores).root()),
+                None => Err(Error::NoMerkleTree { height }),
+            }
+        }
+    }
+
     /// Get the non-existence proof
     #[cfg(any(feature = "tendermint", feature = "tendermint-abcipp"))]
     pub fn get_non_existence_proof(
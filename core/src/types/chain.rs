@@ -192,6 +192,7 @@ pub const DEFAULT_CHAIN_ID: &str = "namada-internal.00000000000000";
     Deserialize,
     BorshSerialize,
     BorshDeserialize,
+    BorshSchema,
     PartialOrd,
     Ord,
     PartialEq,
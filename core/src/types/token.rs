@@ -71,6 +71,13 @@ impl Amount {
         Self { micro: u64::MAX }
     }
 
+    /// Checked addition
+    pub fn checked_add(&self, amount: Amount) -> Option<Self> {
+        self.micro
+            .checked_add(amount.micro)
+            .map(|result| Self { micro: result })
+    }
+
     /// Checked subtraction
     pub fn checked_sub(&self, amount: Amount) -> Option<Self> {
         self.micro
@@ -230,8 +237,9 @@ impl FromStr for Amount {
                 if scale > MAX_DECIMAL_PLACES {
                     return Err(AmountParseError::ScaleTooLarge(scale));
                 }
-                let whole =
-                    decimal * rust_decimal::Decimal::new(SCALE as i64, 0);
+                let whole = decimal
+                    .checked_mul(rust_decimal::Decimal::new(SCALE as i64, 0))
+                    .ok_or(AmountParseError::InvalidRange)?;
                 let micro: u64 =
                     rust_decimal::prelude::ToPrimitive::to_u64(&whole)
                         .ok_or(AmountParseError::InvalidRange)?;
@@ -1,5 +1,5 @@
 //! Namada macros for generating WASM binding code for transactions and validity
-//! predicates.
+//! predicates and for the ledger's query handlers.
 
 #![doc(html_favicon_url = "https://dev.namada.net/master/favicon.png")]
 #![doc(html_logo_url = "https://dev.namada.net/master/rustdoc-logo.png")]
@@ -10,7 +10,7 @@ use proc_macro::TokenStream;
 use proc_macro2::{Span as Span2, TokenStream as TokenStream2};
 use quote::{quote, ToTokens};
 use syn::punctuated::Punctuated;
-use syn::{parse_macro_input, ItemFn, ItemStruct};
+use syn::{parse_macro_input, parse_quote, FnArg, ItemFn, ItemStruct};
 
 /// Generate WASM binding for a transaction main entrypoint function.
 ///
@@ -152,6 +152,172 @@ pub fn validity_predicate(
     TokenStream::from(gen)
 }
 
+/// Generate the signature of a query handler for the `router!` macro from the
+/// `namada` crate's queries.
+///
+/// The handler takes a `ctx: RequestCtx` (without its generics), followed by
+/// its path args, and returns the response's type. The block's value is the
+/// response and errors can be propagated with `?`:
+///
+/// ```compiler_fail
+/// #[router_handler]
+/// fn epoch(ctx: RequestCtx) -> Epoch {
+///     ctx.storage.last_epoch
+/// }
+/// ```
+///
+/// The handler is expanded with the generic storage `D` and hasher `H` params
+/// and it returns a `storage_api::Result` of the response's type. If the
+/// handler also takes a `request: &RequestQuery` after the `ctx`, it's a
+/// handler that uses request (`with_options` in the router) and its response
+/// is borsh-encoded into an `EncodedResponseQuery`.
+#[proc_macro_attribute]
+pub fn router_handler(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    router_handler_inner(input.into()).into()
+}
+
+#[inline]
+fn router_handler_inner(input: TokenStream2) -> TokenStream2 {
+    let mut handler: ItemFn = syn::parse2(input)
+        .expect("Expected a function in the router_handler attribute");
+
+    // the first arg must be the request context
+    let mut args = handler.sig.inputs.iter_mut();
+    match args.next() {
+        Some(FnArg::Typed(ctx)) if is_type(&ctx.ty, "RequestCtx") => {
+            *ctx.ty = parse_quote! {
+                ::namada::ledger::queries::RequestCtx<'_, D, H>
+            };
+        }
+        _ => panic!(
+            "Expected a `RequestCtx` as the first argument of a \
+             router_handler"
+        ),
+    }
+    // the handler uses request, if it's the second arg
+    let with_options = matches!(
+        args.next(),
+        Some(FnArg::Typed(request)) if matches!(
+            &*request.ty,
+            syn::Type::Reference(request) if is_type(&request.elem, "RequestQuery")
+        )
+    );
+
+    let response_ty: syn::Type = match &handler.sig.output {
+        syn::ReturnType::Default => parse_quote! { () },
+        syn::ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    let block = &handler.block;
+    let (output, block): (syn::Type, syn::Block) = if with_options {
+        (
+            parse_quote! {
+                ::namada::ledger::storage_api::Result<
+                    ::namada::ledger::queries::EncodedResponseQuery
+                >
+            },
+            parse_quote! {{
+                let response: #response_ty = #block;
+                let data = ::borsh::BorshSerialize::try_to_vec(&response)
+                    .map_err(::namada::ledger::storage_api::Error::new)?;
+                Ok(::namada::ledger::queries::EncodedResponseQuery {
+                    data,
+                    ..Default::default()
+                })
+            }},
+        )
+    } else {
+        (
+            parse_quote! {
+                ::namada::ledger::storage_api::Result<#response_ty>
+            },
+            parse_quote! {{
+                let response: #response_ty = #block;
+                Ok(response)
+            }},
+        )
+    };
+    handler.sig.output = parse_quote! { -> #output };
+    *handler.block = block;
+
+    handler.sig.generics.params.push(parse_quote! { D });
+    handler.sig.generics.params.push(parse_quote! { H });
+    let where_clause = handler.sig.generics.make_where_clause();
+    where_clause.predicates.push(parse_quote! {
+        D: 'static
+            + ::namada::ledger::storage::DB
+            + for<'iter> ::namada::ledger::storage::DBIter<'iter>
+            + Sync
+    });
+    where_clause.predicates.push(parse_quote! {
+        H: 'static + ::namada::ledger::storage::StorageHasher + Sync
+    });
+
+    handler.into_token_stream()
+}
+
+/// Check if the given type is a path whose last segment is the `name`.
+#[inline]
+fn is_type(ty: &syn::Type, name: &str) -> bool {
+    matches!(
+        ty,
+        syn::Type::Path(path) if path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident == name)
+            .unwrap_or_default()
+    )
+}
+
+/// Define a router with the `router!` macro from the `namada` crate's queries
+/// from an external spec file, e.g. to split a large route tree out of the
+/// source code.
+///
+/// The spec has the same grammar as the `router!` macro's input, i.e. the
+/// router's name followed by its routes, and the given path is relative to
+/// the directory of the crate's manifest:
+///
+/// ```compiler_fail
+/// include_router!("src/ledger/queries/routes.rsrouter");
+/// ```
+///
+/// The spec file is tracked by the compiler, so the router is regenerated when
+/// it changes.
+#[proc_macro]
+pub fn include_router(input: TokenStream) -> TokenStream {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR")
+        .expect("Expected the CARGO_MANIFEST_DIR to be set by cargo");
+    include_router_inner(input.into(), std::path::Path::new(&manifest_dir))
+        .into()
+}
+
+#[inline]
+fn include_router_inner(
+    input: TokenStream2,
+    manifest_dir: &std::path::Path,
+) -> TokenStream2 {
+    let spec_path: syn::LitStr = syn::parse2(input)
+        .expect("Expected a path to a router spec in the include_router macro");
+    let path = manifest_dir.join(spec_path.value());
+    let spec = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!("Failed to read the router spec {}: {}", path.display(), err)
+    });
+    let spec: TokenStream2 = spec.parse().unwrap_or_else(|err| {
+        panic!(
+            "Failed to parse the router spec {}: {}",
+            path.display(),
+            err
+        )
+    });
+    let path = path.to_string_lossy();
+    quote! {
+        // Make the compiler track the spec file
+        const _: &str = include_str!(#path);
+
+        router! { #spec }
+    }
+}
+
 #[proc_macro_derive(StorageKeys)]
 pub fn derive_storage_keys(struct_def: TokenStream) -> TokenStream {
     derive_storage_keys_inner(struct_def.into()).into()
@@ -344,6 +510,84 @@ mod test_proc_macros {
         });
     }
 
+    /// Test that a `router_handler` is expanded with the generic signature.
+    #[test]
+    fn test_router_handler() {
+        let handler: ItemFn = syn::parse2(router_handler_inner(quote! {
+            pub fn sum(ctx: RequestCtx, a: u64, b: u64) -> u64 {
+                a.checked_add(b).ok_or(Overflow)?
+            }
+        }))
+        .expect("Test failed");
+
+        let expected: ItemFn = parse_quote! {
+            pub fn sum<D, H>(
+                ctx: ::namada::ledger::queries::RequestCtx<'_, D, H>,
+                a: u64,
+                b: u64
+            ) -> ::namada::ledger::storage_api::Result<u64>
+            where
+                D: 'static
+                    + ::namada::ledger::storage::DB
+                    + for<'iter> ::namada::ledger::storage::DBIter<'iter>
+                    + Sync,
+                H: 'static + ::namada::ledger::storage::StorageHasher + Sync
+            {
+                let response: u64 = {
+                    a.checked_add(b).ok_or(Overflow)?
+                };
+                Ok(response)
+            }
+        };
+
+        assert_eq!(handler, expected);
+    }
+
+    /// Test that a `router_handler` without a request context is rejected.
+    #[test]
+    #[should_panic(expected = "Expected a `RequestCtx` as the first argument \
+                               of a router_handler")]
+    fn test_router_handler_panics_without_ctx() {
+        router_handler_inner(quote! {
+            fn a(n: u64) -> u64 {
+                n
+            }
+        });
+    }
+
+    /// Test that `include_router` expands a spec file into a `router!`.
+    #[test]
+    fn test_include_router() {
+        let dir = std::env::temp_dir().join("namada_macros_include_router");
+        std::fs::create_dir_all(&dir).expect("Test failed");
+        std::fs::write(
+            dir.join("routes.rsrouter"),
+            "// A comment\nTEST_RPC,\n( \"a\" ) -> u64 = a,\n",
+        )
+        .expect("Test failed");
+
+        let expanded = include_router_inner(quote! { "routes.rsrouter" }, &dir);
+        let path = dir.join("routes.rsrouter");
+        let path = path.to_string_lossy();
+        let expected = quote! {
+            // Make the compiler track the spec file
+            const _: &str = include_str!(#path);
+
+            router! { TEST_RPC, ( "a" ) -> u64 = a, }
+        };
+        assert_eq!(expanded.to_string(), expected.to_string());
+    }
+
+    /// Test that `include_router` rejects a missing spec file.
+    #[test]
+    #[should_panic(expected = "Failed to read the router spec")]
+    fn test_include_router_panics_without_spec() {
+        include_router_inner(
+            quote! { "missing.rsrouter" },
+            std::path::Path::new("/nonexistent"),
+        );
+    }
+
     /// Test that the create storage keys produces
     /// the expected code.
     #[test]
@@ -0,0 +1,528 @@
+//! The path-matching core of the `namada::ledger::queries::router!`:
+//! splitting, normalizing and joining the paths and counting their segments
+//! to bound the patterns that may match them. This crate only depends on
+//! `core` and `alloc`, so that the embedded light clients can resolve the
+//! routes' paths without the rest of the query stack. The `namada` crate
+//! re-exports it as the `namada::ledger::queries::matcher` module.
+
+#![no_std]
+#![warn(missing_docs)]
+#![deny(rustdoc::broken_intra_doc_links)]
+#![deny(rustdoc::private_intra_doc_links)]
+
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Write};
+
+/// The number of bytes reserved for each segment of a path built with
+/// [`format_path`], which fits the typical literal and integer segments, so
+/// that the path's buffer is rarely grown.
+const PATH_SEGMENT_CAPACITY: usize = 16;
+
+/// Split the given path into its segments. The path must start with a
+/// forward slash, otherwise returns `None`. The root path `/` has no segments
+/// and a trailing slash results in an empty last segment, e.g. `/a/` is split
+/// into `["a", ""]`.
+pub fn split_path(path: &str) -> Option<Vec<&str>> {
+    let path = path.strip_prefix('/')?;
+    if path.is_empty() {
+        Some(Vec::new())
+    } else {
+        Some(path.split('/').collect())
+    }
+}
+
+/// Normalize a path so that it has a single leading slash, no trailing slash
+/// and no duplicate slashes, e.g. both `a//b/` and `/a/b/` are normalized into
+/// `/a/b`. An empty path is normalized into the root path `/`. A path that's
+/// already normalized is borrowed as is.
+pub fn normalize_path(path: &str) -> Cow<'_, str> {
+    let is_normalized = path.starts_with('/')
+        && (path.len() == 1 || !path.ends_with('/'))
+        && !path.contains("//");
+    if is_normalized {
+        return Cow::Borrowed(path);
+    }
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Cow::Owned(normalized)
+}
+
+/// Percent-decode a path `segment`, e.g. `%61` into `a`, so that a literal
+/// segment is also matched by its over-encoded forms. A segment without any
+/// `%` is borrowed as is. A segment with a malformed escape (e.g. `%6` or
+/// `%zz`) or whose decoded bytes are not UTF-8 cannot be decoded.
+pub fn percent_decode_segment(segment: &str) -> Option<Cow<'_, str>> {
+    if !segment.contains('%') {
+        return Some(Cow::Borrowed(segment));
+    }
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex_digit =
+                |offset| char::from(*bytes.get(index + offset)?).to_digit(16);
+            let (high, low) = (hex_digit(1)?, hex_digit(2)?);
+            decoded.push((high * 16 + low) as u8);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok().map(Cow::Owned)
+}
+
+/// The encoding of a response's data, which is borsh by default. The JSON
+/// encoding may be selected with a `.json` suffix of a path's last segment
+/// (e.g. `/a.json`) and the borsh encoding with a `.borsh` suffix.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ResponseEncoding {
+    /// The borsh encoding
+    #[default]
+    Borsh,
+    /// The JSON encoding, which is only available for the responses of the
+    /// handlers that don't use request and whose type implements
+    /// `serde::Serialize`
+    Json,
+}
+
+/// Split the `.json` or `.borsh` suffix, if any, from the last segment of a
+/// path without its query string (see [`split_query`]) and get the response
+/// encoding it selects, e.g. `/a.json` into `/a` and
+/// [`ResponseEncoding::Json`]. A path without any suffix is borsh-encoded. An
+/// empty segment with a suffix (e.g. `/a/.json`) is not stripped.
+pub fn split_encoding_suffix(path: &str) -> (&str, ResponseEncoding) {
+    const SUFFIXES: [(&str, ResponseEncoding); 2] = [
+        (".json", ResponseEncoding::Json),
+        (".borsh", ResponseEncoding::Borsh),
+    ];
+    for (suffix, encoding) in SUFFIXES {
+        if let Some(stripped) = path.strip_suffix(suffix) {
+            if !stripped.is_empty() && !stripped.ends_with('/') {
+                return (stripped, encoding);
+            }
+        }
+    }
+    (path, ResponseEncoding::Borsh)
+}
+
+/// Get the response encoding selected by a request `path`'s suffix (see
+/// [`split_encoding_suffix`]).
+pub fn path_encoding(path: &str) -> ResponseEncoding {
+    let (path, _query) = split_query(path);
+    split_encoding_suffix(path).1
+}
+
+/// The prefix of the optional trailing segment of a path that selects the
+/// format of its response's proof, e.g. `/value/key/_proof=compact`
+pub const PROOF_FORMAT_PREFIX: &str = "_proof=";
+
+/// Split the trailing proof format selector segment, if any, from a path
+/// without its query string and encoding suffix (see
+/// [`split_encoding_suffix`]) and get the name of the format it selects, e.g.
+/// `/a/_proof=compact` into `/a` and `compact`. The name is not checked here,
+/// so that the route of a path is found regardless of it.
+pub fn split_proof_format(path: &str) -> (&str, Option<&str>) {
+    match path.rsplit_once('/') {
+        Some((rest, last)) => match last.strip_prefix(PROOF_FORMAT_PREFIX) {
+            Some(format) => (rest, Some(format)),
+            None => (path, None),
+        },
+        None => (path, None),
+    }
+}
+
+/// Get the name of the proof format selected by a request `path`'s trailing
+/// segment (see [`split_proof_format`]), if any.
+pub fn path_proof_format(path: &str) -> Option<&str> {
+    let (path, _query) = split_query(path);
+    let (path, _encoding) = split_encoding_suffix(path);
+    split_proof_format(path).1
+}
+
+/// Split the query string, if any, from a path, e.g. `/search?limit=10` into
+/// `/search` and `limit=10`. The query string's params may supply the
+/// optional args that are absent from the path (see [`query_param`]).
+pub fn split_query(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    }
+}
+
+/// Find the value of the param with the given `name` in a `query` string of
+/// `&`-separated `name=value` pairs, if any. The value is not percent-decoded
+/// and when the param is repeated, the first value is used.
+pub fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(param, _value)| *param == name)
+        .map(|(_param, value)| value)
+}
+
+/// Join the given `prefix` path of a router with the `segments` of a path
+/// under it, skipping the absent ones. The segments are separated by the
+/// router's `separator`, while the prefix is always followed by a slash.
+pub fn join_path<'a>(
+    prefix: &str,
+    separator: char,
+    segments: impl IntoIterator<Item = Option<Cow<'a, str>>>,
+) -> String {
+    let mut path = String::new();
+    write_path(&mut path, prefix, separator, segments);
+    path
+}
+
+/// Join a path like [`join_path`], but from segments that are written into
+/// the path with their `Display`, e.g. the typed args of a route, without
+/// turning each of them into a string of its own first.
+pub fn format_path<'a>(
+    prefix: &str,
+    separator: char,
+    segments: impl IntoIterator<Item = Option<&'a dyn Display>>,
+) -> String {
+    let segments = segments.into_iter();
+    let mut path = String::with_capacity(
+        prefix.len() + segments.size_hint().0 * PATH_SEGMENT_CAPACITY,
+    );
+    write_path(&mut path, prefix, separator, segments);
+    path
+}
+
+/// Append a path joined like [`join_path`] to the `buf`, writing each of the
+/// present `segments` with its `Display` directly into the buffer.
+pub fn write_path<S: Display>(
+    buf: &mut String,
+    prefix: &str,
+    separator: char,
+    segments: impl IntoIterator<Item = Option<S>>,
+) {
+    buf.push_str(prefix);
+    let mut segments = segments.into_iter().flatten();
+    if let Some(first) = segments.next() {
+        // Writing into a `String` cannot fail
+        let _ = write!(buf, "/{first}");
+        for segment in segments {
+            buf.push(separator);
+            let _ = write!(buf, "{segment}");
+        }
+    }
+}
+
+/// Borrow an optional segment of a path as a `dyn Display` for
+/// [`format_path`], so that the segments of different types can be passed
+/// together.
+pub fn display_segment<S: Display>(
+    segment: &Option<S>,
+) -> Option<&dyn Display> {
+    segment.as_ref().map(|segment| segment as &dyn Display)
+}
+
+/// Join the rest of the path `segments` from the `start` index for a
+/// sub-router with a custom `separator`, to be split by the separator instead
+/// of the slashes. Returns `None` for the default slash separator.
+pub fn split_sub_tree(
+    segments: &[&str],
+    start: usize,
+    separator: char,
+) -> Option<(String, char)> {
+    (separator != '/').then(|| {
+        (
+            segments.get(start..).unwrap_or_default().join("/"),
+            separator,
+        )
+    })
+}
+
+/// Split a `path` under a router's `prefix` into the segments to be matched
+/// by the router's patterns, which are separated by the router's `separator`.
+/// Returns `None` if the path is not under the prefix.
+pub fn split_router_path<'a>(
+    path: &'a str,
+    prefix: &str,
+    separator: char,
+) -> Option<Vec<&'a str>> {
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() || rest == "/" {
+        return Some(Vec::new());
+    }
+    Some(rest.strip_prefix('/')?.split(separator).collect())
+}
+
+/// Advance the `start` index of the path `segments` past the segment at the
+/// index, if any. The index never goes past the end of the segments, so it
+/// cannot overflow and slicing the segments from it never panics.
+#[inline]
+pub fn advance_segment(segments: &[&str], start: usize) -> usize {
+    if start < segments.len() {
+        start + 1
+    } else {
+        segments.len()
+    }
+}
+
+/// Advance the `start` index of the path `segments` past the segment at the
+/// index with checked arithmetic. Unlike [`advance_segment`], an index that
+/// is not at a segment, e.g. one past the end passed by a caller, is a
+/// violation for which `None` is returned, so that it can be rejected as a
+/// malformed path.
+#[inline]
+pub fn checked_advance_segment(
+    segments: &[&str],
+    start: usize,
+) -> Option<usize> {
+    if start < segments.len() {
+        start.checked_add(1)
+    } else {
+        None
+    }
+}
+
+/// Count the number of the given path segments, ignoring any trailing empty
+/// segments. For example, both `/a/b` and `/a/b/` have 2 segments and `/`
+/// has none.
+pub fn segment_count(segments: &[&str]) -> usize {
+    segments.len()
+        - segments
+            .iter()
+            .rev()
+            .take_while(|segment| segment.is_empty())
+            .count()
+}
+
+/// Hash a path segment with the 64-bit FNV-1a. It's a `const fn`, so that the
+/// hashes of the patterns' leading literals are computed at compile time and
+/// a pattern that starts with a different literal is skipped by comparing the
+/// hashes only. Equal hashes don't imply equal segments, so the segment must
+/// still be compared with the literal.
+pub const fn segment_hash(segment: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let bytes = segment.as_bytes();
+    let mut hash = OFFSET_BASIS;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(PRIME);
+        i += 1;
+    }
+    hash
+}
+
+/// Count the logical segments of a `path` for a router at the `prefix`, whose
+/// segments under the prefix are separated by the router's `separator`, like
+/// they're counted by the matcher with [`segment_count`]. The leading slash is
+/// optional and a trailing slash or separator is ignored. The segments of the
+/// prefix are counted too, e.g. `/ns/a:b` has 3 segments for a router at
+/// `/ns` with the `:` separator. A path that's not under the prefix is
+/// counted with slash separators.
+pub fn path_segment_count(path: &str, prefix: &str, separator: char) -> usize {
+    let path = if path.starts_with('/') {
+        Cow::Borrowed(path)
+    } else {
+        Cow::Owned(format!("/{path}"))
+    };
+    let count_slashed = |path: &str| {
+        split_path(path)
+            .map(|segments| segment_count(&segments))
+            .unwrap_or_default()
+    };
+    match split_router_path(&path, prefix, separator) {
+        Some(segments) => count_slashed(prefix) + segment_count(&segments),
+        None => count_slashed(&path),
+    }
+}
+
+/// The range of the number of path segments that a pattern can match. This is
+/// used to skip patterns early, without trying to match them segment by
+/// segment, when a path has a segment count that cannot be matched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentBounds {
+    /// Minimum number of segments
+    pub min: usize,
+    /// Maximum number of segments or `None` when unbounded
+    pub max: Option<usize>,
+}
+
+impl SegmentBounds {
+    /// A dynamic argument. It's not counted in the minimum, because an
+    /// argument may be matched against an empty segment at the end of path
+    /// (e.g. an untyped argument) or it may be optional.
+    pub const ARG: Self = Self {
+        min: 0,
+        max: Some(1),
+    };
+    /// An empty pattern
+    pub const EMPTY: Self = Self {
+        min: 0,
+        max: Some(0),
+    };
+    /// A literal segment, which must be present in the path
+    pub const LITERAL: Self = Self {
+        min: 1,
+        max: Some(1),
+    };
+    /// A literal segment between two optional arguments, which may be
+    /// omitted in the query form of a path (see `try_match_segments`)
+    pub const OPTIONAL_LITERAL: Self = Self {
+        min: 0,
+        max: Some(1),
+    };
+    /// A sub-router or an argument that consumes the rest of the path
+    pub const REST: Self = Self { min: 0, max: None };
+
+    /// The bounds of this pattern followed by the `next` pattern.
+    pub const fn then(self, next: Self) -> Self {
+        Self {
+            min: self.min + next.min,
+            max: match (self.max, next.max) {
+                (Some(max), Some(next_max)) => Some(max + next_max),
+                _ => None,
+            },
+        }
+    }
+
+    /// The bounds of either this or the `other` pattern.
+    pub const fn or(self, other: Self) -> Self {
+        Self {
+            min: if self.min < other.min {
+                self.min
+            } else {
+                other.min
+            },
+            max: match (self.max, other.max) {
+                (Some(max), Some(other_max)) => {
+                    Some(if max > other_max { max } else { other_max })
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Check if the given segment count is within the bounds.
+    pub const fn contains(&self, segment_count: usize) -> bool {
+        segment_count >= self.min
+            && match self.max {
+                Some(max) => segment_count <= max,
+                None => true,
+            }
+    }
+}
+
+/// Get the segment of an optional arg at the `start` index of a path's
+/// `segments`. A missing segment at the end of the path is matched as empty,
+/// unless `distinct_empty`, with which only an empty segment that's present
+/// (e.g. after a trailing slash) is matched as empty and a missing one is
+/// `None`.
+pub fn opt_arg_segment<'a>(
+    segments: &[&'a str],
+    start: usize,
+    distinct_empty: bool,
+) -> Option<&'a str> {
+    match segments.get(start) {
+        Some(segment) => Some(segment),
+        None if distinct_empty => None,
+        None => Some(""),
+    }
+}
+
+/// Check if the `rest` of the path segments that remain after a pattern has
+/// been matched is at the end of the path. When `trailing_slash` is allowed,
+/// a single trailing slash (i.e. an empty last segment) is also accepted.
+pub fn is_path_end(rest: &[&str], trailing_slash: bool) -> bool {
+    rest.is_empty() || (trailing_slash && rest == [""])
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::borrow::Cow;
+    use alloc::vec;
+
+    use super::*;
+
+    /// Resolve a request path into its segments, query string and response
+    /// encoding the way the `router!` does before matching its patterns.
+    #[test]
+    fn test_resolve_request_path() {
+        let path = normalize_path("shell//value/a.json/?limit=10");
+        assert_eq!(path, "/shell/value/a.json/?limit=10");
+
+        let (path, query) = split_query("/shell/value/a.json?limit=10");
+        assert_eq!(query_param(query, "limit"), Some("10"));
+        assert_eq!(query_param(query, "offset"), None);
+        let (path, encoding) = split_encoding_suffix(path);
+        assert_eq!(encoding, ResponseEncoding::Json);
+        assert_eq!(path, "/shell/value/a");
+        assert_eq!(
+            path_encoding("/shell/value/a?limit=10"),
+            ResponseEncoding::Borsh
+        );
+
+        let segments = split_path(path).unwrap();
+        assert_eq!(segments, vec!["shell", "value", "a"]);
+        assert_eq!(split_path("no/leading/slash"), None);
+        assert_eq!(split_path("/"), Some(vec![]));
+    }
+
+    /// Bound and walk the segments of a path like the matcher does.
+    #[test]
+    fn test_match_segments() {
+        let segments = split_path("/b/1/").unwrap();
+        assert_eq!(segment_count(&segments), 2);
+
+        // A pattern `( "b" / [n] )` and another `( "b" ) = (sub ...)`
+        let literal_arg = SegmentBounds::LITERAL.then(SegmentBounds::ARG);
+        let sub = SegmentBounds::LITERAL.then(SegmentBounds::REST);
+        assert!(literal_arg.contains(2));
+        assert!(!literal_arg.contains(3));
+        assert!(sub.contains(3));
+        assert_eq!(
+            literal_arg.or(SegmentBounds::EMPTY),
+            SegmentBounds {
+                min: 0,
+                max: Some(2)
+            }
+        );
+
+        let start = advance_segment(&segments, 0);
+        let start = advance_segment(&segments, start);
+        assert!(is_path_end(&segments[start..], true));
+        assert!(!is_path_end(&segments[start..], false));
+        // The index never goes past the end
+        assert_eq!(advance_segment(&segments, segments.len()), segments.len());
+    }
+
+    /// Split and join the paths of a sub-router with a custom separator.
+    #[test]
+    fn test_router_paths() {
+        let segments = split_router_path("/ns/a:b:c", "/ns", ':').unwrap();
+        assert_eq!(segments, vec!["a", "b", "c"]);
+        assert_eq!(split_router_path("/other/a", "/ns", ':'), None);
+        assert_eq!(path_segment_count("ns/a:b:c/", "/ns", ':'), 4);
+
+        let parts = split_path("/ns/a/b").unwrap();
+        assert_eq!(split_sub_tree(&parts, 1, ':'), Some(("a/b".into(), ':')));
+        assert_eq!(split_sub_tree(&parts, 1, '/'), None);
+
+        let path = join_path(
+            "/ns",
+            ':',
+            [Some(Cow::from("a")), None, Some(Cow::from("c"))],
+        );
+        assert_eq!(path, "/ns/a:c");
+        assert_eq!(join_path("/ns", ':', []), "/ns");
+    }
+}
@@ -0,0 +1,100 @@
+#!/usr/bin/env bash
+
+# Measure the compile time of the `namada` crate's unit tests and the size of
+# the code generated by the `router!` macro for the test routers in
+# `shared/src/ledger/queries/router.rs`, which is a large router with every
+# kind of route. This is used to compare the generated code before and after a
+# change to the macro's TT munchers.
+#
+# When given a git ref, the same is measured on a worktree of that ref and a
+# before/after table is printed, e.g. to compare a branch with `main`:
+#
+#   scripts/router-codegen-size.sh --release main
+#
+# The crate is built with CARGO_INCREMENTAL=0 after its dependencies are
+# built, so that only the crate itself is timed.
+#
+# Usage: scripts/router-codegen-size.sh [--release] [<base-ref>]
+
+set -Eeo pipefail
+
+PROFILE_FLAG=""
+if [ "$1" == "--release" ]; then
+    PROFILE_FLAG="--release"
+    shift
+fi
+BASE_REF="$1"
+# The symbols of the test routers' generated methods
+ROUTERS_PATTERN="TestRpc|TestSubRpc|TestSpecRpc|TestCfgRpc"
+
+error_exit()
+{
+    echo "Error: $1" >&2
+    exit 1
+}
+
+command -v nm > /dev/null || error_exit "nm is required"
+command -v size > /dev/null || error_exit "size is required"
+
+# Print the compile time in seconds, the size of the test routers' functions
+# and the text size of the unit tests executable built from the repo in "$1"
+measure()
+{
+    cd "$1"
+    # Build the dependencies first, so that only the crate is timed
+    cargo test -p namada --lib --features testing $PROFILE_FLAG --no-run \
+        > /dev/null 2>&1 || error_exit "Failed to build the unit tests in $1"
+
+    touch shared/src/lib.rs
+    local start end test_bin routers text
+    start=$(date +%s)
+    test_bin=$(CARGO_INCREMENTAL=0 cargo test -p namada --lib \
+        --features testing $PROFILE_FLAG --no-run --message-format=json \
+        2> /dev/null | grep -o '"executable":"[^"]*"' | tail -n 1 \
+        | cut -d '"' -f 4)
+    end=$(date +%s)
+    [ -x "$test_bin" ] || \
+        error_exit "Failed to find the unit tests executable in $1"
+
+    # Sum the sizes of the routers' functions, like `cargo bloat --filter`
+    routers=$(nm -C -S --size-sort "$test_bin" | grep -E "$ROUTERS_PATTERN" \
+        | while read -r _addr size _rest; do echo $((16#$size)); done \
+        | awk '{ total += $1 } END { print total + 0 }')
+    text=$(size "$test_bin" | tail -n 1 | awk '{ print $1 }')
+    echo "$((end - start)) $routers $text"
+}
+
+REPO_DIR=$(git rev-parse --show-toplevel)
+
+if [ -z "$BASE_REF" ]; then
+    read -r TIME ROUTERS TEXT < <(measure "$REPO_DIR")
+    [ -n "$TEXT" ] || error_exit "Failed to measure HEAD"
+    echo "Compile time of the namada crate: $TIME s"
+    echo "Test routers functions: $ROUTERS bytes"
+    echo "Text size of the unit tests executable: $TEXT bytes"
+    exit 0
+fi
+
+BASE_DIR=$(mktemp -d)
+trap 'git -C "$REPO_DIR" worktree remove --force "$BASE_DIR"' EXIT
+git -C "$REPO_DIR" worktree add --detach "$BASE_DIR" "$BASE_REF" \
+    > /dev/null 2>&1 || error_exit "Failed to check out $BASE_REF"
+
+read -r BASE_TIME BASE_ROUTERS BASE_TEXT < <(measure "$BASE_DIR")
+[ -n "$BASE_TEXT" ] || error_exit "Failed to measure $BASE_REF"
+read -r TIME ROUTERS TEXT < <(measure "$REPO_DIR")
+[ -n "$TEXT" ] || error_exit "Failed to measure HEAD"
+
+printf "%-24s %12s %12s %8s\n" "" "$BASE_REF" "HEAD" "change"
+row()
+{
+    awk -v name="$1" -v before="$2" -v after="$3" -v unit="$4" 'BEGIN {
+        change = before ? sprintf("%+.1f%%", (after - before) * 100 / before) \
+            : "-"
+        printf "%-24s %10s %s %10s %s %8s\n", name, before, unit, after, \
+            unit, change
+    }'
+}
+row "build time" "$BASE_TIME" "$TIME" "s"
+row "routers' code" "$BASE_ROUTERS" "$ROUTERS" "B"
+row "text size" "$BASE_TEXT" "$TEXT" "B"
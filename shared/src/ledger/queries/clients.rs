@@ -0,0 +1,2723 @@
+//! [`Client`] implementations that wrap another client to add some
+//! functionality on top of it or that handle the requests in-process. Because
+//! the router's generated methods are generic over the [`Client`], these can
+//! be used in place of any other client.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use super::{
+    router, CachePolicy, Client, EncodedResponseQuery, HealthGate,
+    HealthStatus, HeightSelector, RequestCtx, RequestQuery, ResponseEncoding,
+    Router, RouterError,
+};
+use crate::ledger::storage::{DBIter, StorageHasher, DB};
+use crate::ledger::storage_api;
+use crate::types::storage::BlockHeight;
+
+/// A record of a request sent via the [`LoggingClient`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLog {
+    /// Path of the request
+    pub path: String,
+    /// Data attached to the request, if any
+    pub data: Option<Vec<u8>>,
+    /// The selected block of the request
+    pub height: HeightSelector,
+    /// Whether a proof was requested
+    pub prove: bool,
+    /// Size of the response data in bytes on success, otherwise the error
+    /// message
+    pub response: Result<usize, String>,
+}
+
+/// A sink for the [`RequestLog`]s recorded by the [`LoggingClient`].
+pub trait RequestLogSink {
+    /// Record a request log
+    fn record(&mut self, log: RequestLog);
+}
+
+impl RequestLogSink for Vec<RequestLog> {
+    fn record(&mut self, log: RequestLog) {
+        self.push(log)
+    }
+}
+
+impl<F> RequestLogSink for F
+where
+    F: FnMut(RequestLog),
+{
+    fn record(&mut self, log: RequestLog) {
+        self(log)
+    }
+}
+
+/// A client that records every request together with the response size or
+/// error into a [`RequestLogSink`] and then returns the inner client's
+/// response.
+pub struct LoggingClient<C, S> {
+    inner: C,
+    sink: Mutex<S>,
+}
+
+impl<C, S> LoggingClient<C, S>
+where
+    S: RequestLogSink,
+{
+    /// Wrap the `inner` client to log its requests into the given `sink`.
+    pub fn new(inner: C, sink: S) -> Self {
+        Self {
+            inner,
+            sink: Mutex::new(sink),
+        }
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Take the inner client and the log sink out of this client.
+    pub fn into_parts(self) -> (C, S) {
+        let sink = self
+            .sink
+            .into_inner()
+            .expect("The log sink lock shouldn't be poisoned");
+        (self.inner, sink)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C, S> Client for LoggingClient<C, S>
+where
+    C: Client + Sync,
+    C::Error: Display,
+    S: RequestLogSink + Send,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let result = self
+            .inner
+            .request_at(path.clone(), data.clone(), height, prove, request_id)
+            .await;
+        let log = RequestLog {
+            path,
+            data,
+            height,
+            prove,
+            response: match &result {
+                Ok(response) => Ok(response.data.len()),
+                Err(err) => Err(err.to_string()),
+            },
+        };
+        self.sink
+            .lock()
+            .expect("The log sink lock shouldn't be poisoned")
+            .record(log);
+        result
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// A client that bounds the number of its inner client's requests that are
+/// in-flight at the same time, to avoid overwhelming a node. The requests
+/// above the limit wait for one of the in-flight requests to finish.
+pub struct ThrottledClient<C> {
+    inner: C,
+    semaphore: Semaphore,
+}
+
+impl<C> ThrottledClient<C> {
+    /// Wrap the `inner` client to allow at most `max_in_flight` concurrent
+    /// requests. Panics if `max_in_flight` is zero, because no request could
+    /// ever be sent.
+    pub fn new(inner: C, max_in_flight: usize) -> Self {
+        assert!(
+            max_in_flight > 0,
+            "The maximum number of in-flight requests must be positive"
+        );
+        Self {
+            inner,
+            semaphore: Semaphore::new(max_in_flight),
+        }
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Take the inner client out of this client.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for ThrottledClient<C>
+where
+    C: Client + Sync,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        // The permit is held until the inner request is finished
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("The semaphore is never closed");
+        self.inner
+            .request_at(path, data, height, prove, request_id)
+            .await
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// The path, data, selected block and proof flag of a request cached by the
+/// [`CachingClient`].
+type CacheKey = (String, Option<Vec<u8>>, HeightSelector, bool);
+
+/// A client that caches the successful responses of its inner client to the
+/// requests for the routes of the root router `rpc` with the
+/// [`CachePolicy::Immutable`]. The requests for any other routes, including
+/// the ones without a cache policy, are always sent to the inner client.
+pub struct CachingClient<C, RPC> {
+    inner: C,
+    rpc: RPC,
+    cache: Mutex<HashMap<CacheKey, EncodedResponseQuery>>,
+}
+
+impl<C, RPC> CachingClient<C, RPC>
+where
+    RPC: Router,
+{
+    /// Wrap the `inner` client to cache its responses as declared by the
+    /// routes of the root router `rpc`.
+    pub fn new(inner: C, rpc: RPC) -> Self {
+        Self {
+            inner,
+            rpc,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Get the number of the cached responses
+    pub fn cached_len(&self) -> usize {
+        self.cache
+            .lock()
+            .expect("The response cache lock shouldn't be poisoned")
+            .len()
+    }
+
+    /// Drop all the cached responses
+    pub fn clear(&self) {
+        self.cache
+            .lock()
+            .expect("The response cache lock shouldn't be poisoned")
+            .clear()
+    }
+
+    /// Take the inner client out of this client.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C, RPC> Client for CachingClient<C, RPC>
+where
+    C: Client + Sync,
+    RPC: Router + Sync,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        if self.rpc.cache_policy(&path) != Some(CachePolicy::Immutable) {
+            return self
+                .inner
+                .request_at(path, data, height, prove, request_id)
+                .await;
+        }
+        let key = (path, data, height, prove);
+        if let Some(response) = self
+            .cache
+            .lock()
+            .expect("The response cache lock shouldn't be poisoned")
+            .get(&key)
+        {
+            return Ok(response.clone());
+        }
+        let (path, data, height, prove) = key.clone();
+        let response = self
+            .inner
+            .request_at(path, data, height, prove, request_id)
+            .await?;
+        self.cache
+            .lock()
+            .expect("The response cache lock shouldn't be poisoned")
+            .insert(key, response.clone());
+        Ok(response)
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// A client that multiplexes the requests over several node endpoints. A
+/// request is sent to the endpoints in order of their consecutive failures,
+/// with ties broken by the order in which they were given, until one of them
+/// succeeds. An endpoint's failure count is reset by its first success, so a
+/// recovered endpoint is preferred again. If every endpoint fails, the last
+/// endpoint's error is returned.
+pub struct FailoverClient<C> {
+    endpoints: Vec<C>,
+    failures: Vec<AtomicUsize>,
+}
+
+impl<C> FailoverClient<C> {
+    /// Create a client for the given `endpoints`, which are preferred in the
+    /// given order while they are healthy. Panics if there are no endpoints,
+    /// because no request could ever be served.
+    pub fn new(endpoints: Vec<C>) -> Self {
+        assert!(
+            !endpoints.is_empty(),
+            "The failover client needs at least one endpoint"
+        );
+        let failures = endpoints.iter().map(|_| AtomicUsize::new(0)).collect();
+        Self {
+            endpoints,
+            failures,
+        }
+    }
+
+    /// Get a reference to the endpoints' clients
+    pub fn endpoints(&self) -> &[C] {
+        &self.endpoints
+    }
+
+    /// Get the number of consecutive failures of every endpoint, in the
+    /// order in which the endpoints were given
+    pub fn failure_counts(&self) -> Vec<usize> {
+        self.failures
+            .iter()
+            .map(|failures| failures.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    /// Take the endpoints' clients out of this client.
+    pub fn into_inner(self) -> Vec<C> {
+        self.endpoints
+    }
+
+    /// The indices of the endpoints in the order in which they should be
+    /// tried
+    fn preferred_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.endpoints.len()).collect();
+        // The sort is stable, so the endpoints with the same number of
+        // failures keep their given order
+        order.sort_by_key(|ix| self.failures[*ix].load(Ordering::SeqCst));
+        order
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for FailoverClient<C>
+where
+    C: Client + Sync,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_with_id(path, data, height, prove, None).await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        let mut last_error = None;
+        for ix in self.preferred_order() {
+            let result = self.endpoints[ix]
+                .request_with_id(
+                    path.clone(),
+                    data.clone(),
+                    height,
+                    prove,
+                    request_id.clone(),
+                )
+                .await;
+            match result {
+                Ok(response) => {
+                    self.failures[ix].store(0, Ordering::SeqCst);
+                    return Ok(response);
+                }
+                Err(err) => {
+                    self.failures[ix].fetch_add(1, Ordering::SeqCst);
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(last_error.expect("There is at least one endpoint"))
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        // Every endpoint is closed, even if closing an earlier one failed
+        let mut result = Ok(());
+        for endpoint in self.endpoints {
+            let closed = endpoint.close().await;
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        result
+    }
+}
+
+/// A request and its response recorded by the [`RecordingClient`] into a
+/// [`ReplayLog`].
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ReplayEntry {
+    /// Path of the request
+    pub path: String,
+    /// Data attached to the request, if any
+    pub data: Option<Vec<u8>>,
+    /// The selected block of the request
+    pub height: HeightSelector,
+    /// Whether a proof was requested
+    pub prove: bool,
+    /// The response's data and info on success, otherwise the error message.
+    /// The proofs are not recorded.
+    pub response: Result<(Vec<u8>, String), String>,
+}
+
+impl ReplayEntry {
+    /// Check if this entry was recorded for the given request.
+    fn is_for(
+        &self,
+        path: &str,
+        data: &Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+    ) -> bool {
+        self.path == path
+            && &self.data == data
+            && self.height == height
+            && self.prove == prove
+    }
+}
+
+/// A log of the requests and responses recorded by the [`RecordingClient`],
+/// which can be borsh-encoded (e.g. into a golden file) and served by the
+/// [`ReplayClient`].
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize,
+)]
+pub struct ReplayLog {
+    /// The recorded entries in the order of their requests' responses
+    pub entries: Vec<ReplayEntry>,
+}
+
+/// A client that records every request together with its inner client's
+/// response into a [`ReplayLog`] and then returns the response.
+pub struct RecordingClient<C> {
+    inner: C,
+    log: Mutex<ReplayLog>,
+}
+
+impl<C> RecordingClient<C> {
+    /// Wrap the `inner` client to record its requests and responses.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            log: Mutex::new(ReplayLog::default()),
+        }
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Take the inner client and the recorded log out of this client.
+    pub fn into_parts(self) -> (C, ReplayLog) {
+        let log = self
+            .log
+            .into_inner()
+            .expect("The replay log lock shouldn't be poisoned");
+        (self.inner, log)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for RecordingClient<C>
+where
+    C: Client + Sync,
+    C::Error: Display,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let result = self
+            .inner
+            .request_at(path.clone(), data.clone(), height, prove, request_id)
+            .await;
+        let entry = ReplayEntry {
+            path,
+            data,
+            height,
+            prove,
+            response: match &result {
+                Ok(response) => {
+                    Ok((response.data.clone(), response.info.clone()))
+                }
+                Err(err) => Err(err.to_string()),
+            },
+        };
+        self.log
+            .lock()
+            .expect("The replay log lock shouldn't be poisoned")
+            .entries
+            .push(entry);
+        result
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// A request and its response recorded by the [`HarClient`], with the
+/// request's timing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HarEntry {
+    /// When the request was sent
+    pub started: SystemTime,
+    /// How long the inner client took to respond
+    pub duration: Duration,
+    /// Path of the request
+    pub path: String,
+    /// Size of the data attached to the request in bytes
+    pub request_size: usize,
+    /// The selected block of the request
+    pub height: HeightSelector,
+    /// Whether a proof was requested
+    pub prove: bool,
+    /// The response's encoding selected by the path's suffix
+    pub encoding: ResponseEncoding,
+    /// Size of the response data in bytes on success, otherwise the error
+    /// message
+    pub response: Result<usize, String>,
+}
+
+impl HarEntry {
+    /// The JSON object of this entry in the [`HarLog::to_json`] schema.
+    fn to_json(&self) -> serde_json::Value {
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let time = self.duration.as_secs_f64() * 1000.0;
+        let encoding = match self.encoding {
+            ResponseEncoding::Borsh => "borsh",
+            ResponseEncoding::Json => "json",
+        };
+        let (status, body_size, error) = match &self.response {
+            Ok(size) => ("ok", Some(*size), None),
+            Err(err) => ("error", None, Some(err.as_str())),
+        };
+        serde_json::json!({
+            "startedDateTime": started,
+            "time": time,
+            "request": {
+                "path": self.path,
+                "height": match self.height {
+                    HeightSelector::Latest => serde_json::Value::Null,
+                    HeightSelector::Finalized => "finalized".into(),
+                    HeightSelector::Exact(height) => height.0.into(),
+                },
+                "prove": self.prove,
+                "bodySize": self.request_size,
+            },
+            "response": {
+                "status": status,
+                "encoding": encoding,
+                "bodySize": body_size,
+                "error": error,
+            },
+            "timings": {
+                "wait": time,
+            },
+        })
+    }
+}
+
+/// A log of the requests and responses recorded by the [`HarClient`] for
+/// debugging. Unlike the [`ReplayLog`], it doesn't keep the responses' data
+/// and cannot be replayed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HarLog {
+    /// The recorded entries in the order of their requests' responses
+    pub entries: Vec<HarEntry>,
+}
+
+impl HarLog {
+    /// The version of the JSON schema produced by [`HarLog::to_json`]
+    pub const VERSION: &'static str = "1.0";
+
+    /// Render the log as a HAR-like JSON document:
+    ///
+    /// ```json
+    /// {"log": {
+    ///   "version": "1.0",
+    ///   "creator": {"name": "namada", "version": "<crate version>"},
+    ///   "entries": [{
+    ///     "startedDateTime": <ms since the UNIX epoch>,
+    ///     "time": <ms>,
+    ///     "request": {"path", "height": <height> | null | "finalized",
+    ///                 "prove", "bodySize"},
+    ///     "response": {"status": "ok" | "error", "encoding", "bodySize",
+    ///                  "error"},
+    ///     "timings": {"wait": <ms>}
+    ///   }]
+    /// }}
+    /// ```
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "log": {
+                "version": Self::VERSION,
+                "creator": {
+                    "name": "namada",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "entries": self
+                    .entries
+                    .iter()
+                    .map(HarEntry::to_json)
+                    .collect::<Vec<_>>(),
+            }
+        })
+    }
+}
+
+/// A client that records every request together with its timing and its
+/// inner client's response size or error into a [`HarLog`], which can be
+/// dumped as JSON for debugging, and then returns the response.
+pub struct HarClient<C> {
+    inner: C,
+    log: Mutex<HarLog>,
+}
+
+impl<C> HarClient<C> {
+    /// Wrap the `inner` client to record its requests and responses.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            log: Mutex::new(HarLog::default()),
+        }
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Render the log recorded so far as JSON (see [`HarLog::to_json`]).
+    pub fn to_json(&self) -> serde_json::Value {
+        self.log
+            .lock()
+            .expect("The HAR log lock shouldn't be poisoned")
+            .to_json()
+    }
+
+    /// Take the inner client and the recorded log out of this client.
+    pub fn into_parts(self) -> (C, HarLog) {
+        let log = self
+            .log
+            .into_inner()
+            .expect("The HAR log lock shouldn't be poisoned");
+        (self.inner, log)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for HarClient<C>
+where
+    C: Client + Sync,
+    C::Error: Display,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let request_size = data.as_ref().map(Vec::len).unwrap_or_default();
+        let started = SystemTime::now();
+        let start = Instant::now();
+        let result = self
+            .inner
+            .request_at(path.clone(), data, height, prove, request_id)
+            .await;
+        let entry = HarEntry {
+            started,
+            duration: start.elapsed(),
+            encoding: router::path_encoding(&path),
+            path,
+            request_size,
+            height,
+            prove,
+            response: match &result {
+                Ok(response) => Ok(response.data.len()),
+                Err(err) => Err(err.to_string()),
+            },
+        };
+        self.log
+            .lock()
+            .expect("The HAR log lock shouldn't be poisoned")
+            .entries
+            .push(entry);
+        result
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// A client that requests the responses versioned (see
+/// [`RequestQuery::versioned`]) and checks the schema version byte with which
+/// a node prefixes their data (e.g. the node's
+/// [`SCHEMA_VERSION`](super::SCHEMA_VERSION)). A response with a different
+/// version fails with the [`RouterError::SchemaMismatch`] wrapped in an
+/// `std::io::Error`, instead of being decoded into garbage. The version byte
+/// is stripped from the responses' data that pass the check.
+pub struct VersionedClient<C> {
+    inner: C,
+    schema_version: u8,
+}
+
+impl<C> VersionedClient<C> {
+    /// Wrap the `inner` client to expect the responses with the given
+    /// `schema_version`.
+    pub fn new(inner: C, schema_version: u8) -> Self {
+        Self {
+            inner,
+            schema_version,
+        }
+    }
+
+    /// Get the expected schema version of the responses.
+    pub fn schema_version(&self) -> u8 {
+        self.schema_version
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Take the inner client out of this client.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// Append the request for a versioned response to a `path`.
+    fn versioned_path(path: String) -> String {
+        RequestQuery {
+            path,
+            versioned: true,
+            ..RequestQuery::default()
+        }
+        .abci_path()
+    }
+
+    /// Check and strip the schema version byte of a `response`.
+    fn check_schema_version(
+        &self,
+        mut response: EncodedResponseQuery,
+    ) -> std::io::Result<EncodedResponseQuery> {
+        match response.data.first().copied() {
+            Some(version) if version == self.schema_version => {
+                response.data.remove(0);
+                Ok(response)
+            }
+            found => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                RouterError::SchemaMismatch {
+                    expected: self.schema_version,
+                    found,
+                },
+            )),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for VersionedClient<C>
+where
+    C: Client + Sync,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let path = Self::versioned_path(path);
+        let response = self
+            .inner
+            .request_at(path, data, height, prove, request_id)
+            .await?;
+        Ok(self.check_schema_version(response)?)
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// A client that transparently follows the redirects of the deprecated
+/// routes that have moved to a new path (see
+/// [`ResponseQuery::redirect`](super::ResponseQuery::redirect)), by
+/// requesting the new path with the same options. To prevent loops, a request
+/// that is redirected more than `max_redirects` times fails with the
+/// [`RouterError::TooManyRedirects`] wrapped in an `std::io::Error`.
+pub struct RedirectClient<C> {
+    inner: C,
+    max_redirects: usize,
+}
+
+impl<C> RedirectClient<C> {
+    /// The default maximum number of redirects followed for a request
+    pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+    /// Wrap the `inner` client to follow up to the
+    /// [`RedirectClient::DEFAULT_MAX_REDIRECTS`] redirects for a request.
+    pub fn new(inner: C) -> Self {
+        Self::with_max_redirects(inner, Self::DEFAULT_MAX_REDIRECTS)
+    }
+
+    /// Wrap the `inner` client to follow up to `max_redirects` redirects for
+    /// a request.
+    pub fn with_max_redirects(inner: C, max_redirects: usize) -> Self {
+        Self {
+            inner,
+            max_redirects,
+        }
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Take the inner client out of this client.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for RedirectClient<C>
+where
+    C: Client + Sync,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let mut next_path = path.clone();
+        for _ in 0..=self.max_redirects {
+            let response = self
+                .inner
+                .request_at(
+                    next_path,
+                    data.clone(),
+                    height,
+                    prove,
+                    request_id.clone(),
+                )
+                .await?;
+            match response.redirect_path() {
+                Some(redirect) => next_path = redirect.to_owned(),
+                None => return Ok(response),
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            RouterError::TooManyRedirects {
+                path,
+                limit: self.max_redirects,
+            },
+        )
+        .into())
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// A client that retries the requests that failed transiently, i.e. whose
+/// responses carry a retry hint (see
+/// [`ResponseQuery::retry_after`](super::ResponseQuery::retry_after)), by
+/// waiting for the hinted duration and then requesting the same path with the
+/// same options. A request that is still hinted to be retried after
+/// `max_retries` retries fails with the [`RouterError::RetriesExhausted`]
+/// wrapped in an `std::io::Error`.
+pub struct RetryingClient<C> {
+    inner: C,
+    max_retries: usize,
+}
+
+impl<C> RetryingClient<C> {
+    /// The default maximum number of retries of a request
+    pub const DEFAULT_MAX_RETRIES: usize = 3;
+
+    /// Wrap the `inner` client to retry a request up to the
+    /// [`RetryingClient::DEFAULT_MAX_RETRIES`] times.
+    pub fn new(inner: C) -> Self {
+        Self::with_max_retries(inner, Self::DEFAULT_MAX_RETRIES)
+    }
+
+    /// Wrap the `inner` client to retry a request up to `max_retries` times.
+    pub fn with_max_retries(inner: C, max_retries: usize) -> Self {
+        Self { inner, max_retries }
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Take the inner client out of this client.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for RetryingClient<C>
+where
+    C: Client + Sync,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let mut retries = 0;
+        loop {
+            let response = self
+                .inner
+                .request_at(
+                    path.clone(),
+                    data.clone(),
+                    height,
+                    prove,
+                    request_id.clone(),
+                )
+                .await?;
+            let retry_after = match response.retry_after_hint() {
+                Some(retry_after) => retry_after,
+                None => return Ok(response),
+            };
+            if retries == self.max_retries {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    RouterError::RetriesExhausted {
+                        path,
+                        retries,
+                        retry_after,
+                    },
+                )
+                .into());
+            }
+            tokio::time::sleep(retry_after).await;
+            retries += 1;
+        }
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// A client that sends the requests for the latest block at a pinned block
+/// height instead, so that the results of several queries are mutually
+/// consistent, e.g. at the height fetched once with
+/// [`Client::pin_latest_height`]. A request for an explicit height is sent at
+/// that height.
+pub struct PinnedClient<C> {
+    inner: C,
+    height: BlockHeight,
+}
+
+impl<C> PinnedClient<C> {
+    /// Wrap the `inner` client to send the requests for the latest block at
+    /// the given `height`.
+    pub fn new(inner: C, height: BlockHeight) -> Self {
+        Self { inner, height }
+    }
+
+    /// Wrap the `inner` client to send the requests for the latest block at
+    /// the height of the node's last committed block, as fetched now.
+    pub async fn pin_latest(inner: C) -> Result<Self, C::Error>
+    where
+        C: Client + Sync,
+    {
+        let height = inner.pin_latest_height().await?;
+        Ok(Self::new(inner, height))
+    }
+
+    /// Get the pinned block height
+    pub fn height(&self) -> BlockHeight {
+        self.height
+    }
+
+    /// Get a reference to the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Take the inner client out of this client.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> Client for PinnedClient<C>
+where
+    C: Client + Sync,
+{
+    type Error = C::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, request_id)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        // The height `0` is the latest block. The finalized block is
+        // selected explicitly, so it's not pinned.
+        let height = match height {
+            HeightSelector::Latest | HeightSelector::Exact(BlockHeight(0)) => {
+                HeightSelector::Exact(self.height)
+            }
+            height => height,
+        };
+        self.inner
+            .request_at(path, data, height, prove, request_id)
+            .await
+    }
+
+    async fn pin_latest_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        Ok(self.height)
+    }
+
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.chain_height().await
+    }
+
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.finalized_height().await
+    }
+
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.inner.health().await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        self.inner.health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        self.inner.checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        self.inner.next_request_id()
+    }
+
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.inner.close().await
+    }
+}
+
+/// An object-safe form of a [`Client`], whose errors are converted into an
+/// `std::io::Error`, so that an application can hold a `Box<dyn DynClient>`
+/// and swap the client's backend at run-time (e.g. a Tendermint RPC client
+/// or a `TestClient`) without being generic over it. It's implemented for
+/// every [`Client`] and the `Box<dyn DynClient>` is a [`Client`] itself, with
+/// which the `router!`'s generated methods can be called.
+#[async_trait::async_trait(?Send)]
+pub trait DynClient: Sync {
+    /// Send a query request at the given path with an optional `request_id`
+    /// with the inner client's [`Client::request_with_id`].
+    async fn dyn_request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, std::io::Error>;
+
+    /// The inner client's [`Client::health_gate`]
+    fn dyn_health_gate(&self) -> Option<&HealthGate>;
+
+    /// The inner client's [`Client::checks_height`]
+    fn dyn_checks_height(&self) -> bool;
+
+    /// The inner client's [`Client::next_request_id`]
+    fn dyn_next_request_id(&self) -> Option<String>;
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C> DynClient for C
+where
+    C: Client + Sync,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    async fn dyn_request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, std::io::Error> {
+        self.request_with_id(path, data, height, prove, request_id)
+            .await
+            .map_err(into_io_error)
+    }
+
+    fn dyn_health_gate(&self) -> Option<&HealthGate> {
+        self.health_gate()
+    }
+
+    fn dyn_checks_height(&self) -> bool {
+        self.checks_height()
+    }
+
+    fn dyn_next_request_id(&self) -> Option<String> {
+        self.next_request_id()
+    }
+}
+
+/// Convert a client's error into an `std::io::Error`, which is kept as is if
+/// it's already one (e.g. with the `TimedOut` kind that's classified as a
+/// [`router::QueryError::Timeout`]).
+fn into_io_error<E>(err: E) -> std::io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let err: Box<dyn std::error::Error + Send + Sync> = Box::new(err);
+    match err.downcast::<std::io::Error>() {
+        Ok(io_err) => *io_err,
+        Err(err) => std::io::Error::new(std::io::ErrorKind::Other, err),
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Client for Box<dyn DynClient> {
+    type Error = std::io::Error;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_with_id(path, data, height, prove, None).await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        (**self)
+            .dyn_request_with_id(path, data, height, prove, request_id)
+            .await
+    }
+
+    fn health_gate(&self) -> Option<&HealthGate> {
+        (**self).dyn_health_gate()
+    }
+
+    fn checks_height(&self) -> bool {
+        (**self).dyn_checks_height()
+    }
+
+    fn next_request_id(&self) -> Option<String> {
+        (**self).dyn_next_request_id()
+    }
+}
+
+/// An error of the [`ReplayClient`].
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum ReplayClientError {
+    #[error("No response recorded for the request to {0}")]
+    NotRecorded(String),
+    #[error("Recorded error: {0}")]
+    Recorded(String),
+    #[error("Decoding error: {0}")]
+    Decoding(#[from] std::io::Error),
+}
+
+/// A client that serves the responses from a [`ReplayLog`] recorded by the
+/// [`RecordingClient`], without a live node. A request is served with the
+/// response of the first entry recorded for the same path, data, height and
+/// proof flag.
+pub struct ReplayClient {
+    log: ReplayLog,
+}
+
+impl ReplayClient {
+    /// Create a client that serves the responses from the given `log`.
+    pub fn new(log: ReplayLog) -> Self {
+        Self { log }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Client for ReplayClient {
+    type Error = ReplayClientError;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_at(path, data, height.into(), prove, None)
+            .await
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        _request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let entry = self
+            .log
+            .entries
+            .iter()
+            .find(|entry| entry.is_for(&path, &data, height, prove))
+            .ok_or(ReplayClientError::NotRecorded(path))?;
+        match &entry.response {
+            Ok((data, info)) => Ok(EncodedResponseQuery {
+                data: data.clone(),
+                info: info.clone(),
+                ..Default::default()
+            }),
+            Err(err) => Err(ReplayClientError::Recorded(err.clone())),
+        }
+    }
+}
+
+/// An error of the [`LocalClient`].
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum LocalClientError {
+    #[error("Query failed: {0}")]
+    Query(storage_api::Error),
+    #[error("Decoding error: {0}")]
+    Decoding(#[from] std::io::Error),
+}
+
+/// A client for a node in the same process, which handles the requests by
+/// invoking the root router's `handle` directly with a shared [`RequestCtx`],
+/// without going through ABCI. The simple requests are handled with
+/// [`Router::handle_ref`], so that their typed results are returned without
+/// the borsh encoding and decoding.
+pub struct LocalClient<'shell, RPC, D, H>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    rpc: RPC,
+    ctx: RequestCtx<'shell, D, H>,
+}
+
+impl<'shell, RPC, D, H> LocalClient<'shell, RPC, D, H>
+where
+    RPC: Router,
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    /// Create a client for the given root router `rpc` that handles the
+    /// requests with the `ctx`.
+    pub fn new(rpc: RPC, ctx: RequestCtx<'shell, D, H>) -> Self {
+        Self { rpc, ctx }
+    }
+
+    /// Handle a request at the block selected by the `height_selector`, which
+    /// is resolved by the router's `handle` with the `ctx`.
+    fn handle_request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height_selector: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, LocalClientError> {
+        let request = RequestQuery {
+            data: data.unwrap_or_default(),
+            path,
+            height: BlockHeight::default(),
+            prove,
+            if_none_match: None,
+            request_id,
+            compact_proof: false,
+            in_process: false,
+            caller: None,
+            if_height: None,
+            head: false,
+            height_selector,
+            versioned: false,
+        };
+        self.rpc
+            .handle(self.ctx.clone(), &request)
+            .map_err(LocalClientError::Query)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<'shell, RPC, D, H> Client for LocalClient<'shell, RPC, D, H>
+where
+    RPC: Router + Sync,
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    type Error = LocalClientError;
+
+    async fn request(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_with_id(path, data, height, prove, None).await
+    }
+
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.handle_request(path, data, height.into(), prove, request_id)
+    }
+
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.handle_request(path, data, height, prove, request_id)
+    }
+
+    async fn simple_request_ref(
+        &self,
+        path: String,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        let request = RequestQuery {
+            path,
+            request_id: self.next_request_id(),
+            in_process: true,
+            ..RequestQuery::default()
+        };
+        self.rpc
+            .handle_ref(self.ctx.clone(), &request)
+            .map_err(LocalClientError::Query)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::ledger::queries::router;
+    use crate::ledger::queries::router::test_rpc::{
+        TEST_REDIRECT_RPC, TEST_RETRY_RPC, TEST_RPC,
+    };
+    use crate::ledger::queries::testing::TestClient;
+    use crate::types::token;
+
+    #[tokio::test]
+    async fn test_logging_client() {
+        let client = LoggingClient::new(TestClient::new(TEST_RPC), vec![]);
+
+        let result = TEST_RPC.a(&client).await.unwrap();
+        assert_eq!(result, "a");
+        let balance = token::Amount::from(123_000_000);
+        let result = TEST_RPC.b2i(&client, &balance).await.unwrap();
+        assert_eq!(result, format!("b2i/{balance}"));
+
+        let (_inner, logs) = client.into_parts();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].path, TEST_RPC.a_path());
+        assert_eq!(logs[1].path, TEST_RPC.b2i_path(&balance));
+        for log in logs {
+            assert_eq!(log.data, None);
+            assert_eq!(log.height, HeightSelector::Latest);
+            assert!(!log.prove);
+            assert!(matches!(log.response, Ok(size) if size > 0));
+        }
+
+        // The sink can also be a closure
+        let paths = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let paths = paths.clone();
+            move |log: RequestLog| paths.lock().unwrap().push(log.path)
+        };
+        let client = LoggingClient::new(TestClient::new(TEST_RPC), sink);
+        TEST_RPC.b1(&client).await.unwrap();
+        assert_eq!(*paths.lock().unwrap(), vec![TEST_RPC.b1_path()]);
+    }
+
+    /// A client that tracks the maximum number of its requests that were
+    /// in-flight at the same time. Each request yields to the runtime before
+    /// it's handled, so that the concurrent requests interleave.
+    struct ConcurrencyClient<C> {
+        inner: C,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl<C> Client for ConcurrencyClient<C>
+    where
+        C: Client + Sync,
+    {
+        type Error = C::Error;
+
+        async fn request(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height: Option<BlockHeight>,
+            prove: bool,
+        ) -> Result<EncodedResponseQuery, Self::Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            let result = self.inner.request(path, data, height, prove).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttled_client() {
+        const REQUESTS: usize = 10;
+        let rpc = TEST_RPC;
+
+        let new_client = || ConcurrencyClient {
+            inner: TestClient::new(TEST_RPC),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: AtomicUsize::new(0),
+        };
+
+        // Without the throttling, all the requests are in-flight together
+        let client = new_client();
+        let results =
+            futures::future::join_all((0..REQUESTS).map(|_| rpc.a(&client)))
+                .await;
+        assert!(results.into_iter().all(|result| result.unwrap() == "a"));
+        assert_eq!(client.max_in_flight.load(Ordering::SeqCst), REQUESTS);
+
+        for limit in [1, 3] {
+            let client = ThrottledClient::new(new_client(), limit);
+            let results = futures::future::join_all(
+                (0..REQUESTS).map(|_| rpc.a(&client)),
+            )
+            .await;
+            assert!(results.into_iter().all(|result| result.unwrap() == "a"));
+            let inner = client.into_inner();
+            assert_eq!(inner.max_in_flight.load(Ordering::SeqCst), limit);
+            assert_eq!(inner.in_flight.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_caching_client() {
+        let inner = LoggingClient::new(TestClient::new(TEST_RPC), vec![]);
+        let client = CachingClient::new(inner, TEST_RPC);
+
+        // The `immutable` route is requested only once
+        for _ in 0..3 {
+            assert_eq!(TEST_RPC.b1(&client).await.unwrap(), "b1");
+        }
+        assert_eq!(client.cached_len(), 1);
+
+        // The `no_store` route and a route without a policy are never cached
+        for _ in 0..3 {
+            assert_eq!(TEST_RPC.f(&client).await.unwrap(), "f");
+            assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        }
+        assert_eq!(client.cached_len(), 1);
+
+        let (_inner, logs) = client.into_inner().into_parts();
+        let count =
+            |path: String| logs.iter().filter(|log| log.path == path).count();
+        assert_eq!(count(TEST_RPC.b1_path()), 1);
+        assert_eq!(count(TEST_RPC.f_path()), 3);
+        assert_eq!(count(TEST_RPC.a_path()), 3);
+    }
+
+    /// A client that fails every request with a connection error while it's
+    /// switched off.
+    struct SwitchClient<C> {
+        inner: C,
+        down: AtomicBool,
+    }
+
+    impl<C> SwitchClient<C> {
+        fn new(inner: C, down: bool) -> Self {
+            Self {
+                inner,
+                down: AtomicBool::new(down),
+            }
+        }
+
+        fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl<C> Client for SwitchClient<C>
+    where
+        C: Client + Sync,
+    {
+        type Error = C::Error;
+
+        async fn request(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height: Option<BlockHeight>,
+            prove: bool,
+        ) -> Result<EncodedResponseQuery, Self::Error> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    "The endpoint is down",
+                )
+                .into());
+            }
+            self.inner.request(path, data, height, prove).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_client() {
+        let client = FailoverClient::new(vec![
+            SwitchClient::new(TestClient::new(TEST_RPC), true),
+            SwitchClient::new(TestClient::new(TEST_RPC), false),
+        ]);
+
+        // The first endpoint fails and the second one serves the request
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        assert_eq!(client.failure_counts(), vec![1, 0]);
+
+        // The healthy endpoint is preferred, so the failed one isn't tried
+        let balance = token::Amount::from(123_000_000);
+        let result = TEST_RPC.b2i(&client, &balance).await.unwrap();
+        assert_eq!(result, format!("b2i/{balance}"));
+        assert_eq!(client.failure_counts(), vec![1, 0]);
+
+        // When the endpoints swap, the recovered one serves the request and
+        // its failures are reset
+        client.endpoints()[0].set_down(false);
+        client.endpoints()[1].set_down(true);
+        assert_eq!(TEST_RPC.b1(&client).await.unwrap(), "b1");
+        assert_eq!(client.failure_counts(), vec![0, 1]);
+
+        // If all the endpoints fail, the error is returned
+        client.endpoints()[0].set_down(true);
+        let result = TEST_RPC.a(&client).await;
+        assert!(matches!(
+            result,
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused
+        ));
+        assert_eq!(client.failure_counts(), vec![1, 2]);
+    }
+
+    /// Get the request id with which a request sent via the `client` is
+    /// handled.
+    async fn handled_request_id<C>(client: &C) -> String
+    where
+        C: Client + Sync,
+        C::Error: std::fmt::Debug,
+    {
+        TEST_RPC
+            .request_id(client, None, None, false)
+            .await
+            .unwrap()
+            .data
+    }
+
+    /// Test that the wrapper clients forward the request id, the selected
+    /// block, the health gate and the height checks of their inner client.
+    #[tokio::test]
+    async fn test_wrapper_clients_forward_to_inner() {
+        let new_inner = || {
+            let mut inner = TestClient::new(TEST_RPC);
+            inner.request_id = Some("req-1".to_owned());
+            inner.schema_version = Some(2);
+            inner
+        };
+
+        let client =
+            ThrottledClient::new(LoggingClient::new(new_inner(), vec![]), 1);
+        assert_eq!(client.next_request_id().as_deref(), Some("req-1"));
+        assert_eq!(handled_request_id(&client).await, "req-1");
+        assert_eq!(
+            handled_request_id(&CachingClient::new(new_inner(), TEST_RPC))
+                .await,
+            "req-1"
+        );
+        assert_eq!(
+            handled_request_id(&RecordingClient::new(new_inner())).await,
+            "req-1"
+        );
+        assert_eq!(
+            handled_request_id(&HarClient::new(new_inner())).await,
+            "req-1"
+        );
+        assert_eq!(
+            handled_request_id(&VersionedClient::new(new_inner(), 2)).await,
+            "req-1"
+        );
+        assert_eq!(
+            handled_request_id(&RedirectClient::new(new_inner())).await,
+            "req-1"
+        );
+        assert_eq!(
+            handled_request_id(&RetryingClient::new(new_inner())).await,
+            "req-1"
+        );
+        let height = new_inner().storage.last_height;
+        assert_eq!(
+            handled_request_id(&PinnedClient::new(new_inner(), height)).await,
+            "req-1"
+        );
+
+        // The selected block is logged as it's sent to the inner client
+        let client = client.into_inner();
+        client
+            .raw_abci_query(
+                TEST_RPC.a_path(),
+                None,
+                HeightSelector::Finalized,
+                false,
+            )
+            .await
+            .unwrap();
+        let (mut inner, logs) = client.into_parts();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[1].height, HeightSelector::Finalized);
+
+        // The inner client's health gate and height checks apply
+        inner.catching_up = true;
+        inner.health_gate = Some(HealthGate::new());
+        let client = ThrottledClient::new(LoggingClient::new(inner, vec![]), 1);
+        let err = TEST_RPC.a(&client).await.unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<RouterError>();
+        assert!(matches!(err, Some(RouterError::NodeUnhealthy(_))));
+
+        let mut inner = new_inner();
+        inner.max_height = Some(BlockHeight(10));
+        let client = ThrottledClient::new(LoggingClient::new(inner, vec![]), 1);
+        assert!(client.checks_height());
+        let err = TEST_RPC
+            .c(&client, None, Some(BlockHeight(11)), false)
+            .await
+            .unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<RouterError>();
+        assert!(matches!(err, Some(RouterError::HeightUnavailable { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_versioned_client() {
+        let mut inner = TestClient::new(TEST_RPC);
+        inner.schema_version = Some(2);
+        let client = VersionedClient::new(inner, 2);
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        let balance = token::Amount::from(123_000_000);
+        let result = TEST_RPC.b2i(&client, &balance).await.unwrap();
+        assert_eq!(result, format!("b2i/{balance}"));
+        let response = TEST_RPC.c(&client, None, None, false).await.unwrap();
+        assert_eq!(response.data, "c");
+
+        // A response with another version fails before it's decoded
+        let mut inner = client.into_inner();
+        inner.schema_version = Some(3);
+        let client = VersionedClient::new(inner, 2);
+        let err = TEST_RPC.a(&client).await.unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<RouterError>();
+        assert!(matches!(
+            err,
+            Some(RouterError::SchemaMismatch {
+                expected: 2,
+                found: Some(3)
+            })
+        ));
+        assert!(TEST_RPC.c(&client, None, None, false).await.is_err());
+
+        // A response without any version fails too
+        let mut inner = client.into_inner();
+        inner.schema_version = None;
+        let client = VersionedClient::new(inner, 2);
+        let err = TEST_RPC.a(&client).await.unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<RouterError>();
+        assert!(matches!(
+            err,
+            Some(RouterError::SchemaMismatch { expected: 2, .. })
+        ));
+
+        // A request that's not for a versioned response isn't prefixed
+        let mut inner = client.into_inner();
+        inner.schema_version = Some(2);
+        assert_eq!(TEST_RPC.a(&inner).await.unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_redirect_client() {
+        let client = RedirectClient::new(TestClient::new(TEST_REDIRECT_RPC));
+
+        // The deprecated route's response is the new route's response
+        let response = TEST_REDIRECT_RPC
+            .moved_sub(&client, None, None, false, &7)
+            .await
+            .unwrap();
+        assert_eq!(response.data, "sub_n/7");
+        assert_eq!(response.redirect_path(), None);
+
+        // Without following, the redirect is returned to the caller
+        let response = client
+            .inner()
+            .simple_request_ref(TEST_REDIRECT_RPC.moved_sub_path(&7))
+            .await
+            .unwrap();
+        assert!(response.data.is_empty());
+        assert_eq!(response.redirect_path(), Some("/sub/7"));
+        // The handler's `info` is kept alongside the redirect
+        assert_eq!(response.info, "Moved to /sub/7");
+
+        // A redirect loop is bounded
+        let client = RedirectClient::with_max_redirects(client.into_inner(), 2);
+        let err = TEST_REDIRECT_RPC
+            .redirect_loop(&client, None, None, false)
+            .await
+            .unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<RouterError>();
+        assert!(matches!(
+            err,
+            Some(RouterError::TooManyRedirects { path, limit: 2 })
+                if path == "/loop"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retrying_client() {
+        let client = RetryingClient::new(TestClient::new(TEST_RETRY_RPC));
+
+        // Without retrying, the hint is returned to the caller
+        let response = client
+            .inner()
+            .simple_request_ref(TEST_RETRY_RPC.flaky_path())
+            .await
+            .unwrap();
+        assert!(response.data.is_empty());
+        assert_eq!(
+            response.retry_after_hint(),
+            Some(Duration::from_millis(50))
+        );
+        // The handler's `info` is kept alongside the retry hint
+        assert_eq!(response.info, "Busy");
+        let response = TEST_RETRY_RPC
+            .flaky(client.inner(), None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, 42);
+
+        // The client waits for the hinted duration before the next attempt
+        let start = Instant::now();
+        let response = TEST_RETRY_RPC
+            .flaky(&client, None, None, false)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(response.data, 42);
+        assert_eq!(response.retry_after_hint(), None);
+
+        // The retries are bounded
+        let client = RetryingClient::with_max_retries(client.into_inner(), 2);
+        let err = TEST_RETRY_RPC
+            .busy(&client, None, None, false)
+            .await
+            .unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<RouterError>();
+        assert!(matches!(
+            err,
+            Some(RouterError::RetriesExhausted { path, retries: 2, .. })
+                if path == "/busy"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dyn_client() {
+        let mut clients: Vec<Box<dyn DynClient>> = vec![
+            Box::new(TestClient::new(TEST_RPC)),
+            Box::new(LoggingClient::new(TestClient::new(TEST_RPC), vec![])),
+        ];
+        for client in &clients {
+            let result = TEST_RPC.a(client).await.unwrap();
+            assert_eq!(result, "a");
+            let balance = token::Amount::from(123_000_000);
+            let result = TEST_RPC.b2i(client, &balance).await.unwrap();
+            assert_eq!(result, format!("b2i/{balance}"));
+        }
+
+        // The backend can be swapped at run-time
+        clients[0] =
+            Box::new(RedirectClient::new(TestClient::new(TEST_REDIRECT_RPC)));
+        let response = TEST_REDIRECT_RPC
+            .moved_sub(&clients[0], None, None, false, &7)
+            .await
+            .unwrap();
+        assert_eq!(response.data, "sub_n/7");
+    }
+
+    #[tokio::test]
+    async fn test_pinned_client() {
+        let mut client = TestClient::new(TEST_RPC);
+        client.storage.last_height = BlockHeight(5);
+        let height = client.pin_latest_height().await.unwrap();
+        assert_eq!(height, BlockHeight(5));
+        // Another block is committed after the height is pinned
+        client.storage.last_height = BlockHeight(6);
+        let client = PinnedClient::new(client, height);
+
+        // Both queries are sent at the pinned height
+        let value = TEST_RPC
+            .height_value(&client, None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(value.data, 500);
+        let requested = TEST_RPC
+            .attr_request(&client, None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(requested.data, BlockHeight(5));
+        assert_eq!(client.pin_latest_height().await.unwrap(), height);
+
+        // An explicit height is kept
+        let value = TEST_RPC
+            .height_value(&client, None, Some(BlockHeight(6)), false)
+            .await
+            .unwrap();
+        assert_eq!(value.data, 600);
+
+        // The inner client queries the latest block
+        let value = TEST_RPC
+            .height_value(client.inner(), None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(value.data, 600);
+    }
+
+    /// A client that sets its flag when it's closed.
+    struct ClosingClient<C> {
+        inner: C,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl ClosingClient<TestClient<router::test_rpc::TestRpc>> {
+        fn new() -> (Self, Arc<AtomicBool>) {
+            let closed = Arc::new(AtomicBool::new(false));
+            let client = Self {
+                inner: TestClient::new(TEST_RPC),
+                closed: closed.clone(),
+            };
+            (client, closed)
+        }
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl<C> Client for ClosingClient<C>
+    where
+        C: Client + Sync,
+    {
+        type Error = C::Error;
+
+        async fn request(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height: Option<BlockHeight>,
+            prove: bool,
+        ) -> Result<EncodedResponseQuery, Self::Error> {
+            self.inner.request(path, data, height, prove).await
+        }
+
+        async fn close(self) -> Result<(), Self::Error>
+        where
+            Self: Sized,
+        {
+            self.closed.store(true, Ordering::SeqCst);
+            self.inner.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_close() {
+        // The default `close` does nothing
+        let client = TestClient::new(TEST_RPC);
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        client.close().await.unwrap();
+
+        let (client, closed) = ClosingClient::new();
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        assert!(!closed.load(Ordering::SeqCst));
+        client.close().await.unwrap();
+        assert!(closed.load(Ordering::SeqCst));
+
+        // The wrappers close their inner clients
+        let (inner, closed) = ClosingClient::new();
+        let client = PinnedClient::new(
+            LoggingClient::new(ThrottledClient::new(inner, 1), vec![]),
+            BlockHeight(1),
+        );
+        client.close().await.unwrap();
+        assert!(closed.load(Ordering::SeqCst));
+
+        // All the endpoints of a failover client are closed
+        let ((first, first_closed), (second, second_closed)) =
+            (ClosingClient::new(), ClosingClient::new());
+        let client = FailoverClient::new(vec![first, second]);
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        client.close().await.unwrap();
+        assert!(first_closed.load(Ordering::SeqCst));
+        assert!(second_closed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_recording_and_replay_clients() {
+        let client = RecordingClient::new(TestClient::new(TEST_RPC));
+
+        let a = TEST_RPC.a(&client).await.unwrap();
+        let balance = token::Amount::from(123_000_000);
+        let b2i = TEST_RPC.b2i(&client, &balance).await.unwrap();
+        let (_inner, log) = client.into_parts();
+        assert_eq!(log.entries.len(), 2);
+
+        // The log can be stored and loaded back
+        let bytes = log.try_to_vec().unwrap();
+        let log = ReplayLog::try_from_slice(&bytes).unwrap();
+
+        let client = ReplayClient::new(log);
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), a);
+        assert_eq!(TEST_RPC.b2i(&client, &balance).await.unwrap(), b2i);
+
+        // Requests that weren't recorded cannot be served
+        let other = token::Amount::from(1);
+        let result = TEST_RPC.b2i(&client, &other).await;
+        assert!(matches!(result, Err(ReplayClientError::NotRecorded(_))));
+    }
+
+    #[tokio::test]
+    async fn test_har_client() {
+        let client = HarClient::new(TestClient::new(TEST_RPC));
+
+        TEST_RPC.a(&client).await.unwrap();
+        let balance = token::Amount::from(123_000_000);
+        TEST_RPC.b2i(&client, &balance).await.unwrap();
+
+        let json = client.to_json();
+        assert_eq!(json["log"]["version"], HarLog::VERSION);
+        let entries = json["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["request"]["path"], "/a");
+        assert_eq!(entries[1]["request"]["path"], "/b/2/i/123");
+        for entry in entries {
+            assert!(entry["startedDateTime"].as_u64().unwrap() > 0);
+            assert!(entry["time"].as_f64().unwrap() >= 0.0);
+            assert!(entry["timings"]["wait"].as_f64().is_some());
+            assert_eq!(entry["response"]["status"], "ok");
+            assert_eq!(entry["response"]["encoding"], "borsh");
+            assert!(entry["response"]["bodySize"].as_u64().unwrap() > 0);
+        }
+
+        // The JSON is well-formed
+        let text = json.to_string();
+        let parsed: serde_json::Value = serde_json::from_str(&text).unwrap();
+        // The timings may not round-trip exactly
+        assert_eq!(
+            parsed["log"]["entries"][1]["request"],
+            entries[1]["request"]
+        );
+
+        let (_inner, log) = client.into_parts();
+        assert_eq!(log.entries.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_local_client() {
+        let test_client = TestClient::new(TEST_RPC);
+        let client = LocalClient::new(TEST_RPC, test_client.ctx());
+
+        let a1 = token::Amount::from(345);
+        let a2 = token::Amount::from(123_000);
+        let a3 = token::Amount::from(1_000_999);
+        let result = TEST_RPC.b3(&client, &a1, &a2, &a3).await.unwrap();
+        assert_eq!(result, format!("b3/{a1}/{a2}/{a3}"));
+
+        // Query errors are returned to the client
+        let result = client.simple_request("/invalid".to_owned()).await;
+        assert!(matches!(result, Err(LocalClientError::Query(_))));
+    }
+
+    /// Test that the typed results returned by the [`LocalClient`] without
+    /// encoding are identical to the results of the serialized path.
+    #[tokio::test]
+    async fn test_local_client_typed_responses() {
+        let test_client = TestClient::new(TEST_RPC);
+        let client = LocalClient::new(TEST_RPC, test_client.ctx());
+
+        let a1 = token::Amount::from(345);
+        let a2 = token::Amount::from(123_000);
+        let a3 = token::Amount::from(1_000_999);
+        assert_eq!(
+            TEST_RPC.a(&client).await.unwrap(),
+            TEST_RPC.a(&test_client).await.unwrap()
+        );
+        assert_eq!(
+            TEST_RPC.b3(&client, &a1, &a2, &a3).await.unwrap(),
+            TEST_RPC.b3(&test_client, &a1, &a2, &a3).await.unwrap()
+        );
+        let sum = TEST_RPC.b3sum(&test_client, &a1, &a2, &a3).await.unwrap();
+        assert_eq!(TEST_RPC.b3sum(&client, &a1, &a2, &a3).await.unwrap(), sum);
+        for n in [0, 1] {
+            assert_eq!(
+                TEST_RPC.lookup(&client, &n).await.unwrap(),
+                TEST_RPC.lookup(&test_client, &n).await.unwrap()
+            );
+        }
+        assert_eq!(
+            TEST_RPC.div(&client, &7, &2).await.unwrap(),
+            TEST_RPC.div(&test_client, &7, &2).await.unwrap()
+        );
+        // The routes that use the request are still encoded
+        let response = TEST_RPC.c(&client, None, None, false).await.unwrap();
+        assert_eq!(response.data, "c");
+
+        // The typed value is returned instead of the data
+        let path = TEST_RPC.b3sum_path(&a1, &a2, &a3);
+        let response = client.simple_request_ref(path.clone()).await.unwrap();
+        assert!(response.data.is_empty());
+        let value = response.value.unwrap();
+        assert!(value.take::<String>().is_none());
+        assert_eq!(
+            value.encode().unwrap(),
+            test_client.simple_request(path).await.unwrap()
+        );
+        assert_eq!(value.take::<token::Amount>(), Some(sum));
+        assert!(value.take::<token::Amount>().is_none());
+
+        // A response with another encoding selected by the path's suffix is
+        // encoded
+        let path = format!("{}.json", TEST_RPC.a_path());
+        let response = client.simple_request_ref(path.clone()).await.unwrap();
+        assert!(response.value.is_none());
+        assert_eq!(
+            response.data,
+            test_client.simple_request(path).await.unwrap()
+        );
+    }
+
+    /// A simple benchmark of the [`LocalClient`]'s typed responses compared
+    /// with the same requests handled with the borsh encoding and decoding.
+    /// Run with:
+    /// ```shell
+    /// cargo test --lib bench_local_client_typed_responses -- --ignored --nocapture
+    /// ```
+    #[tokio::test]
+    #[ignore]
+    async fn bench_local_client_typed_responses() {
+        const ITERS: u32 = 100_000;
+        let test_client = TestClient::new(TEST_RPC);
+        let client = LocalClient::new(TEST_RPC, test_client.ctx());
+        let (a1, a2, a3) = (
+            token::Amount::from(345),
+            token::Amount::from(123_000),
+            token::Amount::from(1_000_999),
+        );
+
+        let path = TEST_RPC.b3_path(&a1, &a2, &a3);
+        let start = std::time::Instant::now();
+        for _ in 0..ITERS {
+            let result: String =
+                router::request_simple(&client, path.clone()).await.unwrap();
+            std::hint::black_box(result);
+        }
+        println!("typed: {:?} per request", start.elapsed() / ITERS);
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITERS {
+            let data = client.simple_request(path.clone()).await.unwrap();
+            std::hint::black_box(String::try_from_slice(&data).unwrap());
+        }
+        println!("serialized: {:?} per request", start.elapsed() / ITERS);
+    }
+}
@@ -2,23 +2,49 @@
 //! defined via `router!` macro.
 
 // Re-export to show in rustdoc!
-pub use shell::Shell;
+// The router's path-matching core, which also builds without `std`
+pub use namada_query_matcher as matcher;
+#[cfg(any(test, feature = "testing"))]
+pub use router::SampleArg;
+#[cfg(any(test, feature = "metrics"))]
+pub use router::Timing;
+pub use router::{
+    normalize_path, path_segment_count, CachePolicy, CandidateFailure,
+    CandidateFailureReason, Combined, CompactRequest, Either,
+    Error as RouterError, HandlerConfig, HandlerError, InvalidKeyReason,
+    MatchInfo, Optional, ParamSpec, ParseFailureKind, RateLimit, RateLimiter,
+    ResponseCache, ResponseEncoding, ResponseTransformer, RouteArg,
+    RouteArgDescriptor, RouteDescriptor, RouteInfo, RouteName,
+    ROUTES_META_PATH,
+};
+#[cfg(any(test, feature = "async-client"))]
+pub use router::{request_routes, QueryError};
 use shell::SHELL;
+pub use shell::{Shell, ShellRoute};
 #[cfg(any(test, feature = "async-client"))]
-pub use types::Client;
+pub use types::{BorshSeq, Client, HealthGate, ResponseStream};
 pub use types::{
-    EncodedResponseQuery, RequestCtx, RequestQuery, ResponseQuery, Router,
+    CompactProof, ContinuationToken, EncodedResponseQuery, HealthStatus,
+    HeightSelector, InfoCatalog, InfoMessage, InfoResolver, KeyedProof,
+    NodeInfo, OrderedPrefixValues, PrefixIter, PrefixPage, ProofFormat,
+    ProofFormatParseError, RequestCtx, RequestQuery, ResponseChunk,
+    ResponseMetadata, ResponseQuery, Router, StateDiff, TypedValue, Version,
+    VersionParseError, Warning,
 };
 use vp::VP;
 // Re-export to show in rustdoc!
-pub use vp::{Pos, Vp};
+pub use vp::{Pos, PosRoute, Vp, VpRoute};
 
 use super::storage::{DBIter, StorageHasher, DB};
 use super::storage_api;
 use crate::types::storage::BlockHeight;
 
+#[cfg(any(test, feature = "async-client"))]
+pub mod clients;
+// Public for the paths in the code generated by the exported `router!` macro
+#[doc(hidden)]
 #[macro_use]
-mod router;
+pub mod router;
 mod shell;
 mod types;
 mod vp;
@@ -26,14 +52,23 @@ mod vp;
 // Most commonly expected patterns should be declared first
 router! {RPC,
     // Shell provides storage read access, block metadata and can dry-run a tx
+    #[exclusive]
     ( "shell" ) = (sub SHELL),
 
     // Validity-predicate's specific storage queries
+    #[exclusive]
     ( "vp" ) = (sub VP),
 }
 
+/// The version of the encoding of the node's query responses, which must be
+/// bumped with a breaking change of the encoding. The data of the responses
+/// to the requests for a versioned response (see [`RequestQuery::versioned`])
+/// are prefixed with it.
+pub const SCHEMA_VERSION: u8 = 1;
+
 /// Handle RPC query request in the ledger. On success, returns response with
-/// borsh-encoded data.
+/// borsh-encoded data, prefixed with the [`SCHEMA_VERSION`] if the request
+/// is for a versioned response.
 pub fn handle_path<D, H>(
     ctx: RequestCtx<'_, D, H>,
     request: &RequestQuery,
@@ -42,7 +77,46 @@ where
     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
     H: 'static + StorageHasher + Sync,
 {
-    RPC.handle(ctx, request)
+    if request.versioned {
+        RPC.handle_with_schema_version(ctx, request, SCHEMA_VERSION)
+    } else {
+        RPC.handle(ctx, request)
+    }
+}
+
+/// Query the node's [`NodeInfo`] via the shell's `node_info` route. This can
+/// be used to check that the node is on the expected chain before sending any
+/// other queries.
+#[cfg(any(test, feature = "async-client"))]
+pub async fn node_info<CLIENT>(
+    client: &CLIENT,
+) -> Result<NodeInfo, CLIENT::Error>
+where
+    CLIENT: Client + Sync,
+{
+    RPC.shell().node_info(client).await
+}
+
+/// Query the node's [`HealthStatus`] via the shell's `node_info` route, with
+/// the node assumed not to be catching up. This is the default
+/// [`Client::health`] of the clients that cannot ask the node directly.
+#[cfg(any(test, feature = "async-client"))]
+pub async fn node_health<CLIENT>(
+    client: &CLIENT,
+) -> Result<HealthStatus, CLIENT::Error>
+where
+    CLIENT: Client + Sync,
+{
+    use borsh::BorshDeserialize;
+
+    // Requested directly, because the generated `node_info` method checks the
+    // health itself
+    let data = client.simple_request(RPC.shell().node_info_path()).await?;
+    let info = NodeInfo::try_from_slice(&data[..])?;
+    Ok(HealthStatus {
+        latest_height: info.latest_height,
+        catching_up: false,
+    })
 }
 
 // Handler helpers:
@@ -60,33 +134,120 @@ where
     if request.height != BlockHeight(0)
         && request.height != ctx.storage.last_height
     {
-        return Err(storage_api::Error::new_const(
+        return Err(storage_api::Error::new(RouterError::MethodNotAllowed(
             "This query doesn't support arbitrary block heights, only the \
              latest committed block height ('0' can be used as a special \
              value that means the latest block height)",
-        ));
+        )));
+    }
+    Ok(())
+}
+
+/// For queries that read the storage at a past block height, check that the
+/// height is within the node's configured
+/// [`RequestCtx::storage_read_past_height_limit`], if any, otherwise return an
+/// error.
+pub fn require_past_height_limit<D, H>(
+    ctx: &RequestCtx<'_, D, H>,
+    height: BlockHeight,
+) -> storage_api::Result<()>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    if let Some(past_height_limit) = ctx.storage_read_past_height_limit {
+        if height.0 + past_height_limit < ctx.storage.last_height.0 {
+            return Err(storage_api::Error::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Cannot query more than {past_height_limit} blocks in the \
+                     past (configured via \
+                     `shell.storage_read_past_height_limit`)."
+                ),
+            )));
+        }
     }
     Ok(())
 }
 
+/// Check the request's `If-Height` precondition, if any, i.e. that the latest
+/// committed block height is at least the required height, otherwise return
+/// an error.
+pub fn require_if_height<D, H>(
+    ctx: &RequestCtx<'_, D, H>,
+    request: &RequestQuery,
+) -> storage_api::Result<()>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    match request.if_height {
+        Some(required) if ctx.storage.last_height < required => {
+            Err(storage_api::Error::new(RouterError::PreconditionFailed {
+                required,
+                latest: ctx.storage.last_height,
+            }))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// For queries that do not support proofs, check that proof is not requested,
 /// otherwise return an error.
 pub fn require_no_proof(request: &RequestQuery) -> storage_api::Result<()> {
     if request.prove {
-        return Err(storage_api::Error::new_const(
+        return Err(storage_api::Error::new(RouterError::MethodNotAllowed(
             "This query doesn't support proofs",
-        ));
+        )));
     }
     Ok(())
 }
 
+/// For queries whose responses are encoded by their handlers, check that
+/// another encoding than borsh is not requested with the path's suffix (see
+/// [`router::path_encoding`]), otherwise return an error.
+pub fn require_borsh_encoding(
+    request: &RequestQuery,
+) -> storage_api::Result<()> {
+    if router::path_encoding(&request.path) != ResponseEncoding::Borsh {
+        return Err(storage_api::Error::new(RouterError::MethodNotAllowed(
+            "This query's response cannot be encoded with JSON",
+        )));
+    }
+    Ok(())
+}
+
+/// For the routes with a `#[capability = "..."]` attribute, check that the
+/// node has the `capability` in the context's [`RequestCtx::capabilities`],
+/// otherwise the route is disabled and it fails with
+/// [`RouterError::RouteDisabled`].
+pub fn require_capability<D, H>(
+    ctx: &RequestCtx<'_, D, H>,
+    capability: Option<&'static str>,
+    route: &'static str,
+) -> storage_api::Result<()>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    match capability {
+        Some(capability) if !ctx.capabilities.contains(capability) => {
+            Err(storage_api::Error::new(RouterError::RouteDisabled {
+                route,
+                capability,
+            }))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// For queries that don't use request data, require that there are no data
 /// attached.
 pub fn require_no_data(request: &RequestQuery) -> storage_api::Result<()> {
     if !request.data.is_empty() {
-        return Err(storage_api::Error::new_const(
+        return Err(storage_api::Error::new(RouterError::MethodNotAllowed(
             "This query doesn't accept request data",
-        ));
+        )));
     }
     Ok(())
 }
@@ -141,14 +302,82 @@ pub mod tm {
             .await?;
             use crate::tendermint::abci::Code;
             match response.code {
-                Code::Ok => Ok(EncodedResponseQuery {
-                    data: response.value,
-                    info: response.info,
-                    proof: response.proof,
-                }),
+                Code::Ok => {
+                    let metadata = ResponseMetadata::decode(&response.key)?;
+                    Ok(EncodedResponseQuery {
+                        data: response.value,
+                        info: response.info,
+                        log: response.log.to_string(),
+                        codespace: response.codespace,
+                        proof: response.proof,
+                        ..Default::default()
+                    }
+                    .with_metadata(metadata))
+                }
                 Code::Err(code) => Err(Error::Query(response.info, code)),
             }
         }
+
+        /// The request id is sent in the path's reserved query params (see
+        /// [`RequestQuery::abci_path`])
+        async fn request_with_id(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height: Option<BlockHeight>,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> Result<EncodedResponseQuery, Self::Error> {
+            let path = RequestQuery {
+                path,
+                request_id,
+                ..RequestQuery::default()
+            }
+            .abci_path();
+            self.request(path, data, height, prove).await
+        }
+
+        /// A request for the finalized block is resolved by the node, to
+        /// which the selector is sent in the path's reserved query params
+        /// (see [`RequestQuery::abci_path`])
+        async fn request_at(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height: HeightSelector,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> Result<EncodedResponseQuery, Self::Error>
+        where
+            Self: Sized + Sync,
+        {
+            let exact_height = match height {
+                HeightSelector::Exact(height) => Some(height),
+                HeightSelector::Latest | HeightSelector::Finalized => None,
+            };
+            let path = RequestQuery {
+                path,
+                request_id,
+                height_selector: height,
+                ..RequestQuery::default()
+            }
+            .abci_path();
+            self.request(path, data, exact_height, prove).await
+        }
+
+        /// The health is read from the node's Tendermint status
+        async fn health(&self) -> Result<HealthStatus, Self::Error>
+        where
+            Self: Sized + Sync,
+        {
+            let status = crate::tendermint_rpc::Client::status(self).await?;
+            Ok(HealthStatus {
+                latest_height: BlockHeight(
+                    status.sync_info.latest_block_height.value(),
+                ),
+                catching_up: status.sync_info.catching_up,
+            })
+        }
     }
 }
 
@@ -159,7 +388,9 @@ mod testing {
 
     use super::*;
     use crate::ledger::events::log::EventLog;
+    use crate::ledger::storage::mockdb::MockDB;
     use crate::ledger::storage::testing::TestStorage;
+    use crate::ledger::storage::Sha256Hasher;
     use crate::types::storage::BlockHeight;
     use crate::vm::wasm::{self, TxCache, VpCache};
     use crate::vm::WasmCacheRoAccess;
@@ -183,6 +414,29 @@ mod testing {
         pub vp_cache_dir: TempDir,
         /// tx wasm compilation cache directory
         pub tx_cache_dir: TempDir,
+        /// If set, the client reports this as the chain height and checks the
+        /// requested block heights against it
+        pub max_height: Option<BlockHeight>,
+        /// Whether the client reports the node as catching up, i.e. unhealthy
+        pub catching_up: bool,
+        /// If set, the client checks the node's health before every query
+        pub health_gate: Option<HealthGate>,
+        /// If set, the client attaches this id to the requests sent by the
+        /// `router!`'s generated methods
+        pub request_id: Option<String>,
+        /// Whether the client requests the proofs in the compact format
+        pub compact_proof: bool,
+        /// If set, the requests for a versioned response (see
+        /// [`RequestQuery::versioned`]) are handled with this schema version,
+        /// like by a node with the [`SCHEMA_VERSION`]
+        pub schema_version: Option<u8>,
+        /// If set, the client reports this as the height of the latest
+        /// finalized block, to which the requests for the finalized block are
+        /// resolved, otherwise it's the last committed block
+        pub finalized_height: Option<BlockHeight>,
+        /// The node's config of the query handlers, with which the requests
+        /// are handled
+        pub handler_config: HandlerConfig,
     }
 
     impl<RPC> TestClient<RPC>
@@ -207,6 +461,157 @@ mod testing {
                 tx_wasm_cache: tx_wasm_cache.read_only(),
                 vp_cache_dir,
                 tx_cache_dir,
+                max_height: None,
+                catching_up: false,
+                health_gate: None,
+                request_id: None,
+                compact_proof: false,
+                schema_version: None,
+                finalized_height: None,
+                handler_config: HandlerConfig::default(),
+            }
+        }
+
+        /// Handle a request at the block selected by the `height_selector` by
+        /// invoking the `RPC.handle` directly with the borrowed storage. The
+        /// selector is resolved by the `RPC.handle`.
+        #[allow(dead_code)]
+        fn handle_request(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height_selector: HeightSelector,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> EncodedResponseQuery {
+            let request = self.request_query(
+                path,
+                data,
+                height_selector,
+                prove,
+                request_id,
+            );
+            match self.schema_version {
+                Some(version) if request.versioned => self
+                    .rpc
+                    .handle_with_schema_version(self.ctx(), &request, version),
+                _ => self.rpc.handle(self.ctx(), &request),
+            }
+            .unwrap()
+        }
+
+        /// Handle a request like [`TestClient::handle_request`], but with the
+        /// `RPC.handle_async`, which also handles the routes with `async`
+        /// handlers.
+        #[cfg(any(test, feature = "async-handlers"))]
+        async fn handle_request_async(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height_selector: HeightSelector,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> EncodedResponseQuery {
+            let request = self.request_query(
+                path,
+                data,
+                height_selector,
+                prove,
+                request_id,
+            );
+            match self.schema_version {
+                Some(version) if request.versioned => {
+                    self.rpc
+                        .handle_async_with_schema_version(
+                            self.ctx(),
+                            &request,
+                            version,
+                        )
+                        .await
+                }
+                _ => self.rpc.handle_async(self.ctx(), &request).await,
+            }
+            .unwrap()
+        }
+
+        /// Handle a request with the `RPC.handle_async`, if it's available,
+        /// so that the routes with `async` handlers are handled too, or with
+        /// the `RPC.handle`.
+        async fn respond(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height_selector: HeightSelector,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> EncodedResponseQuery {
+            #[cfg(any(test, feature = "async-handlers"))]
+            {
+                self.handle_request_async(
+                    path,
+                    data,
+                    height_selector,
+                    prove,
+                    request_id,
+                )
+                .await
+            }
+            #[cfg(not(any(test, feature = "async-handlers")))]
+            {
+                self.handle_request(
+                    path,
+                    data,
+                    height_selector,
+                    prove,
+                    request_id,
+                )
+            }
+        }
+
+        /// Build a request like a node from the request's `path`, which may
+        /// carry the request options in its query params.
+        fn request_query(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height_selector: HeightSelector,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> RequestQuery {
+            let mut request = RequestQuery {
+                data: data.unwrap_or_default(),
+                path: String::new(),
+                height: BlockHeight::default(),
+                prove,
+                if_none_match: None,
+                request_id,
+                compact_proof: self.compact_proof,
+                in_process: false,
+                caller: None,
+                if_height: None,
+                head: false,
+                height_selector,
+                versioned: false,
+            };
+            // The options that a client sends in the path, like to a node
+            request.path = request.parse_option_params(path).unwrap();
+            request
+        }
+
+        /// The request context with the borrowed storage, with which the
+        /// client handles its requests.
+        pub fn ctx(&self) -> RequestCtx<'_, MockDB, Sha256Hasher> {
+            RequestCtx {
+                storage: &self.storage,
+                event_log: &self.event_log,
+                vp_wasm_cache: self.vp_wasm_cache.clone(),
+                tx_wasm_cache: self.tx_wasm_cache.clone(),
+                storage_read_past_height_limit: None,
+                version: None,
+                prefix_args: Default::default(),
+                capabilities: Default::default(),
+                finalized_height: self.finalized_height,
+                handler_config: self.handler_config,
             }
         }
     }
@@ -225,25 +630,73 @@ mod testing {
             height: Option<BlockHeight>,
             prove: bool,
         ) -> Result<EncodedResponseQuery, Self::Error> {
-            let data = data.unwrap_or_default();
-            let height = height.unwrap_or_default();
-            // Handle a path by invoking the `RPC.handle` directly with the
-            // borrowed storage
-            let request = RequestQuery {
-                data,
-                path,
-                height,
-                prove,
-            };
-            let ctx = RequestCtx {
-                storage: &self.storage,
-                event_log: &self.event_log,
-                vp_wasm_cache: self.vp_wasm_cache.clone(),
-                tx_wasm_cache: self.tx_wasm_cache.clone(),
-                storage_read_past_height_limit: None,
-            };
-            let response = self.rpc.handle(ctx, &request).unwrap();
-            Ok(response)
+            self.request_with_id(path, data, height, prove, None).await
+        }
+
+        async fn request_with_id(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height: Option<BlockHeight>,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> Result<EncodedResponseQuery, Self::Error> {
+            Ok(self
+                .respond(path, data, height.into(), prove, request_id)
+                .await)
+        }
+
+        async fn request_at(
+            &self,
+            path: String,
+            data: Option<Vec<u8>>,
+            height: HeightSelector,
+            prove: bool,
+            request_id: Option<String>,
+        ) -> Result<EncodedResponseQuery, Self::Error>
+        where
+            Self: Sized + Sync,
+        {
+            Ok(self.respond(path, data, height, prove, request_id).await)
+        }
+
+        async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+        where
+            Self: Sized + Sync,
+        {
+            Ok(self.max_height.unwrap_or(self.storage.last_height))
+        }
+
+        async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+        where
+            Self: Sized + Sync,
+        {
+            Ok(self.finalized_height.unwrap_or(self.storage.last_height))
+        }
+
+        fn checks_height(&self) -> bool {
+            self.max_height.is_some()
+        }
+
+        /// The test client may be used with a router that doesn't have the
+        /// shell's `node_info` route, so the health is read directly from the
+        /// storage instead.
+        async fn health(&self) -> Result<HealthStatus, Self::Error>
+        where
+            Self: Sized + Sync,
+        {
+            Ok(HealthStatus {
+                latest_height: self.storage.last_height,
+                catching_up: self.catching_up,
+            })
+        }
+
+        fn health_gate(&self) -> Option<&HealthGate> {
+            self.health_gate.as_ref()
+        }
+
+        fn next_request_id(&self) -> Option<String> {
+            self.request_id.clone()
         }
     }
 }
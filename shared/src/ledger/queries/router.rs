@@ -5,853 +5,9990 @@
 //! Note that for debugging pattern matching issue, you can uncomment
 //! all the `println!`s in this module.
 
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::hash::Hash as StdHash;
+use std::io::{self, Write};
+#[cfg(any(test, feature = "tooling"))]
+use std::marker::PhantomData;
+use std::str::FromStr;
+#[cfg(any(test, feature = "testing"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+#[cfg(any(test, feature = "tooling"))]
+use borsh::schema::{BorshSchema, BorshSchemaContainer};
+#[cfg(any(test, feature = "async-client"))]
+use borsh::BorshDeserialize;
+/// The future returned by `Router::internal_handle_async`
+#[cfg(any(test, feature = "async-handlers"))]
+pub use futures::future::LocalBoxFuture;
 use thiserror::Error;
 
+pub use super::matcher::{
+    advance_segment, checked_advance_segment, display_segment, format_path,
+    is_path_end, join_path, normalize_path, opt_arg_segment, path_encoding,
+    path_proof_format, path_segment_count, percent_decode_segment, query_param,
+    segment_count, segment_hash, split_encoding_suffix, split_path,
+    split_proof_format, split_query, split_router_path, split_sub_tree,
+    write_path, ResponseEncoding, SegmentBounds, PROOF_FORMAT_PREFIX,
+};
+#[cfg(any(test, feature = "async-client"))]
+use super::{Client, CompactProof, KeyedProof, Warning};
+use super::{
+    EncodedResponseQuery, HealthStatus, HeightSelector, RequestQuery,
+    ResponseChunk, ResponseQuery, Version,
+};
+use crate::ledger::storage_api;
+use crate::types::hash::Hash;
+use crate::types::storage::BlockHeight;
+
+/// The crates used by the code generated by `router!` and its helper macros,
+/// so that a router can be declared in a crate that doesn't depend on them.
+#[doc(hidden)]
+pub mod __private {
+    pub use borsh;
+    #[cfg(any(
+        test,
+        feature = "async-client",
+        feature = "async-handlers",
+        feature = "testing"
+    ))]
+    pub use futures;
+    pub use once_cell;
+    pub use paste;
+    pub use regex;
+    pub use serde_json;
+}
+
+/// The maximum length of a request path in bytes. Longer paths are rejected
+/// before they are matched.
+pub const MAX_PATH_LENGTH: usize = 4096;
+
 /// Router error.
 #[allow(missing_docs)]
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Found no matching pattern for the given path {0}")]
     WrongPath(String),
+    #[error("The path {0} is malformed, it must start with a forward slash")]
+    MalformedPath(String),
+    #[error(
+        "The path is {length} bytes long, which exceeds the maximum of {max} \
+         bytes"
+    )]
+    PathTooLong { length: usize, max: usize },
+    #[error(
+        "Failed to parse the path segment \"{segment}\" as {type_name}: {kind}"
+    )]
+    ParseFailed {
+        segment: String,
+        type_name: &'static str,
+        kind: ParseFailureKind,
+    },
+    #[error("{0}")]
+    MethodNotAllowed(&'static str),
+    #[error(
+        "The requested block height {requested} is not available, the latest \
+         block height is {latest}"
+    )]
+    HeightUnavailable {
+        requested: BlockHeight,
+        latest: BlockHeight,
+    },
+    #[error(
+        "The request requires the latest block height to be at least \
+         {required}, but it's {latest}"
+    )]
+    PreconditionFailed {
+        required: BlockHeight,
+        latest: BlockHeight,
+    },
+    #[error("The arg {arg} = {value} doesn't satisfy `{predicate}`")]
+    ValidationFailed {
+        arg: &'static str,
+        value: String,
+        predicate: &'static str,
+    },
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("The node is unhealthy: {0}")]
+    NodeUnhealthy(HealthStatus),
+    #[error("There is no response chunk at index {0}")]
+    ChunkOutOfRange(u32),
+    #[error(
+        "The response's schema version {} doesn't match the expected version \
+         {expected}",
+        found.map_or_else(|| "(none)".to_owned(), |found| found.to_string())
+    )]
+    SchemaMismatch { expected: u8, found: Option<u8> },
+    #[error(
+        "Too many requests to the route {route}, retry after {retry_after:?}"
+    )]
+    RateLimited {
+        route: &'static str,
+        retry_after: Duration,
+    },
+    #[error(
+        "Found no matching pattern for the path, the closest candidates: {}",
+        itertools::join(candidates, "; ")
+    )]
+    NoMatch { candidates: Vec<CandidateFailure> },
+    #[error("The handler of the route {route} panicked")]
+    HandlerPanicked { route: &'static str },
+    #[error(
+        "The handler of the route {route} is async, the request must be \
+         handled with `Router::handle_async`"
+    )]
+    AsyncHandler { route: &'static str },
+    #[error("Found no route with the compact id {0}")]
+    UnknownCompactRoute(u16),
+    #[error(
+        "Too many redirects (over {limit}) when requesting the path {path}"
+    )]
+    TooManyRedirects { path: String, limit: usize },
+    #[error(
+        "The path {path} is still unavailable after {retries} retries, retry \
+         after {retry_after:?}"
+    )]
+    RetriesExhausted {
+        path: String,
+        retries: usize,
+        retry_after: Duration,
+    },
+    #[error(
+        "The response is {size} bytes long, which exceeds the route's limit \
+         of {limit} bytes"
+    )]
+    ResponseTooLarge { size: usize, limit: usize },
+    #[error(
+        "The route {route} is disabled, because the node doesn't have the \
+         capability {capability}"
+    )]
+    RouteDisabled {
+        route: &'static str,
+        capability: &'static str,
+    },
+    #[error("The storage key \"{key}\" is invalid: {reason}")]
+    InvalidKey {
+        key: String,
+        reason: InvalidKeyReason,
+    },
 }
 
-/// Find the index of a next forward slash after the given `start` index in the
-/// path. When there are no more slashes, returns the index after the end of the
-/// path.
-///
-/// # Panics
-/// The given `start` must be < `path.len()`.
-pub fn find_next_slash_index(path: &str, start: usize) -> usize {
-    path[start..]
-        .find('/')
-        // Offset by the starting position
-        .map(|i| start + i)
-        // If not found, go to the end of path
-        .unwrap_or(path.len())
+/// Split a request path into its segments like [`split_path`], but first
+/// check that the path is not longer than the [`MAX_PATH_LENGTH`]. The path's
+/// query string, encoding suffix and proof format selector, if any, are not a
+/// part of the segments (see [`split_query`], [`split_encoding_suffix`] and
+/// [`split_proof_format`]).
+pub fn split_request_path(path: &str) -> Result<Vec<&str>, Error> {
+    if path.len() > MAX_PATH_LENGTH {
+        return Err(Error::PathTooLong {
+            length: path.len(),
+            max: MAX_PATH_LENGTH,
+        });
+    }
+    let (segments, _query) = split_query(path);
+    let (segments, _encoding) = split_encoding_suffix(segments);
+    let (segments, _proof_format) = split_proof_format(segments);
+    split_path(segments).ok_or_else(|| Error::MalformedPath(path.to_owned()))
 }
 
-/// Invoke the sub-handler or call the handler function with the matched
-/// arguments generated by `try_match_segments`.
-macro_rules! handle_match {
-    // Nested router
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident,
-        (sub $router:tt), ( $( $matched_args:ident, )* ),
-    ) => {
-        // not used anymore - silence the warning
-        let _ = $end;
-        // Undo last '/' advance, the next pattern has to start with `/`.
-        // This cannot underflow because path cannot be empty and must start
-        // with `/`
-        $start -= 1;
-        // Invoke `handle` on the sub router
-        return $router.internal_handle($ctx, $request, $start)
-    };
+/// Restore the trailing slash of a request `path`, which is removed by
+/// [`normalize_path`], as an empty last segment of the normalized path's
+/// `segments`, so that it can be matched by a route with the
+/// `#[empty_segment]` attribute.
+pub fn keep_trailing_slash(path: &str, segments: &mut Vec<&str>) {
+    let (path, _query) = split_query(path);
+    let (path, _encoding) = split_encoding_suffix(path);
+    let (path, _proof_format) = split_proof_format(path);
+    if path.ends_with('/') && !segments.is_empty() {
+        segments.push("");
+    }
+}
 
-    // Handler function that uses a request (`with_options`)
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident,
-        (with_options $handle:tt), ( $( $matched_args:ident, )* ),
-    ) => {
-        // check that we're at the end of the path - trailing slash is optional
-        if !($end == $request.path.len() ||
-            // ignore trailing slashes
-            $end == $request.path.len() - 1 && &$request.path[$end..] == "/") {
-                // we're not at the end, no match
-                println!("Not fully matched");
-                break
+/// Match the optional leading version segment at the `start` of a path's
+/// `segments` for a `versioned` root router. Returns the version, if any.
+pub fn match_version(
+    versioned: bool,
+    segments: &[&str],
+    start: usize,
+) -> Option<Version> {
+    if !versioned {
+        return None;
+    }
+    segments.get(start)?.parse().ok()
+}
+
+/// The reasons why the patterns of a `router!` don't match a path, as
+/// recorded by the generated matching code at the index of the path's segment
+/// at which a pattern fails. The router reports the first failure when no
+/// pattern matches a path with an `Option<Error>` and the router's `peek`
+/// collects the failures of every pattern with the [`CandidateFailures`].
+pub trait MatchFailures {
+    /// The `segment` at the `index` couldn't be parsed into an argument of
+    /// the given type.
+    fn parse_failed(
+        &mut self,
+        index: usize,
+        segment: &str,
+        type_name: &'static str,
+        kind: ParseFailureKind,
+    );
+
+    /// The typed arg at the `index` doesn't satisfy its `where` predicate.
+    fn validation_failed(
+        &mut self,
+        index: usize,
+        arg: &'static str,
+        value: String,
+        predicate: &'static str,
+    );
+
+    /// The segment at the `index` doesn't match a pattern's literal or regex
+    /// or the path ends before it.
+    fn mismatched(&mut self, _index: usize) {}
+
+    /// Start matching a sub-pattern with the given template.
+    fn enter_pattern(&mut self, _template: &'static str) {}
+
+    /// Stop matching the last entered sub-pattern.
+    fn exit_pattern(&mut self) {}
+}
+
+/// Only the first parse or validation failure is reported as the error.
+impl MatchFailures for Option<Error> {
+    fn parse_failed(
+        &mut self,
+        _index: usize,
+        segment: &str,
+        type_name: &'static str,
+        kind: ParseFailureKind,
+    ) {
+        if self.is_none() {
+            *self = Some(Error::ParseFailed {
+                segment: segment.to_owned(),
+                type_name,
+                kind,
+            });
         }
-        let result = $handle($ctx, $request, $( $matched_args ),* )?;
-        // The handle must take care of encoding if needed and return `Vec<u8>`.
-        // This is because for `storage_value` the bytes are returned verbatim
-        // as read from storage.
-        return Ok(result);
-    };
+    }
 
-    // Handler function that doesn't use the request, just the path args, if any
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident,
-        $handle:tt, ( $( $matched_args:ident, )* ),
-    ) => {
-        // check that we're at the end of the path - trailing slash is optional
-        if !($end == $request.path.len() ||
-            // ignore trailing slashes
-            $end == $request.path.len() - 1 && &$request.path[$end..] == "/") {
-                // we're not at the end, no match
-                // println!("Not fully matched");
-                break
+    fn validation_failed(
+        &mut self,
+        _index: usize,
+        arg: &'static str,
+        value: String,
+        predicate: &'static str,
+    ) {
+        if self.is_none() {
+            *self = Some(Error::ValidationFailed {
+                arg,
+                value,
+                predicate,
+            });
         }
-        // Check that the request is not sent with unsupported non-default
-        $crate::ledger::queries::require_latest_height(&$ctx, $request)?;
-        $crate::ledger::queries::require_no_proof($request)?;
-        $crate::ledger::queries::require_no_data($request)?;
+    }
+}
 
-        // If you get a compile error from here with `expected function, found
-        // queries::Storage`, you're probably missing the marker `(sub _)`
-        let data = $handle($ctx, $( $matched_args ),* )?;
-        // Encode the returned data with borsh
-        let data = borsh::BorshSerialize::try_to_vec(&data).into_storage_result()?;
-        return Ok($crate::ledger::queries::EncodedResponseQuery {
-            data,
-            info: Default::default(),
-            proof: None,
-        });
-    };
+/// Record that the path `segment` at the `index` couldn't be parsed into an
+/// argument of the given type. With an `Option<Error>`, it's recorded unless
+/// some earlier failure is already recorded. This is used by the `router!` to
+/// report the failure when no pattern matches a path.
+pub fn record_parse_failure(
+    failure: &mut impl MatchFailures,
+    index: usize,
+    segment: &str,
+    type_name: &'static str,
+    kind: ParseFailureKind,
+) {
+    failure.parse_failed(index, segment, type_name, kind);
 }
 
-/// Using TT muncher pattern on the `$tail` pattern, this macro recursively
-/// generates path matching logic that `break`s if some parts are unmatched.
-macro_rules! try_match_segments {
-    // sub-pattern handle - this should only be invoked if the current
-    // $pattern is already matched
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident,
-        { $( $sub_pattern:tt $( -> $_sub_return_ty:path )? = $handle:tt, )* },
-        $matched_args:tt,
-        ()
-    ) => {
-        // Try to match each sub-patten
-        $(
-            // This loop never repeats, it's only used for a breaking
-            // mechanism when a $pattern is not matched to skip to the
-            // next one, if any
-            loop {
-                #[allow(unused_mut)]
-                let mut $start = $start;
-                let mut $end = $end;
-                // Try to match, parse args and invoke $handle, will
-                // break the `loop` not matched
-                try_match_segments!($ctx, $request, $start, $end,
-                    $handle, $matched_args, $sub_pattern
-                );
-            }
-        )*
-    };
+/// Record that a typed arg at the `index` of a pattern doesn't satisfy its
+/// `where` predicate as the reason why the path is not matched. With an
+/// `Option<Error>`, it's recorded unless an earlier failure is already
+/// recorded.
+pub fn record_validation_failure(
+    failure: &mut impl MatchFailures,
+    index: usize,
+    arg: &'static str,
+    value: String,
+    predicate: &'static str,
+) {
+    failure.validation_failed(index, arg, value, predicate);
+}
 
-    // Terminal tail call, invoked after when all the args in the current
-    // pattern are matched and the $handle is not sub-pattern
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident, $handle:tt,
-        ( $( $matched_args:ident, )* ),
-        ()
-    ) => {
-        handle_match!($ctx, $request, $start, $end, $handle, ( $( $matched_args, )* ), );
-    };
+/// Record that the path's segment at the `index` doesn't match a pattern's
+/// literal or regex, or that the path ends before it.
+pub fn record_mismatch(failure: &mut impl MatchFailures, index: usize) {
+    failure.mismatched(index);
+}
 
-    // Try to match an untyped argument, declares the expected $arg as &str
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident, $handle:ident,
-        ( $( $matched_args:ident, )* ),
-        (
-            [$arg:ident]
-            $( / $( $tail:tt)/ * )?
-        )
-    ) => {
-        let $arg = &$request.path[$start..$end];
-        // Advanced index past the matched arg
-        $start = $end;
-        // advance past next '/', if any
-        if $start + 1 < $request.path.len() {
-            $start += 1;
+/// Why a candidate pattern doesn't match a path, in a [`CandidateFailure`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CandidateFailureReason {
+    /// The segment doesn't match the pattern's literal or regex, or the path
+    /// ends before it
+    Mismatch,
+    /// The segment couldn't be parsed into a typed arg
+    ParseFailed {
+        type_name: &'static str,
+        kind: ParseFailureKind,
+    },
+    /// The typed arg doesn't satisfy its `where` predicate
+    ValidationFailed { predicate: &'static str },
+    /// The whole pattern is matched, but the path has more segments, starting
+    /// with the segment
+    TrailingSegments,
+}
+
+impl Display for CandidateFailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mismatch => write!(f, "mismatched segment"),
+            Self::ParseFailed { type_name, kind } => {
+                write!(f, "{kind} of type {type_name}")
+            }
+            Self::ValidationFailed { predicate } => {
+                write!(f, "violated predicate {predicate}")
+            }
+            Self::TrailingSegments => write!(f, "trailing segments"),
         }
-        $end = find_next_slash_index(&$request.path, $start);
-        try_match_segments!($ctx, $request, $start, $end, $handle,
-            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
-    };
+    }
+}
 
-    // Try to match and parse a typed argument like the case below, but with
-    // the argument optional.
-    // Declares the expected $arg into type $t, if it can be parsed.
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident, $handle:tt,
-        ( $( $matched_args:ident, )* ),
-        (
-            [$arg:ident : opt $arg_ty:ty]
-            $( / $( $tail:tt)/ * )?
-        )
-    ) => {
-        let $arg: Option<$arg_ty> = match $request.path[$start..$end].parse::<$arg_ty>() {
-            Ok(parsed) => {
-                // Only advance if optional argument is present, otherwise stay
-                // in the same position for the next match, if any.
+/// A pattern that doesn't match a path, as reported in [`Error::NoMatch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CandidateFailure {
+    /// The template of the pattern (see [`RouteInfo::template`]). The
+    /// pattern of a sub-pattern or a sub-router's route is joined with its
+    /// parent pattern.
+    pub pattern: String,
+    /// The index of the path's segment at which the pattern fails
+    pub segment: usize,
+    /// Why the pattern fails at the segment
+    pub reason: CandidateFailureReason,
+}
 
-                $start = $end;
-                // advance past next '/', if any
-                if $start + 1 < $request.path.len() {
-                    $start += 1;
-                }
-                $end = find_next_slash_index(&$request.path, $start);
+impl Display for CandidateFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} failed at segment {}: {}",
+            self.pattern, self.segment, self.reason
+        )
+    }
+}
 
-                Some(parsed)
-            },
-            Err(_) =>
-            {
-                // If arg cannot be parsed, ignore it because it's optional
-                None
-            }
-        };
-        try_match_segments!($ctx, $request, $start, $end, $handle,
-            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
-    };
+/// The failures of the candidate patterns of a router's `peek`, each one at
+/// the furthest segment at which the pattern fails. The failures of a
+/// pattern's sub-patterns are recorded separately.
+#[derive(Debug, Default)]
+pub struct CandidateFailures {
+    /// The templates of the entered pattern and its sub-patterns
+    scope: Vec<&'static str>,
+    /// The recorded failures
+    failures: Vec<CandidateFailure>,
+}
 
-    // Special case of the typed argument pattern below. When there are no more
-    // args in the tail and the handle isn't a sub-router (its handler is
-    // ident), we try to match the rest of the path till the end.
-    //
-    // This is specifically needed for storage methods, which have
-    // `storage::Key` param that includes path-like slashes.
-    //
-    // Try to match and parse a typed argument, declares the expected $arg into
-    // type $t, if it can be parsed
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident,
-        $handle:ident,
-        ( $( $matched_args:ident, )* ),
-        (
-            [$arg:ident : $arg_ty:ty]
-        )
-    ) => {
-        let $arg: $arg_ty;
-        $end = $request.path.len();
-        match $request.path[$start..$end].parse::<$arg_ty>() {
-            Ok(parsed) => {
-                // println!("Parsed {}", parsed);
-                $arg = parsed
-            },
-            Err(_) =>
-            {
-                // println!("Cannot parse {} from {}", stringify!($arg_ty), &$request.path[$start..$end]);
-                // If arg cannot be parsed, try to skip to next pattern
-                break
-            }
+impl CandidateFailures {
+    /// Start collecting the failures of a router's pattern with the given
+    /// template.
+    pub fn new(template: &'static str) -> Self {
+        Self {
+            scope: vec![template],
+            failures: vec![],
         }
-        // Invoke the terminal pattern
-        try_match_segments!($ctx, $request, $start, $end, $handle,
-            ( $( $matched_args, )* $arg, ), () );
-    };
+    }
 
-    // One more special case of the typed argument pattern below for a handler
-    // `with_options`, where we try to match the rest of the path till the end.
-    //
-    // This is specifically needed for storage methods, which have
-    // `storage::Key` param that includes path-like slashes.
-    //
-    // Try to match and parse a typed argument, declares the expected $arg into
-    // type $t, if it can be parsed
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident,
-        (with_options $handle:ident),
-        ( $( $matched_args:ident, )* ),
-        (
-            [$arg:ident : $arg_ty:ty]
-        )
-    ) => {
-        let $arg: $arg_ty;
-        $end = $request.path.len();
-        match $request.path[$start..$end].parse::<$arg_ty>() {
-            Ok(parsed) => {
-                // println!("Parsed {}", parsed);
-                $arg = parsed
-            },
-            Err(_) =>
-            {
-                println!("Cannot parse {} from {}", stringify!($arg_ty), &$request.path[$start..$end]);
-                // If arg cannot be parsed, try to skip to next pattern
-                break
+    /// Record the failures of the sub-router's routes reached from the
+    /// entered pattern.
+    pub fn sub_router_failed(&mut self, failures: Vec<CandidateFailure>) {
+        let prefix = self.scope.concat();
+        self.failures.extend(failures.into_iter().map(|failure| {
+            CandidateFailure {
+                pattern: format!("{prefix}{}", failure.pattern),
+                ..failure
             }
-        }
-        // Invoke the terminal pattern
-        try_match_segments!($ctx, $request, $start, $end, (with_options $handle),
-            ( $( $matched_args, )* $arg, ), () );
-    };
+        }));
+    }
 
-    // Try to match and parse a typed argument, declares the expected $arg into
-    // type $t, if it can be parsed
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident, $handle:tt,
-        ( $( $matched_args:ident, )* ),
-        (
-            [$arg:ident : $arg_ty:ty]
-            $( / $( $tail:tt)/ * )?
-        )
-    ) => {
-        let $arg: $arg_ty;
-        match $request.path[$start..$end].parse::<$arg_ty>() {
-            Ok(parsed) => {
-                $arg = parsed
-            },
-            Err(_) =>
-            {
-                // println!("Cannot parse {} from {}", stringify!($arg_ty), &$request.path[$start..$end]);
-                // If arg cannot be parsed, try to skip to next pattern
-                break
+    /// Record the failure of the entered pattern, unless it's already
+    /// recorded at a further segment.
+    fn record(&mut self, segment: usize, reason: CandidateFailureReason) {
+        let pattern = self.scope.concat();
+        match self
+            .failures
+            .iter_mut()
+            .find(|failure| failure.pattern == pattern)
+        {
+            Some(failure) if failure.segment >= segment => {}
+            Some(failure) => {
+                failure.segment = segment;
+                failure.reason = reason;
             }
+            None => self.failures.push(CandidateFailure {
+                pattern,
+                segment,
+                reason,
+            }),
         }
-        $start = $end;
-        // advance past next '/', if any
-        if $start + 1 < $request.path.len() {
-            $start += 1;
-        }
-        $end = find_next_slash_index(&$request.path, $start);
-        try_match_segments!($ctx, $request, $start, $end, $handle,
-            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
-    };
+    }
 
-    // Try to match an expected string literal
-    (
-        $ctx:ident, $request:ident, $start:ident, $end:ident, $handle:tt,
-        ( $( $matched_args:ident, )* ),
-        (
-            $expected:literal
-            $( / $( $tail:tt)/ * )?
-        )
-    ) => {
-        if &$request.path[$start..$end] == $expected {
-            // Advanced index past the matched arg
-            // println!("Matched literal {}", $expected);
-            $start = $end;
-        } else {
-            // println!("{} doesn't match literal {}", &$request.path[$start..$end], $expected);
-            // Try to skip to next pattern
-            break;
-        }
-        // advance past next '/', if any
-        if $start + 1 < $request.path.len() {
-            $start += 1;
+    /// Record that the entered pattern is matched, but the path has more
+    /// segments from the `index`.
+    pub fn trailing_segments(&mut self, index: usize) {
+        self.record(index, CandidateFailureReason::TrailingSegments);
+    }
+
+    /// Get the recorded failures.
+    pub fn into_failures(self) -> Vec<CandidateFailure> {
+        self.failures
+    }
+}
+
+impl MatchFailures for CandidateFailures {
+    fn parse_failed(
+        &mut self,
+        index: usize,
+        _segment: &str,
+        type_name: &'static str,
+        kind: ParseFailureKind,
+    ) {
+        self.record(
+            index,
+            CandidateFailureReason::ParseFailed { type_name, kind },
+        );
+    }
+
+    fn validation_failed(
+        &mut self,
+        index: usize,
+        _arg: &'static str,
+        _value: String,
+        predicate: &'static str,
+    ) {
+        self.record(
+            index,
+            CandidateFailureReason::ValidationFailed { predicate },
+        );
+    }
+
+    fn mismatched(&mut self, index: usize) {
+        self.record(index, CandidateFailureReason::Mismatch);
+    }
+
+    fn enter_pattern(&mut self, template: &'static str) {
+        self.scope.push(template);
+    }
+
+    fn exit_pattern(&mut self) {
+        self.scope.pop();
+    }
+}
+
+/// Keep only the candidate failures at the furthest segment, which are
+/// reported in [`Error::NoMatch`].
+pub fn closest_candidates(
+    mut candidates: Vec<CandidateFailure>,
+) -> Vec<CandidateFailure> {
+    let furthest = candidates
+        .iter()
+        .map(|candidate| candidate.segment)
+        .max()
+        .unwrap_or_default();
+    candidates.retain(|candidate| candidate.segment == furthest);
+    candidates
+}
+
+/// Decode a `[name: b64]` path segment from unpadded base64url, or `None` if
+/// it's not valid base64url.
+pub fn decode_b64_segment(segment: &str) -> Option<Vec<u8>> {
+    data_encoding::BASE64URL_NOPAD
+        .decode(segment.as_bytes())
+        .ok()
+}
+
+/// Encode the bytes of a `[name: b64]` arg into a path segment in unpadded
+/// base64url, which is safe to use in a path without escaping.
+pub fn encode_b64_segment(bytes: &[u8]) -> String {
+    data_encoding::BASE64URL_NOPAD.encode(bytes)
+}
+
+/// The characters other than the ASCII alphanumerics that are allowed in the
+/// segments of a `[name: storage_key]` arg.
+pub const KEY_SEGMENT_SPECIAL_CHARS: &str = "#?-_.:@+~";
+
+/// The reason why a `[name: storage_key]` arg is rejected, as reported in
+/// [`Error::InvalidKey`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvalidKeyReason {
+    /// The key has an empty segment at the index
+    EmptySegment(usize),
+    /// The key has a character that's not allowed in its segments (see
+    /// [`KEY_SEGMENT_SPECIAL_CHARS`])
+    InvalidChar(char),
+    /// The key is not a valid `storage::Key`
+    Unparsable(String),
+}
+
+impl Display for InvalidKeyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptySegment(index) => {
+                write!(f, "the segment at index {index} is empty")
+            }
+            Self::InvalidChar(char) => {
+                write!(f, "the character {char:?} is not allowed")
+            }
+            Self::Unparsable(err) => write!(f, "{err}"),
         }
-        $end = find_next_slash_index(&$request.path, $start);
-        try_match_segments!($ctx, $request, $start, $end, $handle,
-            ( $( $matched_args, )* ), ( $( $( $tail )/ * )? ) );
-    };
+    }
 }
 
-/// Generate a function that tries to match the given pattern and `break`s if
-/// any of its parts are unmatched. This layer will check that the path starts
-/// with `/` and then invoke `try_match_segments` TT muncher that goes through
-/// the patterns.
-macro_rules! try_match {
-    ($ctx:ident, $request:ident, $start:ident, $handle:tt, $segments:tt) => {
-        // check that the initial char is '/'
-        if $request.path.is_empty() || &$request.path[..1] != "/" {
-            // println!("Missing initial slash");
-            break;
+/// Parse the rest of a path matched by a `[name: storage_key]` arg, with its
+/// segments joined back with slashes, into a `storage::Key`. Unlike a typed
+/// arg of `storage::Key`, the key's segments must not be empty and they may
+/// only contain the ASCII alphanumerics and the
+/// [`KEY_SEGMENT_SPECIAL_CHARS`].
+pub fn parse_storage_key(
+    key: &str,
+) -> Result<crate::types::storage::Key, Error> {
+    let invalid = |reason| Error::InvalidKey {
+        key: key.to_owned(),
+        reason,
+    };
+    for (index, segment) in key.split('/').enumerate() {
+        if segment.is_empty() {
+            return Err(invalid(InvalidKeyReason::EmptySegment(index)));
         }
-        // advance past initial '/'
-        $start += 1;
-        // Path is too short to match
-        if $start >= $request.path.len() {
-            // println!("Path is too short");
-            break;
+        if let Some(char) = segment.chars().find(|char| {
+            !char.is_ascii_alphanumeric()
+                && !KEY_SEGMENT_SPECIAL_CHARS.contains(*char)
+        }) {
+            return Err(invalid(InvalidKeyReason::InvalidChar(char)));
         }
-        let mut end = find_next_slash_index(&$request.path, $start);
-        try_match_segments!(
-            $ctx,
-            $request,
-            $start,
-            end,
-            $handle,
-            (),
-            $segments
-        );
-    };
+    }
+    crate::types::storage::Key::parse(key)
+        .map_err(|err| invalid(InvalidKeyReason::Unparsable(err.to_string())))
 }
 
-/// Convert literal pattern into a `&[&'static str]`
-// TODO sub router pattern is not yet used
-#[allow(unused_macros)]
-macro_rules! pattern_to_prefix {
-    ( ( $( $pattern:literal )/ * ) ) => {
-        &[$( $pattern ),*]
-    };
-    ( $pattern:tt ) => {
-        compile_error!("sub-router cannot have non-literal prefix patterns")
-    };
+/// The set of the segments allowed for a `[name: oneof &SET]` arg, which is
+/// only known at run-time (e.g. the symbols of the tokens configured for a
+/// node). A segment that isn't in the set is not matched.
+pub trait SegmentSet {
+    /// Check if the set contains the given path segment.
+    fn contains_segment(&self, segment: &str) -> bool;
 }
 
-/// Turn patterns and their handlers into methods for the router, where each
-/// dynamic pattern is turned into a parameter for the method.
-macro_rules! pattern_and_handler_to_method {
-    // Special terminal rule for `storage_value` handle from
-    // `shared/src/ledger/queries/shell.rs` that returns `Vec<u8>` which should
-    // not be decoded from response.data, but instead return as is
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $return_type:path,
-        (with_options storage_value),
-        ()
-    ) => {
-        // paste! used to construct the `fn $handle_path`'s name.
-        paste::paste! {
-            #[allow(dead_code)]
-            #[doc = "Get a path to query `storage_value`."]
-            pub fn storage_value_path(&self, $( $param: &$param_ty ),* ) -> String {
-                itertools::join(
-                    [ Some(std::borrow::Cow::from(&self.prefix)), $( $prefix ),* ]
-                    .into_iter()
-                    .filter_map(|x| x), "/")
-            }
+impl SegmentSet for HashSet<String> {
+    fn contains_segment(&self, segment: &str) -> bool {
+        self.contains(segment)
+    }
+}
 
-            #[allow(dead_code)]
-            #[allow(clippy::too_many_arguments)]
-            #[cfg(any(test, feature = "async-client"))]
-            #[doc = "Request value with optional data (used for e.g. \
-                `dry_run_tx`), optionally specified height (supported for \
-                `storage_value`) and optional proof (supported for \
-                `storage_value` and `storage_prefix`) from `storage_value`."]
-            pub async fn storage_value<CLIENT>(&self, client: &CLIENT,
-                data: Option<Vec<u8>>,
-                height: Option<$crate::types::storage::BlockHeight>,
-                prove: bool,
-                $( $param: &$param_ty ),*
-            )
-                -> std::result::Result<
-                    $crate::ledger::queries::ResponseQuery<Vec<u8>>,
-                    <CLIENT as $crate::ledger::queries::Client>::Error
-                >
-                where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
-                    let path = self.storage_value_path( $( $param ),* );
-
-                    let $crate::ledger::queries::ResponseQuery {
-                        data, info, proof
-                    } = client.request(path, data, height, prove).await?;
-
-                    Ok($crate::ledger::queries::ResponseQuery {
-                        data,
-                        info,
-                        proof,
-                    })
+/// A set that can be updated at run-time, while the router is in use.
+impl<T: SegmentSet> SegmentSet for RwLock<T> {
+    fn contains_segment(&self, segment: &str) -> bool {
+        self.read().unwrap().contains_segment(segment)
+    }
+}
+
+/// A set that's initialized on its first use, e.g. from the node's config.
+impl<T: SegmentSet, F: FnOnce() -> T> SegmentSet
+    for once_cell::sync::Lazy<T, F>
+{
+    fn contains_segment(&self, segment: &str) -> bool {
+        (**self).contains_segment(segment)
+    }
+}
+
+/// The prefix of a `[name: type radix n]` arg's path segment, which is `0x`
+/// for hexadecimal, `0o` for octal, `0b` for binary and none for any other
+/// radix.
+pub fn radix_prefix(radix: u32) -> &'static str {
+    match radix {
+        16 => "0x",
+        8 => "0o",
+        2 => "0b",
+        _ => "",
+    }
+}
+
+/// An unsigned integer arg declared with a radix, e.g. `[id: u64 radix 16]`,
+/// which is parsed from and formatted into a path segment in the radix
+/// instead of base 10.
+pub trait RadixArg: Sized {
+    /// Parse the arg from a path segment in the given `radix`, with or
+    /// without its [`radix_prefix`].
+    fn parse_radix(
+        segment: &str,
+        radix: u32,
+    ) -> Result<Self, std::num::ParseIntError>;
+
+    /// Format the arg into a path segment in the given `radix`, with its
+    /// [`radix_prefix`].
+    fn format_radix(&self, radix: u32) -> String;
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! impl_radix_arg {
+    ( $( $ty:ty ),* ) => {
+        $(
+            impl RadixArg for $ty {
+                fn parse_radix(
+                    segment: &str,
+                    radix: u32,
+                ) -> Result<Self, std::num::ParseIntError> {
+                    let digits = segment
+                        .strip_prefix(radix_prefix(radix))
+                        .unwrap_or(segment);
+                    Self::from_str_radix(digits, radix)
+                }
+
+                fn format_radix(&self, radix: u32) -> String {
+                    format_radix(*self as u128, radix)
+                }
             }
-        }
+        )*
     };
+}
 
-    // terminal rule for $handle that uses request (`with_options`)
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $return_type:path,
-        (with_options $handle:tt),
-        ()
-    ) => {
-        // paste! used to construct the `fn $handle_path`'s name.
-        paste::paste! {
-            #[allow(dead_code)]
-            #[doc = "Get a path to query `" $handle "`."]
-            pub fn [<$handle _path>](&self, $( $param: &$param_ty ),* ) -> String {
-                itertools::join(
-                    [ Some(std::borrow::Cow::from(&self.prefix)), $( $prefix ),* ]
-                    .into_iter()
-                    .filter_map(|x| x), "/")
-            }
+impl_radix_arg!(u8, u16, u32, u64, u128, usize);
 
-            #[allow(dead_code)]
-            #[allow(clippy::too_many_arguments)]
-            #[cfg(any(test, feature = "async-client"))]
-            #[doc = "Request value with optional data (used for e.g. \
-                `dry_run_tx`), optionally specified height (supported for \
-                `storage_value`) and optional proof (supported for \
-                `storage_value` and `storage_prefix`) from `" $handle "`."]
-            pub async fn $handle<CLIENT>(&self, client: &CLIENT,
-                data: Option<Vec<u8>>,
-                height: Option<$crate::types::storage::BlockHeight>,
-                prove: bool,
-                $( $param: &$param_ty ),*
-            )
-                -> std::result::Result<
-                    $crate::ledger::queries::ResponseQuery<$return_type>,
-                    <CLIENT as $crate::ledger::queries::Client>::Error
-                >
-                where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
-                    let path = self.[<$handle _path>]( $( $param ),* );
-
-                    let $crate::ledger::queries::ResponseQuery {
-                        data, info, proof
-                    } = client.request(path, data, height, prove).await?;
-
-                    let decoded: $return_type =
-                        borsh::BorshDeserialize::try_from_slice(&data[..])?;
-
-                    Ok($crate::ledger::queries::ResponseQuery {
-                        data: decoded,
-                        info,
-                        proof,
-                    })
-            }
+/// Format an unsigned integer in the given `radix`, with its
+/// [`radix_prefix`].
+fn format_radix(mut value: u128, radix: u32) -> String {
+    let mut digits = Vec::new();
+    loop {
+        let digit = (value % u128::from(radix)) as u32;
+        digits.push(
+            std::char::from_digit(digit, radix)
+                .expect("The radix should be in the range 2..=36"),
+        );
+        value /= u128::from(radix);
+        if value == 0 {
+            break;
         }
-    };
+    }
+    radix_prefix(radix)
+        .chars()
+        .chain(digits.into_iter().rev())
+        .collect()
+}
 
-    // terminal rule that $handle that doesn't use request
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $return_type:path,
-        $handle:tt,
-        ()
-    ) => {
-        // paste! used to construct the `fn $handle_path`'s name.
-        paste::paste! {
-            #[allow(dead_code)]
-            #[doc = "Get a path to query `" $handle "`."]
-            pub fn [<$handle _path>](&self, $( $param: &$param_ty ),* ) -> String {
-                itertools::join(
-                    [ Some(std::borrow::Cow::from(&self.prefix)), $( $prefix ),* ]
-                    .into_iter()
-                    .filter_map(|x| x), "/")
-            }
+/// Apply a route's default `info` template to a response whose handler left
+/// the `info` empty and didn't set an `info_message`. Any `{route}` placeholder
+/// in the template is replaced with the name of the route's handler.
+pub fn apply_default_info<T>(
+    response: &mut ResponseQuery<T>,
+    template: Option<&str>,
+    route: &str,
+) {
+    if let Some(template) = template {
+        if response.info.is_empty() && response.info_message.is_none() {
+            response.info = template.replace("{route}", route);
+        }
+    }
+}
 
-            #[allow(dead_code)]
-            #[allow(clippy::too_many_arguments)]
-            #[cfg(any(test, feature = "async-client"))]
-            #[doc = "Request a simple borsh-encoded value from `" $handle "`, \
-                without any additional request data, specified block height or \
-                proof."]
-            pub async fn $handle<CLIENT>(&self, client: &CLIENT,
-                $( $param: &$param_ty ),*
-            )
-                -> std::result::Result<
-                    $return_type,
-                    <CLIENT as $crate::ledger::queries::Client>::Error
-                >
-                where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
-                    let path = self.[<$handle _path>]( $( $param ),* );
+/// Check that the encoded data of a response is not longer than a route's
+/// `#[max_size = N]` limit, if any. An over-limit response is rejected with
+/// [`Error::ResponseTooLarge`] instead of being returned to the client.
+pub fn check_response_size(
+    data: &[u8],
+    limit: Option<usize>,
+) -> storage_api::Result<()> {
+    match limit {
+        Some(limit) if data.len() > limit => {
+            Err(storage_api::Error::new(Error::ResponseTooLarge {
+                size: data.len(),
+                limit,
+            }))
+        }
+        _ => Ok(()),
+    }
+}
 
-                    let data = client.simple_request(path).await?;
+/// The default duration of a handler call above which the query is logged as
+/// slow.
+pub const DEFAULT_SLOW_QUERY_THRESHOLD: Duration = Duration::from_secs(1);
 
-                    let decoded: $return_type =
-                        borsh::BorshDeserialize::try_from_slice(&data[..])?;
-                    Ok(decoded)
-            }
+/// The node's configuration of the calls of the routes' handlers, which is
+/// given to the router with a request in its
+/// [`RequestCtx::handler_config`](super::RequestCtx::handler_config).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandlerConfig {
+    /// The duration of a handler call above which the query is logged as
+    /// slow with `feature = "metrics"`. The default is
+    /// [`DEFAULT_SLOW_QUERY_THRESHOLD`].
+    pub slow_query_threshold: Duration,
+    /// Whether a panic of a handler is caught and returned as
+    /// [`Error::HandlerPanicked`] with the route's name, instead of unwinding
+    /// through the router's `handle`, so that a single buggy handler cannot
+    /// take down the request loop. It's disabled by default.
+    pub catch_panics: bool,
+}
+
+impl Default for HandlerConfig {
+    fn default() -> Self {
+        Self {
+            slow_query_threshold: DEFAULT_SLOW_QUERY_THRESHOLD,
+            catch_panics: false,
         }
-    };
+    }
+}
 
-    // sub-pattern
-    (
-        $param:tt
-        $prefix:tt
-        $( $_return_type:path )?,
-        { $( $sub_pattern:tt $( -> $sub_return_ty:path )? = $handle:tt, )* },
-        $pattern:tt
-    ) => {
-        $(
-            // join pattern with each sub-pattern
-            pattern_and_handler_to_method!(
-                $param
-                $prefix
-                $( $sub_return_ty )?, $handle, $pattern, $sub_pattern
-            );
-        )*
-    };
+/// Whether the patterns that start with a literal are dispatched on the hash
+/// of the path's first segment
+#[cfg(any(test, feature = "testing"))]
+static FIRST_SEGMENT_DISPATCH: AtomicBool = AtomicBool::new(true);
 
-    // literal string arg
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $( $return_type:path )?,
-        $handle:tt,
-        ( $pattern:literal $( / $tail:tt )* )
-    ) => {
-        pattern_and_handler_to_method!(
-            ( $( $param: $param_ty ),* )
-            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($pattern)) } ]
-            $( $return_type )?, $handle, ( $( $tail )/ * )
-        );
-    };
+/// Set whether a router skips the patterns that start with literals other
+/// than the path's first segment by comparing the segment's hash with the
+/// literals' hashes precomputed at compile time, before matching the
+/// patterns one by one. The patterns that start with a dynamic arg are always
+/// matched one by one. It's always enabled outside of tests, which can
+/// disable it to check that the routes are resolved the same either way.
+#[cfg(any(test, feature = "testing"))]
+pub fn set_first_segment_dispatch(enabled: bool) {
+    FIRST_SEGMENT_DISPATCH.store(enabled, Ordering::Relaxed);
+}
 
-    // untyped arg
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $( $return_type:path )?,
-        $handle:tt,
-        ( [$name:tt] $( / $tail:tt )* )
-    ) => {
-        pattern_and_handler_to_method!(
-            ( $( $param: $param_ty, )* $name: str )
-            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($name)) } ]
-            $( $return_type )?, $handle, ( $( $tail )/ * )
-        );
-    };
+/// Check whether the patterns are dispatched on the hash of the path's first
+/// segment (see `set_first_segment_dispatch`).
+pub fn first_segment_dispatch() -> bool {
+    #[cfg(any(test, feature = "testing"))]
+    {
+        FIRST_SEGMENT_DISPATCH.load(Ordering::Relaxed)
+    }
+    #[cfg(not(any(test, feature = "testing")))]
+    {
+        true
+    }
+}
 
-    // typed arg
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $( $return_type:path )?,
-        $handle:tt,
-        ( [$name:tt: $type:ty] $( / $tail:tt )* )
-    ) => {
-        pattern_and_handler_to_method!(
-            ( $( $param: $param_ty, )* $name: $type )
-            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($name.to_string())) } ]
-            $( $return_type )?, $handle, ( $( $tail )/ * )
-        );
-    };
+/// A router's route type, whose routes are named after their handlers. It's
+/// implemented by the route types generated by `router!`, e.g. to name the
+/// route of a request rejected by the [`RateLimiter`].
+pub trait RouteName {
+    /// Get the name of the route's handler, which is one of the router's
+    /// `HANDLER_NAMES`.
+    fn handler_name(&self) -> &'static str;
+}
 
-    // opt typed arg
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $( $return_type:path )?,
-        $handle:tt,
-        ( [$name:tt: opt $type:ty] $( / $tail:tt )* )
-    ) => {
-        pattern_and_handler_to_method!(
-            ( $( $param: $param_ty, )* $name: std::option::Option<$type> )
-            [ $( { $prefix }, )* { $name.map(|arg| std::borrow::Cow::from(arg.to_string())) } ]
-            $( $return_type )?, $handle, ( $( $tail )/ * )
-        );
-    };
+/// Get the [`segment_hash`] of the path's first segment to be matched from
+/// the `start` index, if any and if enabled (see
+/// [`first_segment_dispatch`]). A percent-encoded segment has no hash,
+/// because it may still match a literal once it's decoded.
+pub fn first_segment_hash(segments: &[&str], start: usize) -> Option<u64> {
+    if !first_segment_dispatch() {
+        return None;
+    }
+    segments
+        .get(start)
+        .filter(|segment| !segment.contains('%'))
+        .map(|segment| segment_hash(segment))
+}
 
-    // join pattern with sub-pattern
-    (
-        ( $( $param:tt: $param_ty:ty ),* )
-        [ $( { $prefix:expr } ),* ]
-        $( $return_type:path )?,
-        $handle:tt,
-        ( $( $pattern:tt )/ * ), ( $( $sub_pattern:tt )/ * )
-    ) => {
-        pattern_and_handler_to_method!(
-            ( $( $param: $param_ty ),* )
-            [ $( { $prefix }, )* ]
-            $( $return_type )?,
-            $handle, ( $( $pattern / )* $( $sub_pattern )/ * )
-        );
-    };
+/// Call a `route`'s handler, catching its panic if enabled with
+/// [`HandlerConfig::catch_panics`].
+fn call_handler<T>(
+    config: HandlerConfig,
+    route: &'static str,
+    handler: impl FnOnce() -> storage_api::Result<T>,
+) -> storage_api::Result<T> {
+    if !config.catch_panics {
+        return handler();
+    }
+    // The handlers only have read-only access to the storage, so there's no
+    // broken state to observe after a panic
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(handler))
+        .unwrap_or_else(|_panic| {
+            tracing::error!(route, "Query handler panicked");
+            Err(storage_api::Error::new(Error::HandlerPanicked { route }))
+        })
 }
 
-/// TT muncher macro that generates a `struct $name` with methods for all its
-/// handlers.
-macro_rules! router_type {
-    // terminal rule
-    ($name:ident { $( $methods:item )* }, ) => {
-        paste::paste! {
-            #[doc = "`" $name "`path router type"]
-            pub struct $name {
-                prefix: String,
-            }
+/// The time spent in each phase of handling a request, as measured by
+/// `Router::handle_timed` for performance debugging. A route without a
+/// handler function (e.g. a `const` route) is only matched, so all of its
+/// time is spent in the matching.
+#[cfg(any(test, feature = "metrics"))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// Nanoseconds spent matching the path, up to the handler call
+    pub match_ns: u64,
+    /// Nanoseconds spent in the handler call
+    pub handler_ns: u64,
+    /// Nanoseconds spent after the handler call, mostly encoding the
+    /// response
+    pub encode_ns: u64,
+}
 
-            impl $name {
-                #[doc = "Construct this router as a root router"]
-                pub const fn new() -> Self {
-                    Self {
-                        prefix: String::new(),
-                    }
-                }
+/// The start of a request timed with [`time_request`] and the start and the
+/// duration of its handler call, once it's called.
+#[cfg(any(test, feature = "metrics"))]
+#[derive(Clone, Copy, Debug)]
+struct TimingRecorder {
+    start: Instant,
+    handler: Option<(Instant, Duration)>,
+}
 
-                #[allow(dead_code)]
-                #[doc = "Construct this router as a sub-router at the given prefix path"]
-                pub const fn sub(prefix: String) -> Self {
-                    Self {
-                        prefix,
-                    }
-                }
+#[cfg(any(test, feature = "metrics"))]
+thread_local! {
+    /// The recorder of the request that's being timed with [`time_request`]
+    /// on this thread, if any
+    static TIMING_RECORDER: std::cell::Cell<Option<TimingRecorder>> =
+        const { std::cell::Cell::new(None) };
+}
 
-                // paste the generated methods
-                $( $methods )*
-            }
-        }
+/// Handle a request with the given `handle` function and measure its
+/// [`Timing`], in which the handler call is the one timed with [`timed`].
+#[cfg(any(test, feature = "metrics"))]
+pub fn time_request<T>(handle: impl FnOnce() -> T) -> (T, Timing) {
+    let start = Instant::now();
+    // The recorder of an outer request, if any, is restored afterwards
+    let outer = TIMING_RECORDER.with(|recorder| {
+        recorder.replace(Some(TimingRecorder {
+            start,
+            handler: None,
+        }))
+    });
+    let result = handle();
+    let total = start.elapsed();
+    let recorder = TIMING_RECORDER.with(|recorder| recorder.replace(outer));
+    let nanos = |duration: Duration| {
+        u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
     };
-
-    // a sub router - recursion
-    (
-        $name:ident { $( $methods:item )* },
-        $pattern:tt = (sub $router:ident)
-        $( ,$tail_pattern:tt $( -> $tail_return_type:path )? = $tail:tt )*
-    ) => {
-        paste::paste! {
-            router_type!{
-                $name {
-                    #[doc = "`" $name "` sub-router"]
-                    pub fn [<$router:camel:snake>](&self) -> [<$router:camel>] {
-                        // prefix for a sub can only contain literals
-                        let current_prefix: &[&'static str] = pattern_to_prefix!($pattern);
-                        let path = [&[self.prefix.as_str()][..], current_prefix].concat().join("/");
-                        [<$router:camel>]::sub(path)
-                    }
-                    $( $methods )*
-                },
-                $( $tail_pattern $( -> $tail_return_type )? = $tail ),*
+    let timing = match recorder.and_then(|recorder| recorder.handler) {
+        Some((handler_start, handler)) => {
+            let matching = handler_start.saturating_duration_since(start);
+            Timing {
+                match_ns: nanos(matching),
+                handler_ns: nanos(handler),
+                encode_ns: nanos(
+                    total.saturating_sub(matching).saturating_sub(handler),
+                ),
             }
         }
+        None => Timing {
+            match_ns: nanos(total),
+            ..Timing::default()
+        },
     };
+    (result, timing)
+}
 
-    // a sub-pattern - add a method for each handle inside it
-    (
-        $name:ident
-        { $( $methods:item )* },
-        $pattern:tt = { $( $sub_pattern:tt $( -> $sub_return_ty:path )? = $handle:tt, )* }
-        $( ,$tail_pattern:tt $( -> $tail_return_type:path )? = $tail:tt )*
-    ) => {
-        router_type!{
-            $name {
-                $(
-                    // join pattern with each sub-pattern
-                    pattern_and_handler_to_method!( () [] $( $sub_return_ty )?, $handle,
-                        $pattern, $sub_pattern
-                    );
-                )*
-                $( $methods )*
-            },
-            $( $tail_pattern $( -> $tail_return_type )? = $tail ),*
+/// Call a `route`'s handler. With `feature = "metrics"`, the call is timed
+/// and a warning is logged if it takes longer than the `config`'s
+/// [`HandlerConfig::slow_query_threshold`]. The call is also recorded for the
+/// [`Timing`] of a request handled with [`time_request`]. A panic of the
+/// handler is caught if enabled with [`HandlerConfig::catch_panics`].
+#[inline]
+pub fn timed<T>(
+    config: HandlerConfig,
+    route: &'static str,
+    handler: impl FnOnce() -> storage_api::Result<T>,
+) -> storage_api::Result<T> {
+    #[cfg(any(test, feature = "metrics"))]
+    {
+        // The recorder is taken for the duration of the call, so that a
+        // nested handler call isn't recorded in place of this one
+        let recorder = TIMING_RECORDER.with(|recorder| recorder.take());
+        let start = std::time::Instant::now();
+        let result = call_handler(config, route, handler);
+        let elapsed = start.elapsed();
+        TIMING_RECORDER.with(|current| {
+            current.set(recorder.map(|recorder| TimingRecorder {
+                handler: recorder.handler.or(Some((start, elapsed))),
+                ..recorder
+            }))
+        });
+        if elapsed > config.slow_query_threshold {
+            tracing::warn!(
+                route,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "Slow query"
+            );
         }
-    };
+        result
+    }
+    #[cfg(not(any(test, feature = "metrics")))]
+    {
+        call_handler(config, route, handler)
+    }
+}
 
-    // pattern with a handle - add a method for the handle
-    (
-        $name:ident
-        { $( $methods:item )* },
-        $pattern:tt -> $return_type:path = $handle:tt
-        $( ,$tail_pattern:tt $( -> $tail_return_type:path )? = $tail:tt )*
-    ) => {
-        router_type!{
-            $name {
-                pattern_and_handler_to_method!( () [] $return_type, $handle, $pattern );
-                $( $methods )*
-            },
-            $( $tail_pattern $( -> $tail_return_type )? = $tail ),*
-        }
+/// Call a `route`'s `async` handler like [`timed`] and await its result. The
+/// call is timed with `feature = "metrics"`, but it's not recorded for the
+/// [`Timing`] of a request, which is only measured in the synchronous
+/// dispatch.
+#[cfg(any(test, feature = "async-handlers"))]
+pub async fn timed_async<T>(
+    config: HandlerConfig,
+    route: &'static str,
+    handler: impl std::future::Future<Output = storage_api::Result<T>>,
+) -> storage_api::Result<T> {
+    #[cfg(any(test, feature = "metrics"))]
+    let start = std::time::Instant::now();
+    let result = if config.catch_panics {
+        // The handlers only have read-only access to the storage, so there's
+        // no broken state to observe after a panic
+        futures::FutureExt::catch_unwind(std::panic::AssertUnwindSafe(handler))
+            .await
+            .unwrap_or_else(|_panic| {
+                tracing::error!(route, "Query handler panicked");
+                Err(storage_api::Error::new(Error::HandlerPanicked { route }))
+            })
+    } else {
+        handler.await
     };
+    #[cfg(any(test, feature = "metrics"))]
+    {
+        let elapsed = start.elapsed();
+        if elapsed > config.slow_query_threshold {
+            tracing::warn!(
+                route,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "Slow query"
+            );
+        }
+    }
+    result
 }
 
-/// Compile time tree patterns router with type-safe dynamic parameter parsing,
-/// automatic routing, type-safe path constructors and optional client query
-/// methods (enabled with `feature = "async-client"`).
-///
-/// The `router!` macro implements greedy matching algorithm.
-///
-/// ## Examples
-///
-/// ```rust,ignore
-/// router! {ROOT,
-///   // This pattern matches `/pattern_a/something`, where `something` can be
-///   // parsed with `FromStr` into `ArgType`.
-///   ( "pattern_a" / [typed_dynamic_arg: ArgType] ) -> ReturnType = handler,
-///
-///   ( "pattern_b" / [optional_dynamic_arg: opt ArgType] ) -> ReturnType =
-/// handler,
-///
-///   // Untyped dynamic arg is a string slice `&str`
-///   ( "pattern_c" / [untyped_dynamic_arg] ) -> ReturnType = handler,
-///
-///   // The handler additionally receives the `RequestQuery`, which can have
-///   // some data attached, specified block height and ask for a proof. It
-///   // returns `EncodedResponseQuery` (the `data` must be encoded, if
-///   // necessary), which can have some `info` string and a proof.
-///   ( "pattern_d" ) -> ReturnType = (with_options handler),
-///
-///   ( "another" / "pattern" / "that" / "goes" / "deep" ) -> ReturnType = handler,
-///
-///   // Inlined sub-tree
-///   ( "subtree" / [this_is_fine: ArgType] ) = {
-///     ( "a" ) -> u64 = a_handler,
-///     ( "b" / [another_arg] ) -> u64 = b_handler,
-///   }
-///
-///   // Imported sub-router - The prefix can only have literal segments
-///   ( "sub" / "no_dynamic_args" ) = (sub SUB_ROUTER),
-/// }
-///
-/// router! {SUB_ROUTER,
-///   ( "pattern" ) -> ReturnType = handler,
-/// }
-/// ```
+/// Reject the `call` of the handler of a `route` with the `#[async_handler]`
+/// attribute in the synchronous dispatch, which cannot await it. The returned
+/// future is dropped without being polled. Such route must be handled with
+/// [`Router::handle_async`](super::Router::handle_async).
+#[cfg(any(test, feature = "async-handlers"))]
+pub fn reject_sync_dispatch<F, T, E>(
+    route: &'static str,
+    _call: F,
+) -> Result<T, E>
+where
+    F: std::future::Future<Output = Result<T, E>>,
+    E: From<storage_api::Error>,
+{
+    Err(storage_api::Error::new(Error::AsyncHandler { route }).into())
+}
+
+/// An error of a handler of a route with the `#[domain_error]` attribute. The
+/// handler of such route returns `Result<T, HandlerError<E>>` and the route
+/// must be declared to return `Result<T, E>`. A domain error is borsh-encoded
+/// into the response as `Err(E)`, so that a client decodes it from a
+/// successful response, while a storage error fails the request.
+#[derive(Debug)]
+pub enum HandlerError<E> {
+    /// A domain-level error to be returned in the response
+    Domain(E),
+    /// A storage error that fails the request
+    Storage(storage_api::Error),
+}
+
+impl<E> From<storage_api::Error> for HandlerError<E> {
+    fn from(err: storage_api::Error) -> Self {
+        Self::Storage(err)
+    }
+}
+
+/// Convert the result of a handler of a route with the `#[domain_error]`
+/// attribute into a domain result to be encoded in the response. Only a
+/// storage error is returned as an error.
+pub fn into_domain_result<T, E>(
+    result: Result<T, HandlerError<E>>,
+) -> storage_api::Result<Result<T, E>> {
+    match result {
+        Ok(value) => Ok(Ok(value)),
+        Err(HandlerError::Domain(err)) => Ok(Err(err)),
+        Err(HandlerError::Storage(err)) => Err(err),
+    }
+}
+
+/// Get the chunk of a response from the `chunks` returned by the handler of a
+/// route that returns chunks (`stream`). The chunk's index is borsh-encoded in
+/// the request `data` and a request without any data gets the first chunk.
+/// The chunks before the requested one are skipped and the one after it is
+/// produced too, to find out if there are any more chunks.
+pub fn response_chunk<I>(
+    chunks: I,
+    request: &RequestQuery,
+    height: BlockHeight,
+) -> storage_api::Result<ResponseChunk>
+where
+    I: IntoIterator<Item = Vec<u8>>,
+{
+    let index: u32 = if request.data.is_empty() {
+        0
+    } else {
+        borsh::BorshDeserialize::try_from_slice(&request.data[..])
+            .map_err(storage_api::Error::new)?
+    };
+    let mut chunks = chunks.into_iter().skip(index as usize);
+    let data = match chunks.next() {
+        Some(data) => data,
+        // A handler without any chunks responds with a single empty chunk
+        None if index == 0 => vec![],
+        None => {
+            return Err(storage_api::Error::new(Error::ChunkOutOfRange(index)));
+        }
+    };
+    let next = chunks.next().map(|_| index + 1);
+    Ok(ResponseChunk { data, next, height })
+}
+
+/// Set the ETag of a response handled by the root router, which is the hash
+/// of its encoded data. If it matches the request's `if_none_match` ETag, the
+/// response's data and proof are dropped and it's marked as not modified, so
+/// that the client can use its cached response instead.
+pub fn apply_etag(
+    response: &mut EncodedResponseQuery,
+    if_none_match: Option<&Hash>,
+) {
+    let etag = Hash::sha256(&response.data);
+    if if_none_match == Some(&etag) {
+        response.data = vec![];
+        response.proof = None;
+        response.not_modified = true;
+    }
+    response.etag = Some(etag);
+}
+
+/// A route matched by a path with the values of its args, as resolved by a
+/// router's `match_info` and `match_all` methods. It's displayed as the route's
+/// handler with its args, e.g. `b3(a1=345, a2=123000, a3=1000999)`, for
+/// logging.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchInfo {
+    /// Name of the route's handler
+    pub handler: &'static str,
+    /// The names of the route's args, including the args of its sub-router's
+    /// prefix, with their matched values in the order of the path
+    pub args: Vec<(&'static str, String)>,
+}
+
+impl Display for MatchInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.handler)?;
+        if self.args.is_empty() {
+            return Ok(());
+        }
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        write!(f, "({})", args.join(", "))
+    }
+}
+
+/// Assert that a path constructed by the `*_path` method of the route of a
+/// `handler` is matched back by the router into the same route with the same
+/// args. The `info` is the route that matched the path, if any, and the
+/// `args` are the method's args, formatted like the args of a [`MatchInfo`].
+/// The generated `*_path` methods only check this with `debug_assertions`,
+/// e.g. to catch an arg whose `Display` isn't parsed back by its `FromStr`.
+pub fn assert_path_matches(
+    path: &str,
+    info: Option<MatchInfo>,
+    handler: &'static str,
+    args: Vec<(&'static str, String)>,
+) {
+    let matches = matches!(&info, Some(info) if info.handler == handler
+    && args.iter().all(|(name, value)| {
+        info.args.iter().any(|(matched_name, matched)| {
+            matched_name == name
+                && (matched == value || optional_as_option(matched) == *value)
+        })
+    }));
+    if !matches {
+        let expected = MatchInfo { handler, args };
+        let matched = match info {
+            Some(info) => format!("`{info}`"),
+            None => "no route".to_owned(),
+        };
+        panic!(
+            "The path `{path}` constructed by `{handler}_path` must be \
+             matched back into `{expected}`, but it matched {matched}"
+        );
+    }
+}
+
+/// Format the `Debug` of an [`Optional`] arg, as matched for a route with
+/// `#[presence]`, like the `Debug` of the `Option` arg of its `*_path` method.
+fn optional_as_option(value: &str) -> String {
+    match value.strip_prefix("Present(") {
+        Some(present) => format!("Some({present}"),
+        None if value == "Absent" => "None".to_owned(),
+        None => value.to_owned(),
+    }
+}
+
+/// Information about a leaf route of a router, as listed by the router's
+/// `routes` method.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteInfo {
+    /// Name of the route's handler
+    pub handler: &'static str,
+    /// Path template of the route, in which each dynamic arg is replaced with
+    /// its name in braces, followed by `?` if it's optional (e.g.
+    /// `/b/3/{a1}/{a2}/{a3?}/iii`)
+    pub template: String,
+    /// The dynamic args of the route in the order of the template, including
+    /// the args of its sub-router prefixes
+    pub args: Vec<RouteArg>,
+    /// The route's response type, as declared in the router (e.g.
+    /// `Option<token::Amount>`)
+    pub response_ty: &'static str,
+    /// Whether the route is marked `#[internal]` (or it's under a sub-router
+    /// marked `#[internal]`), in which case it's only listed by the router's
+    /// `internal_routes`
+    pub internal: bool,
+    /// Whether the route's handler uses the request (`with_options`), so that
+    /// it can respond with a proof when the request asks for it
+    pub provable: bool,
+    /// Whether the route is marked `#[deprecated]` (or it's under a
+    /// sub-pattern marked `#[deprecated]`)
+    pub deprecated: bool,
+}
+
+/// The path of a root router's meta-route that returns the router's routes,
+/// when it's constructed with `new_with_meta_routes`
+pub const ROUTES_META_PATH: &str = "/_routes";
+
+/// Check if a path segment is reserved for the routers' meta-routes, i.e. it
+/// starts with `_`. A router's patterns cannot start with such a segment.
+pub const fn is_reserved_segment(segment: &str) -> bool {
+    let bytes = segment.as_bytes();
+    !bytes.is_empty() && bytes[0] == b'_'
+}
+
+/// Check if the path `segments` are the segments of the [`ROUTES_META_PATH`],
+/// with an optional trailing slash.
+pub fn is_routes_meta_path(segments: &[&str]) -> bool {
+    match segments.split_first() {
+        Some((first, rest)) => {
+            ROUTES_META_PATH.strip_prefix('/') == Some(*first)
+                && is_path_end(rest, true)
+        }
+        None => false,
+    }
+}
+
+/// The borsh-encoded counterpart of a [`RouteInfo`], as returned by a root
+/// router's meta-route at the [`ROUTES_META_PATH`].
+#[derive(
+    Clone, Debug, PartialEq, Eq, borsh::BorshSerialize, borsh::BorshDeserialize,
+)]
+pub struct RouteDescriptor {
+    /// Name of the route's handler
+    pub handler: String,
+    /// Path template of the route (see [`RouteInfo::template`])
+    pub template: String,
+    /// The dynamic args of the route in the order of the template
+    pub args: Vec<RouteArgDescriptor>,
+    /// The route's response type, as declared in the router
+    pub response_ty: String,
+}
+
+/// The borsh-encoded counterpart of a [`RouteArg`] in a [`RouteDescriptor`].
+#[derive(
+    Clone, Debug, PartialEq, Eq, borsh::BorshSerialize, borsh::BorshDeserialize,
+)]
+pub struct RouteArgDescriptor {
+    /// Name of the arg
+    pub name: String,
+    /// Type of the arg (see [`RouteArg::ty`])
+    pub ty: String,
+    /// Whether the arg is optional
+    pub optional: bool,
+}
+
+impl From<RouteInfo> for RouteDescriptor {
+    fn from(route: RouteInfo) -> Self {
+        Self {
+            handler: route.handler.to_owned(),
+            template: route.template,
+            args: route.args.into_iter().map(Into::into).collect(),
+            response_ty: route.response_ty.to_owned(),
+        }
+    }
+}
+
+impl From<RouteArg> for RouteArgDescriptor {
+    fn from(arg: RouteArg) -> Self {
+        Self {
+            name: arg.name.to_owned(),
+            ty: arg.ty.to_owned(),
+            optional: arg.optional,
+        }
+    }
+}
+
+/// A request of the compact protocol, which identifies a route by the id of
+/// its `#[compact = id]` attribute instead of a path, and carries the route's
+/// args as a borsh-encoded tuple in their order in the route's pattern, or a
+/// single arg on its own (borsh doesn't implement a 1-tuple). It's
+/// handled by a root router's `handle_compact` method, which skips matching
+/// the path's segments.
+#[derive(
+    Clone, Debug, PartialEq, Eq, borsh::BorshSerialize, borsh::BorshDeserialize,
+)]
+pub struct CompactRequest {
+    /// The compact id of the route
+    pub route: u16,
+    /// The borsh-encoded args of the route
+    pub args: Vec<u8>,
+}
+
+impl CompactRequest {
+    /// Construct a request of the route with the given compact id with a
+    /// tuple of its args, e.g. `(a1, a2)`, a single arg `a1` or `()` for a
+    /// route without args.
+    pub fn new<ARGS>(route: u16, args: &ARGS) -> io::Result<Self>
+    where
+        ARGS: borsh::BorshSerialize,
+    {
+        Ok(Self {
+            route,
+            args: args.try_to_vec()?,
+        })
+    }
+}
+
+/// Request the routes of a root router constructed with `new_with_meta_routes`
+/// from its meta-route at the [`ROUTES_META_PATH`].
+#[cfg(any(test, feature = "async-client"))]
+pub async fn request_routes<CLIENT>(
+    client: &CLIENT,
+) -> Result<Vec<RouteDescriptor>, CLIENT::Error>
+where
+    CLIENT: Client + Sync,
+{
+    request_simple(client, ROUTES_META_PATH.to_owned()).await
+}
+
+/// A hint of how a client may cache the responses of a route, which is
+/// declared with the route's `#[cache(immutable)]` or `#[cache(no_store)]`
+/// attribute and consulted by e.g. the `clients::CachingClient`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CachePolicy {
+    /// The response to the same request never changes (e.g. data at a
+    /// historical block height), so it can be cached indefinitely
+    Immutable,
+    /// The response may change with any block (e.g. data at the latest block
+    /// height), so it must not be cached
+    NoStore,
+}
+
+/// The maximum number of requests to a route that a caller may send within a
+/// time window, as configured in a [`RateLimiter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The maximum number of requests within a window
+    pub max_requests: u32,
+    /// The duration of a window, after which the caller's count is reset
+    pub window: Duration,
+}
+
+/// The default maximum number of the windows of the routes and callers kept
+/// by a [`RateLimiter`]
+pub const DEFAULT_MAX_RATE_WINDOWS: usize = 100_000;
+
+/// The default maximum number of the responses kept by a [`ResponseCache`]
+pub const DEFAULT_MAX_CACHED_RESPONSES: usize = 10_000;
+
+/// A post-dispatch transformer of a response to a request for a router's
+/// route, e.g. to add a signature over the response's data or a standard
+/// `info`. The transformers are applied in order by the router's
+/// `handle_transformed` method after the handler returns successfully, each
+/// to the response returned by the previous one.
+pub type ResponseTransformer<ROUTE> =
+    fn(&ROUTE, EncodedResponseQuery) -> EncodedResponseQuery;
+
+/// A rate limiter of the requests to the routes of a router, keyed by the
+/// router's route type and the caller's identity (see
+/// `RequestQuery::caller`). It's meant to be used in the pre-dispatch hook of
+/// the router's `handle_rate_limited` method, which is invoked with the
+/// matched route. Each route can have its own [`RateLimit`], otherwise the
+/// default limit applies, if any. The requests without a caller's identity
+/// share a single count per route.
 ///
-/// Handler functions used in the patterns should have the expected signature:
-/// ```rust,ignore
-/// fn handler<D, H>(ctx: RequestCtx<'_, D, H>, args ...)
-///   -> storage_api::Result<ReturnType>
-/// where
-///     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
-///     H: 'static + StorageHasher + Sync;
-/// ```
+/// The expired windows are removed when a window is added for a new route
+/// and caller, at most once per the longest limit's window, and the number of
+/// the windows is bounded by [`RateLimiter::with_max_windows`], so that the
+/// callers cannot grow the limiter without a bound.
+#[derive(Debug)]
+pub struct RateLimiter<ROUTE> {
+    limits: HashMap<ROUTE, RateLimit>,
+    default_limit: Option<RateLimit>,
+    max_windows: usize,
+    windows: Mutex<RateWindows<ROUTE>>,
+}
+
+/// The windows of the routes and callers of a [`RateLimiter`]
+#[derive(Debug)]
+struct RateWindows<ROUTE> {
+    /// The start of the current window and the number of requests in it per
+    /// route and caller
+    counts: HashMap<(ROUTE, Option<String>), (Instant, u32)>,
+    /// When the expired windows were last removed
+    last_sweep: Option<Instant>,
+}
+
+impl<ROUTE> Default for RateLimiter<ROUTE> {
+    fn default() -> Self {
+        Self {
+            limits: HashMap::default(),
+            default_limit: None,
+            max_windows: DEFAULT_MAX_RATE_WINDOWS,
+            windows: Mutex::new(RateWindows {
+                counts: HashMap::default(),
+                last_sweep: None,
+            }),
+        }
+    }
+}
+
+impl<ROUTE> RateLimiter<ROUTE>
+where
+    ROUTE: Clone + Eq + StdHash + RouteName,
+{
+    /// Create a rate limiter without any limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the requests to the given `route`
+    pub fn with_limit(mut self, route: ROUTE, limit: RateLimit) -> Self {
+        self.limits.insert(route, limit);
+        self
+    }
+
+    /// Limit the requests to the routes without their own limit
+    pub fn with_default_limit(mut self, limit: RateLimit) -> Self {
+        self.default_limit = Some(limit);
+        self
+    }
+
+    /// Set the maximum number of the windows of the routes and callers that
+    /// are kept. When it's reached, the expired windows are removed and if
+    /// there are none, the oldest window is. The default is
+    /// [`DEFAULT_MAX_RATE_WINDOWS`].
+    pub fn with_max_windows(mut self, max_windows: usize) -> Self {
+        self.max_windows = max_windows;
+        self
+    }
+
+    /// Get the limit of the requests to the `route`, if any
+    fn limit(&self, route: &ROUTE) -> Option<&RateLimit> {
+        self.limits.get(route).or(self.default_limit.as_ref())
+    }
+
+    /// Count a request from the `caller` to the `route` and check that it's
+    /// within the route's limit, otherwise return [`Error::RateLimited`]. A
+    /// rejected request is not counted.
+    pub fn check(
+        &self,
+        route: &ROUTE,
+        caller: Option<&str>,
+    ) -> Result<(), Error> {
+        let limit = match self.limit(route) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let key = (route.clone(), caller.map(ToOwned::to_owned));
+        if !windows.counts.contains_key(&key) {
+            self.make_room(&mut windows, now);
+        }
+        let (start, count) = windows.counts.entry(key).or_insert((now, 0));
+        let elapsed = now.duration_since(*start);
+        if elapsed >= limit.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= limit.max_requests {
+            return Err(Error::RateLimited {
+                route: route.handler_name(),
+                retry_after: limit.window.saturating_sub(elapsed),
+            });
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// Before a window is added, remove the expired windows if the longest
+    /// limit's window has passed since they were last removed or if the
+    /// limiter is full, in which case the oldest window is also removed if
+    /// none has expired.
+    fn make_room(&self, windows: &mut RateWindows<ROUTE>, now: Instant) {
+        let longest_window = self
+            .limits
+            .values()
+            .chain(&self.default_limit)
+            .map(|limit| limit.window)
+            .max()
+            .unwrap_or_default();
+        let full = windows.counts.len() >= self.max_windows;
+        let sweep_due = match windows.last_sweep {
+            Some(last_sweep) => {
+                now.duration_since(last_sweep) >= longest_window
+            }
+            None => true,
+        };
+        if full || sweep_due {
+            windows.counts.retain(|(route, _caller), (start, _count)| {
+                matches!(
+                    self.limit(route),
+                    Some(limit) if now.duration_since(*start) < limit.window
+                )
+            });
+            windows.last_sweep = Some(now);
+        }
+        if windows.counts.len() >= self.max_windows {
+            let oldest = windows
+                .counts
+                .iter()
+                .min_by_key(|(_key, (start, _count))| *start)
+                .map(|(key, _window)| key.clone());
+            if let Some(oldest) = oldest {
+                windows.counts.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A server-side cache of the responses of a router's expensive
+/// deterministic routes, keyed by the router's route type, the route's args,
+/// the block height and whether a proof is requested. It's meant to be used
+/// by the router's `handle_cached` method. Only the responses of the routes
+/// configured with [`ResponseCache::with_route`] are cached, each either for
+/// a TTL or until a new block is committed. A request for the latest block is
+/// keyed by the height of the last committed block. The requests with `data`,
+/// for only the metadata, with an `if_none_match` or in-process requests are
+/// not cached, because their responses depend on more than the key.
 ///
-/// If the handler wants to support request options, it can be defined as
-/// `(with_options $handler)` and then the expected signature is:
-/// ```rust,ignore
-/// fn handler<D, H>(ctx: RequestCtx<'_, D, H>, request: &RequestQuery, args
-/// ...)   -> storage_api::Result<ResponseQuery<ReturnType>>
-/// where
-///     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
-///     H: 'static + StorageHasher + Sync;
-/// ```
-#[macro_export]
-macro_rules! router {
-    { $name:ident, $( $pattern:tt $( -> $return_type:path )? = $handle:tt , )* } => (
+/// The expired responses are removed when a response is cached after a new
+/// block is committed, and the number of the responses is bounded by
+/// [`ResponseCache::with_max_entries`], so that the requests for many args or
+/// heights cannot grow the cache without a bound.
+#[derive(Debug)]
+pub struct ResponseCache<ROUTE> {
+    ttls: HashMap<ROUTE, Option<Duration>>,
+    max_entries: usize,
+    entries: Mutex<CachedResponses<ROUTE>>,
+}
 
-	// `paste!` is used to convert the $name cases for a derived type and function name
-	paste::paste! {
+/// The key of a response cached by a [`ResponseCache`]: the route, its args,
+/// the block height and the proof flag
+type CachedResponseKey<ROUTE> = (ROUTE, Vec<String>, BlockHeight, bool);
 
-        router_type!{[<$name:camel>] {}, $( $pattern $( -> $return_type )? = $handle ),* }
+/// A response cached by a [`ResponseCache`] with the time at which it was
+/// cached and the height of the last committed block at that time
+type CachedResponse = (Instant, BlockHeight, EncodedResponseQuery);
 
-		impl $crate::ledger::queries::Router for [<$name:camel>] {
-            // TODO: for some patterns, there's unused assignment of `$end`
-            #[allow(unused_assignments)]
-            fn internal_handle<D, H>(
-			    &self,
-                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
-                request: &$crate::ledger::queries::RequestQuery,
-                start: usize
-            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
-            where
-                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
-                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+/// The cached responses of a [`ResponseCache`]
+#[derive(Debug)]
+struct CachedResponses<ROUTE> {
+    responses: HashMap<CachedResponseKey<ROUTE>, CachedResponse>,
+    /// The height of the last committed block when the expired responses
+    /// were last removed
+    swept_height: Option<BlockHeight>,
+}
+
+impl<ROUTE> Default for ResponseCache<ROUTE> {
+    fn default() -> Self {
+        Self {
+            ttls: HashMap::default(),
+            max_entries: DEFAULT_MAX_CACHED_RESPONSES,
+            entries: Mutex::new(CachedResponses {
+                responses: HashMap::default(),
+                swept_height: None,
+            }),
+        }
+    }
+}
+
+impl<ROUTE> ResponseCache<ROUTE>
+where
+    ROUTE: Clone + Eq + StdHash,
+{
+    /// Create a cache that doesn't cache the responses of any route
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cache the responses of the given `route` for the `ttl`, or until a new
+    /// block is committed if it's `None`
+    pub fn with_route(mut self, route: ROUTE, ttl: Option<Duration>) -> Self {
+        self.ttls.insert(route, ttl);
+        self
+    }
+
+    /// Set the maximum number of the cached responses. When it's reached,
+    /// the expired responses are removed and if there are none, the oldest
+    /// response is. The default is [`DEFAULT_MAX_CACHED_RESPONSES`].
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Check if the responses of the given `route` are cached
+    pub fn caches(&self, route: &ROUTE) -> bool {
+        self.ttls.contains_key(route)
+    }
+
+    /// Get the cached response to the `request` for the `route` with the
+    /// given `args`, if it's cached and it's not expired, otherwise `handle`
+    /// the request and cache its response, if it's successful.
+    /// `last_height` is the height of the last committed block.
+    pub fn get_or_handle(
+        &self,
+        route: ROUTE,
+        args: Vec<String>,
+        request: &RequestQuery,
+        last_height: BlockHeight,
+        handle: impl FnOnce() -> storage_api::Result<EncodedResponseQuery>,
+    ) -> storage_api::Result<EncodedResponseQuery> {
+        let ttl = match self.ttls.get(&route) {
+            Some(ttl)
+                if request.data.is_empty()
+                    && !request.head
+                    && request.if_none_match.is_none()
+                    && !request.in_process =>
+            {
+                *ttl
+            }
+            _ => return handle(),
+        };
+        // No height or the height `0` is the latest block
+        let height = match request.height {
+            BlockHeight(0) => last_height,
+            height => height,
+        };
+        let key = (route, args, height, request.prove);
+        let now = Instant::now();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some((cached_at, cached_height, response)) =
+                entries.responses.get(&key)
             {
+                if !is_expired(
+                    ttl,
+                    *cached_at,
+                    *cached_height,
+                    now,
+                    last_height,
+                ) {
+                    return Ok(response.clone());
+                }
+                entries.responses.remove(&key);
+            }
+        }
+        let response = handle()?;
+        let mut entries = self.entries.lock().unwrap();
+        self.make_room(&mut entries, now, last_height);
+        entries
+            .responses
+            .insert(key, (now, last_height, response.clone()));
+        Ok(response)
+    }
 
-                // Import for `.into_storage_result()`
-                use $crate::ledger::storage_api::ResultExt;
+    /// Remove all the cached responses
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().responses.clear();
+    }
 
-                // Import helper from this crate used inside the macros
-                use $crate::ledger::queries::router::find_next_slash_index;
-
-				$(
-                    // This loop never repeats, it's only used for a breaking
-                    // mechanism when a $pattern is not matched to skip to the
-                    // next one, if any
-                    loop {
-                        let mut start = start;
-                        // Try to match, parse args and invoke $handle, will
-                        // break the `loop` not matched
-                        try_match!(ctx, request, start, $handle, $pattern);
-                    }
-                )*
+    /// Before a response is cached, remove the expired responses if a new
+    /// block has been committed since they were last removed or if the cache
+    /// is full, in which case the oldest response is also removed if none
+    /// has expired.
+    fn make_room(
+        &self,
+        entries: &mut CachedResponses<ROUTE>,
+        now: Instant,
+        last_height: BlockHeight,
+    ) {
+        let full = entries.responses.len() >= self.max_entries;
+        if full || entries.swept_height != Some(last_height) {
+            entries.responses.retain(
+                |(route, _args, _height, _prove),
+                 (cached_at, cached_height, _response)| {
+                    matches!(
+                        self.ttls.get(route),
+                        Some(ttl) if !is_expired(
+                            *ttl,
+                            *cached_at,
+                            *cached_height,
+                            now,
+                            last_height,
+                        )
+                    )
+                },
+            );
+            entries.swept_height = Some(last_height);
+        }
+        if entries.responses.len() >= self.max_entries {
+            let oldest = entries
+                .responses
+                .iter()
+                .min_by_key(|(_key, (cached_at, _height, _response))| {
+                    *cached_at
+                })
+                .map(|(key, _entry)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.responses.remove(&oldest);
+            }
+        }
+    }
+}
 
-				return Err(
-                    $crate::ledger::queries::router::Error::WrongPath(request.path.clone()))
-                    .into_storage_result();
-			}
-		}
+/// Is a response of a [`ResponseCache`] cached at `cached_at`, when the
+/// height of the last committed block was `cached_height`, expired for the
+/// route's `ttl`?
+fn is_expired(
+    ttl: Option<Duration>,
+    cached_at: Instant,
+    cached_height: BlockHeight,
+    now: Instant,
+    last_height: BlockHeight,
+) -> bool {
+    match ttl {
+        Some(ttl) => now.duration_since(cached_at) >= ttl,
+        None => cached_height != last_height,
+    }
+}
 
-		#[doc = "`" $name "` path router"]
-		pub const $name: [<$name:camel>] = [<$name:camel>]::new();
-	}
+/// A dynamic arg of a route in [`RouteInfo`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteArg {
+    /// Name of the arg
+    pub name: &'static str,
+    /// Type of the arg, which is `str` for an untyped arg, `b64` for a
+    /// base64url-encoded bytes arg, `**` for a glob arg and e.g. `u64 radix
+    /// 16` for an integer arg in a radix
+    pub ty: &'static str,
+    /// Whether the arg is optional
+    pub optional: bool,
+}
 
-    );
+/// The spec of a route's parameter, as returned by the router's
+/// `route_params` method (e.g. to generate a form for a query).
+pub type ParamSpec = RouteArg;
+
+impl Display for RouteArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.optional {
+            write!(f, "{}: opt {}", self.name, self.ty)
+        } else {
+            write!(f, "{}: {}", self.name, self.ty)
+        }
+    }
 }
 
-/// You can expand the `handlers!` macro invocation with e.g.:
-/// ```shell
-/// cargo expand ledger::queries::router::test_rpc_handlers --features "ferveo-tpke, ibc-mocks, testing, wasm-runtime, tendermint-rpc" --tests --lib
-/// ```
-#[cfg(test)]
-mod test_rpc_handlers {
-    use borsh::BorshSerialize;
+/// Write the given `routes` as a table aligned in columns with the route's
+/// template, handler and args, one route per line (e.g. for a CLI's help
+/// output).
+pub fn write_routes_table(
+    out: &mut impl Write,
+    routes: &[RouteInfo],
+) -> io::Result<()> {
+    const HEADER: [&str; 3] = ["ROUTE", "HANDLER", "ARGS"];
+    let rows: Vec<[String; 3]> = routes
+        .iter()
+        .map(|route| {
+            [
+                route.template.clone(),
+                route.handler.to_owned(),
+                itertools::join(&route.args, ", "),
+            ]
+        })
+        .collect();
+    let width = |column: usize| {
+        rows.iter()
+            .map(|row| row[column].len())
+            .chain([HEADER[column].len()])
+            .max()
+            .unwrap_or_default()
+    };
+    let (template_width, handler_width) = (width(0), width(1));
+    for [template, handler, args] in
+        std::iter::once(HEADER.map(ToOwned::to_owned)).chain(rows)
+    {
+        let line = format!(
+            "{template:template_width$}  {handler:handler_width$}  {args}"
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
 
-    use crate::ledger::queries::{
-        EncodedResponseQuery, RequestCtx, RequestQuery, ResponseQuery,
+/// Write a protobuf (proto3) definition of a `service` in the `package` with
+/// an RPC for each of the given `routes` (e.g. for a gRPC gateway). The RPC is
+/// named after the route's handler in camel case and its request message has
+/// a field for each of the route's args, in order. The response message has a
+/// single `value` field with the route's response type.
+///
+/// The args, which are parsed from path segments, are mapped to a protobuf
+/// scalar type when they have an equivalent one and are `string`s otherwise.
+/// A response type without an equivalent protobuf type is `bytes` with the
+/// borsh-encoded response.
+#[cfg(any(test, feature = "tooling"))]
+pub fn write_proto_service(
+    out: &mut impl Write,
+    package: &str,
+    service: &str,
+    routes: &[RouteInfo],
+) -> io::Result<()> {
+    writeln!(out, "syntax = \"proto3\";")?;
+    writeln!(out)?;
+    writeln!(out, "package {package};")?;
+    writeln!(out)?;
+    // The RPCs' names, which must be unique in the service, even if the same
+    // handler name is used by more than one sub-router
+    let names = unique_names(routes, camel_case);
+    writeln!(out, "service {service} {{")?;
+    for (route, name) in routes.iter().zip(&names) {
+        writeln!(out, "  // {}", route.template)?;
+        writeln!(out, "  rpc {name}({name}Request) returns ({name}Response);")?;
+    }
+    writeln!(out, "}}")?;
+    for (route, name) in routes.iter().zip(&names) {
+        writeln!(out)?;
+        writeln!(out, "message {name}Request {{")?;
+        for (number, arg) in route.args.iter().enumerate() {
+            let (label, ty) =
+                proto_type(split_radix(arg.ty).0).unwrap_or(("", "string"));
+            let label = if arg.optional && label.is_empty() {
+                "optional "
+            } else {
+                label
+            };
+            writeln!(out, "  {label}{ty} {} = {};", arg.name, number + 1)?;
+        }
+        writeln!(out, "}}")?;
+        writeln!(out)?;
+        writeln!(out, "message {name}Response {{")?;
+        match proto_type(route.response_ty) {
+            Some((label, ty)) => writeln!(out, "  {label}{ty} value = 1;")?,
+            None => {
+                writeln!(
+                    out,
+                    "  // The borsh-encoded `{}`",
+                    route.response_ty.replace(' ', "")
+                )?;
+                writeln!(out, "  bytes value = 1;")?;
+            }
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+/// Map a Rust type of a route's arg or response, as declared in the router,
+/// to a protobuf field label (with a trailing space, if any) and scalar type,
+/// or `None` if the type doesn't have an equivalent protobuf type.
+#[cfg(any(test, feature = "tooling"))]
+fn proto_type(ty: &str) -> Option<(&'static str, &'static str)> {
+    let ty: String = ty.chars().filter(|c| !c.is_whitespace()).collect();
+    if ty == "Vec<u8>" {
+        return Some(("", "bytes"));
+    }
+    let inner = |wrapper: &str| {
+        ty.strip_prefix(wrapper)
+            .and_then(|ty| ty.strip_prefix('<'))
+            .and_then(|ty| ty.strip_suffix('>'))
     };
-    use crate::ledger::storage::{DBIter, StorageHasher, DB};
-    use crate::ledger::storage_api::{self, ResultExt};
-    use crate::types::storage::Epoch;
-    use crate::types::token;
+    if let Some(inner) = inner("Option") {
+        // A label can't be nested
+        return match proto_type(inner)? {
+            ("", inner) => Some(("optional ", inner)),
+            _ => None,
+        };
+    }
+    if let Some(inner) = inner("Vec") {
+        return match proto_type(inner)? {
+            ("", inner) => Some(("repeated ", inner)),
+            _ => None,
+        };
+    }
+    // The last segment of the type's path
+    let scalar = match ty.rsplit("::").next().unwrap_or_default() {
+        "bool" => "bool",
+        "u8" | "u16" | "u32" => "uint32",
+        "u64" | "usize" | "BlockHeight" | "Epoch" => "uint64",
+        "i8" | "i16" | "i32" => "int32",
+        "i64" | "isize" => "int64",
+        "f32" => "float",
+        "f64" => "double",
+        "String" | "str" => "string",
+        "b64" => "bytes",
+        _ => return None,
+    };
+    Some(("", scalar))
+}
 
-    /// A little macro to generate boilerplate for RPC handler functions.
-    /// These are implemented to return their name as a String, joined by
-    /// slashes with their argument values turned `to_string()`, if any.
-    macro_rules! handlers {
-        (
-            // name and params, if any
-            $( $name:ident $( ( $( $param:ident: $param_ty:ty ),* ) )? ),*
-            // optional trailing comma
+/// Split the type of an integer arg in a radix, e.g. `u64 radix 16`, into the
+/// integer type and the radix, or `None` for the other args.
+#[cfg(any(test, feature = "tooling"))]
+fn split_radix(ty: &str) -> (&str, Option<u32>) {
+    match ty.split_once(" radix ") {
+        Some((ty, radix)) => (ty, radix.parse().ok()),
+        None => (ty, None),
+    }
+}
+
+/// Convert a `snake_case` handler name to `CamelCase`.
+#[cfg(any(test, feature = "tooling"))]
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Name the `routes` after their handlers with the given `case` conversion,
+/// suffixing the repeated names (e.g. when the same handler name is used by
+/// more than one sub-router) with their count to make them unique.
+#[cfg(any(test, feature = "tooling"))]
+fn unique_names(
+    routes: &[RouteInfo],
+    case: impl Fn(&str) -> String,
+) -> Vec<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    routes
+        .iter()
+        .map(|route| {
+            let name = case(route.handler);
+            let count = counts.entry(name.clone()).or_default();
+            *count += 1;
+            if *count == 1 {
+                name
+            } else {
+                format!("{name}{count}")
+            }
+        })
+        .collect()
+}
+
+/// Write a TypeScript module with an async function for each of the given
+/// `routes`, which constructs the route's path from its typed args, sends it
+/// with a `QueryClient` and decodes the borsh-encoded response with the
+/// `borsh` package's `deserialize`. The function is named after the route's
+/// handler in `camelCase` and it takes the route's args with their names, in
+/// order, with the optional args as optional parameters.
+///
+/// The args are mapped to a TypeScript type when they have an equivalent one
+/// and are `string`s (as parsed from the path segments) otherwise. A response
+/// type without an equivalent borsh schema is returned as the borsh-encoded
+/// `Uint8Array`.
+#[cfg(any(test, feature = "tooling"))]
+pub fn write_ts_client(
+    out: &mut impl Write,
+    routes: &[RouteInfo],
+) -> io::Result<()> {
+    writeln!(out, "// Generated from the router's routes, do not edit.")?;
+    writeln!(out, "import {{ deserialize }} from \"borsh\";")?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "/** Sends a query at the `path` and resolves to the response's \
+         borsh-encoded data. */"
+    )?;
+    writeln!(out, "export interface QueryClient {{")?;
+    writeln!(out, "  request(path: string): Promise<Uint8Array>;")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "function encodeB64Segment(bytes: Uint8Array): string {{"
+    )?;
+    writeln!(
+        out,
+        "  const binary = Array.from(bytes, (byte) => \
+         String.fromCharCode(byte)).join(\"\");"
+    )?;
+    writeln!(
+        out,
+        "  return btoa(binary).replace(/\\+/g, \"-\").replace(/\\//g, \
+         \"_\").replace(/=+$/, \"\");"
+    )?;
+    writeln!(out, "}}")?;
+    let names = unique_names(routes, lower_camel_case);
+    for (route, name) in routes.iter().zip(&names) {
+        // An optional parameter cannot be followed by a required one, so the
+        // optional args before a required one take an explicit `undefined`
+        let last_required = route.args.iter().rposition(|arg| !arg.optional);
+        let params: Vec<String> = route
+            .args
+            .iter()
+            .enumerate()
+            .map(|(index, arg)| {
+                let ty = ts_arg_type(split_radix(arg.ty).0);
+                if !arg.optional {
+                    format!("{}: {ty}", arg.name)
+                } else if matches!(last_required, Some(last) if index < last) {
+                    format!("{}: {ty} | undefined", arg.name)
+                } else {
+                    format!("{}?: {ty}", arg.name)
+                }
+            })
+            .collect();
+        let params = std::iter::once("client: QueryClient".to_owned())
+            .chain(params)
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(out)?;
+        writeln!(out, "/** `{}` */", route.template)?;
+        match ts_schema(route.response_ty) {
+            Some((schema, ty)) => {
+                writeln!(
+                    out,
+                    "export async function {name}({params}): Promise<{ty}> {{"
+                )?;
+                writeln!(out, "  const path = {};", ts_path(route))?;
+                writeln!(
+                    out,
+                    "  return deserialize({schema}, await \
+                     client.request(path)) as {ty};"
+                )?;
+            }
+            None => {
+                writeln!(
+                    out,
+                    "// Resolves to the borsh-encoded `{}`",
+                    route.response_ty.replace(' ', "")
+                )?;
+                writeln!(
+                    out,
+                    "export async function {name}({params}): \
+                     Promise<Uint8Array> {{"
+                )?;
+                writeln!(out, "  const path = {};", ts_path(route))?;
+                writeln!(out, "  return client.request(path);")?;
+            }
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+/// Write a graphviz DOT digraph of the route tree of the given `routes` of a
+/// root router named `name` (e.g. for documentation). Every segment of the
+/// routes' templates is a node identified by its path (e.g. `"/b/3"`), with
+/// an edge from its parent segment's node, or from the root node `"/"` for a
+/// first segment. A literal segment is an ellipse and a dynamic arg is a
+/// dashed box labelled with the arg's type. The handler of each route is a
+/// note attached to the node of its last segment.
+#[cfg(any(test, feature = "tooling"))]
+pub fn write_dot(
+    out: &mut impl Write,
+    name: &str,
+    routes: &[RouteInfo],
+) -> io::Result<()> {
+    writeln!(out, "digraph {} {{", dot_id(name))?;
+    writeln!(out, "  rankdir=LR;")?;
+    writeln!(out, "  \"/\" [label={}, shape=doublecircle];", dot_id(name))?;
+    let mut nodes = std::collections::HashSet::new();
+    for route in routes {
+        let mut parent = "/".to_owned();
+        for segment in route.template.split('/').filter(|s| !s.is_empty()) {
+            let path = format!("{}/{segment}", parent.trim_end_matches('/'));
+            if nodes.insert(path.clone()) {
+                let arg = segment
+                    .strip_prefix('{')
+                    .and_then(|arg| arg.strip_suffix('}'))
+                    .map(|arg| arg.trim_end_matches('?'));
+                match arg {
+                    Some(arg) => {
+                        let label = route
+                            .args
+                            .iter()
+                            .find(|route_arg| route_arg.name == arg)
+                            .map_or_else(|| arg.to_owned(), dot_arg_label);
+                        writeln!(
+                            out,
+                            "  {} [label={}, shape=box, style=dashed];",
+                            dot_id(&path),
+                            dot_id(&label)
+                        )?;
+                    }
+                    None => writeln!(
+                        out,
+                        "  {} [label={}, shape=ellipse];",
+                        dot_id(&path),
+                        dot_id(segment)
+                    )?,
+                }
+                writeln!(out, "  {} -> {};", dot_id(&parent), dot_id(&path))?;
+            }
+            parent = path;
+        }
+        let handler = format!("{parent}#{}", route.handler);
+        writeln!(
+            out,
+            "  {} [label={}, shape=note];",
+            dot_id(&handler),
+            dot_id(&format!(
+                "{} -> {}",
+                route.handler,
+                route.response_ty.replace(' ', "")
+            ))
+        )?;
+        writeln!(
+            out,
+            "  {} -> {} [style=dotted];",
+            dot_id(&parent),
+            dot_id(&handler)
+        )?;
+    }
+    writeln!(out, "}}")
+}
+
+/// The label of a route's arg in a DOT graph, which is the arg with its type
+/// without the spaces added by `stringify!`, e.g. `a1: token::Amount`.
+#[cfg(any(test, feature = "tooling"))]
+fn dot_arg_label(arg: &RouteArg) -> String {
+    let (ty, radix) = split_radix(arg.ty);
+    let mut ty = ty.replace(' ', "");
+    if let Some(radix) = radix {
+        ty = format!("{ty} radix {radix}");
+    }
+    let opt = if arg.optional { "opt " } else { "" };
+    format!("{}: {opt}{ty}", arg.name)
+}
+
+/// Quote a graphviz DOT id, escaping its quotes and backslashes.
+#[cfg(any(test, feature = "tooling"))]
+fn dot_id(id: &str) -> String {
+    format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Convert a `snake_case` handler name to `camelCase`.
+#[cfg(any(test, feature = "tooling"))]
+fn lower_camel_case(name: &str) -> String {
+    let name = camel_case(name);
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+/// Map a Rust type of a route's arg, as declared in the router, to a
+/// TypeScript type. The args without an equivalent type are `string`s.
+#[cfg(any(test, feature = "tooling"))]
+fn ts_arg_type(ty: &str) -> &'static str {
+    match ty.rsplit("::").next().unwrap_or_default().trim() {
+        "bool" => "boolean",
+        "u8" | "u16" | "u32" | "i8" | "i16" | "i32" | "f32" | "f64" => "number",
+        "u64" | "u128" | "usize" | "i64" | "i128" | "isize" | "BlockHeight"
+        | "Epoch" => "bigint",
+        "b64" => "Uint8Array",
+        _ => "string",
+    }
+}
+
+/// Map a Rust type of a route's response, as declared in the router, to the
+/// `borsh` package's schema and the TypeScript type it decodes to, or `None`
+/// if the type doesn't have an equivalent schema.
+#[cfg(any(test, feature = "tooling"))]
+fn ts_schema(ty: &str) -> Option<(String, String)> {
+    let ty: String = ty.chars().filter(|c| !c.is_whitespace()).collect();
+    let inner = |wrapper: &str| {
+        ty.strip_prefix(wrapper)
+            .and_then(|ty| ty.strip_prefix('<'))
+            .and_then(|ty| ty.strip_suffix('>'))
+    };
+    if let Some(inner) = inner("Option") {
+        let (schema, ty) = ts_schema(inner)?;
+        return Some((
+            format!("{{ option: {schema} }}"),
+            format!("{ty} | null"),
+        ));
+    }
+    if let Some(inner) = inner("Vec") {
+        let (schema, ty) = ts_schema(inner)?;
+        return Some((
+            format!("{{ array: {{ type: {schema} }} }}"),
+            format!("Array<{ty}>"),
+        ));
+    }
+    // The last segment of the type's path
+    let (schema, ty) = match ty.rsplit("::").next().unwrap_or_default() {
+        "bool" => ("bool", "boolean"),
+        "u8" => ("u8", "number"),
+        "u16" => ("u16", "number"),
+        "u32" => ("u32", "number"),
+        "i8" => ("i8", "number"),
+        "i16" => ("i16", "number"),
+        "i32" => ("i32", "number"),
+        "f32" => ("f32", "number"),
+        "f64" => ("f64", "number"),
+        // A `usize` is borsh-encoded as a `u64`
+        "u64" | "usize" | "BlockHeight" | "Epoch" => ("u64", "bigint"),
+        "u128" => ("u128", "bigint"),
+        "i64" => ("i64", "bigint"),
+        "i128" => ("i128", "bigint"),
+        "String" | "str" => ("string", "string"),
+        _ => return None,
+    };
+    Some((format!("\"{schema}\""), ty.to_owned()))
+}
+
+/// Construct a TypeScript template literal of a route's path from its
+/// template, in which every arg is replaced with its parameter. An optional
+/// arg is omitted together with its preceding separator when it's
+/// `undefined`.
+#[cfg(any(test, feature = "tooling"))]
+fn ts_path(route: &RouteInfo) -> String {
+    let arg = |name: &str| {
+        let ty = route
+            .args
+            .iter()
+            .find(|arg| arg.name == name)
+            .map(|arg| arg.ty)
+            .unwrap_or_default();
+        if ty == "b64" {
+            format!("${{encodeB64Segment({name})}}")
+        } else if let (_, Some(radix)) = split_radix(ty) {
+            format!("{}${{{name}.toString({radix})}}", radix_prefix(radix))
+        } else {
+            format!("${{{name}}}")
+        }
+    };
+    let mut path = String::from("`");
+    let mut rest = route.template.as_str();
+    while let Some((literal, tail)) = rest.split_once('{') {
+        let (name, tail) = match tail.split_once('}') {
+            Some(split) => split,
+            None => break,
+        };
+        let mut literal = literal.to_owned();
+        match name.strip_suffix('?') {
+            Some(name) => {
+                let separator = literal.pop().map(String::from);
+                path.push_str(&escape_ts_template(&literal));
+                path.push_str(&format!(
+                    "${{{name} === undefined ? \"\" : `{}{}`}}",
+                    escape_ts_template(&separator.unwrap_or_default()),
+                    arg(name)
+                ));
+            }
+            None => {
+                path.push_str(&escape_ts_template(&literal));
+                path.push_str(&arg(name));
+            }
+        }
+        rest = tail;
+    }
+    path.push_str(&escape_ts_template(rest));
+    path.push('`');
+    path
+}
+
+/// Escape the characters of a literal part of a TypeScript template literal.
+#[cfg(any(test, feature = "tooling"))]
+fn escape_ts_template(literal: &str) -> String {
+    literal
+        .replace('\\', "\\\\")
+        .replace('`', "\\`")
+        .replace('$', "\\$")
+}
+
+impl RouteInfo {
+    /// Check if the route's template is under the given `prefix` path. The
+    /// `prefix` is matched by whole segments, so e.g. `/b/3` is a prefix of
+    /// `/b/3/{a1}`, but not of `/b/30`.
+    pub fn is_under(&self, prefix: &str) -> bool {
+        let prefix = prefix.trim_end_matches('/');
+        match self.template.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    /// Describe the route as a JSON object with its `template`, `handler`,
+    /// `params` (each with its `name`, `type` and whether it's `optional`),
+    /// `return_type`, and whether it's `provable` and `deprecated`. The
+    /// fields are stable, so it can be consumed by external tooling.
+    pub fn to_json(&self) -> serde_json::Value {
+        let params: Vec<serde_json::Value> = self
+            .args
+            .iter()
+            .map(|arg| {
+                serde_json::json!({
+                    "name": arg.name,
+                    "type": arg.ty,
+                    "optional": arg.optional,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "template": self.template,
+            "handler": self.handler,
+            "params": params,
+            "return_type": self.response_ty,
+            "provable": self.provable,
+            "deprecated": self.deprecated,
+        })
+    }
+}
+
+/// A failure of a query sent with one of the `router!`'s generated `try_`
+/// client methods, classified by its kind, which the plain methods conflate
+/// in the client's error `E`.
+#[cfg(any(test, feature = "async-client"))]
+#[derive(Error, Debug)]
+pub enum QueryError<E> {
+    /// The client failed to send the request or to receive its response
+    #[error("Transport error: {0}")]
+    Transport(E),
+    /// The response's data couldn't be decoded into the expected type
+    #[error("Decoding error: {0}")]
+    Decode(io::Error),
+    /// The query failed with a router's error, e.g. the node is unhealthy
+    #[error("Query failed: {0}")]
+    Server(Error),
+    /// The request timed out
+    #[error("The query timed out")]
+    Timeout,
+}
+
+#[cfg(any(test, feature = "async-client"))]
+impl<E> QueryError<E>
+where
+    E: std::error::Error + 'static,
+{
+    /// Classify an error returned by a client. It's a [`QueryError::Timeout`]
+    /// if it's caused by an `std::io::Error` of the kind `TimedOut`, a
+    /// [`QueryError::Server`] if it's a router's [`Error`] wrapped in an
+    /// `std::io::Error` (like the ones of [`Client::check_health`]) and
+    /// otherwise a [`QueryError::Transport`].
+    pub fn from_client_error(err: E) -> Self {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&err);
+        while let Some(cause) = source {
+            if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+                if io_err.kind() == io::ErrorKind::TimedOut {
+                    return Self::Timeout;
+                }
+            }
+            source = cause.source();
+        }
+
+        let err: Box<dyn std::any::Any> = Box::new(err);
+        let err = match err.downcast::<io::Error>() {
+            Ok(io_err) if matches!(io_err.get_ref(), Some(inner) if inner.is::<Error>()) =>
+            {
+                let inner = io_err
+                    .into_inner()
+                    .expect("The error must have an inner error")
+                    .downcast::<Error>()
+                    .expect("The inner error must be a router's error");
+                return Self::Server(*inner);
+            }
+            Ok(io_err) => io_err as Box<dyn std::any::Any>,
+            Err(err) => err,
+        };
+        Self::Transport(
+            *err.downcast::<E>()
+                .expect("The error must have the client's error type"),
+        )
+    }
+}
+
+/// Decode the value of a response to a simple request. A client in the same
+/// process may return the typed value as is in the response's `value`.
+#[cfg(any(test, feature = "async-client"))]
+fn decode_simple_response<T>(response: EncodedResponseQuery) -> io::Result<T>
+where
+    T: BorshDeserialize + 'static,
+{
+    if let Some(value) = &response.value {
+        if let Some(typed) = value.take::<T>() {
+            return Ok(typed);
+        }
+        // The handler's result has another type than the client's
+        return T::try_from_slice(&value.encode()?[..]);
+    }
+    T::try_from_slice(&response.data[..])
+}
+
+/// Request a borsh-encoded value at the `path` without any additional request
+/// data, specified block height or proof and decode it. This is the body of
+/// the `router!`'s generated client methods of the handlers that don't use
+/// the request, which only construct the path, so that the request isn't
+/// expanded for every route, but only instantiated once per client and
+/// response type.
+#[cfg(any(test, feature = "async-client"))]
+pub async fn request_simple<CLIENT, T>(
+    client: &CLIENT,
+    path: String,
+) -> Result<T, CLIENT::Error>
+where
+    CLIENT: Client + Sync,
+    T: BorshDeserialize + 'static,
+{
+    client.check_health().await?;
+    let response = client.simple_request_ref(path).await?;
+    Ok(decode_simple_response(response)?)
+}
+
+/// Like [`request_simple`], but the failure is classified into a
+/// [`QueryError`]. This is the body of the `router!`'s generated `try_`
+/// client methods of the handlers that don't use the request.
+#[cfg(any(test, feature = "async-client"))]
+pub async fn try_request_simple<CLIENT, T>(
+    client: &CLIENT,
+    path: String,
+) -> Result<T, QueryError<CLIENT::Error>>
+where
+    CLIENT: Client + Sync,
+    CLIENT::Error: std::error::Error + 'static,
+    T: BorshDeserialize + 'static,
+{
+    client
+        .check_health()
+        .await
+        .map_err(QueryError::from_client_error)?;
+    let response = client
+        .simple_request_ref(path)
+        .await
+        .map_err(QueryError::from_client_error)?;
+    decode_simple_response(response).map_err(QueryError::Decode)
+}
+
+/// Like [`request_simple`], but the raw borsh-encoded bytes of the value are
+/// returned without decoding them.
+#[cfg(any(test, feature = "async-client"))]
+pub async fn request_simple_raw<CLIENT>(
+    client: &CLIENT,
+    path: String,
+) -> Result<Vec<u8>, CLIENT::Error>
+where
+    CLIENT: Client + Sync,
+{
+    client.check_health().await?;
+    client.simple_request(path).await
+}
+
+/// Request a value at the `path` with optional data, optionally specified
+/// height and optional proof and decode the response with
+/// [`decode_response`]. This is the body of the `router!`'s generated client
+/// methods of the handlers that use the request (`with_options`).
+#[cfg(any(test, feature = "async-client"))]
+pub async fn request_with_options<CLIENT, T>(
+    client: &CLIENT,
+    path: String,
+    data: Option<Vec<u8>>,
+    height: HeightSelector,
+    prove: bool,
+) -> Result<ResponseQuery<T>, CLIENT::Error>
+where
+    CLIENT: Client + Sync,
+    T: BorshDeserialize,
+{
+    let response = client.raw_abci_query(path, data, height, prove).await?;
+    Ok(decode_response(response)?)
+}
+
+/// Like [`request_with_options`], but the failure is classified into a
+/// [`QueryError`]. This is the body of the `router!`'s generated `try_`
+/// client methods of the handlers that use the request (`with_options`).
+#[cfg(any(test, feature = "async-client"))]
+pub async fn try_request_with_options<CLIENT, T>(
+    client: &CLIENT,
+    path: String,
+    data: Option<Vec<u8>>,
+    height: HeightSelector,
+    prove: bool,
+) -> Result<ResponseQuery<T>, QueryError<CLIENT::Error>>
+where
+    CLIENT: Client + Sync,
+    CLIENT::Error: std::error::Error + 'static,
+    T: BorshDeserialize,
+{
+    let response = client
+        .raw_abci_query(path, data, height, prove)
+        .await
+        .map_err(QueryError::from_client_error)?;
+    decode_response(response).map_err(QueryError::Decode)
+}
+
+/// Decode the borsh-encoded data of a response, expand its compact proof, if
+/// any, with [`expand_proof`], split the proofs of multiple keys, if any,
+/// into its `proofs` and decode its warnings from the `log`, if any.
+#[cfg(any(test, feature = "async-client"))]
+pub fn decode_response<T>(
+    response: EncodedResponseQuery,
+) -> io::Result<ResponseQuery<T>>
+where
+    T: BorshDeserialize,
+{
+    let ResponseQuery {
+        data,
+        info,
+        info_message,
+        log,
+        code,
+        codespace,
+        proof,
+        root,
+        proofs: _,
+        etag,
+        not_modified,
+        value,
+        redirect,
+        retry_after,
+        content_length,
+        warnings,
+        matched_path,
+    } = expand_proof(response);
+    let data = T::try_from_slice(&data[..])?;
+    let proofs = proof
+        .as_ref()
+        .and_then(KeyedProof::split)
+        .unwrap_or_default();
+    let (log, warnings) = match Warning::decode_from_log(&log) {
+        Some(decoded) => decoded,
+        None => (log, warnings),
+    };
+    Ok(ResponseQuery {
+        data,
+        info,
+        info_message,
+        log,
+        code,
+        codespace,
+        proof,
+        root,
+        proofs,
+        etag,
+        not_modified,
+        value,
+        redirect,
+        retry_after,
+        content_length,
+        warnings,
+        matched_path,
+    })
+}
+
+/// Expand the compact proof of a response (see [`CompactProof`]), if any. A
+/// proof that isn't compact is left as is.
+#[cfg(any(test, feature = "async-client"))]
+pub fn expand_proof(
+    mut response: EncodedResponseQuery,
+) -> EncodedResponseQuery {
+    response.proof = response
+        .proof
+        .map(|proof| CompactProof::expand(&proof).unwrap_or(proof));
+    response
+}
+
+/// Build a `curl` command that queries the given router `path` via the
+/// Tendermint RPC `abci_query` endpoint at the `base_url` (e.g.
+/// `http://127.0.0.1:26657`).
+#[cfg(any(test, feature = "tooling"))]
+pub fn curl_command(base_url: &str, path: &str) -> String {
+    // The `path` param is a JSON string, which has to be URL-encoded
+    let path = format!("\"{path}\"");
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    format!(
+        "curl -s '{}/abci_query?path={encoded}'",
+        base_url.trim_end_matches('/')
+    )
+}
+
+/// The changes between an old and a new version of a router's routes, as
+/// found by [`diff_routes`], e.g. for a CI check that flags the breaking
+/// changes of the RPC. Its `Display` is a changelog with a line for each
+/// change.
+#[cfg(any(test, feature = "tooling"))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RouteDiff {
+    /// The routes that are only in the new version, in its order
+    pub added: Vec<RouteInfo>,
+    /// The routes that are only in the old version, in its order
+    pub removed: Vec<RouteInfo>,
+    /// The routes whose args' types or response type changed, as the pairs
+    /// of their old and new versions, in the new version's order
+    pub changed: Vec<(RouteInfo, RouteInfo)>,
+}
+
+#[cfg(any(test, feature = "tooling"))]
+impl RouteDiff {
+    /// Are there no changes?
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+
+    /// Is any of the changes breaking for the existing clients, i.e. is any
+    /// route removed or changed? Adding a route is backward compatible.
+    pub fn is_breaking(&self) -> bool {
+        !self.removed.is_empty() || !self.changed.is_empty()
+    }
+}
+
+#[cfg(any(test, feature = "tooling"))]
+impl Display for RouteDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for route in &self.added {
+            writeln!(f, "+ {}", route_signature(route))?;
+        }
+        for route in &self.removed {
+            writeln!(f, "- {}", route_signature(route))?;
+        }
+        for (old, new) in &self.changed {
+            writeln!(
+                f,
+                "~ {} => {}",
+                route_signature(old),
+                route_signature(new)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the routes that are added, removed or changed in the `new` version of
+/// a router's routes compared to the `old` one, both as listed by the
+/// router's `routes` method. A route is identified by its template with the
+/// args' names erased, so that renaming an arg is not a change, and the
+/// routes with the same template (e.g. a route shadowed by a sub-router's
+/// route) are paired in order. A route is changed when its args' types or
+/// optionality or its response type differ.
+#[cfg(any(test, feature = "tooling"))]
+pub fn diff_routes(old: &[RouteInfo], new: &[RouteInfo]) -> RouteDiff {
+    let old_shapes = route_shapes(old);
+    let new_shapes = route_shapes(new);
+    let old_routes: HashMap<&(String, usize), &RouteInfo> =
+        old_shapes.iter().zip(old).collect();
+    let new_routes: HashSet<&(String, usize)> = new_shapes.iter().collect();
+    let signature = |route: &RouteInfo| {
+        let args: Vec<_> = route
+            .args
+            .iter()
+            .map(|arg| (arg.ty, arg.optional))
+            .collect();
+        (args, route.response_ty.replace(' ', ""))
+    };
+    let mut diff = RouteDiff::default();
+    for (shape, route) in new_shapes.iter().zip(new) {
+        match old_routes.get(shape) {
+            None => diff.added.push(route.clone()),
+            Some(old) if signature(old) != signature(route) => {
+                diff.changed.push(((*old).clone(), route.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    diff.removed = old_shapes
+        .iter()
+        .zip(old)
+        .filter(|(shape, _)| !new_routes.contains(shape))
+        .map(|(_, route)| route.clone())
+        .collect();
+    diff
+}
+
+/// The template of each of the `routes` with the args' names erased (see
+/// [`route_shape`]), with the number of the preceding routes with the same
+/// shape.
+#[cfg(any(test, feature = "tooling"))]
+fn route_shapes(routes: &[RouteInfo]) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    routes
+        .iter()
+        .map(|route| {
+            let shape = route_shape(&route.template);
+            let count = counts.entry(shape.clone()).or_default();
+            *count += 1;
+            (shape, *count - 1)
+        })
+        .collect()
+}
+
+/// Erase the args' names from a route's template, e.g. `/b/{a1}/{a2?}` into
+/// `/b/{}/{}`.
+#[cfg(any(test, feature = "tooling"))]
+fn route_shape(template: &str) -> String {
+    let mut shape = String::with_capacity(template.len());
+    let mut in_arg = false;
+    for char in template.chars() {
+        match char {
+            '{' => in_arg = true,
+            '}' => {
+                in_arg = false;
+                shape.push_str("{}");
+            }
+            _ if !in_arg => shape.push(char),
+            _ => {}
+        }
+    }
+    shape
+}
+
+/// Format a route's template with its args and its response type for a
+/// [`RouteDiff`], e.g. `/b/{a1} (a1: u64) -> String`.
+#[cfg(any(test, feature = "tooling"))]
+fn route_signature(route: &RouteInfo) -> String {
+    let args = itertools::join(&route.args, ", ");
+    if args.is_empty() {
+        format!("{} -> {}", route.template, route.response_ty)
+    } else {
+        format!("{} ({args}) -> {}", route.template, route.response_ty)
+    }
+}
+
+/// A probe for the borsh schema of a route's response type `T`. The generated
+/// `*_response_schema` methods call `response_schema` on a reference to the
+/// probe, which resolves to [`ResponseSchema`] when `T` implements
+/// `BorshSchema` and falls back to [`NoResponseSchema`] otherwise.
+#[cfg(any(test, feature = "tooling"))]
+pub struct SchemaProbe<T: ?Sized>(PhantomData<T>);
+
+#[cfg(any(test, feature = "tooling"))]
+impl<T: ?Sized> SchemaProbe<T> {
+    /// Create a probe for the type `T`.
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+/// The borsh schema of a response type that implements `BorshSchema`.
+#[cfg(any(test, feature = "tooling"))]
+pub trait ResponseSchema {
+    /// Get the response type's schema.
+    fn response_schema(&self) -> Option<BorshSchemaContainer>;
+}
+
+#[cfg(any(test, feature = "tooling"))]
+impl<T: BorshSchema + ?Sized> ResponseSchema for SchemaProbe<T> {
+    fn response_schema(&self) -> Option<BorshSchemaContainer> {
+        Some(T::schema_container())
+    }
+}
+
+/// The fallback for a response type that doesn't implement `BorshSchema`.
+#[cfg(any(test, feature = "tooling"))]
+pub trait NoResponseSchema {
+    /// Returns `None`, because the response type has no schema.
+    fn response_schema(&self) -> Option<BorshSchemaContainer>;
+}
+
+#[cfg(any(test, feature = "tooling"))]
+impl<T: ?Sized> NoResponseSchema for &SchemaProbe<T> {
+    fn response_schema(&self) -> Option<BorshSchemaContainer> {
+        None
+    }
+}
+
+/// A probe for the JSON encoding of a handler's response `T`. The generated
+/// handling code calls `encode_json` on a reference to the probe, which
+/// resolves to [`JsonEncode`] when `T` implements `serde::Serialize` and
+/// falls back to [`NoJsonEncode`] otherwise.
+pub struct JsonProbe<'a, T: ?Sized>(pub &'a T);
+
+/// The JSON encoding of a response type that implements `serde::Serialize`.
+pub trait JsonEncode {
+    /// Encode the response with JSON.
+    fn encode_json(&self) -> storage_api::Result<Vec<u8>>;
+}
+
+impl<T: serde::Serialize + ?Sized> JsonEncode for JsonProbe<'_, T> {
+    fn encode_json(&self) -> storage_api::Result<Vec<u8>> {
+        serde_json::to_vec(self.0).map_err(storage_api::Error::new)
+    }
+}
+
+/// The fallback for a response type that doesn't implement
+/// `serde::Serialize`.
+pub trait NoJsonEncode {
+    /// Fails, because the response cannot be encoded with JSON.
+    fn encode_json(&self) -> storage_api::Result<Vec<u8>>;
+}
+
+impl<T: ?Sized> NoJsonEncode for &JsonProbe<'_, T> {
+    fn encode_json(&self) -> storage_api::Result<Vec<u8>> {
+        Err(storage_api::Error::new(Error::MethodNotAllowed(
+            "This query's response cannot be encoded with JSON",
+        )))
+    }
+}
+
+/// A probe for the formatting of a matched arg's value `T` in a [`MatchInfo`].
+/// The generated matching code calls `format_arg` on a double reference to
+/// the probe, which resolves to [`DisplayArg`] when `T` implements `Display`,
+/// falls back to [`DebugArg`] when `T` implements `Debug` and to [`OpaqueArg`]
+/// otherwise.
+pub struct ArgProbe<'a, T: ?Sized>(pub &'a T);
+
+/// The formatting of a matched arg that implements `Display`.
+pub trait DisplayArg {
+    /// Format the arg with `Display`.
+    fn format_arg(&self) -> String;
+}
+
+impl<T: Display + ?Sized> DisplayArg for &&ArgProbe<'_, T> {
+    fn format_arg(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The fallback formatting of a matched arg that implements `Debug`.
+pub trait DebugArg {
+    /// Format the arg with `Debug`.
+    fn format_arg(&self) -> String;
+}
+
+impl<T: Debug + ?Sized> DebugArg for &ArgProbe<'_, T> {
+    fn format_arg(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+/// The fallback formatting of a matched arg that cannot be formatted.
+pub trait OpaqueArg {
+    /// Format the arg as `_`.
+    fn format_arg(&self) -> String;
+}
+
+impl<T: ?Sized> OpaqueArg for ArgProbe<'_, T> {
+    fn format_arg(&self) -> String {
+        "_".to_owned()
+    }
+}
+
+/// A probe for the value of a matched arg `T` passed to a handler of a route
+/// with the `#[args_map]` attribute or set in the
+/// [`RequestCtx::prefix_args`](super::RequestCtx::prefix_args) for a
+/// sub-router's prefix. The generated code calls `capture_arg` on a
+/// double reference to the probe, which resolves to [`CaptureOptionalArg`]
+/// when `T` is an optional arg's `Option` or [`Optional`], to [`CaptureArg`]
+/// when `T`
+/// implements `Display` and falls back to [`CaptureDebugArg`] otherwise.
+pub struct CaptureProbe<'a, T: ?Sized>(pub &'a T);
+
+/// The captured value of an optional arg.
+pub trait CaptureOptionalArg {
+    /// Format the arg's value with `Display`, if it's present.
+    fn capture_arg(&self) -> Option<String>;
+}
+
+impl<T: Display> CaptureOptionalArg for &&CaptureProbe<'_, Option<T>> {
+    fn capture_arg(&self) -> Option<String> {
+        self.0.as_ref().map(ToString::to_string)
+    }
+}
+
+impl<T: Display> CaptureOptionalArg for &&CaptureProbe<'_, Optional<T>> {
+    fn capture_arg(&self) -> Option<String> {
+        match self.0 {
+            Optional::Present(value) => Some(value.to_string()),
+            Optional::Absent | Optional::Unparseable(_) => None,
+        }
+    }
+}
+
+/// The captured value of a required arg.
+pub trait CaptureArg {
+    /// Format the arg with `Display`.
+    fn capture_arg(&self) -> Option<String>;
+}
+
+impl<T: Display + ?Sized> CaptureArg for &CaptureProbe<'_, T> {
+    fn capture_arg(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+/// The fallback captured value of a required arg that implements `Debug`.
+pub trait CaptureDebugArg {
+    /// Format the arg with `Debug`.
+    fn capture_arg(&self) -> Option<String>;
+}
+
+impl<T: Debug + ?Sized> CaptureDebugArg for CaptureProbe<'_, T> {
+    fn capture_arg(&self) -> Option<String> {
+        Some(format!("{:?}", self.0))
+    }
+}
+
+/// The value of a union typed arg, e.g. `[id: u64 | String]`, which is parsed
+/// with the first of its types that can parse the segment. A union of more
+/// than two types is nested on the right, e.g. `Either<A, Either<B, C>>`.
+#[derive(
+    Clone, Debug, PartialEq, Eq, borsh::BorshSerialize, borsh::BorshDeserialize,
+)]
+pub enum Either<L, R> {
+    /// The value parsed with the first type
+    Left(L),
+    /// The value parsed with the other type(s)
+    Right(R),
+}
+
+impl<L: FromStr, R: FromStr> FromStr for Either<L, R> {
+    type Err = R::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match L::from_str(s) {
+            Ok(left) => Ok(Self::Left(left)),
+            Err(_) => R::from_str(s).map(Self::Right),
+        }
+    }
+}
+
+impl<L: Display, R: Display> Display for Either<L, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Left(left) => left.fmt(f),
+            Self::Right(right) => right.fmt(f),
+        }
+    }
+}
+
+/// The response of an aggregate route, which combines the results of several
+/// sub-queries into one, e.g. for a dashboard that needs them all at once
+/// rather than issuing a batch. It's constructed from the results with the
+/// `combine!` macro, which wraps them in a tuple, e.g. a handler that
+/// returns `Combined<(Amount, Amount, Amount)>` may end with
+/// `Ok(combine!(balance, stake, rewards))`.
+///
+/// The borsh encoding is the same as the results' encodings concatenated in
+/// order, so the client decodes them together with the route's return type.
+#[derive(Clone, Debug, Default, PartialEq, Eq, borsh::BorshSchema)]
+pub struct Combined<T>(pub T);
+
+impl<T> Combined<T> {
+    /// The tuple of the combined results.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: borsh::BorshSerialize> borsh::BorshSerialize for Combined<T> {
+    fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.0.serialize(writer)
+    }
+}
+
+impl<T: borsh::BorshDeserialize> borsh::BorshDeserialize for Combined<T> {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        T::deserialize(buf).map(Self)
+    }
+}
+
+/// Combine the results of several sub-queries into a single [`Combined`]
+/// response of an aggregate route, e.g. `combine!(balance, stake, rewards)`.
+/// Each arg is an expression of a result's value.
+#[macro_export]
+macro_rules! combine {
+    ( $( $result:expr ),+ $(,)? ) => {
+        $crate::ledger::queries::Combined(( $( $result, )+ ))
+    };
+}
+
+/// The value of an optional arg without a default, e.g. `[amount: opt
+/// Amount]`, passed to the handler of a route with the `#[presence]`
+/// attribute instead of an `Option`, which cannot tell an omitted arg from a
+/// segment that couldn't be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Optional<T> {
+    /// The value parsed from the arg's segment or its query param
+    Present(T),
+    /// The arg was omitted from the path and it has no query param
+    Absent,
+    /// The arg's segment, which couldn't be parsed. Only the last arg of a
+    /// pattern can be unparseable, because the segment of an optional arg
+    /// followed by other segments is matched with the rest of the pattern
+    /// instead, like without the attribute.
+    Unparseable(String),
+}
+
+impl<T> Optional<T> {
+    /// Is the arg's value present?
+    pub fn is_present(&self) -> bool {
+        matches!(self, Self::Present(_))
+    }
+
+    /// The arg's value, if it's present.
+    pub fn into_option(self) -> Option<T> {
+        match self {
+            Self::Present(value) => Some(value),
+            Self::Absent | Self::Unparseable(_) => None,
+        }
+    }
+}
+
+impl<T> From<Optional<T>> for Option<T> {
+    fn from(value: Optional<T>) -> Self {
+        value.into_option()
+    }
+}
+
+/// The reason why a path segment couldn't be parsed into a typed arg, as
+/// reported in [`Error::ParseFailed`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseFailureKind {
+    /// The segment is not a valid value of the type
+    Invalid,
+    /// The segment is a valid number, but it's out of the type's range
+    Overflow,
+}
+
+impl Display for ParseFailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "invalid value"),
+            Self::Overflow => write!(f, "the value is out of range"),
+        }
+    }
+}
+
+/// A parse error of a typed arg's type that can tell if the failure is an
+/// overflow of a valid number.
+pub trait ParseOverflow {
+    /// Whether the parsed number is out of the type's range.
+    fn is_overflow(&self) -> bool;
+}
+
+impl ParseOverflow for std::num::ParseIntError {
+    fn is_overflow(&self) -> bool {
+        matches!(
+            self.kind(),
+            std::num::IntErrorKind::PosOverflow
+                | std::num::IntErrorKind::NegOverflow
+        )
+    }
+}
+
+impl ParseOverflow for crate::types::token::AmountParseError {
+    fn is_overflow(&self) -> bool {
+        matches!(self, Self::InvalidRange)
+    }
+}
+
+/// A probe for the [`ParseFailureKind`] of a typed arg's parse error `E`,
+/// used with autoref specialization. Call `parse_failure_kind` on a reference
+/// to the probe, which resolves to [`OverflowParseFailure`] when `E`
+/// implements [`ParseOverflow`] and falls back to [`InvalidParseFailure`]
+/// otherwise.
+pub struct ParseErrorProbe<'a, E>(pub &'a E);
+
+/// The failure kind of a parse error that implements [`ParseOverflow`].
+pub trait OverflowParseFailure {
+    /// Get the kind of the parse failure.
+    fn parse_failure_kind(&self) -> ParseFailureKind;
+}
+
+impl<E: ParseOverflow> OverflowParseFailure for ParseErrorProbe<'_, E> {
+    fn parse_failure_kind(&self) -> ParseFailureKind {
+        if self.0.is_overflow() {
+            ParseFailureKind::Overflow
+        } else {
+            ParseFailureKind::Invalid
+        }
+    }
+}
+
+/// The fallback for a parse error that cannot tell an overflow apart.
+pub trait InvalidParseFailure {
+    /// Returns [`ParseFailureKind::Invalid`].
+    fn parse_failure_kind(&self) -> ParseFailureKind;
+}
+
+impl<E> InvalidParseFailure for &ParseErrorProbe<'_, E> {
+    fn parse_failure_kind(&self) -> ParseFailureKind {
+        ParseFailureKind::Invalid
+    }
+}
+
+/// A type of a dynamic path argument that has a sample value, used to
+/// construct a sample path for every route of a router (e.g. for smoke-testing
+/// that every route is matched).
+#[cfg(any(test, feature = "testing"))]
+pub trait SampleArg: ToOwned {
+    /// Get the sample value of this type.
+    fn sample() -> Self::Owned;
+}
+
+/// Implement [`SampleArg`] with the [`Default`] value for the given types.
+#[cfg(any(test, feature = "testing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! impl_default_sample_arg {
+    ( $( $type:ty ),* $(,)? ) => {
+        $(
+            impl SampleArg for $type {
+                fn sample() -> Self {
+                    Self::default()
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl_default_sample_arg!(
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i64,
+    rust_decimal::Decimal,
+    crate::types::hash::Hash,
+    crate::types::storage::BlockHeight,
+    crate::types::storage::Epoch,
+    crate::types::token::Amount,
+);
+
+#[cfg(any(test, feature = "testing"))]
+impl SampleArg for str {
+    fn sample() -> String {
+        "sample".to_owned()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl SampleArg for String {
+    fn sample() -> String {
+        "sample".to_owned()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl SampleArg for [u8] {
+    fn sample() -> Vec<u8> {
+        b"sample".to_vec()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl SampleArg for crate::types::address::Address {
+    fn sample() -> Self {
+        crate::types::address::nam()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl SampleArg for crate::types::storage::Key {
+    fn sample() -> Self {
+        Self::parse("sample").expect("The sample key should be valid")
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl SampleArg for masp_primitives::asset_type::AssetType {
+    fn sample() -> Self {
+        Self::new(b"sample").expect("The sample asset type should be valid")
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl<T> SampleArg for Option<T>
+where
+    T: SampleArg<Owned = T> + Clone,
+{
+    fn sample() -> Self {
+        Some(T::sample())
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl<L, R> SampleArg for Either<L, R>
+where
+    L: SampleArg<Owned = L> + Clone,
+    R: Clone,
+{
+    fn sample() -> Self {
+        Self::Left(L::sample())
+    }
+}
+
+/// Check if the literal `prefix` of a sub-router collides with any of the
+/// `patterns` of its router, given by their leading literal segments. A
+/// pattern collides when its leading literals and the `prefix` are equal up
+/// to the length of the shorter of the two, so that some paths could be
+/// matched by both. The sub-router's own pattern must be included in the
+/// `patterns` and it's not counted as a collision.
+pub const fn has_prefix_collision(
+    prefix: &[&str],
+    patterns: &[&[&str]],
+) -> bool {
+    const fn str_eq(a: &str, b: &str) -> bool {
+        let (a, b) = (a.as_bytes(), b.as_bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    let mut collisions = 0;
+    let mut i = 0;
+    while i < patterns.len() {
+        let pattern = patterns[i];
+        let mut j = 0;
+        let mut collides = true;
+        while j < prefix.len() && j < pattern.len() {
+            if !str_eq(prefix[j], pattern[j]) {
+                collides = false;
+                break;
+            }
+            j += 1;
+        }
+        if collides {
+            collisions += 1;
+        }
+        i += 1;
+    }
+    // The sub-router's own pattern always matches its prefix
+    collisions > 1
+}
+
+/// Count the handler names in all the `parts`, used for the length of a
+/// router's `HANDLER_NAMES`.
+pub const fn handler_names_len(parts: &[&[&str]]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < parts.len() {
+        len += parts[i].len();
+        i += 1;
+    }
+    len
+}
+
+/// Concatenate the handler names in all the `parts` into an array, whose
+/// length `N` must be given by [`handler_names_len`].
+pub const fn concat_handler_names<const N: usize>(
+    parts: &[&[&'static str]],
+) -> [&'static str; N] {
+    let mut names = [""; N];
+    let mut n = 0;
+    let mut i = 0;
+    while i < parts.len() {
+        let mut j = 0;
+        while j < parts[i].len() {
+            names[n] = parts[i][j];
+            n += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    names
+}
+
+/// Keep the given items only if this crate is built with the `async-client`
+/// feature (or for its own tests), e.g. the client methods generated by
+/// `router!`. A `#[cfg]` in the generated code would check the features of
+/// the crate that declares the router instead of this crate's.
+#[cfg(any(test, feature = "async-client"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_async_client {
+    ( $( $item:tt )* ) => { $( $item )* };
+}
+
+/// Keep the given items only if this crate is built with the `async-client`
+/// feature (see the other definition of this macro).
+#[cfg(not(any(test, feature = "async-client")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_async_client {
+    ($($item:tt)*) => {};
+}
+
+/// Keep the given items only if this crate is built with the
+/// `async-handlers` feature (like `cfg_async_client`), or only without it if
+/// they're marked `@disabled`.
+#[cfg(any(test, feature = "async-handlers"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_async_handlers {
+    ( @disabled $( $item:tt )* ) => {};
+    ( $( $item:tt )* ) => { $( $item )* };
+}
+
+/// Keep the given items only if this crate is built with the
+/// `async-handlers` feature, or only without it if they're marked
+/// `@disabled`.
+#[cfg(not(any(test, feature = "async-handlers")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_async_handlers {
+    ( @disabled $( $item:tt )* ) => { $( $item )* };
+    ( $( $item:tt )* ) => {};
+}
+
+/// Keep the given items only if this crate is built with the `testing`
+/// feature (like `cfg_async_client`).
+#[cfg(any(test, feature = "testing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_testing {
+    ( $( $item:tt )* ) => { $( $item )* };
+}
+
+/// Keep the given items only if this crate is built with the `testing`
+/// feature.
+#[cfg(not(any(test, feature = "testing")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_testing {
+    ($($item:tt)*) => {};
+}
+
+/// Keep the given items only if this crate is built with the `tooling`
+/// feature (like `cfg_async_client`).
+#[cfg(any(test, feature = "tooling"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_tooling {
+    ( $( $item:tt )* ) => { $( $item )* };
+}
+
+/// Keep the given items only if this crate is built with the `tooling`
+/// feature.
+#[cfg(not(any(test, feature = "tooling")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cfg_tooling {
+    ($($item:tt)*) => {};
+}
+
+/// Check that all the attributes of a route with a handler function are
+/// supported by the router.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_route_attrs {
+    ( [] ) => {};
+    ( [ #[info = $template:literal] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[trailing_slash = $allowed:literal] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[domain_error] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[cache(immutable)] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[cache(no_store)] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[internal] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[deprecated] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[params] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[args_map] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[compact = $id:literal] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[max_size = $limit:literal] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[presence] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[empty_segment] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[capability = $capability:literal] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[async_handler] $( $rest:tt )* ] ) => {
+        $crate::cfg_async_handlers! { @disabled
+            compile_error!(
+                "`#[async_handler]` requires the `async-handlers` feature"
+            );
+        }
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[cfg $predicate:tt] $( $rest:tt )* ] ) => {
+        $crate::check_route_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[ $( $attr:tt )* ] $( $rest:tt )* ] ) => {
+        compile_error!(concat!(
+            "unsupported router attribute `#[",
+            stringify!($( $attr )*),
+            "]`"
+        ));
+    };
+}
+
+/// Check that a route with the `#[presence]` attribute has no `#[params]` or
+/// `#[compact = id]` attribute, whose args' types are the optional args'
+/// `Option`s, in any order of the attributes.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_presence_attrs {
+    ( $attrs:tt ) => {
+        $crate::check_presence_attrs!(@find $attrs, $attrs);
+    };
+    (@find [], $_attrs:tt ) => {};
+    (@find [ #[presence] $( $_rest:tt )* ], $attrs:tt ) => {
+        $crate::check_presence_attrs!(@reject $attrs);
+    };
+    (@find [ #[ $( $_attr:tt )* ] $( $rest:tt )* ], $attrs:tt ) => {
+        $crate::check_presence_attrs!(@find [ $( $rest )* ], $attrs);
+    };
+    (@reject [] ) => {};
+    (@reject [ #[params] $( $_rest:tt )* ] ) => {
+        compile_error!("`#[params]` is not supported with `#[presence]`");
+    };
+    (@reject [ #[compact = $_id:literal] $( $_rest:tt )* ] ) => {
+        compile_error!(
+            "`#[compact = id]` is not supported with `#[presence]`"
+        );
+    };
+    (@reject [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::check_presence_attrs!(@reject [ $( $rest )* ]);
+    };
+}
+
+/// Check that all the attributes of a route with a sub-router are supported
+/// by the router.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_sub_router_attrs {
+    ( [] ) => {};
+    ( [ #[exclusive] $( $rest:tt )* ] ) => {
+        $crate::check_sub_router_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[separator = $separator:literal] $( $rest:tt )* ] ) => {
+        $crate::check_sub_router_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[internal] $( $rest:tt )* ] ) => {
+        $crate::check_sub_router_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[cfg $predicate:tt] $( $rest:tt )* ] ) => {
+        $crate::check_sub_router_attrs!([ $( $rest )* ]);
+    };
+    ( [ #[ $( $attr:tt )* ] $( $rest:tt )* ] ) => {
+        compile_error!(concat!(
+            "unsupported sub-router attribute `#[",
+            stringify!($( $attr )*),
+            "]`"
+        ));
+    };
+}
+
+/// Forward the `#[cfg(...)]` attributes of a route onto the given items or
+/// statements, so that they're only compiled when the route is. The other
+/// attributes are skipped.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_cfg {
+    ( [] $( $body:tt )* ) => {
+        $( $body )*
+    };
+    ( [ #[cfg $predicate:tt] $( $rest:tt )* ] $( $body:tt )* ) => {
+        #[cfg $predicate]
+        $crate::route_cfg!{ [ $( $rest )* ] $( $body )* }
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] $( $body:tt )* ) => {
+        $crate::route_cfg!{ [ $( $rest )* ] $( $body )* }
+    };
+}
+
+/// Find the separator of the path segments of a sub-router from its route's
+/// `#[separator = char]` attribute. It's a slash by default.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_separator {
+    ( [] ) => {
+        '/'
+    };
+    ( [ #[separator = $separator:literal] $( $rest:tt )* ] ) => {
+        $separator
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_separator!([ $( $rest )* ])
+    };
+}
+
+/// Shadow the `$segments` and the `$start` index with the rest of the path
+/// split by the custom separator of a sub-router from its route's `$attrs`,
+/// if any.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! sub_router_segments {
+    ($attrs:tt, $segments:ident, $start:ident) => {
+        let rest = $crate::ledger::queries::router::split_sub_tree(
+            $segments,
+            $start,
+            $crate::route_separator!($attrs),
+        );
+        let sub_segments: Vec<&str>;
+        let ($segments, $start) = match &rest {
+            Some((rest, separator)) => {
+                sub_segments = rest.split(*separator).collect();
+                (&sub_segments[..], 0)
+            }
+            None => ($segments, $start),
+        };
+    };
+}
+
+/// Find if a sub-router route is marked with the `#[exclusive]` attribute.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_exclusive {
+    ( [] ) => {
+        false
+    };
+    ( [ #[exclusive] $( $rest:tt )* ] ) => {
+        true
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_exclusive!([ $( $rest )* ])
+    };
+}
+
+/// Find if a route is marked with the `#[internal]` attribute.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_internal {
+    ( [] ) => {
+        false
+    };
+    ( [ #[internal] $( $rest:tt )* ] ) => {
+        true
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_internal!([ $( $rest )* ])
+    };
+}
+
+/// Find if a route is marked with the `#[deprecated]` attribute.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_deprecated {
+    ( [] ) => {
+        false
+    };
+    ( [ #[deprecated] $( $rest:tt )* ] ) => {
+        true
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_deprecated!([ $( $rest )* ])
+    };
+}
+
+/// Get the leading literal segments of a pattern as `&[&str]`, i.e. all its
+/// literals up to the first dynamic argument, if any.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_leading_literals {
+    ( ( $( $pattern:tt )* ) ) => {
+        $crate::pattern_leading_literals!(@acc [] $( $pattern )*)
+    };
+    (
+        @acc [ $( $acc:literal )* ]
+        $lit:literal $( | $_alias:literal )* $( / $( $tail:tt )/ * )?
+    ) => {
+        $crate::pattern_leading_literals!(@acc [ $( $acc )* $lit ] $( $( $tail )/ * )?)
+    };
+    (@acc [ $( $acc:literal )* ] $( $_rest:tt )* ) => {
+        &[ $( $acc ),* ]
+    };
+}
+
+/// Get the [`segment_hash`]es of the leading literal of a pattern and its
+/// aliases as `&[u64]`, or an empty slice if the pattern starts with a dynamic
+/// argument.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! first_literal_hashes {
+    ( ( $expected:literal $( | $alias:literal )* $( / $_tail:tt )* ) ) => {
+        &[
+            $crate::ledger::queries::router::segment_hash($expected),
+            $( $crate::ledger::queries::router::segment_hash($alias), )*
+        ]
+    };
+    ( $_pattern:tt ) => {
+        &[]
+    };
+}
+
+/// Check that every route without a return type, other than a sub-pattern,
+/// is a sub-router marked with `(sub _)` and then generate the router from
+/// the `router!` input in the first token tree. This produces a compile time
+/// error instead of the type errors of calling the router as a handler.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_sub_router_markers {
+    // all the routes are checked
+    ( ( $( $input:tt )* ) ) => {
+        $crate::router!{ @checked $( $input )* }
+    };
+
+    // a sub-router without the `(sub _)` marker
+    (
+        $input:tt
+        $( #[ $( $attr:tt )* ] )*
+        ( $( $pattern:tt )* ) = $router:ident ,
+        $( $rest:tt )*
+    ) => {
+        compile_error!(concat!("the route without a return type must be a \
+            sub-router marked with `(sub _)`, e.g. `= (sub ",
+            stringify!($router), ")`, or a handler with a return type"));
+    };
+
+    // any other route
+    (
+        $input:tt
+        $( #[ $( $attr:tt )* ] )*
+        ( $( $pattern:tt )* ) $( -> $return_type:path )? = $handle:tt ,
+        $( $rest:tt )*
+    ) => {
+        $crate::check_sub_router_markers!{ $input $( $rest )* }
+    };
+}
+
+/// Check that the prefixes of the sub-routers marked with the `#[exclusive]`
+/// attribute don't collide with any of their sibling patterns (see
+/// [`has_prefix_collision`]). This produces a compile time error on a
+/// collision.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_exclusive_sub_routers {
+    ( $( $attrs:tt $pattern:tt $handle:tt ),* ) => {
+        $crate::check_exclusive_sub_routers!(@all [ $( $pattern )* ]
+            $( $attrs $pattern $handle ),* );
+    };
+    (@all $all:tt $( $attrs:tt $pattern:tt $handle:tt ),* ) => {
+        $(
+            $crate::check_exclusive_sub_routers!(@route $all $attrs $pattern $handle);
+        )*
+    };
+    (@route [ $( $all:tt )* ] $attrs:tt $pattern:tt (sub $router:tt) ) => {
+        const _: () = assert!(
+            !$crate::route_exclusive!($attrs)
+                || !$crate::ledger::queries::router::has_prefix_collision(
+                    $crate::pattern_leading_literals!($pattern),
+                    &[ $( $crate::pattern_leading_literals!($all) ),* ],
+                ),
+            concat!(
+                "The prefix of the exclusive sub-router `",
+                stringify!($router),
+                "` collides with a sibling pattern"
+            )
+        );
+    };
+    (@route $all:tt $attrs:tt $pattern:tt $_handle:tt ) => {};
+}
+
+/// Check that the first literal segment of a pattern, including its aliases,
+/// doesn't start with `_`, which is reserved for the router's meta-routes (see
+/// [`is_reserved_segment`]). This produces a compile time error on a
+/// collision.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_reserved_segment {
+    ( ( $lit:literal $( | $alias:literal )* $( / $( $_tail:tt )* )? ) ) => {
+        const _: () = assert!(
+            !$crate::ledger::queries::router::is_reserved_segment($lit)
+                $( && !$crate::ledger::queries::router::is_reserved_segment(
+                    $alias) )*,
+            concat!(
+                "The pattern's first segment \"",
+                $lit,
+                "\" or its alias starts with `_`, which is reserved for the \
+                 router's meta-routes"
+            )
+        );
+    };
+    ( $_pattern:tt ) => {};
+}
+
+/// Find the `info` template from a route's `#[info = "..."]` attribute, if
+/// any.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_info_template {
+    ( [] ) => {
+        None
+    };
+    ( [ #[info = $template:literal] $( $rest:tt )* ] ) => {
+        Some($template)
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_info_template!([ $( $rest )* ])
+    };
+}
+
+/// Find the maximum size in bytes of a route's encoded response from its
+/// `#[max_size = N]` attribute, if any.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_max_size {
+    ( [] ) => {
+        None::<usize>
+    };
+    ( [ #[max_size = $limit:literal] $( $rest:tt )* ] ) => {
+        Some::<usize>($limit)
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_max_size!([ $( $rest )* ])
+    };
+}
+
+/// Find the capability required by a route from its
+/// `#[capability = "..."]` attribute, if any.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_capability {
+    ( [] ) => {
+        None::<&'static str>
+    };
+    ( [ #[capability = $capability:literal] $( $rest:tt )* ] ) => {
+        Some::<&'static str>($capability)
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_capability!([ $( $rest )* ])
+    };
+}
+
+/// Find the [`CachePolicy`] of a route from its `#[cache(...)]` attribute, if
+/// any.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_cache_policy {
+    ( [] ) => {
+        None
+    };
+    ( [ #[cache(immutable)] $( $rest:tt )* ] ) => {
+        Some($crate::ledger::queries::router::CachePolicy::Immutable)
+    };
+    ( [ #[cache(no_store)] $( $rest:tt )* ] ) => {
+        Some($crate::ledger::queries::router::CachePolicy::NoStore)
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_cache_policy!([ $( $rest )* ])
+    };
+}
+
+/// TT muncher that generates the statements of a route type's `cache_policy`
+/// method, which return the [`CachePolicy`] of the route `$self` if it's one
+/// of the given routes. The routes inside a sub-pattern also have the
+/// sub-pattern's attributes, after their own, and a sub-router's route is
+/// delegated to the sub-router's route type.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_cache_policies {
+    // terminal rule
+    ( $self:ident ) => {};
+
+    // a sub router
+    (
+        $self:ident $attrs:tt (sub $router:ident) $( $tail:tt )*
+    ) => {
+        $crate::route_cfg!{ $attrs
+            $crate::ledger::queries::router::__private::paste::paste! {
+                if let Self::[<$router:camel>](route) = $self {
+                    return route.cache_policy();
+                }
+            }
+        }
+        $crate::route_cache_policies!($self $( $tail )*);
+    };
+
+    // a route inside a sub-pattern - join its attributes with the
+    // sub-pattern's
+    (
+        $self:ident { [ $( $attr:tt )* ] [ $( $parent_attr:tt )* ] }
+        $( $tail:tt )*
+    ) => {
+        $crate::route_cache_policies!($self [ $( $attr )* $( $parent_attr )* ]
+            $( $tail )*);
+    };
+
+    // a sub-pattern - add the routes inside it
+    (
+        $self:ident $parent_attrs:tt {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $_sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $sub_handle:tt,
+            )*
+        }
+        $( $tail:tt )*
+    ) => {
+        $crate::route_cache_policies!($self
+            $( { [ $( #[ $( $attr )* ] )* ] $parent_attrs } $sub_handle )*
+            $( $tail )*
+        );
+    };
+
+    // a handler that uses request (`with_options`)
+    (
+        $self:ident $attrs:tt (with_options $handle:ident) $( $tail:tt )*
+    ) => {
+        $crate::route_cache_policies!($self $attrs $handle $( $tail )*);
+    };
+
+    // a handler that returns chunks (`stream`)
+    (
+        $self:ident $attrs:tt (stream $handle:ident) $( $tail:tt )*
+    ) => {
+        $crate::route_cache_policies!($self $attrs $handle $( $tail )*);
+    };
+
+    // a constant response, named after the constant
+    (
+        $self:ident $attrs:tt (const $value:ident) $( $tail:tt )*
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::route_cache_policies!($self $attrs [<$value:lower>] $( $tail )*);
+        }
+    };
+
+    // a handler that doesn't use request
+    (
+        $self:ident $attrs:tt $handle:ident $( $tail:tt )*
+    ) => {
+        $crate::route_cfg!{ $attrs
+            $crate::ledger::queries::router::__private::paste::paste! {
+                if let Self::[<$handle:camel>] = $self {
+                    return $crate::route_cache_policy!($attrs);
+                }
+            }
+        }
+        $crate::route_cache_policies!($self $( $tail )*);
+    };
+}
+
+/// Wrap the result of a handler call into a domain result, if the route has
+/// the `#[domain_error]` attribute. Otherwise, the result is returned as is.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_handler_result {
+    ( [], $result:expr ) => {
+        $result
+    };
+    ( [ #[domain_error] $( $rest:tt )* ], $result:expr ) => {
+        $crate::ledger::queries::router::into_domain_result($result)
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ], $result:expr ) => {
+        $crate::route_handler_result!([ $( $rest )* ], $result)
+    };
+}
+
+/// Make the handler `$call` of the `$route` with the `$attrs` in the `sync`
+/// or `async` dispatch mode (see `handle_match`) with the request's
+/// `HandlerConfig` and wrap its result with `route_handler_result`. The future
+/// returned by the `async` handler of a route with the `#[async_handler]`
+/// attribute is awaited in the `async` mode and rejected in the `sync` mode.
+/// Other handlers are called as is in both modes.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_handler_call {
+    ( $mode:ident, $attrs:tt, $config:expr, $route:expr, $call:expr ) => {
+        $crate::route_handler_call!(@scan $mode, $attrs, $attrs, $config, $route,
+            $call)
+    };
+    (
+        @scan $mode:ident, [], $attrs:tt, $config:expr, $route:expr,
+        $call:expr
+    ) => {
+        $crate::ledger::queries::router::timed($config, $route,
+            || $crate::route_handler_result!($attrs, $call))
+    };
+    (
+        @scan sync, [ #[async_handler] $( $rest:tt )* ], $attrs:tt,
+        $config:expr, $route:expr, $call:expr
+    ) => {
+        $crate::route_handler_result!($attrs,
+            $crate::ledger::queries::router::reject_sync_dispatch(
+                $route, $call))
+    };
+    (
+        @scan async, [ #[async_handler] $( $rest:tt )* ], $attrs:tt,
+        $config:expr, $route:expr, $call:expr
+    ) => {
+        $crate::ledger::queries::router::timed_async($config, $route,
+            async { $crate::route_handler_result!($attrs, $call.await) }).await
+    };
+    (
+        @scan $mode:ident, [ #[ $( $_attr:tt )* ] $( $rest:tt )* ],
+        $attrs:tt, $config:expr, $route:expr, $call:expr
+    ) => {
+        $crate::route_handler_call!(@scan $mode, [ $( $rest )* ], $attrs,
+            $config, $route, $call)
+    };
+}
+
+/// Invoke the `internal_handle` of a sub-router in the `sync` dispatch mode
+/// or await its `internal_handle_async` in the `async` mode (see
+/// `handle_match`).
+#[doc(hidden)]
+#[macro_export]
+macro_rules! sub_router_call {
+    (
+        sync,
+        $router:tt,
+        $ctx:ident,
+        $request:ident,
+        $segments:ident,
+        $start:ident
+    ) => {
+        $router.internal_handle($ctx, $request, $segments, $start)
+    };
+    (
+        async,
+        $router:tt,
+        $ctx:ident,
+        $request:ident,
+        $segments:ident,
+        $start:ident
+    ) => {
+        $router
+            .internal_handle_async($ctx, $request, $segments, $start)
+            .await
+    };
+}
+
+/// Call the handler function `$handle` with the given leading `$args` (the
+/// request context and the request, if it's used), followed by the matched
+/// args or, if the route has the `#[params]` attribute, by the handler's
+/// params struct (see `pattern_to_params`) with the matched args. With the
+/// `#[args_map]` attribute, the matched args are passed in a single map (see
+/// `captured_args`) instead.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_handler_fn_call {
+    (
+        [], $handle:ident, ( $( $args:expr ),* ),
+        ( $( $matched_args:ident, )* )
+    ) => {
+        $handle( $( $args, )* $( $matched_args ),* )
+    };
+    (
+        [ #[params] $( $rest:tt )* ], $handle:ident, ( $( $args:expr ),* ),
+        ( $( $matched_args:ident, )* )
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $handle( $( $args, )* [<$handle:camel Params>] {
+                $( $matched_args: $matched_args.to_owned(), )*
+            })
+        }
+    };
+    (
+        [ #[args_map] $( $rest:tt )* ], $handle:ident, ( $( $args:expr ),* ),
+        ( $( $matched_args:ident, )* )
+    ) => {
+        $handle( $( $args, )* $crate::captured_args!( $( $matched_args, )* ))
+    };
+    (
+        [ #[ $( $_attr:tt )* ] $( $rest:tt )* ], $handle:ident,
+        $args:tt, $matched_args:tt
+    ) => {
+        $crate::route_handler_fn_call!([ $( $rest )* ], $handle, $args, $matched_args)
+    };
+}
+
+/// Check that a route with a handler that uses request (`with_options`) has
+/// no `#[domain_error]` attribute, because such handler encodes its response
+/// itself.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_with_options_attrs {
+    ( [] ) => {};
+    ( [ #[domain_error] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[domain_error]` is not supported for handlers `with_options`, \
+            which should encode a domain result themselves"
+        );
+    };
+    ( [ #[compact = $_id:literal] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[compact = id]` is not supported for handlers `with_options`"
+        );
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::check_with_options_attrs!([ $( $rest )* ]);
+    };
+}
+
+/// Check that a route with a constant response (`const`) has no attributes
+/// that only apply to a handler function.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_const_attrs {
+    ( [] ) => {};
+    ( [ #[domain_error] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[domain_error]` is not supported for a `const` response"
+        );
+    };
+    ( [ #[async_handler] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[async_handler]` is not supported for a `const` response"
+        );
+    };
+    ( [ #[params] $( $rest:tt )* ] ) => {
+        compile_error!("`#[params]` is not supported for a `const` response");
+    };
+    ( [ #[args_map] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[args_map]` is not supported for a `const` response"
+        );
+    };
+    ( [ #[compact = $_id:literal] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[compact = id]` is not supported for a `const` response"
+        );
+    };
+    ( [ #[max_size = $_limit:literal] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[max_size = N]` is not supported for a `const` response"
+        );
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::check_const_attrs!([ $( $rest )* ]);
+    };
+}
+
+/// Check that a route with a handler that returns chunks (`stream`) has no
+/// `#[domain_error]` attribute, because the chunks are already encoded.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_stream_attrs {
+    ( [] ) => {};
+    ( [ #[domain_error] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[domain_error]` is not supported for handlers that return \
+            chunks (`stream`)"
+        );
+    };
+    ( [ #[compact = $_id:literal] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[compact = id]` is not supported for handlers that return \
+            chunks (`stream`)"
+        );
+    };
+    ( [ #[max_size = $_limit:literal] $( $rest:tt )* ] ) => {
+        compile_error!(
+            "`#[max_size = N]` is not supported for handlers that return \
+            chunks (`stream`), whose response is already split into chunks"
+        );
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::check_stream_attrs!([ $( $rest )* ]);
+    };
+}
+
+/// Check that the pattern of a route with the `#[compact = id]` attribute
+/// has only typed args, because the untyped args are passed to the handler as
+/// `&str`, which cannot be decoded from the compact request's args.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_compact_pattern {
+    ( () ) => {};
+    ( ( $expected:literal $( | $_alias:literal )* $( / $tail:tt )* ) ) => {
+        $crate::check_compact_pattern!(( $( $tail )/ * ));
+    };
+    ( ( [$name:ident $( : re $regex:literal )?] $( / $_tail:tt )* ) ) => {
+        compile_error!(concat!(
+            "The untyped arg `", stringify!($name), "` is not supported by \
+            the `#[compact = id]` attribute"
+        ));
+    };
+    ( ( [$name:ident: oneof $set:expr] $( / $_tail:tt )* ) ) => {
+        compile_error!(concat!(
+            "The untyped arg `", stringify!($name), "` is not supported by \
+            the `#[compact = id]` attribute"
+        ));
+    };
+    ( ( [$name:ident: **] ) ) => {
+        compile_error!(concat!(
+            "The glob arg `", stringify!($name), "` is not supported by the \
+            `#[compact = id]` attribute"
+        ));
+    };
+    ( ( [$name:ident: storage_key] ) ) => {
+        compile_error!(concat!(
+            "The storage key arg `", stringify!($name), "` is not supported \
+            by the `#[compact = id]` attribute"
+        ));
+    };
+    ( ( [$raw:ident = $name:ident: $arg_ty:ty] $( / $_tail:tt )* ) ) => {
+        compile_error!(concat!(
+            "The raw arg `", stringify!($raw), "` is not supported by the \
+            `#[compact = id]` attribute"
+        ));
+    };
+    ( ( $_arg:tt $( / $tail:tt )* ) ) => {
+        $crate::check_compact_pattern!(( $( $tail )/ * ));
+    };
+}
+
+/// Generate the compact protocol's code for a route of a router with the
+/// given `$op`:
+/// - `(@handler $route)` returns the name of the handler of the route with the
+///   compact id `$route`, if it's this route or one of its sub-router's routes
+/// - `(@list $routes)` pushes the compact id and the handler of this route and
+///   of its sub-router's routes into `$routes`
+/// - `(@handle $ctx $request $route $args)` decodes the `$args` and calls the
+///   handler of the route with the compact id `$route`, if it's this route or
+///   one of its sub-router's routes
+///
+/// Only the routes with the `#[compact = id]` attribute are included.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! compact_route {
+    // a sub router
+    ( $op:tt, $_attrs:tt, $_pattern:tt, (sub $router:ident) ) => {
+        $crate::compact_route!(@sub $op, $router);
+    };
+
+    // a sub-pattern - include the routes inside it with the sub-pattern
+    // prefixed to their patterns
+    (
+        $op:tt, $_attrs:tt, $pattern:tt,
+        {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $_return_ty:path )? = $handle:tt,
+            )*
+        }
+    ) => {
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                $crate::compact_route!(@join $op, [ $( #[ $( $attr )* ] )* ],
+                    $pattern, ( $( $sub_pattern )* ), $handle);
+            }
+        )*
+    };
+
+    // the other handlers are not supported, which is checked by
+    // `handle_match`
+    ( $op:tt, $_attrs:tt, $_pattern:tt, ( $( $_handle:tt )* ) ) => {};
+
+    // a handler that doesn't use request - find its compact id, if any
+    ( $op:tt, $attrs:tt, $pattern:tt, $handle:ident ) => {
+        $crate::compact_route!(@find $op, $attrs, $attrs, $pattern, $handle);
+    };
+
+    (@find $op:tt, [], $_attrs:tt, $_pattern:tt, $_handle:ident ) => {};
+    (
+        @find $op:tt, [ #[compact = $id:literal] $( $_rest:tt )* ],
+        $attrs:tt, $pattern:tt, $handle:ident
+    ) => {
+        $crate::compact_route!(@route $op, $id, $attrs, $pattern, $handle);
+    };
+    (
+        @find $op:tt, [ #[ $( $_attr:tt )* ] $( $rest:tt )* ],
+        $attrs:tt, $pattern:tt, $handle:ident
+    ) => {
+        $crate::compact_route!(@find $op, [ $( $rest )* ], $attrs, $pattern, $handle);
+    };
+
+    (@join $op:tt, $attrs:tt, (), $sub_pattern:tt, $handle:tt ) => {
+        $crate::compact_route!($op, $attrs, $sub_pattern, $handle);
+    };
+    (@join $op:tt, $attrs:tt, $pattern:tt, (), $handle:tt ) => {
+        $crate::compact_route!($op, $attrs, $pattern, $handle);
+    };
+    (
+        @join $op:tt, $attrs:tt, ( $( $pattern:tt )* ),
+        ( $( $sub_pattern:tt )* ), $handle:tt
+    ) => {
+        $crate::compact_route!($op, $attrs, ( $( $pattern )* / $( $sub_pattern )* ),
+            $handle);
+    };
+
+    (@sub (@handler $route:ident), $router:ident ) => {
+        if let Some(handler) = $router.compact_handler($route) {
+            return Some(handler);
+        }
+    };
+    (@sub (@list $routes:ident), $router:ident ) => {
+        $routes.extend($router.compact_routes());
+    };
+    (@sub (@handle $ctx:ident $request:ident $route:ident $args:ident),
+        $router:ident ) => {
+        if $router.compact_handler($route).is_some() {
+            return $router.internal_handle_compact(
+                $ctx, $request, $route, $args);
+        }
+    };
+
+    (@route (@handler $route:ident), $id:literal, $_attrs:tt, $_pattern:tt,
+        $handle:ident ) => {
+        if $route == $id {
+            return Some(stringify!($handle));
+        }
+    };
+    (@route (@list $routes:ident), $id:literal, $_attrs:tt, $_pattern:tt,
+        $handle:ident ) => {
+        $routes.push(($id, stringify!($handle)));
+    };
+    (@route (@handle $ctx:ident $request:ident $route:ident $args:ident),
+        $id:literal, $attrs:tt, $pattern:tt, $handle:ident ) => {
+        if $route == $id {
+            $crate::check_route_attrs!($attrs);
+            $crate::check_presence_attrs!($attrs);
+            $crate::check_compact_pattern!($pattern);
+            $crate::pattern_arg_types!(compact_route
+                (@call $ctx $request $args $attrs $handle) () $pattern);
+        }
+    };
+
+    // A single arg is encoded on its own, because borsh doesn't implement
+    // a 1-tuple
+    (@decode $args:ident ( $arg:ident: $arg_ty:ty )) => {
+        let $arg: $arg_ty = $crate::ledger::queries::router::__private::borsh::BorshDeserialize::try_from_slice($args)
+            .into_storage_result()?;
+    };
+    (@decode $args:ident ( $( $arg:ident: $arg_ty:ty ),* )) => {
+        let ( $( $arg, )* ): ( $( $arg_ty, )* ) =
+            $crate::ledger::queries::router::__private::borsh::BorshDeserialize::try_from_slice($args)
+                .into_storage_result()?;
+    };
+
+    // Decode the args and call the handler like `handle_match`
+    {
+        (@call $ctx:ident $request:ident $args:ident $attrs:tt $handle:ident)
+        ( $( $arg:ident: $arg_ty:ty ),* )
+    } => {
+        $crate::ledger::queries::require_capability(
+            &$ctx, $crate::route_capability!($attrs), stringify!($handle))?;
+        $crate::ledger::queries::require_latest_height(&$ctx, $request)?;
+        $crate::ledger::queries::require_no_proof($request)?;
+        $crate::compact_route!(@decode $args ( $( $arg: $arg_ty ),* ));
+        let data = $crate::route_handler_call!(sync, $attrs, $ctx.handler_config,
+            stringify!($handle),
+            $crate::route_handler_fn_call!($attrs, $handle, ($ctx),
+                ( $( $arg, )* )))?;
+        let mut response = $crate::ledger::queries::EncodedResponseQuery {
+            data: $crate::ledger::queries::router::__private::borsh::BorshSerialize::try_to_vec(&data)
+                .into_storage_result()?,
+            ..Default::default()
+        };
+        $crate::ledger::queries::router::check_response_size(
+            &response.data, $crate::route_max_size!($attrs))?;
+        $crate::ledger::queries::router::apply_default_info(
+            &mut response, $crate::route_info_template!($attrs), stringify!($handle));
+        return Ok(response);
+    };
+}
+
+/// Find if a trailing slash is allowed from a route's
+/// `#[trailing_slash = bool]` attribute. It's allowed by default.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_trailing_slash {
+    ( [] ) => {
+        true
+    };
+    ( [ #[trailing_slash = $allowed:literal] $( $rest:tt )* ] ) => {
+        $allowed
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_trailing_slash!([ $( $rest )* ])
+    };
+}
+
+/// Match and parse an optional typed arg without a default into an
+/// `Option<$arg_ty>`, if it can be parsed from the path segment or otherwise
+/// from its query param, if any. With the route's `#[presence]` attribute,
+/// it's an [`Optional`] instead, which is [`Optional::Unparseable`] when the
+/// arg is the last of the pattern (`$tail` is empty) and its segment cannot
+/// be parsed. A missing segment at the end of the path is parsed as empty,
+/// unless `$distinct_empty` (see [`opt_arg_segment`]).
+#[doc(hidden)]
+#[macro_export]
+macro_rules! match_opt_arg {
+    (
+        [], $distinct_empty:expr, $arg:ident, $arg_ty:ty, $segments:ident,
+        $query:ident, $start:ident, $_tail:tt
+    ) => {{
+        let segment = $crate::ledger::queries::router::opt_arg_segment(
+            $segments, $start, $distinct_empty);
+        match segment.map(str::parse::<$arg_ty>) {
+            Some(Ok(parsed)) => {
+                // Only advance if optional argument is present, otherwise stay
+                // in the same position for the next match, if any.
+                $start = $crate::ledger::queries::router::advance_segment(
+                    $segments, $start);
+                Some(parsed)
+            },
+            _ =>
+            {
+                // If arg cannot be parsed, use its query param, if any, or
+                // ignore it because it's optional
+                $crate::ledger::queries::router::query_param(
+                    $query, stringify!($arg))
+                    .and_then(|value| value.parse::<$arg_ty>().ok())
+            }
+        }
+    }};
+    (
+        [ #[presence] $( $_rest:tt )* ], $distinct_empty:expr, $arg:ident,
+        $arg_ty:ty, $segments:ident, $query:ident, $start:ident, $tail:tt
+    ) => {{
+        use $crate::ledger::queries::router::Optional;
+        let segment = $crate::ledger::queries::router::opt_arg_segment(
+            $segments, $start, $distinct_empty);
+        match segment.map(str::parse::<$arg_ty>) {
+            Some(Ok(parsed)) => {
+                $start = $crate::ledger::queries::router::advance_segment(
+                    $segments, $start);
+                Optional::Present(parsed)
+            },
+            // Nothing else can match the last segment, so it's consumed and
+            // passed to the handler as is
+            Some(Err(_)) if segment != Some("")
+                && $crate::match_opt_arg!(@last $tail) =>
+            {
+                $start = $crate::ledger::queries::router::advance_segment(
+                    $segments, $start);
+                Optional::Unparseable(segment.unwrap_or_default().to_owned())
+            }
+            _ => {
+                match $crate::ledger::queries::router::query_param(
+                    $query, stringify!($arg))
+                    .and_then(|value| value.parse::<$arg_ty>().ok())
+                {
+                    Some(parsed) => Optional::Present(parsed),
+                    None => Optional::Absent,
+                }
+            }
+        }
+    }};
+    (
+        [ #[ $( $_attr:tt )* ] $( $rest:tt )* ], $distinct_empty:expr,
+        $arg:ident, $arg_ty:ty, $segments:ident, $query:ident, $start:ident,
+        $tail:tt
+    ) => {
+        $crate::match_opt_arg!([ $( $rest )* ], $distinct_empty, $arg, $arg_ty,
+            $segments, $query, $start, $tail)
+    };
+    (@last []) => {
+        true
+    };
+    (@last [ $( $_tail:tt )+ ]) => {
+        false
+    };
+}
+
+/// Find if a route is marked with the `#[empty_segment]` attribute.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_empty_segment {
+    ( [] ) => {
+        false
+    };
+    ( [ #[empty_segment] $( $rest:tt )* ] ) => {
+        true
+    };
+    ( [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] ) => {
+        $crate::route_empty_segment!([ $( $rest )* ])
+    };
+}
+
+/// Return `true` from a router's `keeps_trailing_slash` if the route with
+/// the given `$handle` has the `#[empty_segment]` attribute or if it's a
+/// sub-router or a sub-pattern with such route.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_keeps_trailing_slash {
+    // a sub router
+    ( $attrs:tt (sub $router:ident) ) => {
+        $crate::route_cfg!{ $attrs
+            if $crate::ledger::queries::Router::keeps_trailing_slash(
+                &$router)
+            {
+                return true;
+            }
+        }
+    };
+
+    // a route inside a sub-pattern - join its attributes with the
+    // sub-pattern's
+    ( { [ $( $attr:tt )* ] [ $( $parent_attr:tt )* ] } $handle:tt ) => {
+        $crate::route_keeps_trailing_slash!([ $( $attr )* $( $parent_attr )* ]
+            $handle);
+    };
+
+    // a sub-pattern - check the routes inside it
+    (
+        $parent_attrs:tt {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $_sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $sub_handle:tt,
+            )*
+        }
+    ) => {
+        $(
+            $crate::route_keeps_trailing_slash!(
+                { [ $( #[ $( $attr )* ] )* ] $parent_attrs } $sub_handle);
+        )*
+    };
+
+    // any other route
+    ( $attrs:tt $handle:tt ) => {
+        $crate::route_cfg!{ $attrs
+            if $crate::route_empty_segment!($attrs) {
+                return true;
+            }
+        }
+    };
+}
+
+/// Invoke the sub-handler or call the handler function with the matched
+/// arguments generated by `try_match_segments`. The handlers are invoked in
+/// the `sync` dispatch mode of `Router::internal_handle`, unless the `[async]`
+/// mode of `Router::internal_handle_async` is given (see `handle_match_async`)
+/// to await the sub-routers and the `async` handlers.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! handle_match {
+    // Nested router
+    (
+        [$mode:ident] $ctx:ident, $request:ident, $segments:ident,
+        $start:ident, $attrs:tt,
+        (sub $router:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::check_sub_router_attrs!($attrs);
+        $crate::sub_router_segments!($attrs, $segments, $start);
+        // If the sub router has no route for the remaining segments, skip to
+        // the next pattern, if any
+        if $router.internal_route_id($segments, $start,
+            $crate::ledger::queries::router::split_query(&$request.path).1)
+            .is_none()
+        {
+            break
+        }
+        // The args of the sub router's prefix, if any, are available to its
+        // handlers in the context, after the args of the outer prefixes
+        let mut $ctx = $ctx;
+        $ctx.prefix_args.extend($crate::captured_args!( $( $matched_args, )* ));
+        // Invoke `handle` on the sub router with the remaining segments
+        return $crate::sub_router_call!($mode, $router, $ctx, $request, $segments,
+            $start)
+    };
+
+    // Handler function that uses a request (`with_options`)
+    (
+        [$mode:ident] $ctx:ident, $request:ident, $segments:ident,
+        $start:ident, $attrs:tt,
+        (with_options $handle:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::check_route_attrs!($attrs);
+        $crate::check_presence_attrs!($attrs);
+        $crate::check_with_options_attrs!($attrs);
+        // check that we're at the end of the path - trailing slash is optional,
+        // unless disabled for the route
+        if !$crate::ledger::queries::router::is_path_end(
+            $segments.get($start..).unwrap_or_default(),
+            $crate::route_trailing_slash!($attrs),
+        ) {
+            // we're not at the end, no match
+            // println!("Not fully matched");
+            break
+        }
+        $crate::ledger::queries::require_capability(
+            &$ctx, $crate::route_capability!($attrs), stringify!($handle))?;
+        // The handler encodes the response itself
+        $crate::ledger::queries::require_borsh_encoding($request)?;
+        let mut result = $crate::route_handler_call!($mode, $attrs, $ctx.handler_config,
+            stringify!($handle),
+            $crate::route_handler_fn_call!($attrs, $handle, ($ctx, $request),
+                ( $( $matched_args, )* )))?;
+        $crate::ledger::queries::router::check_response_size(
+            &result.data, $crate::route_max_size!($attrs))?;
+        $crate::ledger::queries::router::apply_default_info(
+            &mut result, $crate::route_info_template!($attrs), stringify!($handle));
+        // The handle must take care of encoding if needed and return `Vec<u8>`.
+        // This is because for `storage_value` the bytes are returned verbatim
+        // as read from storage.
+        return Ok(result);
+    };
+
+    // Handler function that returns chunks of the encoded response (`stream`)
+    (
+        [$mode:ident] $ctx:ident, $request:ident, $segments:ident,
+        $start:ident, $attrs:tt,
+        (stream $handle:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::check_route_attrs!($attrs);
+        $crate::check_presence_attrs!($attrs);
+        $crate::check_stream_attrs!($attrs);
+        // check that we're at the end of the path - trailing slash is optional,
+        // unless disabled for the route
+        if !$crate::ledger::queries::router::is_path_end(
+            $segments.get($start..).unwrap_or_default(),
+            $crate::route_trailing_slash!($attrs),
+        ) {
+            // we're not at the end, no match
+            break
+        }
+        $crate::ledger::queries::require_capability(
+            &$ctx, $crate::route_capability!($attrs), stringify!($handle))?;
+        // The chunks are produced at the latest height, which the client
+        // requests the following chunks at, so that a new block fails the
+        // request instead of mixing chunks of different blocks. The request
+        // data is the chunk's index.
+        $crate::ledger::queries::require_latest_height(&$ctx, $request)?;
+        $crate::ledger::queries::require_no_proof($request)?;
+        // The chunks are encoded by the handler
+        $crate::ledger::queries::require_borsh_encoding($request)?;
+        let height = $ctx.storage.last_height;
+
+        let chunks = $crate::route_handler_call!($mode, $attrs, $ctx.handler_config,
+            stringify!($handle),
+            $crate::route_handler_fn_call!($attrs, $handle, ($ctx),
+                ( $( $matched_args, )* )))?;
+        let chunk = $crate::ledger::queries::router::response_chunk(
+            chunks, $request, height)?;
+        let data = $crate::ledger::queries::router::__private::borsh::BorshSerialize::try_to_vec(&chunk).into_storage_result()?;
+        let mut response = $crate::ledger::queries::EncodedResponseQuery {
+            data,
+            ..Default::default()
+        };
+        $crate::ledger::queries::router::apply_default_info(
+            &mut response, $crate::route_info_template!($attrs), stringify!($handle));
+        return Ok(response);
+    };
+
+    // Constant response without a handler function
+    (
+        [$mode:ident] $ctx:ident, $request:ident, $segments:ident,
+        $start:ident, $attrs:tt,
+        (const $value:ident), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::check_route_attrs!($attrs);
+        $crate::check_presence_attrs!($attrs);
+        $crate::check_const_attrs!($attrs);
+        $( let _ = $matched_args; )*
+        // check that we're at the end of the path - trailing slash is optional,
+        // unless disabled for the route
+        if !$crate::ledger::queries::router::is_path_end(
+            $segments.get($start..).unwrap_or_default(),
+            $crate::route_trailing_slash!($attrs),
+        ) {
+            // we're not at the end, no match
+            break
+        }
+        $crate::ledger::queries::require_capability(
+            &$ctx, $crate::route_capability!($attrs), stringify!($value))?;
+        // The value doesn't depend on the block height, but it cannot be
+        // proven and it doesn't use any data
+        $crate::ledger::queries::require_no_proof($request)?;
+        $crate::ledger::queries::require_no_data($request)?;
+
+        // Encode the value with borsh only once
+        static ENCODED: $crate::ledger::queries::router::__private::once_cell::sync::Lazy<std::io::Result<Vec<u8>>> =
+            $crate::ledger::queries::router::__private::once_cell::sync::Lazy::new(|| $crate::ledger::queries::router::__private::borsh::BorshSerialize::try_to_vec(&$value));
+        let data = match $crate::ledger::queries::router::path_encoding(
+            &$request.path) {
+            $crate::ledger::queries::router::ResponseEncoding::Borsh => {
+                match &*ENCODED {
+                    Ok(data) => data.clone(),
+                    Err(err) => return Err($crate::ledger::storage_api::Error::new(
+                        std::io::Error::new(err.kind(), err.to_string()))),
+                }
+            }
+            $crate::ledger::queries::router::ResponseEncoding::Json => {
+                $crate::encode_response!(Json, &$value)?
+            }
+        };
+        let mut response = $crate::ledger::queries::EncodedResponseQuery {
+            data,
+            ..Default::default()
+        };
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::ledger::queries::router::apply_default_info(
+                &mut response, $crate::route_info_template!($attrs),
+                stringify!([<$value:lower>]));
+        }
+        return Ok(response);
+    };
+
+    // Handler function that doesn't use the request, just the path args, if any
+    (
+        [$mode:ident] $ctx:ident, $request:ident, $segments:ident,
+        $start:ident, $attrs:tt,
+        $handle:tt, ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::check_route_attrs!($attrs);
+        $crate::check_presence_attrs!($attrs);
+        // check that we're at the end of the path - trailing slash is optional,
+        // unless disabled for the route
+        if !$crate::ledger::queries::router::is_path_end(
+            $segments.get($start..).unwrap_or_default(),
+            $crate::route_trailing_slash!($attrs),
+        ) {
+            // we're not at the end, no match
+            // println!("Not fully matched");
+            break
+        }
+        $crate::ledger::queries::require_capability(
+            &$ctx, $crate::route_capability!($attrs), stringify!($handle))?;
+        // Check that the request is not sent with unsupported non-default
+        $crate::ledger::queries::require_latest_height(&$ctx, $request)?;
+        $crate::ledger::queries::require_no_proof($request)?;
+        $crate::ledger::queries::require_no_data($request)?;
+
+        let data = $crate::route_handler_call!($mode, $attrs, $ctx.handler_config,
+            stringify!($handle),
+            $crate::route_handler_fn_call!($attrs, $handle, ($ctx),
+                ( $( $matched_args, )* )))?;
+        // Encode the returned data with borsh, unless another encoding is
+        // selected by the path's suffix. A borsh response to a caller in the
+        // same process is returned as is.
+        let mut response = if $request.in_process
+            && $crate::ledger::queries::router::path_encoding(&$request.path)
+                == $crate::ledger::queries::router::ResponseEncoding::Borsh
+        {
+            $crate::ledger::queries::EncodedResponseQuery {
+                value: Some($crate::ledger::queries::TypedValue::new(data)),
+                ..Default::default()
+            }
+        } else {
+            $crate::ledger::queries::EncodedResponseQuery {
+                data: $crate::encode_response!($request, &data)?,
+                ..Default::default()
+            }
+        };
+        // A borsh response returned as is isn't encoded, so it's not limited
+        $crate::ledger::queries::router::check_response_size(
+            &response.data, $crate::route_max_size!($attrs))?;
+        $crate::ledger::queries::router::apply_default_info(
+            &mut response, $crate::route_info_template!($attrs), stringify!($handle));
+        return Ok(response);
+    };
+
+    // The `sync` dispatch mode by default
+    ( $ctx:ident, $( $rest:tt )* ) => {
+        $crate::handle_match!([sync] $ctx, $( $rest )*)
+    };
+}
+
+/// Invoke the sub-handler or call the handler function like `handle_match`,
+/// but in the `async` dispatch mode of `Router::internal_handle_async`.
+#[cfg(any(test, feature = "async-handlers"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! handle_match_async {
+    ( $( $args:tt )* ) => {
+        $crate::handle_match!([async] $( $args )*)
+    };
+}
+
+/// Encode the `$data` returned by a handler in the encoding selected by the
+/// `$request.path`'s suffix (see `router::path_encoding`) or in the given
+/// encoding. The JSON encoding is only available for the data whose type
+/// implements `serde::Serialize`, which is resolved with the `JsonProbe`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! encode_response {
+    (Json, $data:expr) => {{
+        #[allow(unused_imports)]
+        use $crate::ledger::queries::router::{JsonEncode, NoJsonEncode};
+        (&$crate::ledger::queries::router::JsonProbe($data)).encode_json()
+    }};
+
+    ($request:ident, $data:expr) => {
+        match $crate::ledger::queries::router::path_encoding(&$request.path) {
+            $crate::ledger::queries::router::ResponseEncoding::Borsh => {
+                $crate::ledger::queries::router::__private::borsh::BorshSerialize::try_to_vec($data).into_storage_result()
+            }
+            $crate::ledger::queries::router::ResponseEncoding::Json => {
+                $crate::encode_response!(Json, $data)
+            }
+        }
+    };
+}
+
+/// Resolve the route matched by `try_match_segments` without invoking any
+/// handlers. This is the counterpart of `handle_match` used by the generated
+/// `route_id` method, in which the `$route` is the router's route type and
+/// the path's query string is passed in place of the `$request`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! match_route_id {
+    // Nested router
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        (sub $router:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $( let _ = $matched_args; )*
+        $crate::sub_router_segments!($attrs, $segments, $start);
+        // Resolve the route with the sub router or skip to the next pattern,
+        // if any
+        match $router.internal_route_id($segments, $start, $request) {
+            Some(route) => return Some($crate::ledger::queries::router::__private::paste::paste! {
+                $route::[<$router:camel>](route)
+            }),
+            None => break,
+        }
+    };
+
+    // Handler function that uses a request (`with_options`)
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        (with_options $handle:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::match_route_id!($route, $request, $segments, $start, $attrs, $handle,
+            ( $( $matched_args, )* ), );
+    };
+
+    // Handler function that returns chunks of the encoded response (`stream`)
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        (stream $handle:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::match_route_id!($route, $request, $segments, $start, $attrs, $handle,
+            ( $( $matched_args, )* ), );
+    };
+
+    // Constant response without a handler function
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        (const $value:ident), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::match_route_id!($route, $request, $segments, $start, $attrs, $value,
+            ( $( $matched_args, )* ), );
+    };
+
+    // Handler function that doesn't use the request, just the path args, if any
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        $handle:tt, ( $( $matched_args:ident, )* ),
+    ) => {
+        $( let _ = $matched_args; )*
+        // check that we're at the end of the path - trailing slash is optional,
+        // unless disabled for the route
+        if !$crate::ledger::queries::router::is_path_end(
+            $segments.get($start..).unwrap_or_default(),
+            $crate::route_trailing_slash!($attrs),
+        ) {
+            // we're not at the end, no match
+            break
+        }
+        return Some($crate::ledger::queries::router::__private::paste::paste! { $route::[<$handle:camel>] });
+    };
+}
+
+/// Format the value of a matched arg for a [`MatchInfo`] with the
+/// [`ArgProbe`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! format_matched_arg {
+    ($arg:ident) => {{
+        #[allow(unused_imports)]
+        use $crate::ledger::queries::router::{
+            DebugArg, DisplayArg, OpaqueArg,
+        };
+        (&&&$crate::ledger::queries::router::ArgProbe(&$arg)).format_arg()
+    }};
+}
+
+/// Check that the `$path` constructed by a `*_path` method of the `$router`
+/// for the route of `$handle` with the given `$param`s is matched back into
+/// the same route with the same args (see [`router::assert_path_matches`]).
+/// It's only checked with `debug_assertions` and it's a no-op otherwise.
+///
+/// [`router::assert_path_matches`]: crate::ledger::queries::router::assert_path_matches
+#[doc(hidden)]
+#[macro_export]
+macro_rules! debug_assert_path {
+    ($router:expr, $path:ident, $handle:tt, ( $( $param:tt ),* )) => {
+        #[cfg(debug_assertions)]
+        {
+            let router = $router;
+            // The path is matched like in `Router::handle`
+            let normalized =
+                $crate::ledger::queries::router::normalize_path(&$path);
+            let info = $crate::ledger::queries::router::split_router_path(
+                &normalized, &router.prefix, router.separator)
+                .and_then(|mut segments| {
+                    if $crate::ledger::queries::Router::keeps_trailing_slash(
+                        router) {
+                        $crate::ledger::queries::router::keep_trailing_slash(
+                            &$path, &mut segments);
+                    }
+                    router.internal_match_info(&segments, 0, None)
+                });
+            $crate::ledger::queries::router::assert_path_matches(
+                &$path,
+                info,
+                stringify!($handle),
+                vec![ $(
+                    (stringify!($param), $crate::format_matched_arg!($param)),
+                )* ],
+            );
+        }
+    };
+}
+
+/// Collect the matched args into a `BTreeMap<String, String>` keyed by the
+/// args' names, with the values formatted with the [`CaptureProbe`]. An
+/// absent optional arg is left out of the map.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! captured_args {
+    ( $( $arg:ident, )* ) => {{
+        #[allow(unused_imports)]
+        use $crate::ledger::queries::router::{
+            CaptureArg, CaptureDebugArg, CaptureOptionalArg,
+        };
+        #[allow(unused_mut)]
+        let mut args = std::collections::BTreeMap::<String, String>::new();
+        $(
+            let value = (&&&$crate::ledger::queries::router::CaptureProbe(
+                &$arg,
+            )).capture_arg();
+            if let Some(value) = value {
+                args.insert(stringify!($arg).to_owned(), value);
+            }
+        )*
+        args
+    }};
+}
+
+/// Resolve the [`MatchInfo`] of the route matched by `try_match_segments`
+/// without invoking any handlers, like `match_route_id`, in which the path's
+/// query string is passed in place of both the `$route` and the `$request`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! match_info {
+    // Nested router - its route's args follow the args of its prefix
+    (
+        $_route:ident, $request:ident, $segments:ident, $start:ident,
+        $attrs:tt, (sub $router:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        let prefix_args: Vec<(&'static str, String)> = vec![
+            $( (stringify!($matched_args), $crate::format_matched_arg!($matched_args)), )*
+        ];
+        $crate::sub_router_segments!($attrs, $segments, $start);
+        // Resolve the route with the sub router or skip to the next pattern,
+        // if any
+        match $router.internal_match_info($segments, $start, $request) {
+            Some(mut info) => {
+                info.args.splice(0..0, prefix_args);
+                return Some(info);
+            }
+            None => break,
+        }
+    };
+
+    // Handler function that uses a request (`with_options`)
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        (with_options $handle:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::match_info!($route, $request, $segments, $start, $attrs, $handle,
+            ( $( $matched_args, )* ), );
+    };
+
+    // Handler function that returns chunks of the encoded response (`stream`)
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        (stream $handle:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::match_info!($route, $request, $segments, $start, $attrs, $handle,
+            ( $( $matched_args, )* ), );
+    };
+
+    // Constant response without a handler function, named after the constant
+    (
+        $route:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        (const $value:ident), ( $( $matched_args:ident, )* ),
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::match_info!($route, $request, $segments, $start, $attrs,
+                [<$value:lower>], ( $( $matched_args, )* ), );
+        }
+    };
+
+    // Handler function that doesn't use the request, just the path args, if any
+    (
+        $_route:ident, $_request:ident, $segments:ident, $start:ident,
+        $attrs:tt, $handle:tt, ( $( $matched_args:ident, )* ),
+    ) => {
+        // check that we're at the end of the path - trailing slash is optional,
+        // unless disabled for the route
+        if !$crate::ledger::queries::router::is_path_end(
+            $segments.get($start..).unwrap_or_default(),
+            $crate::route_trailing_slash!($attrs),
+        ) {
+            // we're not at the end, no match
+            break
+        }
+        return Some($crate::ledger::queries::router::MatchInfo {
+            handler: stringify!($handle),
+            args: vec![ $(
+                (stringify!($matched_args), $crate::format_matched_arg!($matched_args)),
+            )* ],
+        });
+    };
+}
+
+/// Resolve the [`MatchInfo`] of the route matched by `try_match_segments` like
+/// `match_info` for a router's `peek`, in which the [`CandidateFailures`] are
+/// passed in place of the `$route` to record a sub-router's failures and the
+/// trailing segments of a matched pattern.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! peek_match {
+    // Nested router - its route's args follow the args of its prefix
+    (
+        $failures:ident, $request:ident, $segments:ident, $start:ident,
+        $attrs:tt, (sub $router:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        let prefix_args: Vec<(&'static str, String)> = vec![
+            $( (stringify!($matched_args), $crate::format_matched_arg!($matched_args)), )*
+        ];
+        $crate::sub_router_segments!($attrs, $segments, $start);
+        match $router.internal_peek($segments, $start, $request) {
+            Ok(mut info) => {
+                info.args.splice(0..0, prefix_args);
+                return Some(info);
+            }
+            Err(failures) => {
+                $failures.sub_router_failed(failures);
+                break;
+            }
+        }
+    };
+
+    // Any other route is matched like in `match_info`
+    (
+        $failures:ident, $request:ident, $segments:ident, $start:ident,
+        $attrs:tt, $handle:tt, ( $( $matched_args:ident, )* ),
+    ) => {
+        let info = (|| {
+            loop {
+                $crate::match_info!($request, $request, $segments, $start, $attrs,
+                    $handle, ( $( $matched_args, )* ), );
+            }
+            None
+        })();
+        match info {
+            Some(info) => return Some(info),
+            None => {
+                $failures.trailing_segments($start);
+                break;
+            }
+        }
+    };
+}
+
+/// Collect the [`MatchInfo`]s of all the routes matched by
+/// `try_match_segments`, like `match_info`, in which the `$matches` are passed
+/// in place of the `$route`. A pattern's match is returned from the closure
+/// in which the pattern is tried, so that the next patterns are tried too.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! match_all {
+    // Nested router - all its matched routes with the args of its prefix
+    (
+        $_matches:ident, $request:ident, $segments:ident, $start:ident,
+        $attrs:tt, (sub $router:tt), ( $( $matched_args:ident, )* ),
+    ) => {
+        let prefix_args: Vec<(&'static str, String)> = vec![
+            $( (stringify!($matched_args), $crate::format_matched_arg!($matched_args)), )*
+        ];
+        $crate::sub_router_segments!($attrs, $segments, $start);
+        let mut sub_matches = vec![];
+        $router.internal_match_all($segments, $start, $request, &mut sub_matches);
+        if sub_matches.is_empty() {
+            break;
+        }
+        for info in &mut sub_matches {
+            info.args.splice(0..0, prefix_args.iter().cloned());
+        }
+        return sub_matches;
+    };
+
+    // Any other route is matched like in `match_info`
+    (
+        $_matches:ident, $request:ident, $segments:ident, $start:ident,
+        $attrs:tt, $handle:tt, ( $( $matched_args:ident, )* ),
+    ) => {
+        let info = (|| {
+            loop {
+                $crate::match_info!($request, $request, $segments, $start, $attrs,
+                    $handle, ( $( $matched_args, )* ), );
+            }
+            None
+        })();
+        match info {
+            Some(info) => return vec![info],
+            None => break,
+        }
+    };
+}
+
+/// The type of a union typed arg, e.g. `[id: u64 | String]`, as nested
+/// [`Either`]s.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! union_arg_type {
+    ( $ty:ty ) => { $ty };
+    ( $left:ty | $( $right:ty )|+ ) => {
+        $crate::ledger::queries::router::Either<$left, $crate::union_arg_type!($( $right )|+)>
+    };
+}
+
+/// Find the [`ParseFailureKind`] of a typed arg's parse error.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! parse_failure_kind {
+    ($err:expr) => {{
+        #[allow(unused_imports)]
+        use $crate::ledger::queries::router::{
+            InvalidParseFailure, OverflowParseFailure,
+        };
+        (&$crate::ledger::queries::router::ParseErrorProbe(&$err))
+            .parse_failure_kind()
+    }};
+}
+
+/// Check if an optional path segment matches a literal or any of its aliases,
+/// as is or after it's percent-decoded (see `percent_decode_segment`), e.g.
+/// both `a` and `%61` match the literal `"a"`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! literal_matches {
+    ($segment:expr, $expected:literal $( | $alias:literal )*) => {
+        match $segment {
+            Some($expected $( | $alias )*) => true,
+            Some(segment) if segment.contains('%') => matches!(
+                $crate::ledger::queries::router::percent_decode_segment(
+                    segment).as_deref(),
+                Some($expected $( | $alias )*)
+            ),
+            _ => false,
+        }
+    };
+}
+
+/// Reject a path whose `[name: storage_key]` arg is malformed with the
+/// `Error::InvalidKey`. When a request is handled or a path is validated, the
+/// error is returned without trying the next patterns. Otherwise, when only
+/// resolving the route, the key is recorded as a parse failure of the pattern
+/// and it's skipped.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! reject_invalid_key {
+    (handle_match, $failure:ident, $start:ident, $rest:ident, $err:ident) => {
+        return Err($crate::ledger::storage_api::Error::new($err))
+    };
+    (
+        handle_match_async,
+        $failure:ident,
+        $start:ident,
+        $rest:ident,
+        $err:ident
+    ) => {
+        return Err($crate::ledger::storage_api::Error::new($err))
+    };
+    (validated_args, $failure:ident, $start:ident, $rest:ident, $err:ident) => {
+        return Err($err)
+    };
+    (
+        $_terminal:ident, $failure:ident, $start:ident, $rest:ident, $err:ident
+    ) => {
+        $crate::ledger::queries::router::record_parse_failure(
+            &mut $failure,
+            $start,
+            &$rest,
+            "storage_key",
+            $crate::ledger::queries::router::ParseFailureKind::Invalid,
+        );
+        break
+    };
+}
+
+/// Skip to the next pattern when the path ends before a typed arg, so that a
+/// missing arg is a path mismatch rather than a failure to parse an empty
+/// segment. An empty segment that is present, e.g. before a trailing slash,
+/// is still parsed.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! require_arg_segment {
+    ($segments:ident, $start:ident, $failure:ident) => {
+        if $segments.get($start).is_none() {
+            $crate::ledger::queries::router::record_mismatch(
+                &mut $failure,
+                $start,
+            );
+            break;
+        }
+    };
+}
+
+/// Using TT muncher pattern on the `$tail` pattern, this macro recursively
+/// generates path matching logic that `break`s if some parts are unmatched.
+/// The `$segments` are the path's segments and `$start` is the index of the
+/// next segment to be matched. The `$query` is the path's query string, if
+/// any, from which an absent optional argument may be supplied. The
+/// `$failure` is an `Option<Error>` in which the first typed argument that
+/// fails to parse is recorded.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! try_match_segments {
+    // sub-pattern handle - this should only be invoked if the current
+    // $pattern is already matched
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $_attrs:tt,
+        {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $handle:tt,
+            )*
+        },
+        $matched_args:tt,
+        ()
+    ) => {
+        $crate::check_exclusive_sub_routers!( $(
+            [ $( #[ $( $attr )* ] )* ] ( $( $sub_pattern )* ) $handle
+        ),* );
+        // Try to match each sub-patten
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                // This loop never repeats, it's only used for a breaking
+                // mechanism when a $pattern is not matched to skip to the
+                // next one, if any
+                $crate::ledger::queries::router::MatchFailures::enter_pattern(
+                    &mut $failure, $crate::pattern_template!(( $( $sub_pattern )* )));
+                loop {
+                    #[allow(unused_mut)]
+                    let mut $start = $start;
+                    // Try to match, parse args and invoke $handle, will
+                    // break the `loop` not matched
+                    $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+                        $start, $failure, [ $( #[ $( $attr )* ] )* ], $handle,
+                        $matched_args, ( $( $sub_pattern )* )
+                    );
+                }
+                $crate::ledger::queries::router::MatchFailures::exit_pattern(
+                    &mut $failure);
+            }
+        )*
+        // None of the sub-patterns matched, skip to the next pattern, if any
+        break;
+    };
+
+    // Terminal tail call, invoked after when all the args in the current
+    // pattern are matched and the $handle is not sub-pattern
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        ()
+    ) => {
+        $crate::$terminal!($ctx, $request, $segments, $start, $attrs, $handle,
+            ( $( $matched_args, )* ), );
+    };
+
+    // Try to match an untyped argument, declares the expected $arg as &str
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        // A missing segment at the end of the path is matched as empty
+        let $arg: &str = $segments.get($start).copied().unwrap_or_default();
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match an untyped argument that must match the given regex,
+    // declares the expected $arg as &str
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : re $regex:literal]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        let $arg: &str = $segments.get($start).copied().unwrap_or_default();
+        {
+            // The regex is compiled only once, on its first use
+            static REGEX: $crate::ledger::queries::router::__private::once_cell::sync::Lazy<$crate::ledger::queries::router::__private::regex::Regex> =
+                $crate::ledger::queries::router::__private::once_cell::sync::Lazy::new(|| {
+                    $crate::ledger::queries::router::__private::regex::Regex::new($regex).expect(concat!(
+                        "The regex \"", $regex, "\" of arg `",
+                        stringify!($arg), "` should be valid"))
+                });
+            if !REGEX.is_match($arg) {
+                // println!("{} doesn't match regex {}", $arg, $regex);
+                // Try to skip to next pattern
+                $crate::ledger::queries::router::record_mismatch(
+                    &mut $failure, $start);
+                break;
+            }
+        }
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match an untyped argument that must be in the given run-time
+    // set of segments, declares the expected $arg as &str
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : oneof $set:expr]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        let $arg: &str = $segments.get($start).copied().unwrap_or_default();
+        if !$crate::ledger::queries::router::SegmentSet::contains_segment(
+            $set, $arg)
+        {
+            // println!("{} is not in the set {}", $arg, stringify!($set));
+            // Try to skip to next pattern
+            $crate::ledger::queries::router::record_mismatch(
+                &mut $failure, $start);
+            break;
+        }
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match a base64url-encoded argument, declares the expected $arg
+    // as `Vec<u8>` with the decoded bytes, if the segment can be decoded
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : b64]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        $crate::require_arg_segment!($segments, $start, $failure);
+        let segment: &str = $segments.get($start).copied().unwrap_or_default();
+        let $arg: Vec<u8> =
+            match $crate::ledger::queries::router::decode_b64_segment(segment) {
+                Some(decoded) => decoded,
+                None => {
+                    // If arg cannot be decoded, try to skip to next pattern
+                    $crate::ledger::queries::router::record_parse_failure(
+                        &mut $failure, $start, segment, "base64url",
+                        $crate::ledger::queries::router::ParseFailureKind::Invalid);
+                    break
+                }
+            };
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match and convert an argument with `TryFrom<&str>`, declares the
+    // expected $arg into type $t, if it can be converted. Like an integer arg
+    // in a radix, it only matches a single segment, even at the end of the
+    // path.
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : try $arg_ty:ty]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        $crate::require_arg_segment!($segments, $start, $failure);
+        let segment: &str = $segments.get($start).copied().unwrap_or_default();
+        let $arg: $arg_ty =
+            match <$arg_ty as std::convert::TryFrom<&str>>::try_from(segment) {
+                Ok(converted) => converted,
+                Err(err) => {
+                    // If arg cannot be converted, try to skip to next pattern
+                    $crate::ledger::queries::router::record_parse_failure(
+                        &mut $failure, $start, segment,
+                        std::any::type_name::<$arg_ty>(),
+                        $crate::parse_failure_kind!(err));
+                    break
+                }
+            };
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match and parse an integer argument in the declared radix,
+    // declares the expected $arg into type $t, if it can be parsed. Unlike a
+    // typed arg, it only matches a single segment, even at the end of the
+    // path.
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : $arg_ty:ident radix $radix:literal]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        const _: () = assert!(
+            2 <= $radix && $radix <= 36,
+            concat!("The radix of arg `", stringify!($arg),
+                "` must be in the range 2..=36")
+        );
+        $crate::require_arg_segment!($segments, $start, $failure);
+        let segment: &str = $segments.get($start).copied().unwrap_or_default();
+        let $arg: $arg_ty =
+            match <$arg_ty as $crate::ledger::queries::router::RadixArg>
+                ::parse_radix(segment, $radix) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    // If arg cannot be parsed, try to skip to next pattern
+                    $crate::ledger::queries::router::record_parse_failure(
+                        &mut $failure, $start, segment,
+                        concat!(stringify!($arg_ty), " radix ", $radix),
+                        $crate::parse_failure_kind!(err));
+                    break
+                }
+            };
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Match a glob argument, which consumes the rest of the path at any depth
+    // (including none), declares the expected $arg as &str with the remaining
+    // segments joined back with slashes
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : **]
+        )
+    ) => {
+        let glob = $segments.get($start..).unwrap_or_default().join("/");
+        let $arg: &str = &glob;
+        // Advance past all the segments
+        $start = $segments.len();
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), () );
+    };
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        (
+            [$arg:ident : **]
+            / $( $tail:tt)/ *
+        )
+    ) => {
+        compile_error!(concat!(
+            "The glob arg `", stringify!($arg),
+            "` must be the last segment of a pattern"
+        ));
+    };
+
+    // Match a storage key argument, which consumes the rest of the path like
+    // a glob arg, declares the expected $arg as `storage::Key` parsed from the
+    // remaining segments joined back with slashes. A malformed key is not
+    // skipped to the next pattern, it's rejected with `Error::InvalidKey`.
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : storage_key]
+        )
+    ) => {
+        let rest = $segments.get($start..).unwrap_or_default().join("/");
+        if rest.is_empty() {
+            // The path ends before the key, try to skip to next pattern
+            $crate::ledger::queries::router::record_mismatch(
+                &mut $failure, $start);
+            break;
+        }
+        let $arg: $crate::types::storage::Key =
+            match $crate::ledger::queries::router::parse_storage_key(&rest) {
+                Ok(key) => key,
+                Err(err) => {
+                    $crate::reject_invalid_key!($terminal, $failure, $start, rest, err);
+                }
+            };
+        // Advance past all the segments
+        $start = $segments.len();
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), () );
+    };
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        (
+            [$arg:ident : storage_key]
+            / $( $tail:tt)/ *
+        )
+    ) => {
+        compile_error!(concat!(
+            "The storage key arg `", stringify!($arg),
+            "` must be the last segment of a pattern"
+        ));
+    };
+
+    // Try to match and parse an optional typed argument with a default value.
+    // Declares the expected $arg into type $t, if it can be parsed, otherwise
+    // it's parsed from its query param, if any, or set to the $default. The
+    // path segment takes precedence over the query param.
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : opt $arg_ty:ty = $default:expr]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        let segment: &str = $segments.get($start).copied().unwrap_or_default();
+        let $arg: $arg_ty = match segment.parse::<$arg_ty>() {
+            Ok(parsed) => {
+                // Only advance if optional argument is present, otherwise stay
+                // in the same position for the next match, if any.
+                $start = $crate::ledger::queries::router::advance_segment(
+                    $segments, $start);
+                parsed
+            },
+            Err(_) =>
+            {
+                // If arg cannot be parsed, use its query param, if any,
+                // or the default
+                $crate::ledger::queries::router::query_param(
+                    $query, stringify!($arg))
+                    .and_then(|value| value.parse::<$arg_ty>().ok())
+                    .unwrap_or_else(|| $default)
+            }
+        };
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), (@after_opt $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match and parse a typed argument like the case below, but with
+    // the argument optional.
+    // Declares the expected $arg into type $t, if it can be parsed from the
+    // path segment or otherwise from its query param, if any.
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : opt $arg_ty:ty]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        let $arg = $crate::match_opt_arg!($attrs, $crate::route_empty_segment!($attrs), $arg,
+            $arg_ty, $segments, $query, $start, [ $( $( $tail )/ * )? ]);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), (@after_opt $( $( $tail )/ * )? ) );
+    };
+
+    // A literal segment between two optional args, which may be omitted in
+    // the query form of a path, i.e. when the path ends before the literal
+    // and it has a query string, from which the following optional args are
+    // supplied by name in any order
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        (
+            @after_opt $expected:literal
+            / [$next:ident : opt $( $next_rest:tt )*]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        if $crate::literal_matches!($segments.get($start).copied(), $expected) {
+            $start = $crate::ledger::queries::router::advance_segment(
+                $segments, $start);
+        } else if $query.is_none()
+            || !$crate::ledger::queries::router::is_path_end(
+                $segments.get($start..).unwrap_or_default(), true)
+        {
+            // Try to skip to next pattern
+            $crate::ledger::queries::router::record_mismatch(
+                &mut $failure, $start);
+            break;
+        }
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, $matched_args,
+            ( [$next : opt $( $next_rest )*] $( / $( $tail )/ * )? ) );
+    };
+
+    // The rest of a pattern after an optional arg is matched as usual
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        ( @after_opt $( $tail:tt )* )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, $matched_args, ( $( $tail )* ) );
+    };
+
+    // A typed arg with a `where` predicate is matched like a typed arg,
+    // including the special cases below for the last arg, and then its
+    // predicate is checked
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:ident,
+        $matched_args:tt,
+        ( [$arg:ident : $arg_ty:ty where $pred:expr] )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, $matched_args,
+            (@rest [$arg: $arg_ty] / (@where $arg $pred)) );
+    };
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt,
+        (with_options $handle:ident),
+        $matched_args:tt,
+        ( [$arg:ident : $arg_ty:ty where $pred:expr] )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, (with_options $handle), $matched_args,
+            (@rest [$arg: $arg_ty] / (@where $arg $pred)) );
+    };
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        (
+            [$arg:ident : $arg_ty:ty where $pred:expr]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, $matched_args,
+            ( [$arg: $arg_ty] / (@where $arg $pred) $( / $( $tail )/ * )? ) );
+    };
+
+    // Check the `where` predicate of the typed arg matched just before, if
+    // it's not satisfied, try to skip to next pattern
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        (
+            (@where $arg:ident $pred:expr)
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        if !$pred {
+            // The arg's segment is the one just before
+            $crate::ledger::queries::router::record_validation_failure(
+                &mut $failure, $start.saturating_sub(1), stringify!($arg),
+                $crate::format_matched_arg!($arg), stringify!($pred));
+            break
+        }
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, $matched_args,
+            ( $( $( $tail )/ * )? ) );
+    };
+
+    // A typed arg with a custom `display` function is matched like a typed
+    // arg, because the function is only used to construct its path
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        (
+            [$arg:ident : $arg_ty:ty, display = $_display:path]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, $matched_args,
+            ( [$arg: $arg_ty] $( / $( $tail )/ * )? ) );
+    };
+
+    // A union typed arg is matched like a typed arg of its `Either` type
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $matched_args:tt,
+        (
+            [$arg:ident : $left:ty $( | $right:ty )+]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, $matched_args,
+            ( [$arg: $crate::union_arg_type!($left $( | $right )+)] $( / $( $tail )/ * )? ) );
+    };
+
+    // Special case of the typed argument pattern below. When there are no more
+    // args in the tail and the handle isn't a sub-router (its handler is
+    // ident), we try to match the rest of the path till the end.
+    //
+    // This is specifically needed for storage methods, which have
+    // `storage::Key` param that includes path-like slashes.
+    //
+    // Try to match and parse a typed argument, declares the expected $arg into
+    // type $t, if it can be parsed
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:ident,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : $arg_ty:ty]
+        )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle, ( $( $matched_args, )* ),
+            (@rest [$arg: $arg_ty]) );
+    };
+
+    // One more special case of the typed argument pattern below for a handler
+    // `with_options`, where we try to match the rest of the path till the end.
+    //
+    // This is specifically needed for storage methods, which have
+    // `storage::Key` param that includes path-like slashes.
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt,
+        (with_options $handle:ident),
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : $arg_ty:ty]
+        )
+    ) => {
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, (with_options $handle),
+            ( $( $matched_args, )* ),
+            (@rest [$arg: $arg_ty]) );
+    };
+
+    // Try to match and parse a typed argument from the rest of the path,
+    // joined back with slashes, declares the expected $arg into type $t, if
+    // it can be parsed
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (@rest [$arg:ident : $arg_ty:ty] $( / $( $tail:tt)/ * )?)
+    ) => {
+        $crate::require_arg_segment!($segments, $start, $failure);
+        let rest = $segments.get($start..).unwrap_or_default().join("/");
+        let $arg: $arg_ty = match rest.parse::<$arg_ty>() {
+            Ok(parsed) => {
+                // println!("Parsed {}", parsed);
+                parsed
+            },
+            Err(err) =>
+            {
+                // println!("Cannot parse {} from {}", stringify!($arg_ty), rest);
+                // If arg cannot be parsed, try to skip to next pattern
+                $crate::ledger::queries::router::record_parse_failure(
+                    &mut $failure, $start, &rest, std::any::type_name::<$arg_ty>(),
+                    $crate::parse_failure_kind!(err));
+                break
+            }
+        };
+        // Advance past all the segments
+        $start = $segments.len();
+        // Invoke the terminal pattern, after the arg's predicate, if any
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match and parse a typed argument, declares the expected $arg into
+    // type $t, if it can be parsed
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$arg:ident : $arg_ty:ty]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        $crate::require_arg_segment!($segments, $start, $failure);
+        let segment: &str = $segments.get($start).copied().unwrap_or_default();
+        let $arg: $arg_ty = match segment.parse::<$arg_ty>() {
+            Ok(parsed) => parsed,
+            Err(err) =>
+            {
+                // println!("Cannot parse {} from {}", stringify!($arg_ty), segment);
+                // If arg cannot be parsed, try to skip to next pattern
+                $crate::ledger::queries::router::record_parse_failure(
+                    &mut $failure, $start, segment, std::any::type_name::<$arg_ty>(),
+                    $crate::parse_failure_kind!(err));
+                break
+            }
+        };
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match and parse a typed argument like the case above, but also
+    // bind the segment to the $raw arg as &str. Unlike the case above, the
+    // argument only matches a single segment, even at the end of the path.
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            [$raw:ident = $arg:ident : $arg_ty:ty]
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        $crate::require_arg_segment!($segments, $start, $failure);
+        let $raw: &str = $segments.get($start).copied().unwrap_or_default();
+        // The segment is parsed only once for both of the args
+        let $arg: $arg_ty = match $raw.parse::<$arg_ty>() {
+            Ok(parsed) => parsed,
+            Err(err) =>
+            {
+                // If arg cannot be parsed, try to skip to next pattern
+                $crate::ledger::queries::router::record_parse_failure(
+                    &mut $failure, $start, $raw, std::any::type_name::<$arg_ty>(),
+                    $crate::parse_failure_kind!(err));
+                break
+            }
+        };
+        // Advance past the matched arg, if any
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* $raw, $arg, ), ( $( $( $tail )/ * )? ) );
+    };
+
+    // Try to match an expected string literal or any of its aliases
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident,
+        $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        ( $( $matched_args:ident, )* ),
+        (
+            $expected:literal $( | $alias:literal )*
+            $( / $( $tail:tt)/ * )?
+        )
+    ) => {
+        if $crate::literal_matches!($segments.get($start).copied(),
+            $expected $( | $alias )*) {
+            // Advanced index past the matched arg
+            // println!("Matched literal {}", $expected);
+            $start = $crate::ledger::queries::router::advance_segment(
+                $segments, $start);
+        } else {
+            // println!("{:?} doesn't match literal {}", $segments.get($start), $expected);
+            // Try to skip to next pattern
+            $crate::ledger::queries::router::record_mismatch(
+                &mut $failure, $start);
+            break;
+        }
+        $crate::try_match_segments!($terminal, $ctx, $request, $segments, $query,
+            $start, $failure, $attrs, $handle,
+            ( $( $matched_args, )* ), ( $( $( $tail )/ * )? ) );
+    };
+}
+
+/// Compute the [`SegmentBounds`] of a pattern with its handle at compile time.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! segment_bounds {
+    // A sub-router may match any number of segments after the pattern
+    ( $pattern:tt, (sub $router:tt) ) => {
+        $crate::segment_bounds!(@pattern $pattern)
+            .then($crate::ledger::queries::router::SegmentBounds::REST)
+    };
+
+    // Any of the sub-patterns may follow the pattern
+    (
+        $pattern:tt,
+        {
+            $( #[ $( $_first_attr:tt )* ] )*
+            ( $( $first_pattern:tt )* ) $( -> $_first_return_ty:path )? = $first_handle:tt,
+            $(
+                $( #[ $( $_attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $handle:tt,
+            )*
+        }
+    ) => {
+        $crate::segment_bounds!(@pattern $pattern).then(
+            $crate::segment_bounds!(( $( $first_pattern )* ), $first_handle)
+            $( .or($crate::segment_bounds!(( $( $sub_pattern )* ), $handle)) )*
+        )
+    };
+
+    // Handler function
+    ( $pattern:tt, $handle:tt ) => {
+        $crate::segment_bounds!(@leaf $pattern)
+    };
+
+    // The segments of a pattern followed by a sub-router or sub-patterns
+    (@pattern ()) => {
+        $crate::ledger::queries::router::SegmentBounds::EMPTY
+    };
+    (@pattern ( $expected:literal $( | $_alias:literal )* $( / $tail:tt )* )) => {
+        $crate::ledger::queries::router::SegmentBounds::LITERAL
+            .then($crate::segment_bounds!(@pattern ( $( $tail )/ * )))
+    };
+    (@pattern ( [ $( $arg:tt )* ] $( / $tail:tt )* )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+            .then($crate::segment_bounds!(@pattern ( $( $tail )/ * )))
+    };
+
+    // The segments of a pattern with a handler function, in which the last
+    // typed argument consumes the rest of the path (see `try_match_segments`)
+    (@leaf ()) => {
+        $crate::ledger::queries::router::SegmentBounds::EMPTY
+    };
+    (@leaf ( [$arg:ident : opt $arg_ty:ty $( = $default:expr )?] )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+    };
+    (@leaf (
+        [$arg:ident : opt $( $_arg_rest:tt )*] / $expected:literal
+            / [$next:ident : opt $( $next_rest:tt )*] $( / $tail:tt )*
+    )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+            .then($crate::ledger::queries::router::SegmentBounds::OPTIONAL_LITERAL)
+            .then($crate::segment_bounds!(@leaf (
+                [$next : opt $( $next_rest )*] $( / $tail )*
+            )))
+    };
+    (@leaf ( [$arg:ident : re $regex:literal] )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+    };
+    (@leaf ( [$arg:ident : oneof $set:expr] )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+    };
+    (@leaf ( [$arg:ident : b64] )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+    };
+    (@leaf ( [$arg:ident : $arg_ty:ident radix $radix:literal] )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+    };
+    (@leaf ( [$arg:ident : try $arg_ty:ty] )) => {
+        $crate::ledger::queries::router::SegmentBounds::ARG
+    };
+    (@leaf ( [$arg:ident : **] )) => {
+        $crate::ledger::queries::router::SegmentBounds::REST
+    };
+    (@leaf ( [$arg:ident : storage_key] )) => {
+        $crate::ledger::queries::router::SegmentBounds::REST
+    };
+    (@leaf ( [$arg:ident : $left:ty $( | $right:ty )+] )) => {
+        $crate::ledger::queries::router::SegmentBounds::REST
+    };
+    (@leaf ( [$arg:ident : $arg_ty:ty] )) => {
+        $crate::ledger::queries::router::SegmentBounds::REST
+    };
+    (@leaf ( [$arg:ident : $arg_ty:ty where $_pred:expr] )) => {
+        $crate::ledger::queries::router::SegmentBounds::REST
+    };
+    (@leaf ( [$arg:ident : $arg_ty:ty, display = $_display:path] )) => {
+        $crate::ledger::queries::router::SegmentBounds::REST
+    };
+    (@leaf ( $expected:literal $( | $_alias:literal )+ $( / $tail:tt )* )) => {
+        $crate::ledger::queries::router::SegmentBounds::LITERAL
+            .then($crate::segment_bounds!(@leaf ( $( $tail )/ * )))
+    };
+    (@leaf ( $head:tt $( / $tail:tt )* )) => {
+        $crate::segment_bounds!(@pattern ( $head ))
+            .then($crate::segment_bounds!(@leaf ( $( $tail )/ * )))
+    };
+}
+
+/// Generate a function that tries to match the given pattern and `break`s if
+/// any of its parts are unmatched. This layer will check that there are some
+/// segments left to be matched and then invoke `try_match_segments` TT muncher
+/// that goes through the patterns.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! try_match {
+    (
+        $terminal:ident,
+        $ctx:ident,
+        $request:ident,
+        $segments:ident,
+        $query:ident,
+        $start:ident,
+        $failure:ident,
+        $attrs:tt,
+        $handle:tt,
+        $pattern:tt
+    ) => {
+        // Path is too short to match
+        if $start >= $segments.len() {
+            // println!("Path is too short");
+            $crate::ledger::queries::router::record_mismatch(
+                &mut $failure,
+                $start,
+            );
+            break;
+        }
+        $crate::try_match_segments!(
+            $terminal,
+            $ctx,
+            $request,
+            $segments,
+            $query,
+            $start,
+            $failure,
+            $attrs,
+            $handle,
+            (),
+            $pattern
+        );
+    };
+}
+
+/// Like `try_match`, but a pattern that is a single literal segment with a
+/// handler function is matched with a direct comparison of the segment and
+/// the handler is invoked without going through `try_match_segments`, as a
+/// fast path for the simplest routes (e.g. `( "a" ) -> String = a`). The
+/// `$terminal` is `handle_match` or `handle_match_async`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! try_match_fast {
+    // a single literal segment with a handler function
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $_query:ident, $start:ident, $failure:ident, $attrs:tt,
+        $handle:ident,
+        ( $expected:literal $( | $alias:literal )* )
+    ) => {
+        $crate::try_match_fast!(@literal $terminal, $ctx, $request, $segments,
+            $start, $attrs, $handle, $expected $( | $alias )*);
+    };
+
+    // a single literal segment with a handler function that uses request
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $_query:ident, $start:ident, $failure:ident, $attrs:tt,
+        (with_options $handle:ident),
+        ( $expected:literal $( | $alias:literal )* )
+    ) => {
+        $crate::try_match_fast!(@literal $terminal, $ctx, $request, $segments,
+            $start, $attrs, (with_options $handle), $expected $( | $alias )*);
+    };
+
+    // a single literal segment with a constant response
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $_query:ident, $start:ident, $failure:ident, $attrs:tt,
+        (const $value:ident),
+        ( $expected:literal $( | $alias:literal )* )
+    ) => {
+        $crate::try_match_fast!(@literal $terminal, $ctx, $request, $segments,
+            $start, $attrs, (const $value), $expected $( | $alias )*);
+    };
+
+    // any other pattern
+    (
+        $terminal:ident, $ctx:ident, $request:ident, $segments:ident,
+        $query:ident, $start:ident, $failure:ident, $attrs:tt, $handle:tt,
+        $pattern:tt
+    ) => {
+        $crate::try_match!($terminal, $ctx, $request, $segments, $query, $start,
+            $failure, $attrs, $handle, $pattern);
+    };
+
+    (
+        @literal $terminal:ident, $ctx:ident, $request:ident,
+        $segments:ident, $start:ident, $attrs:tt, $handle:tt,
+        $expected:literal $( | $alias:literal )*
+    ) => {
+        if !$crate::literal_matches!($segments.get($start).copied(),
+            $expected $( | $alias )*) {
+            // Try to skip to next pattern
+            break;
+        }
+        $start = $crate::ledger::queries::router::advance_segment(
+            $segments, $start);
+        $crate::$terminal!($ctx, $request, $segments, $start, $attrs, $handle, (),);
+    };
+}
+
+/// Turn a sub-router's prefix pattern into a method that constructs the
+/// sub-router at the prefix with the given `$separator`, where each typed arg
+/// is turned into a parameter for the method. For testing, another method
+/// constructs the sub-router at the prefix with the sample values of the args.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_to_prefix {
+    // terminal rule
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ()
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[doc = "`" $router "` sub-router"]
+            pub fn [<$router:camel:snake>](&self, $( $param: &$param_ty ),* )
+                -> [<$router:camel>] {
+                let path = $crate::ledger::queries::router::format_path(
+                    &self.prefix, self.separator, [ $(
+                        $crate::ledger::queries::router::display_segment(
+                            &$prefix) ),* ]);
+                [<$router:camel>]::sub_with_separator(path, $separator)
+            }
+
+            $crate::cfg_testing! {
+                #[allow(dead_code)]
+                #[doc = "`" $router "` sub-router with sample args."]
+                pub fn [<$router:camel:snake _sample>](&self) -> [<$router:camel>] {
+                    self.[<$router:camel:snake>]( $( std::borrow::Borrow::borrow(
+                        &<$param_ty as $crate::ledger::queries::router::SampleArg>::sample()
+                    ) ),* )
+                }
+            }
+        }
+    };
+
+    // literal string arg, constructed with its first form if it has aliases
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( $pattern:literal $( | $_alias:literal )* $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty ),* )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($pattern)) } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // an optional arg with a default value cannot be matched unambiguously in
+    // a prefix
+    (
+        $router:ident $separator:tt $params:tt $prefixes:tt
+        ( [$name:ident: opt $type:ty = $default:expr] $( / $tail:tt )* )
+    ) => {
+        compile_error!("sub-router cannot have an optional arg with a default \
+            value in its prefix");
+        // the sub-router's methods with the prefix so far, so that the error
+        // above is the only one
+        $crate::pattern_to_prefix!($router $separator $params $prefixes ());
+    };
+
+    // optional arg, which is omitted from the prefix when it's `None`
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( [$name:ident: opt $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty, )* $name: Option<$type> )
+            [ $( { $prefix }, )* { $name.as_ref() } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // a glob arg consumes the rest of the path, which is the sub-router's
+    (
+        $router:ident $separator:tt $params:tt $prefixes:tt
+        ( [$name:ident: **] $( / $tail:tt )* )
+    ) => {
+        compile_error!("sub-router cannot have a glob arg in its prefix");
+        $crate::pattern_to_prefix!($router $separator $params $prefixes ());
+    };
+
+    // a storage key arg consumes the rest of the path like a glob arg
+    (
+        $router:ident $separator:tt $params:tt $prefixes:tt
+        ( [$name:ident: storage_key] $( / $tail:tt )* )
+    ) => {
+        compile_error!(
+            "sub-router cannot have a storage key arg in its prefix");
+        $crate::pattern_to_prefix!($router $separator $params $prefixes ());
+    };
+
+    // untyped arg matched against a regex
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( [$name:ident: re $regex:literal] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty, )* $name: str )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($name)) } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // untyped arg matched against a run-time set
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( [$name:ident: oneof $set:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty, )* $name: str )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($name)) } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // base64url-encoded bytes arg
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( [$name:ident: b64] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty, )* $name: [u8] )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from(
+                $crate::ledger::queries::router::encode_b64_segment($name))) } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // typed arg converted with `TryFrom<&str>`, whose segment is formatted
+    // like a typed arg's
+    (
+        $router:ident $separator:tt
+        $params:tt
+        $prefixes:tt
+        ( [$name:ident: try $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            $params
+            $prefixes
+            ( [$name: $type] $( / $tail )* )
+        );
+    };
+
+    // integer arg in the declared radix
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( [$name:ident: $type:ident radix $radix:literal] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty, )* $name: $type )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from(
+                $crate::ledger::queries::router::RadixArg::format_radix(
+                    $name, $radix))) } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // typed arg with a custom `display` function, which formats its segment
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( [$name:ident: $type:ty, display = $display:path] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty, )* $name: $type )
+            [ $( { $prefix }, )* { std::option::Option::Some(
+                std::borrow::Cow::<str>::from($display($name))) } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // typed arg
+    (
+        $router:ident $separator:tt
+        ( $( $param:ident: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        ( [$name:ident: $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            ( $( $param: $param_ty, )* $name: $type )
+            [ $( { $prefix }, )* { std::option::Option::Some($name) } ]
+            ( $( $tail )/ * )
+        );
+    };
+
+    // union typed arg, which is a typed arg of its `Either` type
+    (
+        $router:ident $separator:tt
+        $params:tt
+        $prefixes:tt
+        ( [$name:ident: $left:ty $( | $right:ty )+] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            $params
+            $prefixes
+            ( [$name: $crate::union_arg_type!($left $( | $right )+)] $( / $tail )* )
+        );
+    };
+
+    // typed arg that is also bound as raw `&str`, which is not a parameter
+    (
+        $router:ident $separator:tt
+        $params:tt
+        $prefixes:tt
+        ( [$raw:ident = $name:ident: $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            $params
+            $prefixes
+            ( [$name: $type] $( / $tail )* )
+        );
+    };
+
+    // typed arg with a `where` predicate, which is only checked when matching
+    (
+        $router:ident $separator:tt
+        $params:tt
+        $prefixes:tt
+        ( [$name:ident: $type:ty where $_pred:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_to_prefix!(
+            $router $separator
+            $params
+            $prefixes
+            ( [$name: $type] $( / $tail )* )
+        );
+    };
+
+    (
+        $router:ident $separator:tt $params:tt $prefixes:tt
+        ( $head:tt $( / $tail:tt )* )
+    ) => {
+        compile_error!("sub-router prefix can only have literal and typed arg \
+            segments");
+        $crate::pattern_to_prefix!($router $separator $params $prefixes ());
+    };
+}
+
+/// Turn patterns and their handlers into methods for the router, where each
+/// dynamic pattern is turned into a parameter for the method.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_and_handler_to_method {
+    // Special terminal rule for `storage_value` handle from
+    // `shared/src/ledger/queries/shell.rs` that returns `Vec<u8>` which should
+    // not be decoded from response.data, but instead return as is
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $return_type:path,
+        (with_options storage_value),
+        ()
+    ) => {
+        // paste! used to construct the `fn $handle_path`'s name.
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[allow(dead_code)]
+            #[doc = "Get a path to query `storage_value`."]
+            pub fn storage_value_path(&self, $( $param: &$param_ty ),* ) -> String {
+                let path = $crate::ledger::queries::router::format_path(
+                    &self.prefix, self.separator, [ $(
+                        $crate::ledger::queries::router::display_segment(
+                            &$prefix) ),* ]);
+                $crate::debug_assert_path!(self, path, storage_value, ( $( $param ),* ));
+                path
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get a `curl` command to query `storage_value` via the \
+                    Tendermint RPC at the given `base_url`."]
+                pub fn storage_value_curl(&self, base_url: &str, $( $param: &$param_ty ),* ) -> String {
+                    $crate::ledger::queries::router::curl_command(
+                        base_url, &self.storage_value_path( $( $param ),* ))
+                }
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get the borsh schema of the response of `storage_value`, if \
+                    its type implements `BorshSchema`."]
+                pub fn storage_value_response_schema(&self)
+                    -> Option<$crate::ledger::queries::router::__private::borsh::schema::BorshSchemaContainer> {
+                    #[allow(unused_imports)]
+                    use $crate::ledger::queries::router::{
+                        NoResponseSchema, ResponseSchema,
+                    };
+                    (&$crate::ledger::queries::router::SchemaProbe::<$return_type>::new())
+                        .response_schema()
+                }
+            }
+
+            $crate::cfg_testing! {
+                #[allow(dead_code)]
+                #[doc = "Get a sample path to query `storage_value` with sample \
+                    args."]
+                pub fn storage_value_sample_path(&self) -> String {
+                    self.storage_value_path( $( std::borrow::Borrow::borrow(
+                        &<$param_ty as $crate::ledger::queries::router::SampleArg>::sample()
+                    ) ),* )
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request value with optional data (used for e.g. \
+                    `dry_run_tx`), optionally specified height (supported for \
+                    `storage_value`) and optional proof (supported for \
+                    `storage_value` and `storage_prefix`) from `storage_value`."]
+                pub async fn storage_value<CLIENT>(&self, client: &CLIENT,
+                    data: Option<Vec<u8>>,
+                    height: impl Into<$crate::ledger::queries::HeightSelector>,
+                    prove: bool,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $crate::ledger::queries::ResponseQuery<Vec<u8>>,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let path = self.storage_value_path( $( $param ),* );
+                        let response = client.raw_abci_query(path, data, height.into(),
+                            prove).await?;
+                        Ok($crate::ledger::queries::router::expand_proof(response))
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request value from `storage_value` at the given block \
+                    `height`, without any data or proof."]
+                pub async fn storage_value_at_height<CLIENT>(&self, client: &CLIENT,
+                    height: $crate::types::storage::BlockHeight,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        Vec<u8>,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let response = self.storage_value(
+                            client, None, Some(height), false, $( $param ),* ).await?;
+                        Ok(response.data)
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Like `storage_value`, but the failure is classified into                 a `QueryError`."]
+                pub async fn try_storage_value<CLIENT>(&self, client: &CLIENT,
+                    data: Option<Vec<u8>>,
+                    height: impl Into<$crate::ledger::queries::HeightSelector>,
+                    prove: bool,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $crate::ledger::queries::ResponseQuery<Vec<u8>>,
+                        $crate::ledger::queries::router::QueryError<
+                            <CLIENT as $crate::ledger::queries::Client>::Error>
+                    >
+                    where
+                        CLIENT: $crate::ledger::queries::Client + std::marker::Sync,
+                        <CLIENT as $crate::ledger::queries::Client>::Error:
+                            std::error::Error + 'static,
+                {
+                        let path = self.storage_value_path( $( $param ),* );
+                        let response = client.raw_abci_query(path, data, height.into(),
+                            prove).await.map_err(
+                                $crate::ledger::queries::router::QueryError::from_client_error)?;
+                        Ok($crate::ledger::queries::router::expand_proof(response))
+                }
+            }
+        }
+    };
+
+    // terminal rule for $handle that returns chunks (`stream`)
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $return_type:path,
+        (stream $handle:tt),
+        ()
+    ) => {
+        // paste! used to construct the `fn $handle_path`'s name.
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[allow(dead_code)]
+            #[doc = "Get a path to query `" $handle "`."]
+            pub fn [<$handle _path>](&self, $( $param: &$param_ty ),* ) -> String {
+                let path = $crate::ledger::queries::router::format_path(
+                    &self.prefix, self.separator, [ $(
+                        $crate::ledger::queries::router::display_segment(
+                            &$prefix) ),* ]);
+                $crate::debug_assert_path!(self, path, $handle, ( $( $param ),* ));
+                path
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get a `curl` command to query the first chunk of `"
+                    $handle "` via the Tendermint RPC at the given `base_url`."]
+                pub fn [<$handle _curl>](&self, base_url: &str, $( $param: &$param_ty ),* ) -> String {
+                    $crate::ledger::queries::router::curl_command(
+                        base_url, &self.[<$handle _path>]( $( $param ),* ))
+                }
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get the borsh schema of the response of `" $handle "`, \
+                    if its type implements `BorshSchema`."]
+                pub fn [<$handle _response_schema>](&self)
+                    -> Option<$crate::ledger::queries::router::__private::borsh::schema::BorshSchemaContainer> {
+                    #[allow(unused_imports)]
+                    use $crate::ledger::queries::router::{
+                        NoResponseSchema, ResponseSchema,
+                    };
+                    (&$crate::ledger::queries::router::SchemaProbe::<$return_type>::new())
+                        .response_schema()
+                }
+            }
+
+            $crate::cfg_testing! {
+                #[allow(dead_code)]
+                #[doc = "Get a sample path to query `" $handle "` with sample args."]
+                pub fn [<$handle _sample_path>](&self) -> String {
+                    self.[<$handle _path>]( $( std::borrow::Borrow::borrow(
+                        &<$param_ty as $crate::ledger::queries::router::SampleArg>::sample()
+                    ) ),* )
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request all the chunks of a borsh-encoded value from `"
+                    $handle "` and decode the value reassembled from them."]
+                pub async fn $handle<CLIENT>(&self, client: &CLIENT,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $return_type,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let data = self.[<$handle _stream>](client, $( $param ),* )
+                            .await?
+                            .reassemble()
+                            .await?;
+
+                        let decoded: $return_type =
+                            $crate::ledger::queries::router::__private::borsh::BorshDeserialize::try_from_slice(&data[..])?;
+                        Ok(decoded)
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Like `" $handle "`, but the failure is classified into a                 `QueryError`."]
+                pub async fn [<try_ $handle>]<CLIENT>(&self, client: &CLIENT,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $return_type,
+                        $crate::ledger::queries::router::QueryError<
+                            <CLIENT as $crate::ledger::queries::Client>::Error>
+                    >
+                    where
+                        CLIENT: $crate::ledger::queries::Client + std::marker::Sync,
+                        <CLIENT as $crate::ledger::queries::Client>::Error:
+                            std::error::Error + 'static,
+                {
+                        use $crate::ledger::queries::router::QueryError;
+                        let data = self.[<$handle _stream>](client, $( $param ),* )
+                            .await
+                            .map_err(QueryError::from_client_error)?
+                            .reassemble()
+                            .await
+                            .map_err(QueryError::from_client_error)?;
+
+                        $crate::ledger::queries::router::__private::borsh::BorshDeserialize::try_from_slice(&data[..])
+                            .map_err(QueryError::Decode)
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Get a stream of the chunks of a borsh-encoded value from `"
+                    $handle "`, which are requested one by one as they're \
+                    consumed."]
+                pub async fn [<$handle _stream>]<'client, CLIENT>(&self,
+                    client: &'client CLIENT,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $crate::ledger::queries::ResponseStream<'client, CLIENT>,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        client.check_health().await?;
+
+                        Ok($crate::ledger::queries::ResponseStream::new(client, path))
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Get a stream of the items of a borsh-encoded sequence \
+                    (e.g. a `Vec`) from `" $handle "`, which are decoded one by \
+                    one as the chunks are requested, without buffering the whole \
+                    value."]
+                pub fn [<$handle _iter>]<'client, CLIENT, ITEM>(&self,
+                    client: &'client CLIENT,
+                    $( $param: &$param_ty ),*
+                )
+                    -> impl $crate::ledger::queries::router::__private::futures::Stream<Item = std::result::Result<
+                        ITEM,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >> + 'client
+                    where
+                        CLIENT: $crate::ledger::queries::Client + std::marker::Sync,
+                        ITEM: $crate::ledger::queries::router::__private::borsh::BorshDeserialize + 'client,
+                        $return_type: $crate::ledger::queries::BorshSeq<ITEM>,
+                {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        $crate::ledger::queries::ResponseStream::with_health_check(
+                            client, path).items()
+                }
+            }
+        }
+    };
+
+    // terminal rule for a constant response (`const`), which has the same
+    // methods as a $handle that doesn't use request, named after the constant
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $return_type:path,
+        (const $value:ident),
+        ()
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::pattern_and_handler_to_method!(
+                ( $( $param: $param_ty ),* )
+                [ $( { $prefix } ),* ]
+                $return_type,
+                [<$value:lower>],
+                ()
+            );
+        }
+    };
+
+    // terminal rule for $handle that uses request (`with_options`)
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $return_type:path,
+        (with_options $handle:tt),
+        ()
+    ) => {
+        // paste! used to construct the `fn $handle_path`'s name.
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[allow(dead_code)]
+            #[doc = "Get a path to query `" $handle "`."]
+            pub fn [<$handle _path>](&self, $( $param: &$param_ty ),* ) -> String {
+                let path = $crate::ledger::queries::router::format_path(
+                    &self.prefix, self.separator, [ $(
+                        $crate::ledger::queries::router::display_segment(
+                            &$prefix) ),* ]);
+                $crate::debug_assert_path!(self, path, $handle, ( $( $param ),* ));
+                path
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get a `curl` command to query `" $handle "` via the \
+                    Tendermint RPC at the given `base_url`."]
+                pub fn [<$handle _curl>](&self, base_url: &str, $( $param: &$param_ty ),* ) -> String {
+                    $crate::ledger::queries::router::curl_command(
+                        base_url, &self.[<$handle _path>]( $( $param ),* ))
+                }
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get the borsh schema of the response of `" $handle "`, \
+                    if its type implements `BorshSchema`."]
+                pub fn [<$handle _response_schema>](&self)
+                    -> Option<$crate::ledger::queries::router::__private::borsh::schema::BorshSchemaContainer> {
+                    #[allow(unused_imports)]
+                    use $crate::ledger::queries::router::{
+                        NoResponseSchema, ResponseSchema,
+                    };
+                    (&$crate::ledger::queries::router::SchemaProbe::<$return_type>::new())
+                        .response_schema()
+                }
+            }
+
+            $crate::cfg_testing! {
+                #[allow(dead_code)]
+                #[doc = "Get a sample path to query `" $handle "` with sample args."]
+                pub fn [<$handle _sample_path>](&self) -> String {
+                    self.[<$handle _path>]( $( std::borrow::Borrow::borrow(
+                        &<$param_ty as $crate::ledger::queries::router::SampleArg>::sample()
+                    ) ),* )
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request value with optional data (used for e.g. \
+                    `dry_run_tx`), optionally specified height (supported for \
+                    `storage_value`) and optional proof (supported for \
+                    `storage_value` and `storage_prefix`) from `" $handle "`. \
+                    The `height` is a `HeightSelector`, or an optional height, \
+                    where `None` selects the latest block."]
+                pub async fn $handle<CLIENT>(&self, client: &CLIENT,
+                    data: Option<Vec<u8>>,
+                    height: impl Into<$crate::ledger::queries::HeightSelector>,
+                    prove: bool,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $crate::ledger::queries::ResponseQuery<$return_type>,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        $crate::ledger::queries::router::request_with_options::<
+                            CLIENT, $return_type>(client, path, data, height.into(), prove)
+                            .await
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Like `" $handle "`, but the failure is classified into a                 `QueryError`."]
+                pub async fn [<try_ $handle>]<CLIENT>(&self, client: &CLIENT,
+                    data: Option<Vec<u8>>,
+                    height: impl Into<$crate::ledger::queries::HeightSelector>,
+                    prove: bool,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $crate::ledger::queries::ResponseQuery<$return_type>,
+                        $crate::ledger::queries::router::QueryError<
+                            <CLIENT as $crate::ledger::queries::Client>::Error>
+                    >
+                    where
+                        CLIENT: $crate::ledger::queries::Client + std::marker::Sync,
+                        <CLIENT as $crate::ledger::queries::Client>::Error:
+                            std::error::Error + 'static,
+                {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        $crate::ledger::queries::router::try_request_with_options::<
+                            CLIENT, $return_type>(client, path, data, height.into(), prove)
+                            .await
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request value with optional data and optionally \
+                    specified height from `" $handle "` together with the proofs \
+                    of all the keys that the handler proves."]
+                pub async fn [<$handle _with_proofs>]<CLIENT>(&self, client: &CLIENT,
+                    data: Option<Vec<u8>>,
+                    height: impl Into<$crate::ledger::queries::HeightSelector>,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        ($return_type, Vec<$crate::ledger::queries::KeyedProof>),
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let response =
+                            self.$handle(client, data, height, true, $( $param ),* ).await?;
+                        Ok((response.data, response.proofs))
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request the values found under a storage prefix from `"
+                    $handle "` like `" $handle "`, and iterate them as typed \
+                    `(storage::Key, V)` pairs, whose values are decoded only when \
+                    they're reached (see `PrefixIter`)."]
+                pub async fn [<$handle _pairs>]<CLIENT, V>(&self, client: &CLIENT,
+                    data: Option<Vec<u8>>,
+                    height: impl Into<$crate::ledger::queries::HeightSelector>,
+                    prove: bool,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $crate::ledger::queries::ResponseQuery<
+                            $crate::ledger::queries::PrefixIter<V>>,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where
+                        CLIENT: $crate::ledger::queries::Client + std::marker::Sync,
+                        $crate::ledger::queries::PrefixIter<V>: From<$return_type>,
+                {
+                        let response =
+                            self.$handle(client, data, height, prove, $( $param ),* ).await?;
+                        Ok(response.map(From::from))
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request value from `" $handle "` at the given block \
+                    `height`, without any data or proof."]
+                pub async fn [<$handle _at_height>]<CLIENT>(&self, client: &CLIENT,
+                    height: $crate::types::storage::BlockHeight,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $return_type,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let response =
+                            self.$handle(client, None, Some(height), false, $( $param ),* ).await?;
+                        Ok(response.data)
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request the raw response of `" $handle "` with optional \
+                    data, optionally specified height and optional proof, \
+                    without decoding its borsh-encoded data."]
+                pub async fn [<$handle _raw>]<CLIENT>(&self, client: &CLIENT,
+                    data: Option<Vec<u8>>,
+                    height: impl Into<$crate::ledger::queries::HeightSelector>,
+                    prove: bool,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $crate::ledger::queries::EncodedResponseQuery,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        client.raw_abci_query(path, data, height.into(), prove).await
+                }
+            }
+        }
+    };
+
+    // terminal rule that $handle that doesn't use request
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $return_type:path,
+        $handle:tt,
+        ()
+    ) => {
+        // paste! used to construct the `fn $handle_path`'s name.
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[allow(dead_code)]
+            #[doc = "Get a path to query `" $handle "`."]
+            pub fn [<$handle _path>](&self, $( $param: &$param_ty ),* ) -> String {
+                let path = $crate::ledger::queries::router::format_path(
+                    &self.prefix, self.separator, [ $(
+                        $crate::ledger::queries::router::display_segment(
+                            &$prefix) ),* ]);
+                $crate::debug_assert_path!(self, path, $handle, ( $( $param ),* ));
+                path
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get a `curl` command to query `" $handle "` via the \
+                    Tendermint RPC at the given `base_url`."]
+                pub fn [<$handle _curl>](&self, base_url: &str, $( $param: &$param_ty ),* ) -> String {
+                    $crate::ledger::queries::router::curl_command(
+                        base_url, &self.[<$handle _path>]( $( $param ),* ))
+                }
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Get the borsh schema of the response of `" $handle "`, \
+                    if its type implements `BorshSchema`."]
+                pub fn [<$handle _response_schema>](&self)
+                    -> Option<$crate::ledger::queries::router::__private::borsh::schema::BorshSchemaContainer> {
+                    #[allow(unused_imports)]
+                    use $crate::ledger::queries::router::{
+                        NoResponseSchema, ResponseSchema,
+                    };
+                    (&$crate::ledger::queries::router::SchemaProbe::<$return_type>::new())
+                        .response_schema()
+                }
+            }
+
+            $crate::cfg_testing! {
+                #[allow(dead_code)]
+                #[doc = "Get a sample path to query `" $handle "` with sample args."]
+                pub fn [<$handle _sample_path>](&self) -> String {
+                    self.[<$handle _path>]( $( std::borrow::Borrow::borrow(
+                        &<$param_ty as $crate::ledger::queries::router::SampleArg>::sample()
+                    ) ),* )
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request a simple borsh-encoded value from `" $handle "`, \
+                    without any additional request data, specified block height or \
+                    proof."]
+                pub async fn $handle<CLIENT>(&self, client: &CLIENT,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $return_type,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        $crate::ledger::queries::router::request_simple::<
+                            CLIENT, $return_type>(client, path).await
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Like `" $handle "`, but the failure is classified into a                 `QueryError`."]
+                pub async fn [<try_ $handle>]<CLIENT>(&self, client: &CLIENT,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        $return_type,
+                        $crate::ledger::queries::router::QueryError<
+                            <CLIENT as $crate::ledger::queries::Client>::Error>
+                    >
+                    where
+                        CLIENT: $crate::ledger::queries::Client + std::marker::Sync,
+                        <CLIENT as $crate::ledger::queries::Client>::Error:
+                            std::error::Error + 'static,
+                {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        $crate::ledger::queries::router::try_request_simple::<
+                            CLIENT, $return_type>(client, path).await
+                }
+            }
+
+            $crate::cfg_async_client! {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                #[doc = "Request the raw borsh-encoded bytes of a value from `" $handle "`, \
+                    without decoding them, and without any additional request \
+                    data, specified block height or proof."]
+                pub async fn [<$handle _raw>]<CLIENT>(&self, client: &CLIENT,
+                    $( $param: &$param_ty ),*
+                )
+                    -> std::result::Result<
+                        Vec<u8>,
+                        <CLIENT as $crate::ledger::queries::Client>::Error
+                    >
+                    where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                        let path = self.[<$handle _path>]( $( $param ),* );
+                        $crate::ledger::queries::router::request_simple_raw(
+                            client, path).await
+                }
+            }
+        }
+    };
+
+    // sub-pattern
+    (
+        $param:tt
+        $prefix:tt
+        $( $_return_type:path )?,
+        {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $sub_return_ty:path )? = $handle:tt,
+            )*
+        },
+        $pattern:tt
+    ) => {
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                // join pattern with each sub-pattern
+                $crate::pattern_and_handler_to_method!(
+                    $param
+                    $prefix
+                    $( $sub_return_ty )?, $handle, $pattern, ( $( $sub_pattern )* )
+                );
+            }
+        )*
+    };
+
+    // literal string arg, constructed with its first form if it has aliases
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( $pattern:literal $( | $_alias:literal )* $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty ),* )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($pattern)) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // untyped arg
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: str )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($name)) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // untyped arg matched against a regex
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: re $regex:literal] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: str )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($name)) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // untyped arg matched against a run-time set
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: oneof $set:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: str )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from($name)) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // base64url-encoded bytes arg
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: b64] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: [u8] )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from(
+                $crate::ledger::queries::router::encode_b64_segment($name))) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // typed arg converted with `TryFrom<&str>`, whose segment is formatted
+    // like a typed arg's
+    (
+        $params:tt
+        $prefixes:tt
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: try $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            $params
+            $prefixes
+            $( $return_type )?, $handle, ( [$name: $type] $( / $tail )* )
+        );
+    };
+
+    // integer arg in the declared radix
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: $type:ident radix $radix:literal] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: $type )
+            [ $( { $prefix }, )* { std::option::Option::Some(std::borrow::Cow::from(
+                $crate::ledger::queries::router::RadixArg::format_radix(
+                    $name, $radix))) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // glob arg, which is omitted from the path when it's empty
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: **] )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: str )
+            [ $( { $prefix }, )* {
+                (!$name.is_empty()).then(|| std::borrow::Cow::from($name))
+            } ]
+            $( $return_type )?, $handle, ()
+        );
+    };
+
+    // storage key arg, which is a typed arg of `storage::Key`
+    (
+        $params:tt
+        $prefixes:tt
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: storage_key] )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            $params
+            $prefixes
+            $( $return_type )?, $handle,
+            ( [$name: $crate::types::storage::Key] )
+        );
+    };
+
+    // typed arg with a custom `display` function, which formats its segment
+    // so that it's parsed back by the arg's `FromStr`
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: $type:ty, display = $display:path] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: $type )
+            [ $( { $prefix }, )* { std::option::Option::Some(
+                std::borrow::Cow::<str>::from($display($name))) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // typed arg
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: $type )
+            [ $( { $prefix }, )* { std::option::Option::Some($name) } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // union typed arg, which is a typed arg of its `Either` type
+    (
+        $params:tt
+        $prefixes:tt
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: $left:ty $( | $right:ty )+] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            $params
+            $prefixes
+            $( $return_type )?, $handle,
+            ( [$name: $crate::union_arg_type!($left $( | $right )+)] $( / $tail )* )
+        );
+    };
+
+    // typed arg that is also bound as raw `&str`, which is not a parameter,
+    // because it's the same segment as the typed arg
+    (
+        $params:tt
+        $prefixes:tt
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$raw:tt = $name:tt: $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            $params
+            $prefixes
+            $( $return_type )?, $handle, ( [$name: $type] $( / $tail )* )
+        );
+    };
+
+    // typed arg with a `where` predicate, which is only checked when matching
+    (
+        $params:tt
+        $prefixes:tt
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: $type:ty where $_pred:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            $params
+            $prefixes
+            $( $return_type )?, $handle, ( [$name: $type] $( / $tail )* )
+        );
+    };
+
+    // opt typed arg with a default value, which is omitted from the path
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: opt $type:ty = $default:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: $type )
+            [ $( { $prefix }, )* {
+                {
+                    let default: $type = $default;
+                    (*$name != default).then_some($name)
+                }
+            } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // opt typed arg
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( [$name:tt: opt $type:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty, )* $name: std::option::Option<$type> )
+            [ $( { $prefix }, )* { $name.as_ref() } ]
+            $( $return_type )?, $handle, ( $( $tail )/ * )
+        );
+    };
+
+    // join pattern with sub-pattern, without the literals' aliases that are
+    // only matched when handling a path
+    (
+        ( $( $param:tt: $param_ty:ty ),* )
+        [ $( { $prefix:expr } ),* ]
+        $( $return_type:path )?,
+        $handle:tt,
+        ( $( $pattern:tt $( | $_alias:literal )* )/ * ),
+        ( $( $sub_pattern:tt $( | $_sub_alias:literal )* )/ * )
+    ) => {
+        $crate::pattern_and_handler_to_method!(
+            ( $( $param: $param_ty ),* )
+            [ $( { $prefix }, )* ]
+            $( $return_type )?,
+            $handle, ( $( $pattern / )* $( $sub_pattern )/ * )
+        );
+    };
+}
+
+/// TT muncher macro that collects the names of the dynamic args of a pattern
+/// with the types in which they're returned by the `*_validate_path` methods
+/// and passed to the handlers in their params struct, i.e. `String` for an
+/// untyped or a glob arg (which is matched as `&str`), `Vec<u8>` for a
+/// base64url-encoded bytes arg, `Option<T>` for an opt arg without a default
+/// value, an `Either` for a union arg and the arg's type otherwise. Then it
+/// invokes the `$callback` macro with the given `$args` followed by
+/// `( $( $name: $type ),* )`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_arg_types {
+    // terminal rule
+    ( $callback:ident $args:tt $fields:tt () ) => {
+        $crate::$callback!{ $args $fields }
+    };
+
+    // literal string arg
+    (
+        $callback:ident $args:tt $fields:tt
+        ( $expected:literal $( | $_alias:literal )* $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args $fields ( $( $tail )/ * ));
+    };
+
+    // untyped args, which are bound as `&str`
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident $( : re $regex:literal )?] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: String ) ( $( $tail )/ * ));
+    };
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: oneof $set:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: String ) ( $( $tail )/ * ));
+    };
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: **] )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: String ) ());
+    };
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: storage_key] )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: $crate::types::storage::Key ) ());
+    };
+
+    // base64url-encoded bytes arg
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: b64] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: Vec<u8> ) ( $( $tail )/ * ));
+    };
+
+    // typed arg converted with `TryFrom<&str>`
+    (
+        $callback:ident $args:tt $fields:tt
+        ( [$name:ident: try $arg_ty:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args $fields
+            ( [$name: $arg_ty] $( / $tail )* ));
+    };
+
+    // integer arg in the declared radix
+    (
+        $callback:ident $args:tt $fields:tt
+        ( [$name:ident: $arg_ty:ident radix $radix:literal] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args $fields
+            ( [$name: $arg_ty] $( / $tail )* ));
+    };
+
+    // opt typed arg with a default value
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: opt $arg_ty:ty = $default:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: $arg_ty ) ( $( $tail )/ * ));
+    };
+
+    // opt typed arg
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: opt $arg_ty:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: Option<$arg_ty> )
+            ( $( $tail )/ * ));
+    };
+
+    // typed arg that is also bound as raw `&str`
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$raw:ident = $name:ident: $arg_ty:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $raw: String, $name: $arg_ty )
+            ( $( $tail )/ * ));
+    };
+
+    // typed arg with a `where` predicate
+    (
+        $callback:ident $args:tt $fields:tt
+        ( [$name:ident: $arg_ty:ty where $_pred:expr] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args $fields
+            ( [$name: $arg_ty] $( / $tail )* ));
+    };
+
+    // typed arg with a custom `display` function
+    (
+        $callback:ident $args:tt $fields:tt
+        ( [$name:ident: $arg_ty:ty, display = $_display:path] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args $fields
+            ( [$name: $arg_ty] $( / $tail )* ));
+    };
+
+    // union typed arg
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: $left:ty $( | $right:ty )+] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )*
+                $name: $crate::union_arg_type!($left $( | $right )+) )
+            ( $( $tail )/ * ));
+    };
+
+    // typed arg
+    (
+        $callback:ident $args:tt ( $( $field:ident: $type:ty ),* )
+        ( [$name:ident: $arg_ty:ty] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_arg_types!($callback $args
+            ( $( $field: $type, )* $name: $arg_ty ) ( $( $tail )/ * ));
+    };
+}
+
+/// Turn a pattern and its handler into a method that parses the handler's args
+/// from a path, without handling it. The types of the args are collected
+/// first with `pattern_arg_types`, then the pattern is matched as a whole with
+/// `try_match`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_to_validator {
+    // the args' types are collected, named after the handler
+    (
+        (@typed $attrs:tt (with_options $handle:ident) $pattern:tt)
+        $fields:tt
+    ) => {
+        $crate::pattern_to_validator!(@method $handle $fields $attrs
+            (with_options $handle) $pattern);
+    };
+    ( (@typed $attrs:tt (stream $handle:ident) $pattern:tt) $fields:tt ) => {
+        $crate::pattern_to_validator!(@method $handle $fields $attrs
+            (stream $handle) $pattern);
+    };
+    ( (@typed $attrs:tt (const $value:ident) $pattern:tt) $fields:tt ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::pattern_to_validator!(@method [<$value:lower>] $fields $attrs
+                (const $value) $pattern);
+        }
+    };
+    ( (@typed $attrs:tt $handle:ident $pattern:tt) $fields:tt ) => {
+        $crate::pattern_to_validator!(@method $handle $fields $attrs $handle $pattern);
+    };
+
+    // join pattern with sub-pattern, without the literals' aliases that are
+    // only matched when handling a path
+    (
+        $attrs:tt $handle:tt ( $( $pattern:tt $( | $_alias:literal )* )/ * ),
+        ( $( $sub_pattern:tt $( | $_sub_alias:literal )* )/ * )
+    ) => {
+        $crate::pattern_to_validator!($attrs $handle
+            ( $( $pattern / )* $( $sub_pattern )/ * ));
+    };
+
+    // nested sub-pattern - add a method for each handle inside it
+    (
+        $_attrs:tt {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $handle:tt,
+            )*
+        }
+        $pattern:tt
+    ) => {
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                $crate::pattern_to_validator!( [ $( #[ $( $attr )* ] )* ] $handle
+                    $pattern, ( $( $sub_pattern )* ) );
+            }
+        )*
+    };
+
+    ( $attrs:tt $handle:tt $pattern:tt ) => {
+        $crate::pattern_arg_types!(pattern_to_validator
+            (@typed $attrs $handle $pattern) () $pattern);
+    };
+
+    (
+        @method $name:ident ( $( $_field:ident: $type:ty ),* ) $attrs:tt
+        $handle:tt $pattern:tt
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[allow(dead_code)]
+            #[doc = "Parse the args of `" $name "` from a path as \
+                constructed by `" $name "_path`, without handling it. The \
+                args are returned in the order in which the handler receives \
+                them. Like `Router::handle`, this fails if the path doesn't \
+                match the route or if an arg cannot be parsed."]
+            pub fn [<$name _validate_path>](&self, path: &str)
+                -> std::result::Result<( $( $type, )* ),
+                    $crate::ledger::queries::router::Error> {
+                let path = $crate::ledger::queries::router::normalize_path(path);
+                let wrong_path = ||
+                    $crate::ledger::queries::router::Error::WrongPath(
+                        path.to_string());
+                #[allow(unused_variables)]
+                let (path_only, query) =
+                    $crate::ledger::queries::router::split_query(&path);
+                let (path_only, _encoding) =
+                    $crate::ledger::queries::router::split_encoding_suffix(
+                        path_only);
+                let (path_only, _proof_format) =
+                    $crate::ledger::queries::router::split_proof_format(
+                        path_only);
+                let segments = $crate::ledger::queries::router::split_router_path(
+                    path_only, &self.prefix, self.separator)
+                    .ok_or_else(wrong_path)?;
+                let segments = segments.as_slice();
+                // Skip the optional version segment of a versioned router
+                let start = match $crate::ledger::queries::router::match_version(
+                    self.versioned, segments, 0) {
+                    Some(_) => $crate::ledger::queries::router::advance_segment(
+                        segments, 0),
+                    None => 0,
+                };
+                #[allow(unused_mut)]
+                let mut failure: Option<$crate::ledger::queries::router::Error> = None;
+                // This loop never repeats, it's only used for a breaking
+                // mechanism when the pattern is not matched. The `_ctx` and
+                // `_request` are never bound, because they're not used when
+                // only parsing the args.
+                loop {
+                    #[allow(unused_mut)]
+                    let mut start = start;
+                    $crate::try_match!(validated_args, _ctx, _request, segments, query,
+                        start, failure, $attrs, $handle, $pattern);
+                }
+                Err(failure.unwrap_or_else(wrong_path))
+            }
+        }
+    };
+}
+
+/// The terminal of `try_match_segments` for `pattern_to_validator`, which
+/// returns the matched args if the whole path is matched.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! validated_args {
+    (
+        $ctx:ident, $request:ident, $segments:ident, $start:ident, $attrs:tt,
+        $handle:tt, ( $( $matched_args:ident, )* ),
+    ) => {
+        // check that we're at the end of the path - trailing slash is optional,
+        // unless disabled for the route
+        if !$crate::ledger::queries::router::is_path_end(
+            $segments.get($start..).unwrap_or_default(),
+            $crate::route_trailing_slash!($attrs),
+        ) {
+            // we're not at the end, no match
+            break
+        }
+        // An `Optional` arg of a route with `#[presence]` is returned as an
+        // `Option`, like the arg of the generated methods
+        return Ok(( $( $matched_args.to_owned().into(), )* ));
+    };
+}
+
+/// Generate the params struct of a route with the `#[params]` attribute, whose
+/// handler receives its args in the struct instead of one by one. The struct
+/// is named after the handler (e.g. `B3iiiiParams` for `b3iiii`) and it has a
+/// field for each arg of the route's pattern, including its parent patterns,
+/// with the types collected by `pattern_arg_types`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_to_params {
+    // the args' types are collected, named after the handler
+    ( (@struct (with_options $handle:ident)) $fields:tt ) => {
+        $crate::pattern_to_params!(@define $handle $fields);
+    };
+    ( (@struct (stream $handle:ident)) $fields:tt ) => {
+        $crate::pattern_to_params!(@define $handle $fields);
+    };
+    // rejected by `check_const_attrs`
+    ( (@struct (const $value:ident)) $fields:tt ) => {};
+    ( (@struct $handle:ident) $fields:tt ) => {
+        $crate::pattern_to_params!(@define $handle $fields);
+    };
+
+    (@define $handle:ident ( $( $field:ident: $type:ty ),* )) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[doc = "The args of the `" $handle "` handler, which it \
+                receives in this struct because its route has the \
+                `#[params]` attribute."]
+            #[derive(Clone, Debug)]
+            pub struct [<$handle:camel Params>] {
+                $(
+                    #[doc = "The `" $field "` arg"]
+                    pub $field: $type,
+                )*
+            }
+        }
+    };
+
+    // find the `#[params]` attribute
+    (@check [] $handle:tt $pattern:tt) => {};
+    (@check [ #[params] $( $rest:tt )* ] $handle:tt $pattern:tt) => {
+        $crate::pattern_arg_types!(pattern_to_params (@struct $handle) () $pattern);
+    };
+    (
+        @check [ #[ $( $_attr:tt )* ] $( $rest:tt )* ] $handle:tt
+        $pattern:tt
+    ) => {
+        $crate::pattern_to_params!(@check [ $( $rest )* ] $handle $pattern);
+    };
+
+    // join pattern with sub-pattern, without the literals' aliases that are
+    // only matched when handling a path
+    (
+        $attrs:tt $handle:tt ( $( $pattern:tt $( | $_alias:literal )* )/ * ),
+        ( $( $sub_pattern:tt $( | $_sub_alias:literal )* )/ * )
+    ) => {
+        $crate::pattern_to_params!($attrs $handle
+            ( $( $pattern / )* $( $sub_pattern )/ * ));
+    };
+
+    // nested sub-pattern - add a struct for each handle inside it
+    (
+        $_attrs:tt {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $handle:tt,
+            )*
+        }
+        $pattern:tt
+    ) => {
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                $crate::pattern_to_params!( [ $( #[ $( $attr )* ] )* ] $handle
+                    $pattern, ( $( $sub_pattern )* ) );
+            }
+        )*
+    };
+
+    // a sub-router has no handler
+    ( $_attrs:tt (sub $router:ident) $pattern:tt ) => {};
+
+    ( $attrs:tt $handle:tt $pattern:tt ) => {
+        $crate::pattern_to_params!(@check $attrs $handle $pattern);
+    };
+}
+
+/// Generate a constant with the path of a route that has only literal
+/// segments, which is known at compile-time. It's named after the handler
+/// (e.g. `A_PATH` for `a`) and it's the path of the route in the router as a
+/// root router, unlike the `*_path` method that also works for a sub-router.
+/// No constant is generated for a route with args.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! route_static_path {
+    // join pattern with sub-pattern, without the literals' aliases that are
+    // only matched when handling a path
+    (
+        $handle:tt ( $( $pattern:tt $( | $_alias:literal )* )/ * ),
+        ( $( $sub_pattern:tt $( | $_sub_alias:literal )* )/ * )
+    ) => {
+        $crate::route_static_path!($handle ( $( $pattern / )* $( $sub_pattern )/ * ));
+    };
+
+    // nested sub-pattern - add a constant for each handle inside it
+    (
+        {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $handle:tt,
+            )*
+        }
+        $pattern:tt
+    ) => {
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                $crate::route_static_path!( $handle $pattern, ( $( $sub_pattern )* ) );
+            }
+        )*
+    };
+
+    // a sub-router has no handler
+    ( (sub $router:ident) $pattern:tt ) => {};
+
+    // a route with only literal segments
+    ( (with_options $handle:ident) ( $( $literal:literal )/ * ) ) => {
+        $crate::route_static_path!(@const $handle ( $( $literal )/ * ));
+    };
+    ( (stream $handle:ident) ( $( $literal:literal )/ * ) ) => {
+        $crate::route_static_path!(@const $handle ( $( $literal )/ * ));
+    };
+    ( (const $value:ident) ( $( $literal:literal )/ * ) ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::route_static_path!(@const [<$value:lower>] ( $( $literal )/ * ));
+        }
+    };
+    ( $handle:ident ( $( $literal:literal $( | $_alias:literal )* )/ * ) ) => {
+        $crate::route_static_path!(@const $handle ( $( $literal )/ * ));
+    };
+
+    // a route with args
+    ( $handle:tt $pattern:tt ) => {};
+
+    (@const $handle:ident $pattern:tt) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[allow(dead_code)]
+            #[doc = "The path to query `" $handle "` with this router as a \
+                root router, which is a constant because its route has no \
+                args. It's equal to the path from `" $handle "_path` \
+                without allocating it."]
+            pub const [<$handle:upper _PATH>]: &str = $crate::pattern_template!($pattern);
+        }
+    };
+}
+
+/// TT muncher macro that generates a `struct $name` with methods for all its
+/// handlers.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! router_type {
+    // terminal rule
+    ($name:ident { $( $methods:item )* }, ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[doc = "`" $name "`path router type"]
+            pub struct $name {
+                prefix: String,
+                versioned: bool,
+                separator: char,
+                meta_routes: bool,
+            }
+
+            impl $name {
+                #[doc = "Construct this router as a root router"]
+                pub const fn new() -> Self {
+                    Self {
+                        prefix: String::new(),
+                        versioned: false,
+                        separator: '/',
+                        meta_routes: false,
+                    }
+                }
+
+                #[allow(dead_code)]
+                #[doc = "Construct this router as a sub-router at the given prefix path"]
+                pub const fn sub(prefix: String) -> Self {
+                    Self::sub_with_separator(prefix, '/')
+                }
+
+                #[allow(dead_code)]
+                #[doc = "Construct this router as a sub-router at the given \
+                    prefix path, in which the segments of the paths under \
+                    the prefix are separated by the given `separator`"]
+                pub const fn sub_with_separator(prefix: String, separator: char)
+                    -> Self {
+                    Self {
+                        prefix,
+                        versioned: false,
+                        separator,
+                        meta_routes: false,
+                    }
+                }
+
+                #[allow(dead_code)]
+                #[doc = "Construct this router as a root router that \
+                    accepts an optional leading version segment in its paths, \
+                    e.g. both `/a` and `/v2/a` are matched like `/a`. The \
+                    version is available to the handlers in the \
+                    `RequestCtx::version`. A segment that can be parsed as a \
+                    `Version` is always matched as the version, so the \
+                    router's patterns shouldn't start with one."]
+                pub const fn new_versioned() -> Self {
+                    Self {
+                        prefix: String::new(),
+                        versioned: true,
+                        separator: '/',
+                        meta_routes: false,
+                    }
+                }
+
+                #[allow(dead_code)]
+                #[doc = "Construct this router as a root router that also \
+                    serves its meta-routes, i.e. the `/_routes` route that \
+                    returns the router's `routes` as borsh-encoded \
+                    `Vec<RouteDescriptor>`, so that a client can discover the \
+                    route table (see `router::request_routes`). The first \
+                    segments of the router's patterns starting with `_` are \
+                    reserved for the meta-routes."]
+                pub const fn new_with_meta_routes() -> Self {
+                    Self {
+                        prefix: String::new(),
+                        versioned: false,
+                        separator: '/',
+                        meta_routes: true,
+                    }
+                }
+
+                $crate::cfg_async_client! {
+                    #[allow(dead_code)]
+                    #[doc = "Send an arbitrary query at the given `path` under \
+                        this router's prefix (e.g. `/a` for a root router), \
+                        without decoding the response's borsh-encoded data. This \
+                        is an escape hatch for the routes that aren't modelled \
+                        by the router's patterns."]
+                    pub async fn raw<CLIENT>(&self, client: &CLIENT,
+                        path: &str,
+                        data: Option<Vec<u8>>,
+                        height: impl Into<$crate::ledger::queries::HeightSelector>,
+                        prove: bool,
+                    )
+                        -> std::result::Result<
+                            $crate::ledger::queries::EncodedResponseQuery,
+                            <CLIENT as $crate::ledger::queries::Client>::Error
+                        >
+                        where CLIENT: $crate::ledger::queries::Client + std::marker::Sync {
+                            let path = format!("{}{}", self.prefix, path);
+                            client.raw_abci_query(path, data, height.into(), prove).await
+                    }
+                }
+
+                // paste the generated methods
+                $( $methods )*
+            }
+        }
+    };
+
+    // a sub router - recursion
+    (
+        $name:ident { $( $methods:item )* },
+        $attrs:tt $pattern:tt = (sub $router:ident)
+        $( ,$tail_attrs:tt $tail_pattern:tt $( -> $tail_return_type:path )? = $tail:tt )*
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::router_type!{
+                $name {
+                    $crate::route_cfg!{ $attrs
+                        $crate::pattern_to_prefix!($router ($crate::route_separator!($attrs))
+                            () [] $pattern);
+                    }
+                    $( $methods )*
+                },
+                $( $tail_attrs $tail_pattern $( -> $tail_return_type )? = $tail ),*
+            }
+        }
+    };
+
+    // a sub-pattern - add a method for each handle inside it
+    (
+        $name:ident
+        { $( $methods:item )* },
+        $attrs:tt $pattern:tt = {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $sub_return_ty:path )? = $handle:tt,
+            )*
+        }
+        $( ,$tail_attrs:tt $tail_pattern:tt $( -> $tail_return_type:path )? = $tail:tt )*
+    ) => {
+        $crate::router_type!{
+            $name {
+                $crate::route_cfg!{ $attrs
+                    $(
+                        $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                            // join pattern with each sub-pattern
+                            $crate::pattern_and_handler_to_method!( () [] $( $sub_return_ty )?,
+                                $handle, $pattern, ( $( $sub_pattern )* )
+                            );
+                            $crate::pattern_to_validator!( [ $( #[ $( $attr )* ] )* ]
+                                $handle $pattern, ( $( $sub_pattern )* )
+                            );
+                            $crate::route_static_path!( $handle $pattern,
+                                ( $( $sub_pattern )* ) );
+                        }
+                    )*
+                }
+                $( $methods )*
+            },
+            $( $tail_attrs $tail_pattern $( -> $tail_return_type )? = $tail ),*
+        }
+    };
+
+    // pattern with a handle - add a method for the handle
+    (
+        $name:ident
+        { $( $methods:item )* },
+        $attrs:tt $pattern:tt -> $return_type:path = $handle:tt
+        $( ,$tail_attrs:tt $tail_pattern:tt $( -> $tail_return_type:path )? = $tail:tt )*
+    ) => {
+        $crate::router_type!{
+            $name {
+                $crate::route_cfg!{ $attrs
+                    $crate::pattern_and_handler_to_method!( () [] $return_type, $handle, $pattern );
+                    $crate::pattern_to_validator!( $attrs $handle $pattern );
+                    $crate::route_static_path!( $handle $pattern );
+                }
+                $( $methods )*
+            },
+            $( $tail_attrs $tail_pattern $( -> $tail_return_type )? = $tail ),*
+        }
+    };
+}
+
+/// TT muncher macro that collects all the handlers and sub-routers of a
+/// router, going through all the nested sub-patterns, and then invokes the
+/// `$callback` macro with the given `$args` followed by
+/// `[ $( $cfgs $handle )* ]` and `[ $( $cfgs $router )* ]`, where `$cfgs` are
+/// the `#[cfg(...)]` attributes of the route and of its parent patterns, if
+/// any, as `[ $( #[cfg $predicate] )* ]`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! router_handles {
+    // terminal rule
+    ( $callback:ident $args:tt $handles:tt $routers:tt ) => {
+        $crate::$callback!{ $args $handles $routers }
+    };
+
+    // keep only the `#[cfg(...)]` attributes of a route
+    (
+        @filter $callback:ident $args:tt $handles:tt $routers:tt
+        [ $( $cfgs:tt )* ] [ #[cfg $predicate:tt] $( $rest:tt )* ]
+        $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{@filter $callback $args $handles $routers
+            [ $( $cfgs )* #[cfg $predicate] ] [ $( $rest )* ] $( $tail )*
+        }
+    };
+    (
+        @filter $callback:ident $args:tt $handles:tt $routers:tt
+        $cfgs:tt [ #[ $( $_attr:tt )* ] $( $rest:tt )* ]
+        $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{@filter $callback $args $handles $routers
+            $cfgs [ $( $rest )* ] $( $tail )*
+        }
+    };
+    (
+        @filter $callback:ident $args:tt $handles:tt $routers:tt
+        $cfgs:tt [] $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{@route $callback $args $handles $routers
+            $cfgs $( $tail )*
+        }
+    };
+
+    // a sub router
+    (
+        @route $callback:ident $args:tt $handles:tt [ $( $router:tt )* ]
+        $cfgs:tt (sub $sub_router:ident) $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{$callback $args $handles
+            [ $( $router )* $cfgs $sub_router ] $( $tail )*
+        }
+    };
+
+    // a sub-pattern - add the handles inside it, which inherit its `$cfgs`
+    (
+        @route $callback:ident $args:tt $handles:tt $routers:tt
+        $cfgs:tt {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $_sub_pattern:tt )* ) $( -> $_sub_return_ty:path )? = $sub_handle:tt,
+            )*
+        }
+        $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{$callback $args $handles $routers
+            $( { $cfgs [ $( #[ $( $attr )* ] )* ] } $sub_handle )* $( $tail )*
+        }
+    };
+
+    // a handler that uses request (`with_options`)
+    (
+        @route $callback:ident $args:tt [ $( $handle:tt )* ] $routers:tt
+        $cfgs:tt (with_options $new_handle:ident) $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{$callback $args [ $( $handle )* $cfgs $new_handle ]
+            $routers $( $tail )*
+        }
+    };
+
+    // a handler that returns chunks (`stream`)
+    (
+        @route $callback:ident $args:tt [ $( $handle:tt )* ] $routers:tt
+        $cfgs:tt (stream $new_handle:ident) $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{$callback $args [ $( $handle )* $cfgs $new_handle ]
+            $routers $( $tail )*
+        }
+    };
+
+    // a constant response, named after the constant
+    (
+        @route $callback:ident $args:tt [ $( $handle:tt )* ] $routers:tt
+        $cfgs:tt (const $value:ident) $( $tail:tt )*
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::router_handles!{$callback $args
+                [ $( $handle )* $cfgs [<$value:lower>] ]
+                $routers $( $tail )*
+            }
+        }
+    };
+
+    // a handler that doesn't use request
+    (
+        @route $callback:ident $args:tt [ $( $handle:tt )* ] $routers:tt
+        $cfgs:tt $new_handle:ident $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{$callback $args [ $( $handle )* $cfgs $new_handle ]
+            $routers $( $tail )*
+        }
+    };
+
+    // a nested route - join the `$cfgs` of its parent with its attributes
+    (
+        $callback:ident $args:tt $handles:tt $routers:tt
+        { [ $( $cfgs:tt )* ] [ $( $attrs:tt )* ] } $handle:tt $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{@filter $callback $args $handles $routers
+            [ $( $cfgs )* ] [ $( $attrs )* ] $handle $( $tail )*
+        }
+    };
+
+    // a route with its attributes
+    (
+        $callback:ident $args:tt $handles:tt $routers:tt
+        $attrs:tt $handle:tt $( $tail:tt )*
+    ) => {
+        $crate::router_handles!{@filter $callback $args $handles $routers
+            [] $attrs $handle $( $tail )*
+        }
+    };
+}
+
+/// Turn a pattern into its path template for [`RouteInfo`], which is a string
+/// literal with each dynamic arg replaced with its name in braces.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_template {
+    // terminal rule
+    (()) => { "" };
+
+    // literal string arg, in its first form if it has aliases
+    (( $pattern:literal $( | $_alias:literal )* $( / $tail:tt )* )) => {
+        concat!("/", $pattern, $crate::pattern_template!(( $( $tail )/ * )))
+    };
+
+    // optional arg
+    (( [$name:tt: opt $( $_rest:tt )*] $( / $tail:tt )* )) => {
+        concat!("/{", stringify!($name), "?}",
+            $crate::pattern_template!(( $( $tail )/ * )))
+    };
+
+    // typed arg that is also bound as raw `&str`
+    (( [$raw:tt = $name:tt: $( $_rest:tt )*] $( / $tail:tt )* )) => {
+        $crate::pattern_template!(( [$name] $( / $tail )* ))
+    };
+
+    // any other dynamic arg
+    (( [$name:tt $( : $( $_rest:tt )* )?] $( / $tail:tt )* )) => {
+        concat!("/{", stringify!($name), "}",
+            $crate::pattern_template!(( $( $tail )/ * )))
+    };
+}
+
+/// Push the [`RouteArg`]s of a pattern's dynamic args into the `$args`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pattern_args {
+    // terminal rule
+    ($args:ident, ()) => {};
+
+    // literal string arg
+    ($args:ident, ( $pattern:literal $( | $_alias:literal )* $( / $tail:tt )* )) => {
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // untyped arg
+    ($args:ident, ( [$name:tt] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: "str",
+            optional: false,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // untyped arg matched against a regex
+    ($args:ident, ( [$name:tt: re $regex:literal] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: "str",
+            optional: false,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // untyped arg matched against a run-time set
+    ($args:ident, ( [$name:tt: oneof $set:expr] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: "str",
+            optional: false,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // base64url-encoded bytes arg
+    ($args:ident, ( [$name:tt: b64] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: "b64",
+            optional: false,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // typed arg converted with `TryFrom<&str>`
+    ($args:ident, ( [$name:tt: try $type:ty] $( / $tail:tt )* )) => {
+        $crate::pattern_args!($args, ( [$name: $type] $( / $tail )* ));
+    };
+
+    // integer arg in the declared radix
+    ($args:ident, ( [$name:tt: $type:ident radix $radix:literal] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: concat!(stringify!($type), " radix ", $radix),
+            optional: false,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // glob arg
+    ($args:ident, ( [$name:tt: **] )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: "**",
+            optional: false,
+        });
+    };
+
+    // storage key arg
+    ($args:ident, ( [$name:tt: storage_key] )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: "storage_key",
+            optional: false,
+        });
+    };
+
+    // opt typed arg
+    ($args:ident, ( [$name:tt: opt $type:ty $( = $default:expr )?] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: stringify!($type),
+            optional: true,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // union typed arg
+    ($args:ident, ( [$name:tt: $left:ty $( | $right:ty )+] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: concat!(stringify!($left) $( , " | ", stringify!($right) )+),
+            optional: false,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+
+    // typed arg that is also bound as raw `&str`
+    ($args:ident, ( [$raw:tt = $name:tt: $type:ty] $( / $tail:tt )* )) => {
+        $crate::pattern_args!($args, ( [$name: $type] $( / $tail )* ));
+    };
+
+    // typed arg with a `where` predicate
+    ($args:ident, ( [$name:tt: $type:ty where $_pred:expr] $( / $tail:tt )* )) => {
+        $crate::pattern_args!($args, ( [$name: $type] $( / $tail )* ));
+    };
+
+    // typed arg with a custom `display` function
+    (
+        $args:ident,
+        ( [$name:tt: $type:ty, display = $_display:path] $( / $tail:tt )* )
+    ) => {
+        $crate::pattern_args!($args, ( [$name: $type] $( / $tail )* ));
+    };
+
+    // typed arg
+    ($args:ident, ( [$name:tt: $type:ty] $( / $tail:tt )* )) => {
+        $args.push($crate::ledger::queries::router::RouteArg {
+            name: stringify!($name),
+            ty: stringify!($type),
+            optional: false,
+        });
+        $crate::pattern_args!($args, ( $( $tail )/ * ));
+    };
+}
+
+/// Push a [`RouteInfo`] for each leaf route of a `$pattern` with a `$handle`,
+/// going through all the nested sub-patterns and sub-routers, into the
+/// `$routes` of the router `$self`. The `$parents` are the parent patterns of
+/// the `$pattern` and `$internal` is whether the route or any of its parents
+/// is marked `#[internal]`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! push_route_infos {
+    // a sub router - add all its routes, prefixed with the args of its prefix
+    (
+        $self:ident, $routes:ident, $internal:expr, $deprecated:expr, [ $( $parents:tt )* ],
+        $pattern:tt, $_return_ty:tt, (sub $router:ident)
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {{
+            let template = concat!(
+                $( $crate::pattern_template!($parents), )* $crate::pattern_template!($pattern));
+            #[allow(unused_mut)]
+            let mut prefix_args: Vec<$crate::ledger::queries::router::RouteArg> = vec![];
+            $( $crate::pattern_args!(prefix_args, $parents); )*
+            $crate::pattern_args!(prefix_args, $pattern);
+            let sub_router = [<$router:camel>]::sub(
+                format!("{}{}", $self.prefix, template));
+            $routes.extend(sub_router.all_routes().into_iter().map(|mut route| {
+                route.args.splice(0..0, prefix_args.iter().cloned());
+                route.internal |= $internal;
+                route.deprecated |= $deprecated;
+                route
+            }));
+        }}
+    };
+
+    // a sub-pattern - add the routes inside it
+    (
+        $self:ident, $routes:ident, $internal:expr, $deprecated:expr, $parents:tt, $pattern:tt,
+        $_return_ty:tt,
+        {
+            $(
+                $( #[ $( $attr:tt )* ] )*
+                ( $( $sub_pattern:tt )* ) $( -> $sub_return_ty:path )? = $handle:tt,
+            )*
+        }
+    ) => {
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                $crate::push_route_infos!(@nested $self, $routes,
+                    $internal || $crate::route_internal!([ $( #[ $( $attr )* ] )* ]),
+                    $deprecated || $crate::route_deprecated!([ $( #[ $( $attr )* ] )* ]),
+                    $parents, $pattern, ( $( $sub_pattern )* ),
+                    [ $( $sub_return_ty )? ], $handle);
+            }
+        )*
+    };
+
+    // a route of a sub-pattern - add the sub-pattern to its parents
+    (
+        @nested $self:ident, $routes:ident, $internal:expr, $deprecated:expr,
+        [ $( $parents:tt )* ], $pattern:tt, $sub_pattern:tt, $return_ty:tt,
+        $handle:tt
+    ) => {
+        $crate::push_route_infos!($self, $routes, $internal, $deprecated,
+            [ $( $parents )* $pattern ], $sub_pattern, $return_ty, $handle);
+    };
+
+    // a handler that uses request (`with_options`)
+    (
+        $self:ident, $routes:ident, $internal:expr, $deprecated:expr, $parents:tt, $pattern:tt,
+        $return_ty:tt, (with_options $handle:ident)
+    ) => {
+        $crate::push_route_infos!(@leaf $self, $routes, $internal, $deprecated, true,
+            $parents, $pattern, $return_ty, $handle)
+    };
+
+    // a handler that returns chunks (`stream`)
+    (
+        $self:ident, $routes:ident, $internal:expr, $deprecated:expr, $parents:tt, $pattern:tt,
+        $return_ty:tt, (stream $handle:ident)
+    ) => {
+        $crate::push_route_infos!($self, $routes, $internal, $deprecated, $parents, $pattern,
+            $return_ty, $handle)
+    };
+
+    // a constant response, named after the constant
+    (
+        $self:ident, $routes:ident, $internal:expr, $deprecated:expr, $parents:tt, $pattern:tt,
+        $return_ty:tt, (const $value:ident)
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            $crate::push_route_infos!($self, $routes, $internal, $deprecated, $parents, $pattern,
+                $return_ty, [<$value:lower>])
+        }
+    };
+
+    // a handler that doesn't use request
+    (
+        $self:ident, $routes:ident, $internal:expr, $deprecated:expr,
+        $parents:tt, $pattern:tt, $return_ty:tt, $handle:ident
+    ) => {
+        $crate::push_route_infos!(@leaf $self, $routes, $internal, $deprecated, false,
+            $parents, $pattern, $return_ty, $handle)
+    };
+
+    // a leaf route - `$provable` is whether its handler uses request
+    (
+        @leaf $self:ident, $routes:ident, $internal:expr, $deprecated:expr,
+        $provable:expr, [ $( $parents:tt )* ], $pattern:tt,
+        [ $return_ty:path ], $handle:ident
+    ) => {{
+        let template = concat!(
+            $( $crate::pattern_template!($parents), )* $crate::pattern_template!($pattern));
+        #[allow(unused_mut)]
+        let mut args: Vec<$crate::ledger::queries::router::RouteArg> = vec![];
+        $( $crate::pattern_args!(args, $parents); )*
+        $crate::pattern_args!(args, $pattern);
+        $routes.push($crate::ledger::queries::router::RouteInfo {
+            handler: stringify!($handle),
+            template: format!("{}{}", $self.prefix, template),
+            args,
+            response_ty: stringify!($return_ty),
+            internal: $internal,
+            provable: $provable,
+            deprecated: $deprecated,
+        });
+    }};
+}
+
+/// Generate a route type `enum $name` with a variant for each handler and for
+/// each sub-router (wrapping its route type), as collected by
+/// `router_handles`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! router_route_type {
+    (
+        ($name:ident)
+        [ $( [ $( #[cfg $handle_cfg:tt] )* ] $handle:ident )* ]
+        [ $( [ $( #[cfg $router_cfg:tt] )* ] $router:ident )* ]
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[doc = "`" $name "` identifies a route of a router, as resolved \
+                from a path by its `route_id` method."]
+            #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+            pub enum $name {
+                $(
+                    $( #[cfg $handle_cfg] )*
+                    #[doc = "Route handled by `" $handle "`"]
+                    [<$handle:camel>],
+                )*
+                $(
+                    $( #[cfg $router_cfg] )*
+                    #[doc = "Route of the `" $router "` sub-router"]
+                    [<$router:camel>]([<$router:camel Route>]),
+                )*
+            }
+
+            impl $name {
+                #[allow(dead_code)]
+                #[doc = "Get the name of this route's handler, e.g. for \
+                    metrics or logging, which is one of the router's \
+                    `HANDLER_NAMES`."]
+                pub fn handler_name(&self) -> &'static str {
+                    match *self {
+                        $(
+                            $( #[cfg $handle_cfg] )*
+                            Self::[<$handle:camel>] => stringify!($handle),
+                        )*
+                        $(
+                            $( #[cfg $router_cfg] )*
+                            Self::[<$router:camel>](route) =>
+                                route.handler_name(),
+                        )*
+                    }
+                }
+            }
+
+            impl $crate::ledger::queries::router::RouteName for $name {
+                fn handler_name(&self) -> &'static str {
+                    Self::handler_name(self)
+                }
+            }
+        }
+    };
+}
+
+/// Generate methods that construct a sample path for each handler of the
+/// router `$name` and its sub-routers, as collected by `router_handles`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! router_sample_paths {
+    (
+        ($name:ident)
+        [ $( [ $( #[cfg $handle_cfg:tt] )* ] $handle:ident )* ]
+        [ $( [ $( #[cfg $router_cfg:tt] )* ] $router:ident )* ]
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            impl $name {
+                $crate::cfg_testing! {
+                    #[allow(dead_code)]
+                    #[doc = "Get a sample path for every route of this router, \
+                        including its sub-routers. The dynamic args are filled \
+                        with their `SampleArg` values."]
+                    #[allow(clippy::vec_init_then_push)]
+                    pub fn sample_paths(&self) -> Vec<String> {
+                        #[allow(unused_mut)]
+                        let mut paths = vec![];
+                        $(
+                            $( #[cfg $handle_cfg] )*
+                            paths.push(self.[<$handle _sample_path>]());
+                        )*
+                        $(
+                            $( #[cfg $router_cfg] )*
+                            paths.extend(
+                                self.[<$router:camel:snake _sample>]().sample_paths());
+                        )*
+                        paths
+                    }
+                }
+
+                $crate::cfg_testing! {
+                    #[allow(dead_code)]
+                    #[doc = "A stream of `sample_paths`, e.g. for smoke-testing \
+                        every route with a client."]
+                    pub fn sample_paths_stream(&self) -> impl $crate::ledger::queries::router::__private::futures::Stream<Item = String> {
+                        $crate::ledger::queries::router::__private::futures::stream::iter(self.sample_paths())
+                    }
+                }
+
+                $crate::cfg_testing! {
+                    #[allow(dead_code)]
+                    #[doc = "Get the `sample_paths` of this router as a root \
+                        router, without an instance, e.g. for a load generator \
+                        that replays a path for every route."]
+                    pub fn all_sample_paths() -> Vec<String> {
+                        Self::new().sample_paths()
+                    }
+                }
+            }
+        }
+    };
+}
+
+/// Generate a method that collects the response schemas of all the handlers
+/// of the router `$name` and its sub-routers, as collected by
+/// `router_handles`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! router_response_schemas {
+    (
+        ($name:ident)
+        [ $( [ $( #[cfg $handle_cfg:tt] )* ] $handle:ident )* ]
+        [ $( [ $( #[cfg $router_cfg:tt] )* ] $router:ident )* ]
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            impl $name {
+                $crate::cfg_tooling! {
+                    #[allow(dead_code)]
+                    #[doc = "Get the borsh schema of the response of every route \
+                        of this router, including its sub-routers, keyed by the \
+                        route's handler name. The `#[internal]` routes and the \
+                        routes whose response type doesn't implement \
+                        `BorshSchema` are skipped."]
+                    pub fn response_schemas(&self)
+                        -> Vec<(&'static str, $crate::ledger::queries::router::__private::borsh::schema::BorshSchemaContainer)> {
+                        #[allow(unused_mut)]
+                        let mut schemas = vec![];
+                        $(
+                            $( #[cfg $handle_cfg] )*
+                            if let Some(schema) = self.[<$handle _response_schema>]() {
+                                schemas.push((stringify!($handle), schema));
+                            }
+                        )*
+                        $(
+                            $( #[cfg $router_cfg] )*
+                            schemas.extend([<$router:camel>]::new().response_schemas());
+                        )*
+                        let internal = self.internal_routes();
+                        schemas.retain(|(handler, _)| {
+                            !internal.iter().any(|route| route.handler == *handler)
+                        });
+                        schemas
+                    }
+                }
+            }
+        }
+    };
+}
+
+/// Generate a constant with the names of all the handlers of the router
+/// `$name` and its sub-routers, as collected by `router_handles`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! router_handler_names {
+    (
+        ($name:ident)
+        [ $( [ $( #[cfg $handle_cfg:tt] )* ] $handle:ident )* ]
+        [ $( [ $( #[cfg $router_cfg:tt] )* ] $router:ident )* ]
+    ) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            impl $name {
+                #[allow(dead_code)]
+                #[doc = "The names of the handlers of every route of this \
+                    router, followed by the handlers of its sub-routers."]
+                pub const HANDLER_NAMES: &'static [&'static str] = {
+                    const PARTS: &[&[&str]] = &[
+                        &[ $(
+                            $( #[cfg $handle_cfg] )*
+                            stringify!($handle),
+                        )* ],
+                        $(
+                            $( #[cfg $router_cfg] )*
+                            [<$router:camel>]::HANDLER_NAMES,
+                        )*
+                    ];
+                    const LEN: usize =
+                        $crate::ledger::queries::router::handler_names_len(PARTS);
+                    const NAMES: [&str; LEN] =
+                        $crate::ledger::queries::router::concat_handler_names(PARTS);
+                    &NAMES
+                };
+            }
+        }
+    };
+}
+
+/// The body of a router's `Router::internal_handle` with the `handle_match`
+/// `$terminal` and of its `Router::internal_handle_async` with the
+/// `handle_match_async` `$terminal`, generated by `router!`. It matches the
+/// `$segments` from the `$start` index against the patterns of the routes and
+/// dispatches the request to the first matched route.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! router_internal_handle {
+    (
+        $self:ident, $ctx:ident, $request:ident, $segments:ident,
+        $start:ident, $terminal:ident,
+        $( $attrs:tt ( $( $pattern:tt )* ) $handle:tt )*
+    ) => {{
+        // Import for `.into_storage_result()`
+        use $crate::ledger::storage_api::ResultExt;
+
+        // The index of the first segment to be matched is given by
+        // the caller, e.g. a parent router. An index past the end of
+        // the segments cannot come from a well-formed path.
+        let malformed_path = || $crate::ledger::storage_api::Error::new(
+            $crate::ledger::queries::router::Error::MalformedPath(
+                $request.path.clone()));
+        if $segments.get($start..).is_none() {
+            return Err(malformed_path());
+        }
+
+        if $self.meta_routes
+            && $crate::ledger::queries::router::is_routes_meta_path(
+                $segments.get($start..).unwrap_or_default())
+        {
+            $crate::ledger::queries::require_borsh_encoding($request)?;
+            let routes: Vec<$crate::ledger::queries::router::RouteDescriptor> =
+                $self.routes().into_iter().map(Into::into).collect();
+            let data = $crate::ledger::queries::router::__private::borsh::BorshSerialize::try_to_vec(&routes)
+                .into_storage_result()?;
+            return Ok($crate::ledger::queries::EncodedResponseQuery {
+                data,
+                ..Default::default()
+            });
+        }
+
+        // Match the optional version segment of a versioned router
+        let ($ctx, $start) = match $crate::ledger::queries::router::match_version(
+            $self.versioned, $segments, $start) {
+            Some(version) => ($crate::ledger::queries::RequestCtx {
+                version: Some(version),
+                ..$ctx
+            }, $crate::ledger::queries::router::checked_advance_segment(
+                $segments, $start).ok_or_else(malformed_path)?),
+            None => ($ctx, $start),
+        };
+
+        // Count the remaining segments to be matched
+        let segment_count = $crate::ledger::queries::router::segment_count(
+            $segments.get($start..).unwrap_or_default());
+        // The query string may supply the absent optional args
+        #[allow(unused_variables)]
+        let query = $crate::ledger::queries::router::split_query(
+            &$request.path).1;
+        // The hash of the first segment to be matched, with which the
+        // patterns that start with another literal are skipped
+        #[allow(unused_variables)]
+        let first_segment = $crate::ledger::queries::router::first_segment_hash(
+            $segments, $start);
+        // The first typed arg that couldn't be parsed, if any, is
+        // reported when no pattern is matched
+        #[allow(unused_mut)]
+        let mut failure: Option<$crate::ledger::queries::router::Error> = None;
+
+        $(
+            $crate::route_cfg!{ $attrs
+                // This loop never repeats, it's only used for a
+                // breaking mechanism when a $pattern is not matched
+                // to skip to the next one, if any
+                loop {
+                    // Skip the pattern early if it cannot match the
+                    // path's segment count
+                    const BOUNDS: $crate::ledger::queries::router::SegmentBounds =
+                        $crate::segment_bounds!(( $( $pattern )* ), $handle);
+                    if !BOUNDS.contains(segment_count) {
+                        break;
+                    }
+                    // Skip the pattern early if it starts with a
+                    // literal that hashes differently from the path's
+                    // first segment. A hash collision is ruled out
+                    // when the literal is matched.
+                    const FIRST_LITERALS: &[u64] =
+                        $crate::first_literal_hashes!(( $( $pattern )* ));
+                    match first_segment {
+                        Some(hash) if !FIRST_LITERALS.is_empty()
+                            && !FIRST_LITERALS.contains(&hash) => break,
+                        _ => {}
+                    }
+                    let mut $start = $start;
+                    // Try to match, parse args and invoke $handle,
+                    // will break the `loop` not matched
+                    $crate::try_match_fast!($terminal, $ctx, $request,
+                        $segments, query, $start, failure, $attrs,
+                        $handle, ( $( $pattern )* ));
+                }
+            }
+        )*
+
+        return Err(failure.unwrap_or_else(||
+            $crate::ledger::queries::router::Error::WrongPath(
+                format!("/{}", $segments.join("/")))))
+            .into_storage_result();
+
+    }};
+}
+
+/// Compile time tree patterns router with type-safe dynamic parameter parsing,
+/// automatic routing, type-safe path constructors, optional client query
+/// methods (enabled with `feature = "async-client"`) and optional `curl`
+/// command constructors for debugging (enabled with `feature = "tooling"`).
+///
+/// The `router!` macro implements greedy matching algorithm.
+///
+/// ## Matching order
+///
+/// The sibling patterns are tried in the order of their declaration and the
+/// first one that matches the path wins, even if a later one would match it
+/// too (`match_all` lists all of them). There's no other precedence, e.g. a
+/// literal segment isn't preferred over a typed arg at the same position, so
+/// to have a literal take precedence over a typed arg that could parse it,
+/// declare its pattern first. Within a pattern:
+///
+/// - A sub-router's pattern matches only if the sub-router has a route for the
+///   rest of the path, otherwise the next sibling pattern is tried.
+/// - The last typed arg of a pattern with a handler consumes the rest of the
+///   path, so it doesn't match a path with more segments than the pattern
+///   unless the rest can be parsed as the arg's type (e.g. a `storage::Key`).
+/// - An optional arg whose segment cannot be parsed is absent and the segment
+///   is matched with the next part of the pattern.
+///
+/// ## Examples
+///
+/// ```rust,ignore
+/// router! {ROOT,
+///   // This pattern matches `/pattern_a/something`, where `something` can be
+///   // parsed with `FromStr` into `ArgType`.
+///   ( "pattern_a" / [typed_dynamic_arg: ArgType] ) -> ReturnType = handler,
+///
+///   // The response of a handler that doesn't use request is borsh-encoded,
+///   // unless the JSON encoding is selected with a `.json` suffix of the
+///   // path, e.g. `/pattern_a/something.json`, for a `ReturnType` that
+///   // implements `serde::Serialize`.
+///
+///   // An optional arg that's absent from the path may be supplied as a query
+///   // param instead, e.g. `/pattern_b?optional_dynamic_arg=something`. When
+///   // both are present, the path segment takes precedence.
+///   ( "pattern_b" / [optional_dynamic_arg: opt ArgType] ) -> ReturnType =
+/// handler,
+///
+///   // A literal segment between two optional args may be omitted at the end
+///   // of a path with a query string, so that all the args can be supplied
+///   // by name in any order, e.g. `/pattern_c?arg_b=2&arg_a=1`.
+///   ( "pattern_c" / [arg_a: opt ArgType] / "sep" / [arg_b: opt ArgType] )
+///     -> ReturnType = handler,
+///
+///   // An optional arg can have a default value, which the handler receives
+///   // when the arg is absent. The `*_path` method omits the arg when it's
+///   // equal to the default.
+///   ( "pattern_default" / [arg_with_default: opt ArgType = DEFAULT] )
+///     -> ReturnType = handler,
+///
+///   // Signed integers and decimals work like any other `FromStr` type. Their
+///   // parsing doesn't depend on the system's locale - the decimal separator
+///   // is always `.` and there are no thousands separators (e.g. `-1234.5`).
+///   ( "pattern_signed" / [signed_arg: i64] / [decimal_arg: Decimal] )
+///     -> ReturnType = handler,
+///
+///   // Untyped dynamic arg is a string slice `&str`
+///   ( "pattern_c" / [untyped_dynamic_arg] ) -> ReturnType = handler,
+///
+///   // Untyped dynamic arg that is only matched if it matches the regex
+///   ( "pattern_slug" / [slug: re "^[a-z0-9-]+$"] ) -> ReturnType = handler,
+///
+///   // Untyped dynamic arg that is only matched if it's in a set that's only
+///   // known at run-time, e.g. a `static TOKEN_SET: Lazy<RwLock<HashSet<String>>>`
+///   // (see `SegmentSet`). Its sample path is only matched if the set
+///   // contains the `"sample"` segment.
+///   ( "pattern_symbol" / [sym: oneof &TOKEN_SET] ) -> ReturnType = handler,
+///
+///   // Bytes arg `Vec<u8>` encoded in the segment as unpadded base64url. The
+///   // segment isn't matched if it's not valid base64url.
+///   ( "pattern_bytes" / [bytes_arg: b64] ) -> ReturnType = handler,
+///
+///   // Unsigned integer arg in a radix other than 10, e.g. `/pattern_hex/0xff`
+///   // with `255`. The radix' prefix (`0x`, `0o` or `0b`) may be omitted from
+///   // the segment, but the generated `_path` methods always include it.
+///   ( "pattern_hex" / [id: u64 radix 16] ) -> ReturnType = handler,
+///
+///   // Typed arg converted from its segment with `TryFrom<&str>` instead of
+///   // `FromStr`, e.g. to reuse an existing conversion. The segment isn't
+///   // matched if the conversion fails. Like the radix arg, it only matches a
+///   // single segment, even at the end of the path.
+///   ( "pattern_try" / [try_arg: try ArgType] ) -> ReturnType = handler,
+///
+///   // Glob arg `&str` that matches the rest of the path at any depth, e.g.
+///   // `/pattern_glob/a/b/c` with `"a/b/c"` or `/pattern_glob` with `""`.
+///   // Unlike a typed arg that consumes the rest of the path (e.g. a
+///   // `storage::Key`), it's not parsed. It must be the last segment.
+///   ( "pattern_glob" / [glob_arg: **] ) -> ReturnType = handler,
+///
+///   // Storage key arg `storage::Key` parsed from the rest of the path, like
+///   // a typed arg of `storage::Key`, but its segments must not be empty and
+///   // they may only contain the ASCII alphanumerics and the
+///   // `KEY_SEGMENT_SPECIAL_CHARS`. A malformed key is rejected with
+///   // `Error::InvalidKey` instead of trying the next pattern. It must be the
+///   // last segment.
+///   ( "pattern_key" / [key: storage_key] ) -> ReturnType = handler,
+///
+///   // Union typed arg that is parsed with the first of its types that can
+///   // parse the segment, passed to the handler as `Either<ArgType,
+///   // OtherArgType>` (e.g. an id that's either a number or a name)
+///   ( "pattern_union" / [union_arg: ArgType | OtherArgType] )
+///     -> ReturnType = handler,
+///
+///   // The segment is bound to both `raw: &str` and `parsed: ArgType`, which
+///   // the handler receives in this order. The segment is parsed only once
+///   // and the generated methods only take the `parsed` arg.
+///   ( "pattern_raw" / [raw = parsed: ArgType] ) -> ReturnType = handler,
+///
+///   // A typed arg can have a predicate, which is checked after the arg is
+///   // parsed. The segment isn't matched if the predicate is violated and
+///   // when no other pattern matches the path, the request fails with
+///   // `Error::ValidationFailed`. The generated methods don't check it, so the
+///   // arg's `SampleArg` must satisfy it for `generate_router_tests`. In
+///   // debug builds, a `*_path` with an arg that violates it panics, because
+///   // its path isn't matched back (see below).
+///   ( "pattern_where" / [positive_arg: u64 where positive_arg > 0] )
+///     -> ReturnType = handler,
+///
+///   // The generated methods format a typed arg's segment with its `Display`
+///   // by default. When that cannot be parsed back by its `FromStr` (e.g. it
+///   // contains a `/`), a `fn(&ArgType) -> String` can format it instead.
+///   // It must produce a segment that `FromStr` parses back into the arg.
+///   // In debug builds, every `*_path` method matches its path back with the
+///   // router and panics unless it's the same route with the same args.
+///   ( "pattern_display" / [display_arg: ArgType, display = format_arg] )
+///     -> ReturnType = handler,
+///
+///   // The handler additionally receives the `RequestQuery`, which can have
+///   // some data attached, specified block height and ask for a proof. It
+///   // returns `EncodedResponseQuery` (the `data` must be encoded, if
+///   // necessary), which can have some `info` string and a proof. Besides
+///   // the client method with all the options, there's a `handler_at_height`
+///   // method to request the value at a given block height.
+///   ( "pattern_d" ) -> ReturnType = (with_options handler),
+///
+///   ( "another" / "pattern" / "that" / "goes" / "deep" ) -> ReturnType = handler,
+///
+///   // The leading literal of a pattern can have aliases, e.g. for a renamed
+///   // route, so this matches both `/balance` and `/bal`. The generated
+///   // methods (e.g. `*_path`) and the route's template use the first form.
+///   ( "balance" | "bal" ) -> ReturnType = handler,
+///
+///   // A route with a handler function can have attributes. With `info`, the
+///   // given template is used as the response's `info` whenever the handler
+///   // leaves it empty. The `{route}` placeholder in the template is replaced
+///   // with the handler's name.
+///   #[info = "Served by {route}"]
+///   ( "pattern_e" ) -> ReturnType = handler,
+///
+///   // A hint of how a client may cache the route's responses, either
+///   // `immutable` (e.g. for historical-height data) or `no_store` (e.g. for
+///   // latest-height data), which is found with the router's `cache_policy`
+///   #[cache(immutable)]
+///   ( "pattern_cached" / [height: BlockHeight] ) -> ReturnType = handler,
+///
+///   // By default, paths with a trailing slash are matched too (e.g.
+///   // `/pattern_f/`). This can be disabled for a route, which only applies
+///   // to the segments matched with `handle_segments`, because the paths are
+///   // otherwise normalized without the trailing slash (see
+///   // `normalize_path`).
+///   #[trailing_slash = false]
+///   ( "pattern_f" ) -> ReturnType = handler,
+///
+///   // A trailing slash can instead be matched as an empty last segment, so
+///   // that `/pattern_empty/` gives `Some("")` to an optional arg and
+///   // `/pattern_empty` gives `None`. The router's `handle` then keeps the
+///   // trailing slash of all the paths (see `Router::keeps_trailing_slash`).
+///   #[empty_segment]
+///   ( "pattern_empty" / [q: opt String] ) -> ReturnType = handler,
+///
+///   // A route that's only enabled when the node has the given capability
+///   // in its `RequestCtx::capabilities`, e.g. an archive node that keeps
+///   // the historical state, otherwise it fails with `Error::RouteDisabled`.
+///   #[capability = "archive"]
+///   ( "pattern_archive" / [height: BlockHeight] ) -> ReturnType = handler,
+///
+///   // A handler can return domain errors, which are encoded in the response
+///   // (see `HandlerError`). The client method returns the decoded
+///   // `Result<ReturnType, DomainError>`.
+///   #[domain_error]
+///   ( "pattern_g" ) -> Result<ReturnType, DomainError> = handler,
+///
+///   // With `feature = "async-handlers"`, a handler can be an `async fn`.
+///   // Its future is awaited by the router's `Router::handle_async`, while
+///   // the synchronous `Router::handle` rejects the request with
+///   // `Error::AsyncHandler`. It works with `with_options` handlers too.
+///   #[async_handler]
+///   ( "pattern_async" ) -> ReturnType = async_handler,
+///
+///   // A handler with many args can receive them in a single params struct
+///   // instead of one by one. The struct is generated next to the router and
+///   // it's named after the handler (here `WideHandlerParams` with the fields
+///   // `a1`, `a2` and `a3`), with an untyped arg as a `String`.
+///   #[params]
+///   ( "pattern_params" / [a1: ArgType] / [a2: ArgType] / [a3] )
+///     -> ReturnType = wide_handler,
+///
+///   // A fully dynamic handler, e.g. a proxy, can instead receive the args
+///   // in a single `BTreeMap<String, String>` keyed by the args' names, with
+///   // the values formatted with `Display`. An absent optional arg is left
+///   // out of the map.
+///   #[args_map]
+///   ( "pattern_map" / [a1: ArgType] / [a2: opt ArgType] / [a3] )
+///     -> ReturnType = dynamic_handler,
+///
+///   // A handler can tell an omitted optional arg from one that couldn't be
+///   // parsed, with its optional args passed as `Optional<ArgType>` instead
+///   // of `Option<ArgType>`, e.g. `Unparseable("abc")` for
+///   // `/pattern_presence/abc`. Only the last arg of a pattern can be
+///   // unparseable and the args with a default are unaffected. It's not
+///   // supported with `#[params]` or `#[compact = id]`.
+///   #[presence]
+///   ( "pattern_presence" / [amount: opt ArgType] ) -> ReturnType = handler,
+///
+///   // A guardrail against accidentally huge responses, separate from any
+///   // pagination. When the handler's encoded response is longer than the
+///   // given number of bytes, `handle` fails with `Error::ResponseTooLarge`
+///   // instead of returning it. A borsh response to a caller in the same
+///   // process isn't encoded, so it's not limited.
+///   #[max_size = 65536]
+///   ( "pattern_limited" ) -> ReturnType = handler,
+///
+///   // A lookup handler can return an `Option`, which is borsh-encoded like
+///   // any other type, so the client method returns `Option<ReturnType>`.
+///   // `None` is a successful response, not an error - for a `with_options`
+///   // handler, it's the result at the requested height and the handler may
+///   // attach a proof of the value's absence.
+///   ( "pattern_lookup" / [key: ArgType] ) -> Option<ReturnType> = handler,
+///
+///   // A precomputed response without a handler function. The value of the
+///   // constant `VALUE` is borsh-encoded once, on the first request, and then
+///   // served for any block height. The generated methods are named after
+///   // the constant in lowercase (e.g. `value_path`).
+///   ( "pattern_const" ) -> ReturnType = (const VALUE),
+///
+///   // A handler that returns the borsh-encoded `ReturnType` in chunks, for a
+///   // value too large to be sent in a single response. Each request gets
+///   // one chunk, with the chunk's index in the request data. Besides the
+///   // client method that reassembles the value, there's a `handler_stream`
+///   // method to process the chunks as they're requested and, for a
+///   // `ReturnType` that's a sequence like a `Vec` (see `BorshSeq`), a
+///   // `handler_iter` method to decode its items one by one.
+///   ( "pattern_stream" ) -> ReturnType = (stream handler),
+///
+///   // Inlined sub-tree
+///   ( "subtree" / [this_is_fine: ArgType] ) = {
+///     ( "a" ) -> u64 = a_handler,
+///     ( "b" / [another_arg] ) -> u64 = b_handler,
+///   }
+///
+///   // Imported sub-router
+///   ( "sub" / "no_dynamic_args" ) = (sub SUB_ROUTER),
+///
+///   // Imported sub-router with a dynamic prefix - The prefix can only have
+///   // literal and typed segments. The args are parameters of the method that
+///   // constructs the sub-router, e.g. `ROOT.sub_router(&arg)`. The handlers
+///   // of the sub-router don't receive the args as parameters, but they can
+///   // read them from the context (see `RequestCtx::prefix_arg`).
+///   ( "dynamic" / [typed_dynamic_arg: ArgType] ) = (sub DYNAMIC_SUB_ROUTER),
+///
+///   // An optional arg in a prefix is matched whenever its segment can be
+///   // parsed, otherwise the sub-router is entered without it, so this matches
+///   // both e.g. `/acct/5/balance` and `/acct/balance` with a pattern
+///   // `( "balance" )` of the `ACCT_SUB_ROUTER`. The method that constructs
+///   // the sub-router takes an `Option` of the arg, e.g. `ROOT.acct(&None)`.
+///   ( "acct" / [id: opt ArgType] ) = (sub ACCT_SUB_ROUTER),
+///
+///   // The segments of the paths under a sub-router's prefix can be separated
+///   // by a custom separator instead of the slash, so this matches e.g.
+///   // `/keys/a:b` with a pattern `( [x] / [y] )` of the `KEYS_SUB_ROUTER`
+///   #[separator = ':']
+///   ( "keys" ) = (sub KEYS_SUB_ROUTER),
+///
+///   // When a sub-router has no route for a path, the following patterns are
+///   // tried, so this matches e.g. `/sub/no_dynamic_args/123`, unless
+///   // `SUB_ROUTER` has a matching route
+///   ( "sub" / "no_dynamic_args" / [fallback: u64] ) -> ReturnType = handler,
+///
+///   // A route can be conditionally compiled. The attribute is forwarded onto
+///   // the route's generated methods and its matching, so the route doesn't
+///   // exist at all when the predicate isn't satisfied.
+///   #[cfg(feature = "debug-rpc")]
+///   ( "dump_state" ) -> ReturnType = handler,
+///
+///   // An internal route is handled like any other, but it's excluded from
+///   // the public introspection (`routes` and the methods built on it, and
+///   // `response_schemas`) and listed by `internal_routes` instead. On a
+///   // sub-router, it applies to all the sub-router's routes.
+///   #[internal]
+///   ( "node_internal" ) -> ReturnType = handler,
+///
+///   // A deprecated route is handled like any other, but it's flagged in its
+///   // `RouteInfo` (e.g. for the generated documentation)
+///   #[deprecated]
+///   ( "old_pattern" ) -> ReturnType = handler,
+///
+///   // An exclusive sub-router's prefix must not collide with the patterns of
+///   // any of its siblings (i.e. no sibling can match a path that starts with
+///   // `/exclusive`), otherwise the router fails to compile
+///   #[exclusive]
+///   ( "exclusive" ) = (sub ANOTHER_SUB_ROUTER),
+/// }
+///
+/// router! {SUB_ROUTER,
+///   ( "pattern" ) -> ReturnType = handler,
+/// }
+/// ```
+///
+/// Besides the router type and its constant, the macro generates a route type
+/// with a variant for each handler and for each sub-router, which wraps the
+/// sub-router's route type (e.g. `RootRoute` with a variant
+/// `SubRouter(SubRouterRoute)` for the example above). The router's `route_id`
+/// method resolves a path into its route without invoking the handler, which
+/// is useful as a cheap key for metrics or access control. Because of this,
+/// each handler function can only be used in a single pattern of a router.
+/// The `match_info` method also resolves the values of the route's args into
+/// a [`MatchInfo`], whose `Display` describes the route for logging, and the
+/// `match_all` method resolves every route that matches a path, including the
+/// ones shadowed by the first one, to diagnose the routes' ordering. When no
+/// route matches a path, the `peek` method reports the failures of the
+/// patterns that get furthest in it with [`Error::NoMatch`].
+/// The router's `handle_authorized` method uses it to invoke an authorization
+/// hook with the matched route before the handler runs,
+/// `handle_rate_limited` to invoke a rate-limiting hook (e.g. a
+/// [`RateLimiter`]) with the matched route and the caller's identity,
+/// `handle_transformed` to apply [`ResponseTransformer`]s with the matched
+/// route to the response after the handler returns, `handle_cached` to serve
+/// the cached responses of the expensive deterministic routes from a
+/// [`ResponseCache`], and
+/// `dispatch_with_ctx_builder` uses it to construct the request's context only
+/// for a path that's matched by some route.
+///
+/// A route with a handler that doesn't use request can be given a stable
+/// `#[compact = id]` attribute with a `u16` id that's unique in the root
+/// router, including its sub-routers. The root router's `handle_compact`
+/// method handles a [`CompactRequest`] with the route's id and its
+/// borsh-encoded args by calling the route's handler directly, which skips
+/// matching a path. The args of such route must be typed. The args of a
+/// sub-router's prefix are not a part of the request, because they're not
+/// passed to the handler.
+///
+/// A panic of a handler unwinds through the router's `handle`, unless it's
+/// caught with the request's [`HandlerConfig::catch_panics`], in which case
+/// it's returned as [`Error::HandlerPanicked`] with the handler's name.
+///
+/// A root router constructed with `new_with_meta_routes` also serves its
+/// `routes` at the reserved `/_routes` path, so the first segment of a pattern
+/// cannot start with `_`.
+///
+/// The router's `routes` method lists the handler and the path template (e.g.
+/// `/pattern_a/{typed_dynamic_arg}`) of every leaf route, including its
+/// sub-routers' routes, and `routes_under` lists only the routes under a given
+/// path prefix, e.g. to generate documentation for a module's endpoints. The
+/// `print_routes` method writes the routes with their args' types as an
+/// aligned table. For every route, the `*_validate_path` method is the
+/// counterpart of the `*_path` method, which parses the handler's args from a
+/// path without handling it (e.g. to validate a user's input on the client
+/// side). The routes marked `#[internal]` are only listed by the
+/// `internal_routes` method and `all_routes` lists every route with its
+/// [`RouteInfo::internal`] flag. The `routes_json` method describes the
+/// routes in a stable JSON array for external tooling. The router's type also
+/// has a `HANDLER_NAMES` constant with the names of all the handlers,
+/// including its sub-routers' handlers, e.g. to check that every handler is
+/// covered by a test. A route found with `route_id` has its handler's name
+/// from its `handler_name` method (also of the [`RouteName`] trait), which
+/// doesn't allocate, e.g. to label its metrics. A route with only literal
+/// segments also has its path as a constant named after its handler (e.g.
+/// `A_PATH` for `a`), which can be used in a `const` context.
+///
+/// For a route that isn't modelled by a router's patterns, the router's `raw`
+/// method sends a query at an arbitrary path under the router's prefix via
+/// the `Client::raw_abci_query` and returns the response without decoding
+/// its data.
+///
+/// A large router can be defined in an external spec file with the same
+/// grammar as this macro's input instead, with
+/// `namada_macros::include_router!("path/to/routes.rsrouter")`, where the path
+/// is relative to the crate's manifest directory.
+///
+/// For tooling (enabled with `feature = "tooling"`), the router's
+/// `response_schemas` method collects the borsh schema of every route's
+/// response type that implements `BorshSchema`, which can be used as a
+/// machine-readable contract of the responses' layout. The router's
+/// `print_proto_service` method writes a protobuf service definition with an
+/// RPC for every route (see [`write_proto_service`]), e.g. for a gRPC gateway,
+/// and the `print_ts_client` method writes a TypeScript client with a typed
+/// function for every route (see [`write_ts_client`]), e.g. for a frontend.
+/// The `routes` of two versions of a router can be compared with
+/// [`diff_routes`], e.g. for a CI check that flags the breaking changes of the
+/// RPC.
+///
+/// For testing (enabled with `feature = "testing"`), the router's
+/// `sample_paths` method constructs a path for every route, including its
+/// sub-routers' routes, with the dynamic args filled with their `SampleArg`
+/// values. The `generate_router_tests!` macro uses them to generate a test
+/// that checks that the router matches every route's path. The router type's
+/// `all_sample_paths` function returns them for the root router, e.g. for a
+/// load generator.
+///
+/// Handler functions used in the patterns should have the expected signature:
+/// ```rust,ignore
+/// fn handler<D, H>(ctx: RequestCtx<'_, D, H>, args ...)
+///   -> storage_api::Result<ReturnType>
+/// where
+///     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+///     H: 'static + StorageHasher + Sync;
+/// ```
+///
+/// The dynamic args are parsed from the path, so a handler must not assume
+/// anything about their values. In particular, arithmetic on args such as
+/// `token::Amount` should use checked operations and return an error rather
+/// than panic on overflow:
+/// ```rust,ignore
+/// fn sum<D, H>(ctx: RequestCtx<'_, D, H>, a1: token::Amount, a2:
+/// token::Amount)   -> storage_api::Result<token::Amount>
+/// where
+///     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+///     H: 'static + StorageHasher + Sync,
+/// {
+///     a1.checked_add(a2)
+///         .ok_or(AmountOverflowError)
+///         .into_storage_result()
+/// }
+/// ```
+///
+/// If the handler wants to support request options, it can be defined as
+/// `(with_options $handler)` and then the expected signature is:
+/// ```rust,ignore
+/// fn handler<D, H>(ctx: RequestCtx<'_, D, H>, request: &RequestQuery, args
+/// ...)   -> storage_api::Result<ResponseQuery<ReturnType>>
+/// where
+///     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+///     H: 'static + StorageHasher + Sync;
+/// ```
+///
+/// Such a handler of a deprecated route that has moved to a new path may
+/// return `ResponseQuery::redirect(new_path)` instead of the data, which the
+/// `clients::RedirectClient` follows to the new path. Likewise, a handler
+/// whose request failed transiently may return
+/// `ResponseQuery::retry_after(duration)`, which the
+/// `clients::RetryingClient` waits for before retrying the request.
+///
+/// Every client method that requests a handler's value has a `try_` variant
+/// (e.g. `try_handler`), which classifies its failure into a `QueryError`
+/// instead of returning the client's error.
+///
+/// A handler that returns chunks (`(stream $handler)`) has the signature of
+/// a handler that doesn't use the request, but it returns an iterator of the
+/// chunks of the borsh-encoded `ReturnType`, which may be produced lazily:
+/// ```rust,ignore
+/// fn handler<D, H>(ctx: RequestCtx<'_, D, H>, args ...)
+///   -> storage_api::Result<impl IntoIterator<Item = Vec<u8>>>
+/// where
+///     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+///     H: 'static + StorageHasher + Sync;
+/// ```
+///
+/// The first two signatures can be generated with the
+/// `namada_macros::router_handler` attribute from a shorter one:
+/// ```rust,ignore
+/// #[router_handler]
+/// fn handler(ctx: RequestCtx, args ...) -> ReturnType { ... }
+///
+/// #[router_handler]
+/// fn handler(ctx: RequestCtx, request: &RequestQuery, args ...) -> ReturnType
+/// { ... }
+/// ```
+#[macro_export]
+macro_rules! router {
+    // Check the routes with `check_sub_router_markers` before the router is
+    // generated
+    {
+        $name:ident,
+        $(
+            $( #[ $( $attr:tt )* ] )*
+            ( $( $pattern:tt )* ) $( -> $return_type:path )? = $handle:tt ,
+        )*
+    } => {
+        $crate::check_sub_router_markers!{
+            ( $name, $(
+                $( #[ $( $attr )* ] )*
+                ( $( $pattern )* ) $( -> $return_type )? = $handle ,
+            )* )
+            $(
+                $( #[ $( $attr )* ] )*
+                ( $( $pattern )* ) $( -> $return_type )? = $handle ,
+            )*
+        }
+    };
+
+    {
+        @checked $name:ident,
+        $(
+            $( #[ $( $attr:tt )* ] )*
+            ( $( $pattern:tt )* ) $( -> $return_type:path )? = $handle:tt ,
+        )*
+    } => (
+
+	// `paste!` is used to convert the $name cases for a derived type and function name
+	$crate::ledger::queries::router::__private::paste::paste! {
+
+        $crate::router_type!{[<$name:camel>] {},
+            $(
+                [ $( #[ $( $attr )* ] )* ] ( $( $pattern )* )
+                $( -> $return_type )? = $handle
+            ),*
+        }
+
+        $(
+            $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                $crate::pattern_to_params!( [ $( #[ $( $attr )* ] )* ] $handle
+                    ( $( $pattern )* ) );
+            }
+        )*
+
+		impl $crate::ledger::queries::Router for [<$name:camel>] {
+            fn cache_policy(&self, path: &str)
+                -> Option<$crate::ledger::queries::router::CachePolicy> {
+                self.route_id(path).and_then(|route| route.cache_policy())
+            }
+
+            fn segment_count(&self, path: &str) -> usize {
+                $crate::ledger::queries::router::path_segment_count(
+                    path, &self.prefix, self.separator)
+            }
+
+            fn keeps_trailing_slash(&self) -> bool {
+                $(
+                    $crate::route_keeps_trailing_slash!([ $( #[ $( $attr )* ] )* ]
+                        $handle);
+                )*
+                false
+            }
+
+            fn internal_handle<D, H>(
+			    &self,
+                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
+                request: &$crate::ledger::queries::RequestQuery,
+                segments: &[&str],
+                start: usize
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+            {
+                $crate::router_internal_handle!(self, ctx, request, segments, start,
+                    handle_match,
+                    $( [ $( #[ $( $attr )* ] )* ] ( $( $pattern )* ) $handle )*)
+			}
+
+            $crate::cfg_async_handlers! {
+                fn internal_handle_async<'a, D, H>(
+                    &'a self,
+                    ctx: $crate::ledger::queries::RequestCtx<'a, D, H>,
+                    request: &'a $crate::ledger::queries::RequestQuery,
+                    segments: &'a [&'a str],
+                    start: usize
+                ) -> $crate::ledger::queries::router::LocalBoxFuture<'a,
+                    $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>>
+                where
+                    D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                    H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+                {
+                    Box::pin(async move {
+                        $crate::router_internal_handle!(self, ctx, request, segments,
+                            start, handle_match_async,
+                            $( [ $( #[ $( $attr )* ] )* ] ( $( $pattern )* )
+                                $handle )*)
+                    })
+                }
+            }
+		}
+
+        $crate::router_handles!{router_route_type ([<$name:camel Route>]) [] []
+            $( [ $( #[ $( $attr )* ] )* ] $handle )* }
+
+        impl [<$name:camel Route>] {
+            #[allow(dead_code, irrefutable_let_patterns)]
+            #[doc = "Get the cache policy of this route, as declared with \
+                its `#[cache(...)]` attribute, if any."]
+            pub fn cache_policy(&self)
+                -> Option<$crate::ledger::queries::router::CachePolicy> {
+                $crate::route_cache_policies!(self
+                    $( [ $( #[ $( $attr )* ] )* ] $handle )*);
+                None
+            }
+        }
+
+        $crate::router_handles!{router_sample_paths ([<$name:camel>]) [] []
+            $( [ $( #[ $( $attr )* ] )* ] $handle )* }
+
+        $crate::router_handles!{router_response_schemas ([<$name:camel>]) [] []
+            $( [ $( #[ $( $attr )* ] )* ] $handle )* }
+
+        $crate::router_handles!{router_handler_names ([<$name:camel>]) [] []
+            $( [ $( #[ $( $attr )* ] )* ] $handle )* }
+
+        $crate::check_exclusive_sub_routers!( $(
+            [ $( #[ $( $attr )* ] )* ] ( $( $pattern )* ) $handle
+        ),* );
+
+        $( $crate::check_reserved_segment!( ( $( $pattern )* ) ); )*
+
+        impl [<$name:camel>] {
+            #[allow(dead_code)]
+            #[doc = "Find the route that matches the given path, if any, \
+                without handling it. The path is normalized like in \
+                `Router::handle`. This must be invoked on the root router to \
+                be able to match the `path` fully."]
+            pub fn route_id(&self, path: &str) -> Option<[<$name:camel Route>]> {
+                let path = $crate::ledger::queries::router::normalize_path(path);
+                let (path, query) =
+                    $crate::ledger::queries::router::split_query(&path);
+                let (path, _encoding) =
+                    $crate::ledger::queries::router::split_encoding_suffix(path);
+                let (path, _proof_format) =
+                    $crate::ledger::queries::router::split_proof_format(path);
+                let segments = $crate::ledger::queries::router::split_path(path)?;
+                self.internal_route_id(&segments, 0, query)
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Find the route that matches the given path with the \
+                values of its args like `match_info` or otherwise report why \
+                the path isn't matched with `Error::NoMatch`, which has the \
+                failures of the candidate patterns that get furthest in the \
+                path, e.g. to diagnose a mistyped path. This must be invoked \
+                on the root router to be able to match the `path` fully."]
+            pub fn peek(&self, path: &str) -> std::result::Result<
+                $crate::ledger::queries::router::MatchInfo,
+                $crate::ledger::queries::router::Error,
+            > {
+                let path = $crate::ledger::queries::router::normalize_path(path);
+                let (path_only, query) =
+                    $crate::ledger::queries::router::split_query(&path);
+                let (path_only, _encoding) =
+                    $crate::ledger::queries::router::split_encoding_suffix(
+                        path_only);
+                let (path_only, _proof_format) =
+                    $crate::ledger::queries::router::split_proof_format(
+                        path_only);
+                let segments =
+                    $crate::ledger::queries::router::split_path(path_only)
+                        .ok_or_else(||
+                            $crate::ledger::queries::router::Error::WrongPath(
+                                path.to_string()))?;
+                self.internal_peek(&segments, 0, query).map_err(|candidates| {
+                    $crate::ledger::queries::router::Error::NoMatch {
+                        candidates:
+                            $crate::ledger::queries::router::closest_candidates(
+                                candidates),
+                    }
+                })
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Find the route that matches the given path with the \
+                values of its args, if any, without handling it, e.g. to log \
+                a dispatched request with the `Display` of the \
+                `router::MatchInfo`. The path is normalized like in \
+                `Router::handle`. This must be invoked on the root router to \
+                be able to match the `path` fully."]
+            pub fn match_info(&self, path: &str)
+                -> Option<$crate::ledger::queries::router::MatchInfo> {
+                let path = $crate::ledger::queries::router::normalize_path(path);
+                let (path, query) =
+                    $crate::ledger::queries::router::split_query(&path);
+                let (path, _encoding) =
+                    $crate::ledger::queries::router::split_encoding_suffix(path);
+                let (path, _proof_format) =
+                    $crate::ledger::queries::router::split_proof_format(path);
+                let segments = $crate::ledger::queries::router::split_path(path)?;
+                self.internal_match_info(&segments, 0, query)
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Find every route that matches the given path with the \
+                values of its args in the order of declaration, like \
+                `match_info`, but without stopping at the first match. The \
+                first route is the one that handles the path, while the rest \
+                are shadowed by it, e.g. to diagnose an unexpected handler. \
+                This must be invoked on the root router to be able to match \
+                the `path` fully."]
+            pub fn match_all(&self, path: &str)
+                -> Vec<$crate::ledger::queries::router::MatchInfo> {
+                let path = $crate::ledger::queries::router::normalize_path(path);
+                let (path, query) =
+                    $crate::ledger::queries::router::split_query(&path);
+                let (path, _encoding) =
+                    $crate::ledger::queries::router::split_encoding_suffix(path);
+                let mut matches = vec![];
+                if let Some(segments) =
+                    $crate::ledger::queries::router::split_path(path) {
+                    self.internal_match_all(&segments, 0, query, &mut matches);
+                }
+                matches
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Handle a given request like `Router::handle`, but first \
+                invoke the `authorize` hook with the matched route and the \
+                context, which can reject the request (e.g. with \
+                `Error::Unauthorized`) before the handler runs. The hook is \
+                not invoked for a path that doesn't match any route. This \
+                must be invoked on the root router."]
+            pub fn handle_authorized<D, H, AUTH>(
+                &self,
+                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
+                request: &$crate::ledger::queries::RequestQuery,
+                authorize: AUTH,
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+                AUTH: FnOnce(
+                    &[<$name:camel Route>],
+                    &$crate::ledger::queries::RequestCtx<'_, D, H>,
+                ) -> std::result::Result<(), $crate::ledger::queries::router::Error>,
+            {
+                if let Some(route) = self.route_id(&request.path) {
+                    authorize(&route, &ctx)
+                        .map_err($crate::ledger::storage_api::Error::new)?;
+                }
+                $crate::ledger::queries::Router::handle(self, ctx, request)
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Handle a given request like `Router::handle`, but first \
+                invoke the `rate_limit` hook with the matched route and the \
+                request's caller identity (`RequestQuery::caller`), which can \
+                reject the request with `Error::RateLimited` before the \
+                handler runs (e.g. with `router::RateLimiter::check`). The \
+                hook is not invoked for a path that doesn't match any route. \
+                This must be invoked on the root router."]
+            pub fn handle_rate_limited<D, H, LIMIT>(
+                &self,
+                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
+                request: &$crate::ledger::queries::RequestQuery,
+                rate_limit: LIMIT,
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+                LIMIT: FnOnce(
+                    &[<$name:camel Route>],
+                    Option<&str>,
+                ) -> std::result::Result<(), $crate::ledger::queries::router::Error>,
+            {
+                if let Some(route) = self.route_id(&request.path) {
+                    rate_limit(&route, request.caller.as_deref())
+                        .map_err($crate::ledger::storage_api::Error::new)?;
+                }
+                $crate::ledger::queries::Router::handle(self, ctx, request)
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Handle a given request like `Router::handle`, but serve \
+                the response of a route that's cached in the `cache` from \
+                it, keyed by the matched route, its args and the block \
+                height (see `router::ResponseCache`), instead of invoking \
+                the handler again. This must be invoked on the root router."]
+            pub fn handle_cached<D, H>(
+                &self,
+                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
+                request: &$crate::ledger::queries::RequestQuery,
+                cache: &$crate::ledger::queries::router::ResponseCache<
+                    [<$name:camel Route>]>,
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+            {
+                match self.route_id(&request.path) {
+                    Some(route) if cache.caches(&route) => {
+                        let args = self
+                            .match_info(&request.path)
+                            .map(|info| {
+                                info.args.into_iter().map(|(_, arg)| arg).collect()
+                            })
+                            .unwrap_or_default();
+                        let last_height = ctx.storage.last_height;
+                        cache.get_or_handle(route, args, request, last_height, || {
+                            $crate::ledger::queries::Router::handle(self, ctx, request)
+                        })
+                    }
+                    _ => $crate::ledger::queries::Router::handle(self, ctx, request),
+                }
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Handle a given request like `Router::handle` and then \
+                apply the `transformers` in order to its response with the \
+                matched route, each to the response of the previous one. A \
+                failed request's error is returned as is, without applying \
+                the transformers. This must be invoked on the root router."]
+            pub fn handle_transformed<D, H>(
+                &self,
+                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
+                request: &$crate::ledger::queries::RequestQuery,
+                transformers: &[$crate::ledger::queries::router::ResponseTransformer<
+                    [<$name:camel Route>]>],
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+            {
+                let route = self.route_id(&request.path);
+                let response =
+                    $crate::ledger::queries::Router::handle(self, ctx, request)?;
+                Ok(match route {
+                    Some(route) => transformers
+                        .iter()
+                        .fold(response, |response, transform| {
+                            transform(&route, response)
+                        }),
+                    None => response,
+                })
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Handle a given request like `Router::handle`, but only \
+                construct its context with the `build_ctx` once the path is \
+                matched by some route, so that a path that doesn't match any \
+                route is rejected with `Error::WrongPath` without the cost of \
+                the context setup. This must be invoked on the root router."]
+            pub fn dispatch_with_ctx_builder<'shell, D, H, BUILD>(
+                &self,
+                request: &$crate::ledger::queries::RequestQuery,
+                build_ctx: BUILD,
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+                BUILD: FnOnce() -> $crate::ledger::queries::RequestCtx<'shell, D, H>,
+            {
+                let path = $crate::ledger::queries::router::normalize_path(
+                    &request.path);
+                let segments = $crate::ledger::queries::router::split_request_path(
+                    &path).map_err($crate::ledger::storage_api::Error::new)?;
+                let query =
+                    $crate::ledger::queries::router::split_query(&path).1;
+                if self.internal_route_id(&segments, 0, query).is_none()
+                    && !(self.meta_routes
+                        && $crate::ledger::queries::router::is_routes_meta_path(
+                            &segments))
+                {
+                    return Err($crate::ledger::storage_api::Error::new(
+                        $crate::ledger::queries::router::Error::WrongPath(
+                            request.path.clone())));
+                }
+                $crate::ledger::queries::Router::handle_segments(
+                    self, build_ctx(), request, &segments)
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Get the information about every leaf route of this \
+                router, including its sub-routers' routes, except for the \
+                `#[internal]` routes."]
+            pub fn routes(&self) -> Vec<$crate::ledger::queries::router::RouteInfo> {
+                self.all_routes()
+                    .into_iter()
+                    .filter(|route| !route.internal)
+                    .collect()
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Describe every leaf route of this router, as listed by \
+                `routes`, in a JSON array of the routes' \
+                [`RouteInfo::to_json`] objects (e.g. to feed external \
+                tooling)."]
+            pub fn routes_json(&self) -> $crate::ledger::queries::router::__private::serde_json::Value {
+                $crate::ledger::queries::router::__private::serde_json::Value::Array(
+                    self.routes()
+                        .iter()
+                        .map($crate::ledger::queries::router::RouteInfo::to_json)
+                        .collect(),
+                )
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Get the information about the `#[internal]` leaf \
+                routes of this router, including its sub-routers' routes, \
+                which are not listed by `routes`."]
+            pub fn internal_routes(&self)
+                -> Vec<$crate::ledger::queries::router::RouteInfo> {
+                self.all_routes()
+                    .into_iter()
+                    .filter(|route| route.internal)
+                    .collect()
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Get the information about every leaf route of this \
+                router, including its sub-routers' routes and the \
+                `#[internal]` routes."]
+            #[allow(clippy::vec_init_then_push)]
+            pub fn all_routes(&self) -> Vec<$crate::ledger::queries::router::RouteInfo> {
+                #[allow(unused_mut)]
+                let mut routes = vec![];
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        $crate::push_route_infos!(self, routes,
+                            $crate::route_internal!([ $( #[ $( $attr )* ] )* ]),
+                            $crate::route_deprecated!([ $( #[ $( $attr )* ] )* ]),
+                            [], ( $( $pattern )* ), [ $( $return_type )? ],
+                            $handle);
+                    }
+                )*
+                routes
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Write a table of every leaf route of this router, \
+                including its sub-routers' routes, with the route's \
+                template, handler and args (e.g. for a CLI's help output)."]
+            pub fn print_routes(&self, out: &mut impl std::io::Write)
+                -> std::io::Result<()> {
+                $crate::ledger::queries::router::write_routes_table(
+                    out, &self.routes())
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Write a protobuf definition of a gRPC `service` in the \
+                    `package` with an RPC for every leaf route of this router, \
+                    including its sub-routers' routes, with request and response \
+                    messages derived from the route's args and response type."]
+                pub fn print_proto_service(&self, out: &mut impl std::io::Write,
+                    package: &str, service: &str) -> std::io::Result<()> {
+                    $crate::ledger::queries::router::write_proto_service(
+                        out, package, service, &self.routes())
+                }
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Write a TypeScript client module with a function for \
+                    every leaf route of this router, including its sub-routers' \
+                    routes, with the route's typed args as parameters and a \
+                    borsh decoding of its response."]
+                pub fn print_ts_client(&self, out: &mut impl std::io::Write)
+                    -> std::io::Result<()> {
+                    $crate::ledger::queries::router::write_ts_client(
+                        out, &self.routes())
+                }
+            }
+
+            $crate::cfg_tooling! {
+                #[allow(dead_code)]
+                #[doc = "Render the route tree of every leaf route of this \
+                    router, including its sub-routers' routes, as a graphviz DOT \
+                    digraph, with the literal segments and the dynamic args as \
+                    distinct node styles."]
+                pub fn to_dot(&self) -> String {
+                    let mut buf = vec![];
+                    $crate::ledger::queries::router::write_dot(
+                        &mut buf, stringify!($name), &self.routes())
+                        .expect("Writing into a `Vec` cannot fail");
+                    String::from_utf8(buf)
+                        .expect("The DOT graph must be valid UTF-8")
+                }
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Get the information about the leaf routes of this \
+                router, including its sub-routers' routes, whose template is \
+                under the given `prefix` path (e.g. `/b/3`)."]
+            pub fn routes_under(&self, prefix: &str)
+                -> Vec<$crate::ledger::queries::router::RouteInfo> {
+                self.routes()
+                    .into_iter()
+                    .filter(|route| route.is_under(prefix))
+                    .collect()
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Get the ordered params of the route with the given \
+                `handler` name, including the args of its sub-router \
+                prefixes, or `None` if there is no such route."]
+            pub fn route_params(&self, handler: &str)
+                -> Option<Vec<$crate::ledger::queries::router::ParamSpec>> {
+                self.routes()
+                    .into_iter()
+                    .find(|route| route.handler == handler)
+                    .map(|route| route.args)
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Internal method which shouldn't be invoked directly. \
+                Instead, you may want to call `self.route_id()`.\n\n\
+                Find the route that matches the given path `segments`, \
+                starting to try to match them against the router's patterns \
+                at the given `start` segment index. The path's `query` \
+                string, if any, allows to omit the literal segments between \
+                optional args."]
+            #[allow(unused_variables)]
+            pub fn internal_route_id(
+                &self,
+                segments: &[&str],
+                start: usize,
+                query: Option<&str>,
+            ) -> Option<[<$name:camel Route>]> {
+                // An index past the end of the segments matches no route
+                segments.get(start..)?;
+                // Skip the optional version segment of a versioned router
+                let start = match $crate::ledger::queries::router::match_version(
+                    self.versioned, segments, start) {
+                    Some(_) => $crate::ledger::queries::router::checked_advance_segment(
+                        segments, start)?,
+                    None => start,
+                };
+
+                // Count the remaining segments to be matched
+                let segment_count = $crate::ledger::queries::router::segment_count(
+                    segments.get(start..).unwrap_or_default());
+                // The parse failures are not reported when only resolving the
+                // route
+                #[allow(unused_mut, unused_variables)]
+                let mut failure: Option<$crate::ledger::queries::router::Error> = None;
+
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        // This loop never repeats, it's only used for a
+                        // breaking mechanism when a $pattern is not matched
+                        // to skip to the next one, if any
+                        loop {
+                            // Skip the pattern early if it cannot match the
+                            // path's segment count
+                            const BOUNDS: $crate::ledger::queries::router::SegmentBounds =
+                                $crate::segment_bounds!(( $( $pattern )* ), $handle);
+                            if !BOUNDS.contains(segment_count) {
+                                break;
+                            }
+                            let mut start = start;
+                            // Try to match and parse args, will break the
+                            // `loop` not matched. The `query` is passed in
+                            // place of the request, which is not used when
+                            // only resolving the route.
+                            $crate::try_match!(match_route_id, [<$name:camel Route>],
+                                query, segments, query, start, failure,
+                                [ $( #[ $( $attr )* ] )* ], $handle,
+                                ( $( $pattern )* ));
+                        }
+                    }
+                )*
+
+                None
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Internal method which shouldn't be invoked directly. \
+                Instead, you may want to call `self.match_info()`.\n\n\
+                Find the route that matches the given path `segments` with \
+                the values of its args, starting at the given `start` \
+                segment index like `internal_route_id`."]
+            #[allow(unused_variables)]
+            pub fn internal_match_info(
+                &self,
+                segments: &[&str],
+                start: usize,
+                query: Option<&str>,
+            ) -> Option<$crate::ledger::queries::router::MatchInfo> {
+                // Skip the optional version segment of a versioned router
+                let start = match $crate::ledger::queries::router::match_version(
+                    self.versioned, segments, start) {
+                    Some(_) => $crate::ledger::queries::router::advance_segment(
+                        segments, start),
+                    None => start,
+                };
+
+                // Count the remaining segments to be matched
+                let segment_count = $crate::ledger::queries::router::segment_count(
+                    segments.get(start..).unwrap_or_default());
+                // The parse failures are not reported when only resolving the
+                // route
+                #[allow(unused_mut, unused_variables)]
+                let mut failure: Option<$crate::ledger::queries::router::Error> = None;
+
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        // This loop never repeats, it's only used for a
+                        // breaking mechanism when a $pattern is not matched
+                        // to skip to the next one, if any
+                        loop {
+                            const BOUNDS: $crate::ledger::queries::router::SegmentBounds =
+                                $crate::segment_bounds!(( $( $pattern )* ), $handle);
+                            if !BOUNDS.contains(segment_count) {
+                                break;
+                            }
+                            let mut start = start;
+                            // The `query` is passed in place of the route type
+                            // and the request, which are not used
+                            $crate::try_match!(match_info, query, query, segments,
+                                query, start, failure,
+                                [ $( #[ $( $attr )* ] )* ], $handle,
+                                ( $( $pattern )* ));
+                        }
+                    }
+                )*
+
+                None
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Internal method which shouldn't be invoked directly. \
+                Instead, you may want to call `self.match_all()`.\n\n\
+                Collect all the routes that match the given path `segments` \
+                into the `matches`, starting at the given `start` segment \
+                index like `internal_match_info`."]
+            #[allow(unused_variables)]
+            pub fn internal_match_all(
+                &self,
+                segments: &[&str],
+                start: usize,
+                query: Option<&str>,
+                matches: &mut Vec<$crate::ledger::queries::router::MatchInfo>,
+            ) {
+                // Skip the optional version segment of a versioned router
+                let start = match $crate::ledger::queries::router::match_version(
+                    self.versioned, segments, start) {
+                    Some(_) => $crate::ledger::queries::router::advance_segment(
+                        segments, start),
+                    None => start,
+                };
+
+                // Count the remaining segments to be matched
+                let segment_count = $crate::ledger::queries::router::segment_count(
+                    segments.get(start..).unwrap_or_default());
+
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        // Every pattern is tried in its own closure, from
+                        // which its matches are returned
+                        #[allow(clippy::redundant_closure_call)]
+                        matches.extend((|| {
+                            // The parse failures are not reported when only
+                            // resolving the routes
+                            #[allow(unused_mut, unused_variables)]
+                            let mut failure: Option<$crate::ledger::queries::router::Error> = None;
+                            // This loop never repeats, it's only used for a
+                            // breaking mechanism when a $pattern is not matched
+                            loop {
+                                const BOUNDS: $crate::ledger::queries::router::SegmentBounds =
+                                    $crate::segment_bounds!(( $( $pattern )* ), $handle);
+                                if !BOUNDS.contains(segment_count) {
+                                    break;
+                                }
+                                let mut start = start;
+                                // The `matches` are passed in place of the
+                                // route type, but they're not used
+                                $crate::try_match!(match_all, matches, query, segments,
+                                    query, start, failure,
+                                    [ $( #[ $( $attr )* ] )* ], $handle,
+                                    ( $( $pattern )* ));
+                            }
+                            vec![]
+                        })());
+                    }
+                )*
+            }
+
+            #[doc = "Internal method which shouldn't be invoked from \
+                outside this module.\n\n\
+                Instead, you may want to call `self.peek()`.\n\n\
+                Resolve the route that matches the given path `segments` \
+                like `internal_match_info` or otherwise collect the failures \
+                of every pattern, starting at the given `start` segment \
+                index."]
+            #[allow(unused_variables)]
+            pub fn internal_peek(
+                &self,
+                segments: &[&str],
+                start: usize,
+                query: Option<&str>,
+            ) -> std::result::Result<
+                $crate::ledger::queries::router::MatchInfo,
+                Vec<$crate::ledger::queries::router::CandidateFailure>,
+            > {
+                // Skip the optional version segment of a versioned router
+                let start = match $crate::ledger::queries::router::match_version(
+                    self.versioned, segments, start) {
+                    Some(_) => $crate::ledger::queries::router::advance_segment(
+                        segments, start),
+                    None => start,
+                };
+
+                #[allow(unused_mut)]
+                let mut candidates = vec![];
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        {
+                            let mut failure =
+                                $crate::ledger::queries::router::CandidateFailures::new(
+                                    $crate::pattern_template!(( $( $pattern )* )));
+                            // Every pattern is tried in its own closure, from
+                            // which its match is returned. The patterns are
+                            // not skipped early on the segment count, so that
+                            // their failures are recorded.
+                            #[allow(clippy::redundant_closure_call)]
+                            let info = (|| {
+                                // This loop never repeats, it's only used for
+                                // a breaking mechanism when a $pattern is not
+                                // matched
+                                loop {
+                                    let mut start = start;
+                                    // The `failure` is passed in place of the
+                                    // route type too
+                                    $crate::try_match!(peek_match, failure, query,
+                                        segments, query, start, failure,
+                                        [ $( #[ $( $attr )* ] )* ], $handle,
+                                        ( $( $pattern )* ));
+                                }
+                                None
+                            })();
+                            if let Some(info) = info {
+                                return Ok(info);
+                            }
+                            candidates.extend(failure.into_failures());
+                        }
+                    }
+                )*
+                Err(candidates)
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Get the compact id and the handler of every route of \
+                this router with the `#[compact = id]` attribute, including \
+                its sub-routers' routes."]
+            #[allow(clippy::vec_init_then_push)]
+            pub fn compact_routes(&self) -> Vec<(u16, &'static str)> {
+                #[allow(unused_mut)]
+                let mut routes = vec![];
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        $crate::compact_route!((@list routes),
+                            [ $( #[ $( $attr )* ] )* ], ( $( $pattern )* ),
+                            $handle);
+                    }
+                )*
+                routes
+            }
+
+            #[allow(dead_code, unused_variables)]
+            #[doc = "Find the handler of the route with the given compact \
+                id, including its sub-routers' routes, if any."]
+            pub fn compact_handler(&self, route: u16) -> Option<&'static str> {
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        $crate::compact_route!((@handler route),
+                            [ $( #[ $( $attr )* ] )* ], ( $( $pattern )* ),
+                            $handle);
+                    }
+                )*
+                None
+            }
+
+            #[allow(dead_code)]
+            #[doc = "Handle a request of the compact protocol, whose data is \
+                a borsh-encoded `router::CompactRequest`, by calling the \
+                handler of the route with the request's compact id directly, \
+                without matching a path. The request's path is ignored and \
+                the response is always borsh-encoded. This must be invoked \
+                on the root router."]
+            pub fn handle_compact<D, H>(
+                &self,
+                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
+                request: &$crate::ledger::queries::RequestQuery,
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+            {
+                use $crate::ledger::storage_api::ResultExt;
+
+                $crate::ledger::queries::require_if_height(&ctx, request)?;
+                let compact: $crate::ledger::queries::router::CompactRequest =
+                    $crate::ledger::queries::router::__private::borsh::BorshDeserialize::try_from_slice(&request.data)
+                        .into_storage_result()?;
+                self.internal_handle_compact(ctx, request, compact.route,
+                    &compact.args)
+            }
+
+            #[allow(dead_code, unused_variables)]
+            #[doc = "Internal method which shouldn't be invoked directly. \
+                Instead, you may want to call `self.handle_compact()`.\n\n\
+                Call the handler of the route with the given compact id with \
+                its borsh-encoded `args`."]
+            pub fn internal_handle_compact<D, H>(
+                &self,
+                ctx: $crate::ledger::queries::RequestCtx<'_, D, H>,
+                request: &$crate::ledger::queries::RequestQuery,
+                route: u16,
+                args: &[u8],
+            ) -> $crate::ledger::storage_api::Result<$crate::ledger::queries::EncodedResponseQuery>
+            where
+                D: 'static + $crate::ledger::storage::DB + for<'iter> $crate::ledger::storage::DBIter<'iter> + Sync,
+                H: 'static + $crate::ledger::storage::StorageHasher + Sync,
+            {
+                // Import for `.into_storage_result()`
+                #[allow(unused_imports)]
+                use $crate::ledger::storage_api::ResultExt;
+
+                $(
+                    $crate::route_cfg!{ [ $( #[ $( $attr )* ] )* ]
+                        $crate::compact_route!((@handle ctx request route args),
+                            [ $( #[ $( $attr )* ] )* ], ( $( $pattern )* ),
+                            $handle);
+                    }
+                )*
+
+                Err($crate::ledger::storage_api::Error::new(
+                    $crate::ledger::queries::router::Error::UnknownCompactRoute(
+                        route)))
+            }
+        }
+
+		#[doc = "`" $name "` path router"]
+		pub const $name: [<$name:camel>] = [<$name:camel>]::new();
+	}
+
+    );
+}
+
+/// Generate a `#[test]` that checks the self-consistency of the router
+/// `$name`: the sample path of every route, including its sub-routers' routes,
+/// as constructed by the generated `*_path` methods with the `SampleArg`
+/// values, must be matched by the router itself.
+///
+/// ```rust,ignore
+/// generate_router_tests!(ROOT);
+/// ```
+#[cfg(any(test, feature = "testing"))]
+#[macro_export]
+macro_rules! generate_router_tests {
+    ($name:ident) => {
+        $crate::ledger::queries::router::__private::paste::paste! {
+            #[test]
+            fn [<$name:lower _sample_paths_are_matched>]() {
+                let paths = $name.sample_paths();
+                assert!(!paths.is_empty(), "The router has no routes");
+                let unmatched: Vec<&String> = paths
+                    .iter()
+                    .filter(|path| $name.route_id(path).is_none())
+                    .collect();
+                assert!(
+                    unmatched.is_empty(),
+                    "The paths {:?} are not matched by `{}`",
+                    unmatched,
+                    stringify!($name)
+                );
+            }
+
+            #[test]
+            fn [<$name:lower _compact_ids_are_unique>]() {
+                let mut routes = $name.compact_routes();
+                routes.sort();
+                let duplicates: Vec<_> = routes
+                    .windows(2)
+                    .filter(|pair| pair[0].0 == pair[1].0)
+                    .collect();
+                assert!(
+                    duplicates.is_empty(),
+                    "The compact ids of the routes {:?} of `{}` are not unique",
+                    duplicates,
+                    stringify!($name)
+                );
+            }
+        }
+    };
+}
+
+/// You can expand the `handlers!` macro invocation with e.g.:
+/// ```shell
+/// cargo expand ledger::queries::router::test_rpc_handlers --features "ferveo-tpke, ibc-mocks, testing, wasm-runtime, tendermint-rpc" --tests --lib
+/// ```
+#[cfg(test)]
+mod test_rpc_handlers {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+    use namada_macros::router_handler;
+    use rust_decimal::Decimal;
+
+    use super::test_rpc::WideParams;
+    use crate::ledger::queries::{
+        Combined, Either, EncodedResponseQuery, HandlerError, InfoMessage,
+        KeyedProof, Optional, RequestCtx, RequestQuery, ResponseQuery, Warning,
+    };
+    use crate::ledger::storage::{DBIter, StorageHasher, DB};
+    use crate::ledger::storage_api::{self, ResultExt};
+    use crate::tendermint::merkle::proof::{Proof, ProofOp};
+    use crate::types::address::Address;
+    use crate::types::storage::{self, Epoch};
+    use crate::types::token;
+
+    /// A little macro to generate boilerplate for RPC handler functions.
+    /// These are implemented to return their name as a String, joined by
+    /// slashes with their argument values turned `to_string()`, if any.
+    macro_rules! handlers {
+        (
+            // name and params, if any
+            $( $name:ident $( ( $( $param:ident: $param_ty:ty ),* ) )? ),*
+            // optional trailing comma
             $(,)? ) => {
             $(
                 pub fn $name<D, H>(
@@ -868,223 +10005,5516 @@ mod test_rpc_handlers {
                     )* )?
                     Ok(data)
                 }
-            )*
+            )*
+        };
+    }
+
+    // Generate handler functions for the router below
+    handlers!(
+        a,
+        b0i,
+        b0ii,
+        b1,
+        b2i(balance: token::Amount),
+        b4,
+        balance,
+        b3(a1: token::Amount, a2: token::Amount, a3: token::Amount),
+        b3i(a1: token::Amount, a2: token::Amount, a3: token::Amount),
+        b3ii(a1: token::Amount, a2: token::Amount, a3: token::Amount),
+        d(dec: Decimal),
+        e(int: i64),
+        f,
+        g,
+        h(slug: &str),
+        sub_n(n: u64),
+        triple(a: u64, b: u64, c: u64),
+        validator_name,
+        limited(limit: u64),
+        x,
+        y(untyped_arg: &str),
+        z(untyped_arg: &str),
+        dump_state,
+        addr_raw(raw: &str, addr: Address),
+        ns(a: &str, b: &str, c: &str),
+        ratio_value(ratio: Ratio),
+        symbol_value(sym: &str),
+    );
+
+    /// A handler that always panics
+    pub fn panicking<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        panic!("The handler panicked")
+    }
+
+    /// Error returned by the handlers that sum amounts when the sum overflows
+    #[derive(Debug, thiserror::Error)]
+    #[error("The sum of the amounts overflows")]
+    pub struct AmountOverflowError;
+
+    /// This handler is hand-written, because the test helper macro doesn't
+    /// support arithmetic. The args come from the path, so the sum must be
+    /// checked for overflow.
+    pub fn b3sum<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        a1: token::Amount,
+        a2: token::Amount,
+        a3: token::Amount,
+    ) -> storage_api::Result<token::Amount>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        a1.checked_add(a2)
+            .and_then(|sum| sum.checked_add(a3))
+            .ok_or(AmountOverflowError)
+            .into_storage_result()
+    }
+
+    /// A lookup handler that finds an amount only for a non-zero `n`
+    pub fn lookup<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        n: u64,
+    ) -> storage_api::Result<Option<token::Amount>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok((n != 0).then(|| token::Amount::from(n)))
+    }
+
+    /// A handler with its signature generated by `#[router_handler]`, which
+    /// adds the arg to the last committed block height
+    #[router_handler]
+    pub fn attr_height(ctx: RequestCtx, n: u64) -> u64 {
+        ctx.storage
+            .last_height
+            .0
+            .checked_add(n)
+            .ok_or_else(|| storage_api::Error::new_const("Height overflow"))?
+    }
+
+    /// A handler that uses request with its signature generated by
+    /// `#[router_handler]`, which returns the requested block height
+    #[router_handler]
+    pub fn attr_request(
+        _ctx: RequestCtx,
+        request: &RequestQuery,
+    ) -> storage::BlockHeight {
+        request.height
+    }
+
+    /// A handler that finds a value by an id that's either a number or a
+    /// name
+    pub fn find<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        id: Either<u64, String>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(match id {
+            Either::Left(number) => format!("number {number}"),
+            Either::Right(name) => format!("name {name}"),
+        })
+    }
+
+    /// A handler that receives its args in the generated params struct
+    #[router_handler]
+    pub fn wide(_ctx: RequestCtx, params: WideParams) -> String {
+        let WideParams { a1, a2, name } = params;
+        match a2 {
+            Some(a2) => format!("wide/{a1}/{a2}/{name}"),
+            None => format!("wide/{a1}/{name}"),
+        }
+    }
+
+    /// A handler that receives its args in a map keyed by their names
+    #[router_handler]
+    pub fn proxy(
+        _ctx: RequestCtx,
+        args: std::collections::BTreeMap<String, String>,
+    ) -> String {
+        args.iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// A handler of a sub-router that reads the optional id of its prefix
+    #[router_handler]
+    pub fn acct_balance(ctx: RequestCtx) -> String {
+        match ctx.prefix_arg::<u64>("id") {
+            Some(id) => format!("balance of {id}"),
+            None => "balance of the default account".to_owned(),
+        }
+    }
+
+    /// A handler that returns the id of the request, if any
+    #[router_handler]
+    pub fn request_id(_ctx: RequestCtx, request: &RequestQuery) -> String {
+        request.request_id.clone().unwrap_or_default()
+    }
+
+    /// A handler that returns a list of numbers, borsh-encoded in three
+    /// chunks
+    pub fn dump<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+    ) -> storage_api::Result<impl Iterator<Item = Vec<u8>>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let numbers: Vec<u64> = (0..30).collect();
+        let bytes = numbers.try_to_vec().into_storage_result()?;
+        // The 244 encoded bytes are split into chunks of 100 bytes at most
+        let chunks: Vec<Vec<u8>> =
+            bytes.chunks(100).map(<[u8]>::to_vec).collect();
+        Ok(chunks.into_iter())
+    }
+
+    /// A handler that returns the rest of the path matched by a glob
+    pub fn watch<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        rest: &str,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(format!("watch {rest}"))
+    }
+
+    /// A handler that returns the bytes decoded from a base64url segment
+    pub fn bytes<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        tok: Vec<u8>,
+    ) -> storage_api::Result<Vec<u8>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(tok)
+    }
+
+    /// An `async` handler that awaits its result from another thread
+    pub async fn async_add<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        a: u64,
+        b: u64,
+    ) -> storage_api::Result<u64>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        std::thread::spawn(move || sender.send(a.checked_add(b)));
+        receiver
+            .await
+            .into_storage_result()?
+            .ok_or_else(|| storage_api::Error::new_const("The sum overflows"))
+    }
+
+    /// A handler of a route that's only for the node's internal use
+    pub fn internal_stats<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok("internal_stats".to_owned())
+    }
+
+    /// A handler that returns the version of a versioned router's request,
+    /// if any
+    pub fn api_version<D, H>(
+        ctx: RequestCtx<'_, D, H>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(match ctx.version {
+            Some(version) => format!("api_version/{version}"),
+            None => "api_version".to_owned(),
+        })
+    }
+
+    /// The number of calls of the `counted` handler
+    pub static COUNTED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A handler that counts its calls, e.g. to check that its responses are
+    /// cached
+    pub fn counted<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        n: u64,
+    ) -> storage_api::Result<u64>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        COUNTED_CALLS.fetch_add(1, Ordering::SeqCst);
+        Ok(n * 2)
+    }
+
+    /// A handler of an aggregate route, which combines the balance, stake
+    /// and rewards of an owner into one response
+    pub fn portfolio<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        owner: u64,
+    ) -> storage_api::Result<Combined<(u64, u64, String)>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let balance = owner * 100;
+        let stake = owner * 10;
+        let rewards = format!("{owner} NAM");
+        Ok(combine!(balance, stake, rewards))
+    }
+
+    /// A handler that returns the segments of a storage key
+    pub fn key_segments<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        key: storage::Key,
+    ) -> storage_api::Result<Vec<String>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(key.segments.iter().map(ToString::to_string).collect())
+    }
+
+    /// A handler that takes longer than the slow query threshold set in the
+    /// test
+    pub fn slow<D, H>(_ctx: RequestCtx<'_, D, H>) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        Ok("slow".to_owned())
+    }
+
+    /// A domain error of the `div` handler
+    #[derive(
+        Debug,
+        Clone,
+        PartialEq,
+        Eq,
+        BorshSerialize,
+        BorshDeserialize,
+        BorshSchema,
+    )]
+    pub enum DivError {
+        /// Division by zero
+        DivisionByZero,
+    }
+
+    /// This handler is hand-written, because the test helper macro doesn't
+    /// support domain errors.
+    pub fn div<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        a: u64,
+        b: u64,
+    ) -> Result<u64, HandlerError<DivError>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        a.checked_div(b)
+            .ok_or(HandlerError::Domain(DivError::DivisionByZero))
+    }
+
+    /// This handler is hand-written, because the test helper macro doesn't
+    /// support optional args.
+    pub fn search<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        limit: Option<u64>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data = "search".to_owned();
+        let data = limit.map(|limit| format!("{data}/{limit}")).unwrap_or(data);
+        Ok(data)
+    }
+
+    pub fn b3iii<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        a1: token::Amount,
+        a2: token::Amount,
+        a3: Option<token::Amount>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data = "b3iii".to_owned();
+        let data = format!("{data}/{}", a1);
+        let data = format!("{data}/{}", a2);
+        let data = a3.map(|a3| format!("{data}/{}", a3)).unwrap_or(data);
+        Ok(data)
+    }
+
+    /// This handler is hand-written, because the test helper macro doesn't
+    /// support optional args.
+    pub fn b3iiii<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        a1: token::Amount,
+        a2: token::Amount,
+        a3: Option<token::Amount>,
+        a4: Option<Epoch>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data = "b3iiii".to_owned();
+        let data = format!("{data}/{}", a1);
+        let data = format!("{data}/{}", a2);
+        let data = a3.map(|a3| format!("{data}/{}", a3)).unwrap_or(data);
+        let data = a4.map(|a4| format!("{data}/{}", a4)).unwrap_or(data);
+        Ok(data)
+    }
+
+    /// This handler is hand-written, because the test helper macro doesn't
+    /// support handlers with `with_options`.
+    pub fn c<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data = "c".to_owned().try_to_vec().into_storage_result()?;
+        Ok(ResponseQuery {
+            data,
+            ..ResponseQuery::default()
+        })
+    }
+
+    /// A handler of a deprecated route that redirects to the `sub` route,
+    /// with an `info` about the move.
+    pub fn moved_sub<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+        n: u64,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let path = super::test_rpc::TEST_REDIRECT_RPC.sub_n_path(&n);
+        let info = format!("Moved to {path}");
+        Ok(ResponseQuery::redirect(path).with_info(info))
+    }
+
+    /// A handler that succeeds with a value from a stale cache, which it
+    /// warns about.
+    pub fn stale_value<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data = 42_u64.try_to_vec().into_storage_result()?;
+        Ok(ResponseQuery::new(data)
+            .with_log("served from the cache")
+            .with_warning(Warning::new(
+                "stale_cache",
+                "The value is from a stale cache",
+            )))
+    }
+
+    /// A handler that tells an omitted amount from one that couldn't be
+    /// parsed.
+    pub fn amount_presence<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        amount: Optional<token::Amount>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(match amount {
+            Optional::Present(amount) => format!("present/{amount}"),
+            Optional::Absent => "absent".to_owned(),
+            Optional::Unparseable(segment) => format!("unparseable/{segment}"),
+        })
+    }
+
+    /// The entries found by the `scan` handler under the given `prefix`
+    pub fn scan_entries(prefix: &storage::Key) -> Vec<(storage::Key, u64)> {
+        (0..5)
+            .map(|i| {
+                let key = storage::Key::parse(format!("{prefix}/{i}")).unwrap();
+                (key, i * 10)
+            })
+            .collect()
+    }
+
+    /// A handler that scans the entries under a prefix and returns them in
+    /// chunks of 16 bytes, so that an entry may be split across chunks
+    pub fn scan<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        prefix: storage::Key,
+    ) -> storage_api::Result<impl Iterator<Item = Vec<u8>>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let bytes = scan_entries(&prefix).try_to_vec().into_storage_result()?;
+        let chunks: Vec<Vec<u8>> =
+            bytes.chunks(16).map(<[u8]>::to_vec).collect();
+        Ok(chunks.into_iter())
+    }
+
+    /// A handler that returns its optional search query's debug format.
+    pub fn search_query<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        q: Option<String>,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(format!("{q:?}"))
+    }
+
+    /// Whether the next call of the `flaky` handler fails
+    pub static FLAKY_FAILS: AtomicBool = AtomicBool::new(true);
+
+    /// A handler that fails transiently on every other call, hinting the
+    /// client to retry after 50ms, with an `info` about the failure
+    pub fn flaky<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        if FLAKY_FAILS.fetch_xor(true, Ordering::SeqCst) {
+            return Ok(ResponseQuery::retry_after(Duration::from_millis(50))
+                .with_info("Busy"));
+        }
+        let data = 42_u64.try_to_vec().into_storage_result()?;
+        Ok(ResponseQuery::new(data))
+    }
+
+    /// A handler that always fails transiently, hinting the client to retry
+    /// after 1ms
+    pub fn busy<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(ResponseQuery::retry_after(Duration::from_millis(1)))
+    }
+
+    /// A handler that redirects to its own route.
+    pub fn redirect_loop<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let path = super::test_rpc::TEST_REDIRECT_RPC.redirect_loop_path();
+        Ok(ResponseQuery::redirect(path))
+    }
+
+    /// A handler that sets the ABCI `log`, `code` and `codespace` of its
+    /// response
+    pub fn abci_fields<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data = "abci_fields"
+            .to_owned()
+            .try_to_vec()
+            .into_storage_result()?;
+        Ok(ResponseQuery {
+            data,
+            log: "abci_fields log".to_owned(),
+            code: 7,
+            codespace: "namada".to_owned(),
+            ..ResponseQuery::default()
+        })
+    }
+
+    /// A handler that sets a keyed info message to be localized by the client
+    pub fn keyed_info<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        _request: &RequestQuery,
+        n: u64,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data =
+            "keyed_info".to_owned().try_to_vec().into_storage_result()?;
+        Ok(ResponseQuery {
+            data,
+            info_message: Some(InfoMessage::new(
+                "items.found",
+                vec![n.to_string()],
+            )),
+            ..ResponseQuery::default()
+        })
+    }
+
+    /// A handler that returns a value of the requested block height, which
+    /// must not be above the last committed block height
+    pub fn height_value<D, H>(
+        ctx: RequestCtx<'_, D, H>,
+        request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let height = match request.height {
+            storage::BlockHeight(0) => ctx.storage.last_height,
+            height => height,
+        };
+        if height > ctx.storage.last_height {
+            return Err(storage_api::Error::new_const(
+                "The block height is not committed yet",
+            ));
+        }
+        let data = (height.0 * 100).try_to_vec().into_storage_result()?;
+        Ok(ResponseQuery {
+            data,
+            ..ResponseQuery::default()
+        })
+    }
+
+    /// A handler that proves two keys at once, if a proof is requested. The
+    /// proofs are made-up, because only their keys matter to the router.
+    pub fn two_proofs<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let data =
+            "two_proofs".to_owned().try_to_vec().into_storage_result()?;
+        let proofs = if request.prove {
+            ["validator/state", "validator/stake"]
+                .into_iter()
+                .map(|key| {
+                    let key = storage::Key::parse(key).into_storage_result()?;
+                    let op = ProofOp {
+                        field_type: "test".to_owned(),
+                        key: key.to_string().into_bytes(),
+                        data: vec![1, 2, 3],
+                    };
+                    Ok(KeyedProof {
+                        key,
+                        proof: Proof {
+                            ops: vec![op.clone(), op],
+                        },
+                    })
+                })
+                .collect::<storage_api::Result<_>>()?
+        } else {
+            vec![]
+        };
+        Ok(ResponseQuery {
+            data,
+            proofs,
+            ..ResponseQuery::default()
+        })
+    }
+
+    /// A ratio arg, whose `Display` (e.g. `3/4`) would be split into two
+    /// segments, so it's parsed from e.g. `3:4` instead
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Ratio {
+        pub num: u64,
+        pub den: u64,
+    }
+
+    impl std::fmt::Display for Ratio {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+
+    impl std::str::FromStr for Ratio {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (num, den) = s
+                .split_once(':')
+                .ok_or_else(|| format!("Missing `:` in ratio {s}"))?;
+            Ok(Self {
+                num: num.parse().map_err(|err| format!("{err}"))?,
+                den: den.parse().map_err(|err| format!("{err}"))?,
+            })
+        }
+    }
+
+    impl super::SampleArg for Ratio {
+        fn sample() -> Self {
+            Self { num: 1, den: 2 }
+        }
+    }
+
+    /// Format a ratio arg's path segment, which is parsed back by its
+    /// `FromStr`
+    pub fn format_ratio(ratio: &Ratio) -> String {
+        format!("{}:{}", ratio.num, ratio.den)
+    }
+
+    /// Format a ratio arg's path segment with its `Display`, which isn't
+    /// parsed back by its `FromStr`
+    pub fn format_ratio_broken(ratio: &Ratio) -> String {
+        ratio.to_string()
+    }
+
+    /// A color arg, which is converted from its segment with `TryFrom<&str>`
+    /// and doesn't implement `FromStr`
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Color {
+        Red,
+        Green,
+    }
+
+    impl std::fmt::Display for Color {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Red => write!(f, "red"),
+                Self::Green => write!(f, "green"),
+            }
+        }
+    }
+
+    impl TryFrom<&str> for Color {
+        type Error = String;
+
+        fn try_from(s: &str) -> Result<Self, Self::Error> {
+            match s {
+                "red" => Ok(Self::Red),
+                "green" => Ok(Self::Green),
+                _ => Err(format!("Unknown color {s}")),
+            }
+        }
+    }
+
+    impl super::SampleArg for Color {
+        fn sample() -> Self {
+            Self::Red
+        }
+    }
+
+    /// A handler that returns its color arg's debug format
+    pub fn paint<D, H>(
+        _ctx: RequestCtx<'_, D, H>,
+        color: Color,
+    ) -> storage_api::Result<String>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Ok(format!("paint {color:?}"))
+    }
+}
+
+/// You can expand the `router!` macro invocation with e.g.:
+/// ```shell
+/// cargo expand ledger::queries::router::test_rpc --features "ferveo-tpke, ibc-mocks, testing, wasm-runtime, tendermint-rpc" --tests --lib
+/// ```
+#[cfg(test)]
+pub(super) mod test_rpc {
+    use namada_macros::include_router;
+    use rust_decimal::Decimal;
+
+    use super::test_rpc_handlers::*;
+    use crate::ledger::queries::Combined;
+    use crate::types::address::Address;
+    use crate::types::storage::{self, BlockHeight, Epoch};
+    use crate::types::token;
+
+    /// A precomputed response served without a handler function
+    pub const PROTOCOL_VERSION: &str = "namada-test-1";
+
+    // Setup an RPC router for testing
+    router! {TEST_RPC,
+        ( "sub" ) = (sub TEST_SUB_RPC),
+        // Matched only if `TEST_SUB_RPC` has no route for the path
+        ( "sub" / [n: u64] ) -> String = sub_n,
+        ( "a" ) -> String = a,
+        ( "b" ) = {
+            ( "0" ) = {
+                ( "i" ) -> String = b0i,
+                ( "ii" ) -> String = b0ii,
+            },
+            #[cache(immutable)]
+            ( "1" ) -> String = b1,
+            ( "4" | "four" ) -> String = b4,
+            ( "2" ) = {
+                ( "i" / [balance: token::Amount] ) -> String = b2i,
+            },
+            ( "3" / [a1: token::Amount] / [a2: token::Amount] ) = {
+                ( "sum" / [a3: token::Amount] ) -> token::Amount = b3sum,
+                ( "i" / [a3: token:: Amount] ) -> String = b3i,
+                #[compact = 1]
+                ( [a3: token:: Amount] ) -> String = b3,
+                ( [a3: token:: Amount] / "ii" ) -> String = b3ii,
+                ( [a3: opt token::Amount] / "iii" ) -> String = b3iii,
+                ( "iiii" / [a3: opt token::Amount] / "xyz" / [a4: opt Epoch] ) -> String = b3iiii,
+            },
+        },
+        #[info = "Served by {route}"]
+        ( "c" ) -> String = (with_options c),
+        ( "d" / [dec: Decimal] ) -> String = d,
+        ( "e" / [int: i64] ) -> String = e,
+        #[info = "Served by {route}"]
+        #[cache(no_store)]
+        ( "f" ) -> String = f,
+        #[trailing_slash = false]
+        ( "g" ) -> String = g,
+        ( "h" / [slug: re "^[a-z0-9-]+$"] ) -> String = h,
+        #[domain_error]
+        ( "div" / [a: u64] / [b: u64] ) -> Result<u64, DivError> = div,
+        ( "validator" / [addr: Address] ) = (sub TEST_VALIDATOR_RPC),
+        #[separator = ':']
+        ( "ns" ) = (sub TEST_NS_RPC),
+        ( "limit" / [limit: opt u64 = 50] ) -> String = limited,
+        ( "search" / [limit: opt u64] ) -> String = search,
+        ( "slow" ) -> String = slow,
+        ( "addr" / [raw = addr: Address] ) -> String = addr_raw,
+        ( "bytes" / [tok: b64] ) -> Vec<u8> = bytes,
+        ( "watch" / [rest: **] ) -> String = watch,
+        ( "dump" ) -> Vec<u64> = (stream dump),
+        ( "lookup" / [n: u64] ) -> Option<token::Amount> = lookup,
+        #[compact = 2]
+        ( "find" / [id: u64 | String] ) -> String = find,
+        ( "balance" | "bal" | "bl" ) -> String = balance,
+        ( "wide" / [a1: token::Amount] / [a2: opt Epoch] ) = {
+            #[params]
+            ( "params" / [name] ) -> String = wide,
+        },
+        ( "two_proofs" ) -> String = (with_options two_proofs),
+        ( "height_value" ) -> u64 = (with_options height_value),
+        ( "abci_fields" ) -> String = (with_options abci_fields),
+        ( "keyed_info" / [n: u64] ) -> String = (with_options keyed_info),
+        ( "attr_height" / [n: u64] ) -> u64 = attr_height,
+        ( "attr_request" ) -> BlockHeight = (with_options attr_request),
+        ( "request_id" ) -> String = (with_options request_id),
+        ( "api_version" ) -> String = api_version,
+        #[internal]
+        ( "internal_stats" ) -> String = internal_stats,
+        ( "protocol_version" ) -> String = (const PROTOCOL_VERSION),
+        #[async_handler]
+        ( "async_add" / [a: u64] / [b: u64] ) -> u64 = async_add,
+    }
+
+    /// The `TEST_RPC` router that accepts an optional version segment
+    pub const TEST_VERSIONED_RPC: TestRpc = TestRpc::new_versioned();
+
+    // Check that every route's path is matched by the routers
+    generate_router_tests!(TEST_RPC);
+    generate_router_tests!(TEST_VERSIONED_RPC);
+
+    router! {TEST_VALIDATOR_RPC,
+        #[compact = 3]
+        ( "name" ) -> String = validator_name,
+    }
+
+    // A router with a handler that always panics
+    router! {TEST_PANIC_RPC,
+        ( "panic" ) -> String = panicking,
+        ( "a" ) -> String = a,
+    }
+
+    // A router with an arg that must satisfy a predicate. The sample of the
+    // arg doesn't satisfy it, so the router is not tested with
+    // `generate_router_tests`.
+    router! {TEST_WHERE_RPC,
+        ( "sub" / [n: u64 where n > 0] ) -> String = sub_n,
+        ( "limit" / [limit: u64 where limit <= 100] / "x" ) -> String = limited,
+    }
+
+    // A router with a deprecated route that has moved to a new path
+    router! {TEST_REDIRECT_RPC,
+        #[deprecated]
+        ( "old_sub" / [n: u64] ) -> String = (with_options moved_sub),
+        ( "sub" / [n: u64] ) -> String = sub_n,
+        ( "loop" ) -> String = (with_options redirect_loop),
+    }
+
+    // A router with handlers that fail transiently with a retry hint. The
+    // `flaky` handler's state is shared across the tests, so it has no
+    // generated tests.
+    router! {TEST_RETRY_RPC,
+        ( "flaky" ) -> u64 = (with_options flaky),
+        ( "busy" ) -> u64 = (with_options busy),
+    }
+
+    // A router with a handler whose response has a warning
+    router! {TEST_WARNING_RPC,
+        ( "stale" ) -> u64 = (with_options stale_value),
+    }
+
+    generate_router_tests!(TEST_WARNING_RPC);
+
+    // A router with a handler that receives its optional arg's presence
+    router! {TEST_PRESENCE_RPC,
+        #[presence]
+        ( "amount" / [amount: opt token::Amount] ) -> String = amount_presence,
+    }
+
+    generate_router_tests!(TEST_PRESENCE_RPC);
+
+    // A router with a route that matches an explicitly empty last segment
+    router! {TEST_EMPTY_SEGMENT_RPC,
+        #[empty_segment]
+        ( "search" / [q: opt String] ) -> String = search_query,
+    }
+
+    generate_router_tests!(TEST_EMPTY_SEGMENT_RPC);
+
+    // A router with a prefix scan that returns its entries in chunks
+    router! {TEST_SCAN_RPC,
+        ( "scan" / [prefix: storage::Key] )
+            -> Vec<(storage::Key, u64)> = (stream scan),
+    }
+
+    generate_router_tests!(TEST_SCAN_RPC);
+
+    // A router with a route that's only enabled by a node's capability
+    router! {TEST_CAPABILITY_RPC,
+        ( "a" ) -> String = a,
+        #[capability = "archive"]
+        ( "archived" ) -> String = b1,
+    }
+
+    generate_router_tests!(TEST_CAPABILITY_RPC);
+
+    // A router with a sub-router behind an optional arg
+    router! {TEST_OPT_PREFIX_RPC,
+        ( "acct" / [id: opt u64] ) = (sub TEST_ACCT_RPC),
+    }
+
+    generate_router_tests!(TEST_OPT_PREFIX_RPC);
+
+    router! {TEST_ACCT_RPC,
+        ( "balance" ) -> String = acct_balance,
+    }
+
+    // A router with integer args in a radix other than 10
+    router! {TEST_RADIX_RPC,
+        ( "item" / [id: u64 radix 16] ) -> String = sub_n,
+        ( "limit" / [limit: u64 radix 8] / "x" ) -> String = limited,
+        ( "mask" / [mask: u8 radix 2] ) = (sub TEST_SUB_RPC),
+    }
+
+    generate_router_tests!(TEST_RADIX_RPC);
+
+    // A router with an arg whose path segment is formatted with a custom
+    // function instead of its `Display`
+    router! {TEST_DISPLAY_RPC,
+        ( "ratio" / [ratio: Ratio, display = format_ratio] / "value" )
+            -> String = ratio_value,
+        ( "of" / [ratio: Ratio, display = format_ratio] )
+            = (sub TEST_SUB_RPC),
+    }
+
+    generate_router_tests!(TEST_DISPLAY_RPC);
+
+    // A router with an arg that's converted with `TryFrom<&str>`
+    router! {TEST_TRY_RPC,
+        ( "paint" / [color: try Color] ) -> String = paint,
+    }
+
+    generate_router_tests!(TEST_TRY_RPC);
+
+    // A router with an arg whose path segment is formatted with a broken
+    // custom function. Its paths are not matched back, so it has no
+    // generated tests.
+    router! {TEST_BROKEN_DISPLAY_RPC,
+        ( "ratio" / [ratio: Ratio, display = format_ratio_broken] / "value" )
+            -> String = ratio_value,
+    }
+
+    /// The symbols allowed in the paths of `TEST_ONEOF_RPC`, which can be
+    /// updated at run-time
+    pub static TOKEN_SET: once_cell::sync::Lazy<
+        std::sync::RwLock<std::collections::HashSet<String>>,
+    > = once_cell::sync::Lazy::new(|| {
+        std::sync::RwLock::new(
+            ["NAM", "BTC"].into_iter().map(str::to_owned).collect(),
+        )
+    });
+
+    // A router with an arg that's matched against a run-time set. Its sample
+    // paths are not in the set, so it has no generated tests.
+    router! {TEST_ONEOF_RPC,
+        ( "symbol" / [sym: oneof &TOKEN_SET] / "value" )
+            -> String = symbol_value,
+    }
+
+    // A router with a handler that counts its calls, whose responses can be
+    // cached
+    router! {TEST_CACHED_RPC,
+        ( "counted" / [n: u64] ) -> u64 = counted,
+    }
+
+    generate_router_tests!(TEST_CACHED_RPC);
+
+    // A router with a storage key arg, followed by a glob arg under the same
+    // literal that would match a malformed key. The glob arg's sample path is
+    // a valid key, so it has no generated tests.
+    router! {TEST_KEY_RPC,
+        ( "key" / [key: storage_key] ) -> Vec<String> = key_segments,
+        ( "key" / [rest: **] ) -> String = watch,
+    }
+
+    // A router with an aggregate route, whose response combines three results
+    router! {TEST_AGGREGATE_RPC,
+        ( "portfolio" / [owner: u64] ) -> Combined<(u64, u64, String)>
+            = portfolio,
+    }
+
+    generate_router_tests!(TEST_AGGREGATE_RPC);
+
+    // A router with a route with three typed args, whose path is built in a
+    // single buffer
+    router! {TEST_PATH_RPC,
+        ( "triple" / [a: u64] / [b: u64] / [c: u64] ) -> String = triple,
+    }
+
+    generate_router_tests!(TEST_PATH_RPC);
+
+    // A router with a route whose encoded response is limited in size
+    router! {TEST_MAX_SIZE_RPC,
+        #[max_size = 12]
+        ( "sub" / [n: u64] ) -> String = sub_n,
+    }
+
+    generate_router_tests!(TEST_MAX_SIZE_RPC);
+
+    // A router with a dynamic handler that receives its args in a map
+    router! {TEST_ARGS_MAP_RPC,
+        #[args_map]
+        ( "proxy" / [chain] / [module] / [height: opt u64] )
+            -> String = proxy,
+    }
+
+    // A router with overlapping routes, whose paths are matched by the first
+    // route and shadowed for the next one
+    router! {TEST_OVERLAP_RPC,
+        ( "item" / [id: u64 | String] ) -> String = find,
+        ( "item" / [n: u64] ) -> Option<token::Amount> = lookup,
+        ( "sub" ) = (sub TEST_SUB_RPC),
+        ( "sub" / "x" ) -> String = a,
+    }
+
+    // A sub-router whose paths' segments are separated by `:`
+    router! {TEST_NS_RPC,
+        ( [a] / [b] / [c] ) -> String = ns,
+    }
+
+    router! {TEST_SUB_RPC,
+        #[cache(immutable)]
+        ( "x" ) -> String = x,
+        ( "y" / [untyped_arg] ) -> String = y,
+        ( "z" / [untyped_arg] ) -> String = z,
+    }
+
+    // Setup an RPC router with routes that are conditionally compiled
+    router! {TEST_CFG_RPC,
+        ( "a" ) -> String = a,
+        #[cfg(feature = "tooling")]
+        ( "dump_state" ) -> String = dump_state,
+        ( "b" ) = {
+            ( "1" ) -> String = b1,
+            // The handler doesn't exist, so this only compiles because the
+            // route is never included in tests
+            #[cfg(not(test))]
+            ( "2" ) -> String = missing_handler,
+        },
+        #[cfg(not(test))]
+        ( "c" ) = {
+            ( "i" ) -> String = b0i,
+        },
+        #[cfg(not(test))]
+        ( "sub" ) = (sub TEST_SUB_RPC),
+    }
+
+    // Setup an RPC router from an external spec file
+    include_router!("src/ledger/queries/test_spec_rpc.rsrouter");
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+
+    use borsh::{BorshDeserialize, BorshSerialize};
+    use rust_decimal::Decimal;
+
+    use super::test_rpc::TEST_RPC;
+    use super::*;
+    use crate::ledger::queries::testing::TestClient;
+    use crate::ledger::queries::{
+        CompactProof, EncodedResponseQuery, InfoCatalog, InfoMessage,
+        InfoResolver, KeyedProof, RequestCtx, RequestQuery, ResponseMetadata,
+        Router, Warning,
+    };
+    use crate::ledger::storage_api;
+    use crate::types::storage::Epoch;
+    use crate::types::token;
+
+    /// Handle a request for the given path directly with the client's
+    /// router.
+    fn handle_path<RPC>(
+        client: &TestClient<RPC>,
+        path: &str,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        RPC: Router,
+    {
+        let request = RequestQuery {
+            path: path.to_owned(),
+            ..RequestQuery::default()
+        };
+        let ctx = client.ctx();
+        client.rpc.handle(ctx, &request)
+    }
+
+    /// Handle a request for the given path like [`handle_path`], but with
+    /// the router's `handle_async`.
+    async fn handle_path_async<RPC>(
+        client: &TestClient<RPC>,
+        path: &str,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        RPC: Router,
+    {
+        let request = RequestQuery {
+            path: path.to_owned(),
+            ..RequestQuery::default()
+        };
+        let ctx = client.ctx();
+        client.rpc.handle_async(ctx, &request).await
+    }
+
+    /// Test all the possible paths in `TEST_RPC` router.
+    #[tokio::test]
+    async fn test_router_macro() -> storage_api::Result<()> {
+        let client = TestClient::new(TEST_RPC);
+
+        // Test request with an invalid path
+        let request = RequestQuery {
+            path: "/invalid".to_owned(),
+            ..RequestQuery::default()
+        };
+        let ctx = client.ctx();
+        let result = TEST_RPC.handle(ctx, &request);
+        assert!(result.is_err());
+
+        // Test requests to valid paths using the router's methods
+
+        let result = TEST_RPC.a(&client).await.unwrap();
+        assert_eq!(result, "a");
+
+        let result = TEST_RPC.b0i(&client).await.unwrap();
+        assert_eq!(result, "b0i");
+
+        let result = TEST_RPC.b0ii(&client).await.unwrap();
+        assert_eq!(result, "b0ii");
+
+        let result = TEST_RPC.b1(&client).await.unwrap();
+        assert_eq!(result, "b1");
+
+        let balance = token::Amount::from(123_000_000);
+        let result = TEST_RPC.b2i(&client, &balance).await.unwrap();
+        assert_eq!(result, format!("b2i/{balance}"));
+
+        let a1 = token::Amount::from(345);
+        let a2 = token::Amount::from(123_000);
+        let a3 = token::Amount::from(1_000_999);
+        let result = TEST_RPC.b3(&client, &a1, &a2, &a3).await.unwrap();
+        assert_eq!(result, format!("b3/{a1}/{a2}/{a3}"));
+
+        let result = TEST_RPC.b3i(&client, &a1, &a2, &a3).await.unwrap();
+        assert_eq!(result, format!("b3i/{a1}/{a2}/{a3}"));
+
+        let result = TEST_RPC.b3ii(&client, &a1, &a2, &a3).await.unwrap();
+        assert_eq!(result, format!("b3ii/{a1}/{a2}/{a3}"));
+
+        let result =
+            TEST_RPC.b3iii(&client, &a1, &a2, &Some(a3)).await.unwrap();
+        assert_eq!(result, format!("b3iii/{a1}/{a2}/{a3}"));
+
+        let result = TEST_RPC.b3iii(&client, &a1, &a2, &None).await.unwrap();
+        assert_eq!(result, format!("b3iii/{a1}/{a2}"));
+
+        let result = TEST_RPC
+            .b3iiii(&client, &a1, &a2, &Some(a3), &None)
+            .await
+            .unwrap();
+        assert_eq!(result, format!("b3iiii/{a1}/{a2}/{a3}"));
+
+        let a4 = Epoch::from(10);
+        let result = TEST_RPC
+            .b3iiii(&client, &a1, &a2, &Some(a3), &Some(a4))
+            .await
+            .unwrap();
+        assert_eq!(result, format!("b3iiii/{a1}/{a2}/{a3}/{a4}"));
+
+        let result = TEST_RPC
+            .b3iiii(&client, &a1, &a2, &None, &None)
+            .await
+            .unwrap();
+        assert_eq!(result, format!("b3iiii/{a1}/{a2}"));
+
+        let result = TEST_RPC.c(&client, None, None, false).await.unwrap();
+        assert_eq!(result.data, format!("c"));
+
+        let result = TEST_RPC.test_sub_rpc().x(&client).await.unwrap();
+        assert_eq!(result, format!("x"));
+
+        let arg = "test123";
+        let result = TEST_RPC.test_sub_rpc().y(&client, arg).await.unwrap();
+        assert_eq!(result, format!("y/{arg}"));
+
+        let arg = "test321";
+        let result = TEST_RPC.test_sub_rpc().z(&client, arg).await.unwrap();
+        assert_eq!(result, format!("z/{arg}"));
+
+        // Test the raw queries under the routers' prefixes
+        let response = TEST_RPC
+            .raw(&client, "/a", None, None, false)
+            .await
+            .unwrap();
+        let result = String::try_from_slice(&response.data).unwrap();
+        assert_eq!(result, "a");
+
+        let response = TEST_RPC
+            .test_sub_rpc()
+            .raw(&client, "/y/test123", None, None, false)
+            .await
+            .unwrap();
+        let result = String::try_from_slice(&response.data).unwrap();
+        assert_eq!(result, "y/test123");
+
+        Ok(())
+    }
+    /// Test that signed integer and decimal args are parsed the same way
+    /// regardless of the system's locale and that the `*_path` constructors
+    /// emit the same canonical form that the matcher accepts.
+    #[tokio::test]
+    async fn test_router_macro_signed_and_decimal_args(
+    ) -> storage_api::Result<()> {
+        let client = TestClient::new(TEST_RPC);
+
+        // Decimal with a fractional part
+        let dec = Decimal::from_str("-1234.5678").unwrap();
+        let path = TEST_RPC.d_path(&dec);
+        assert_eq!(path, "/d/-1234.5678");
+        let result = TEST_RPC.d(&client, &dec).await.unwrap();
+        assert_eq!(result, format!("d/{dec}"));
+        let dec = Decimal::from_str("0.001").unwrap();
+        let result = TEST_RPC.d(&client, &dec).await.unwrap();
+        assert_eq!(result, "d/0.001");
+
+        // Negative and positive signed integers
+        for int in [-42_i64, 0, i64::MIN, i64::MAX] {
+            let path = TEST_RPC.e_path(&int);
+            assert_eq!(path, format!("/e/{int}"));
+            let result = TEST_RPC.e(&client, &int).await.unwrap();
+            assert_eq!(result, format!("e/{int}"));
+        }
+
+        // Locale-specific separators are not accepted
+        for path in ["/d/1,5", "/d/1,000.5", "/d/1 000", "/e/1,000", "/e/1.0"] {
+            let result = handle_path(&client, path);
+            assert!(result.is_err(), "{path} should not be matched");
+        }
+
+        Ok(())
+    }
+    /// Test that when a sub-router has no route for a path, the following
+    /// sibling patterns are tried.
+    #[test]
+    fn test_router_macro_sub_router_precedence() {
+        use super::test_rpc::{TestRpcRoute, TestSubRpcRoute};
+
+        let client = TestClient::new(TEST_RPC);
+
+        // The sub-router takes precedence
+        let response = handle_path(&client, "/sub/x").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "x");
+        assert_eq!(
+            TEST_RPC.route_id("/sub/x"),
+            Some(TestRpcRoute::TestSubRpc(TestSubRpcRoute::X))
+        );
+
+        // The sub-router has no route, so the sibling leaf is matched
+        let response = handle_path(&client, "/sub/123").unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "sub_n/123"
+        );
+        assert_eq!(TEST_RPC.route_id("/sub/123"), Some(TestRpcRoute::SubN));
+
+        // Neither matches
+        assert!(handle_path(&client, "/sub/abc").is_err());
+        assert_eq!(TEST_RPC.route_id("/sub/abc"), None);
+    }
+
+    /// Test a sub-router behind an optional arg, which is entered both with
+    /// and without the arg.
+    #[tokio::test]
+    async fn test_router_macro_opt_arg_sub_router() {
+        use super::test_rpc::TEST_OPT_PREFIX_RPC;
+        let client = TestClient::new(TEST_OPT_PREFIX_RPC);
+
+        let acct = TEST_OPT_PREFIX_RPC.test_acct_rpc(&Some(5));
+        assert_eq!(acct.acct_balance_path(), "/acct/5/balance");
+        let result = acct.acct_balance(&client).await.unwrap();
+        assert_eq!(result, "balance of 5");
+
+        let acct = TEST_OPT_PREFIX_RPC.test_acct_rpc(&None);
+        assert_eq!(acct.acct_balance_path(), "/acct/balance");
+        let result = acct.acct_balance(&client).await.unwrap();
+        assert_eq!(result, "balance of the default account");
+
+        // The absent arg may be supplied by its query param
+        let response = handle_path(&client, "/acct/balance?id=7").unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "balance of 7"
+        );
+
+        let routes = TEST_OPT_PREFIX_RPC.routes();
+        assert_eq!(routes[0].template, "/acct/{id?}/balance");
+        assert_eq!(routes[0].args[0].to_string(), "id: opt u64");
+    }
+
+    /// Test the detection of collisions of exclusive sub-routers' prefixes
+    /// with their sibling patterns.
+    #[test]
+    fn test_router_sub_router_prefix_collision() {
+        assert_eq!(pattern_leading_literals!(("a" / "b")), &["a", "b"]);
+        assert_eq!(pattern_leading_literals!(("a" / [x] / "b")), &["a"]);
+        let no_literals: &[&str] = pattern_leading_literals!(([x] / "a"));
+        assert!(no_literals.is_empty());
+
+        let sub: &[&str] = &["sub"];
+        // Only the sub-router's own pattern
+        assert!(!has_prefix_collision(sub, &[sub]));
+        // Siblings with different prefixes
+        assert!(!has_prefix_collision(sub, &[&["a"], sub, &["subb", "x"]]));
+        // A sibling leaf with the same prefix and an arg
+        assert!(has_prefix_collision(sub, &[sub, &["sub"]]));
+        // A sibling leaf that extends the prefix
+        assert!(has_prefix_collision(sub, &[sub, &["sub", "a"]]));
+        // A sibling with a dynamic first segment
+        assert!(has_prefix_collision(sub, &[&[], sub]));
+        // A sibling that is a prefix of a longer sub-router prefix
+        assert!(has_prefix_collision(&["a", "b"], &[&["a"], &["a", "b"]]));
+        assert!(!has_prefix_collision(
+            &["a", "b"],
+            &[&["a", "c"], &["a", "b"]]
+        ));
+
+        // The collision check can be evaluated at compile time
+        const _: () =
+            assert!(has_prefix_collision(&["sub"], &[&["sub"], &["sub"]]));
+    }
+
+    /// Test that the sample path of every route is dispatched to its route.
+    #[tokio::test]
+    async fn test_router_macro_sample_paths() {
+        use futures::StreamExt;
+
+        use super::test_rpc::{TestRpcRoute, TestSubRpcRoute};
+
+        let client = TestClient::new(TEST_RPC);
+
+        let paths: Vec<String> = TEST_RPC.sample_paths_stream().collect().await;
+        // Every route has a sample path
+        assert_eq!(paths.len(), 47);
+        assert!(paths.contains(&TEST_RPC.b3iiii_sample_path()));
+        assert_eq!(TEST_RPC.b2i_sample_path(), "/b/2/i/0");
+        assert_eq!(TEST_RPC.h_sample_path(), "/h/sample");
+        assert_eq!(TEST_RPC.b3iiii_sample_path(), "/b/3/0/0/iiii/0/xyz/0");
+
+        let mut routes = std::collections::HashSet::new();
+        for path in &paths {
+            let result = handle_path_async(&client, path).await;
+            assert!(result.is_ok(), "path {path}: {result:?}");
+            let route = TEST_RPC.route_id(path);
+            assert!(route.is_some(), "path {path}");
+            routes.insert(route);
+        }
+        // Every sample path is dispatched to a different route
+        assert_eq!(routes.len(), paths.len());
+        assert!(routes.contains(&Some(TestRpcRoute::SubN)));
+        assert!(routes
+            .contains(&Some(TestRpcRoute::TestSubRpc(TestSubRpcRoute::Y))));
+    }
+
+    /// Test that the sample paths for load testing have an entry for every
+    /// declared route, including the sub-routers' leaves.
+    #[test]
+    fn test_router_macro_all_sample_paths() {
+        use super::test_rpc::{TestRpc, TestSubRpc};
+
+        let paths = TestRpc::all_sample_paths();
+        assert_eq!(paths, TEST_RPC.sample_paths());
+        assert_eq!(paths.len(), TEST_RPC.all_routes().len());
+        // The sub-router's leaves are under its prefix
+        let sub_paths = TestSubRpc::all_sample_paths();
+        assert!(!sub_paths.is_empty());
+        for sub_path in sub_paths {
+            let path = format!("/sub{sub_path}");
+            assert!(paths.contains(&path), "path {path}");
+        }
+    }
+
+    /// Test that a handler's domain error is encoded in the response and
+    /// decoded by the client.
+    #[tokio::test]
+    async fn test_router_macro_domain_error() -> storage_api::Result<()> {
+        use super::test_rpc_handlers::DivError;
+
+        let client = TestClient::new(TEST_RPC);
+
+        let result = TEST_RPC.div(&client, &6, &3).await.unwrap();
+        assert_eq!(result, Ok(2));
+
+        let result = TEST_RPC.div(&client, &6, &0).await.unwrap();
+        assert_eq!(result, Err(DivError::DivisionByZero));
+
+        // The domain error is a successful response
+        let response = handle_path(&client, &TEST_RPC.div_path(&6, &0))?;
+        let decoded: Result<u64, DivError> =
+            BorshDeserialize::try_from_slice(&response.data).unwrap();
+        assert_eq!(decoded, Err(DivError::DivisionByZero));
+
+        // A storage error still fails the request
+        let result: Result<u64, HandlerError<DivError>> = Err(
+            HandlerError::Storage(storage_api::Error::new_const("failed")),
+        );
+        assert!(into_domain_result(result).is_err());
+
+        Ok(())
+    }
+
+    /// Test a sub-router mounted at a prefix with a typed arg.
+    #[tokio::test]
+    async fn test_router_macro_dynamic_sub_router_prefix() {
+        use super::test_rpc::{TestRpcRoute, TestValidatorRpcRoute};
+        use crate::types::address;
+
+        let client = TestClient::new(TEST_RPC);
+
+        let addr = address::nam();
+        let validator_rpc = TEST_RPC.test_validator_rpc(&addr);
+        assert_eq!(
+            validator_rpc.validator_name_path(),
+            format!("/validator/{addr}/name")
+        );
+        let result = validator_rpc.validator_name(&client).await.unwrap();
+        assert_eq!(result, "validator_name");
+        assert_eq!(
+            TEST_RPC.route_id(&validator_rpc.validator_name_path()),
+            Some(TestRpcRoute::TestValidatorRpc(
+                TestValidatorRpcRoute::ValidatorName
+            ))
+        );
+
+        // The prefix arg must be parsed
+        for path in ["/validator/not_an_address/name", "/validator/name"] {
+            assert!(handle_path(&client, path).is_err(), "path {path}");
+            assert!(TEST_RPC.route_id(path).is_none(), "path {path}");
+        }
+    }
+
+    /// Test a path segment bound to both a raw and a parsed handler arg.
+    #[tokio::test]
+    async fn test_router_macro_raw_and_parsed_arg() {
+        use crate::types::address;
+
+        let client = TestClient::new(TEST_RPC);
+
+        // The generated methods only take the parsed arg
+        let addr = address::nam();
+        assert_eq!(TEST_RPC.addr_raw_path(&addr), format!("/addr/{addr}"));
+        let result = TEST_RPC.addr_raw(&client, &addr).await.unwrap();
+        assert_eq!(result, format!("addr_raw/{addr}/{addr}"));
+
+        let route = TEST_RPC
+            .routes()
+            .into_iter()
+            .find(|route| route.handler == "addr_raw")
+            .unwrap();
+        assert_eq!(route.template, "/addr/{addr}");
+        assert_eq!(route.args.len(), 1);
+        assert_eq!(route.args[0].name, "addr");
+
+        // The raw segment must still be parsed
+        let err = handle_path(&client, "/addr/not_an_address").unwrap_err();
+        assert!(err.to_string().contains("not_an_address"), "{err}");
+        // The arg only matches a single segment
+        let path = format!("/addr/{addr}/x");
+        assert!(handle_path(&client, &path).is_err());
+    }
+
+    /// Test that the ABCI `log`, `code` and `codespace` set by a handler are
+    /// kept in the response.
+    #[tokio::test]
+    async fn test_router_macro_abci_fields() {
+        let client = TestClient::new(TEST_RPC);
+
+        let response =
+            handle_path(&client, &TEST_RPC.abci_fields_path()).unwrap();
+        assert_eq!(response.log, "abci_fields log");
+        assert_eq!(response.code, 7);
+        assert_eq!(response.codespace, "namada");
+        assert!(response.info.is_empty());
+
+        let response = TEST_RPC
+            .abci_fields(&client, None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, "abci_fields");
+        assert_eq!(response.log, "abci_fields log");
+        assert_eq!(response.code, 7);
+        assert_eq!(response.codespace, "namada");
+    }
+
+    /// Test requesting a value at a given block height.
+    #[tokio::test]
+    async fn test_router_macro_at_height() {
+        use crate::types::storage::BlockHeight;
+
+        let mut client = TestClient::new(TEST_RPC);
+        client.storage.last_height = BlockHeight(5);
+
+        for height in [1, 5] {
+            let result = TEST_RPC
+                .height_value_at_height(&client, BlockHeight(height))
+                .await
+                .unwrap();
+            assert_eq!(result, height * 100);
+        }
+        // The latest height
+        let result = TEST_RPC
+            .height_value_at_height(&client, BlockHeight(0))
+            .await
+            .unwrap();
+        assert_eq!(result, 500);
+        // The same as the request with options
+        let response = TEST_RPC
+            .height_value(&client, None, Some(BlockHeight(3)), false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, 300);
+
+        // A height that's not committed yet
+        client.max_height = Some(BlockHeight(5));
+        let result = TEST_RPC
+            .height_value_at_height(&client, BlockHeight(6))
+            .await;
+        assert!(result.is_err());
+    }
+
+    /// Test requesting a value at the block selected by a height selector.
+    #[tokio::test]
+    async fn test_router_macro_height_selector() {
+        use crate::ledger::queries::HeightSelector;
+        use crate::types::storage::BlockHeight;
+
+        let mut client = TestClient::new(TEST_RPC);
+        client.storage.last_height = BlockHeight(5);
+        client.finalized_height = Some(BlockHeight(4));
+
+        for (height, expected) in [
+            (HeightSelector::Latest, 500),
+            (HeightSelector::Finalized, 400),
+            (HeightSelector::Exact(BlockHeight(2)), 200),
+        ] {
+            let response = TEST_RPC
+                .height_value(&client, None, height, false)
+                .await
+                .unwrap();
+            assert_eq!(response.data, expected, "{height:?}");
+        }
+
+        // An optional height is the latest or an exact block
+        let response = TEST_RPC
+            .height_value(&client, None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, 500);
+        let response = TEST_RPC
+            .height_value(&client, None, Some(BlockHeight(3)), false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, 300);
+
+        // Without a finalized height, the last committed block is final
+        client.finalized_height = None;
+        let response = TEST_RPC
+            .height_value(&client, None, HeightSelector::Finalized, false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, 500);
+    }
+
+    /// Test a sub-router that splits its paths on a custom separator.
+    #[tokio::test]
+    async fn test_router_macro_custom_separator() {
+        use super::test_rpc::{TestNsRpcRoute, TestRpcRoute};
+
+        let client = TestClient::new(TEST_RPC);
+
+        let ns = TEST_RPC.test_ns_rpc();
+        assert_eq!(ns.ns_path("a", "b", "c"), "/ns/a:b:c");
+        let result = ns.ns(&client, "a", "b", "c").await.unwrap();
+        assert_eq!(result, "ns/a/b/c");
+        assert_eq!(
+            TEST_RPC.route_id("/ns/a:b:c"),
+            Some(TestRpcRoute::TestNsRpc(TestNsRpcRoute::Ns))
+        );
+
+        // The slashes don't separate the segments under the sub-router
+        let response = handle_path(&client, "/ns/x/y:b:c").unwrap();
+        let result = String::try_from_slice(&response.data).unwrap();
+        assert_eq!(result, "ns/x/y/b/c");
+        let path = "/ns/a:b:c:d";
+        assert!(handle_path(&client, path).is_err());
+        assert!(TEST_RPC.route_id(path).is_none());
+    }
+
+    /// Test a route that returns an `Option` with both `Some` and `None`.
+    #[tokio::test]
+    async fn test_router_macro_option_response() {
+        let client = TestClient::new(TEST_RPC);
+
+        let result = TEST_RPC.lookup(&client, &5).await.unwrap();
+        assert_eq!(result, Some(token::Amount::from(5)));
+        let result = TEST_RPC.lookup(&client, &0).await.unwrap();
+        assert_eq!(result, None);
+
+        // The absence is encoded distinctly from any value
+        let none = handle_path(&client, &TEST_RPC.lookup_path(&0)).unwrap();
+        assert_eq!(none.data, vec![0]);
+        let some = handle_path(&client, &TEST_RPC.lookup_path(&5)).unwrap();
+        assert_eq!(some.data[0], 1);
+        assert_eq!(
+            Option::<token::Amount>::try_from_slice(&some.data).unwrap(),
+            Some(token::Amount::from(5))
+        );
+    }
+
+    /// Test parsing a route's args from a path without handling it.
+    #[test]
+    fn test_router_validate_path() {
+        let amount = |micro: u64| token::Amount::from(micro);
+
+        let path = TEST_RPC.b3_path(&amount(1), &amount(2), &amount(3));
+        assert_eq!(
+            TEST_RPC.b3_validate_path(&path).unwrap(),
+            (amount(1), amount(2), amount(3))
+        );
+        // The path is normalized
+        assert_eq!(
+            TEST_RPC.b3_validate_path("b/3/1/2/3/").unwrap(),
+            (
+                token::Amount::whole(1),
+                token::Amount::whole(2),
+                token::Amount::whole(3)
+            )
+        );
+
+        // A malformed arg
+        let err = TEST_RPC.b3_validate_path("/b/3/1/x/3").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ParseFailed { segment, .. } if segment == "x"
+        ));
+        // A path of another route
+        let err = TEST_RPC.b3_validate_path("/b/3/1/2/3/ii").unwrap_err();
+        assert!(matches!(err, Error::ParseFailed { .. }));
+        let err = TEST_RPC.b3_validate_path("/b/1").unwrap_err();
+        assert!(matches!(err, Error::WrongPath(path) if path == "/b/1"));
+
+        // Other kinds of args
+        assert_eq!(TEST_RPC.a_validate_path("/a").unwrap(), ());
+        assert_eq!(
+            TEST_RPC.b3iii_validate_path("/b/3/1/2/iii").unwrap(),
+            (token::Amount::whole(1), token::Amount::whole(2), None)
+        );
+        assert_eq!(
+            TEST_RPC.addr_raw_validate_path("/addr/atest1x").unwrap(),
+            (
+                "atest1x".to_owned(),
+                crate::types::address::Address::from_str("atest1x").unwrap()
+            )
+        );
+        // A sub-router's route, under its prefix
+        assert_eq!(
+            TEST_RPC
+                .test_sub_rpc()
+                .y_validate_path("/sub/y/arg")
+                .unwrap(),
+            ("arg".to_owned(),)
+        );
+        assert!(TEST_RPC.test_sub_rpc().y_validate_path("/y/arg").is_err());
+    }
+
+    /// Test a union typed arg, which binds the first of its types that parses
+    /// the segment.
+    #[tokio::test]
+    async fn test_router_macro_union_arg() {
+        let client = TestClient::new(TEST_RPC);
+
+        let response = handle_path(&client, "/find/42").unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "number 42"
+        );
+        let response = handle_path(&client, "/find/alice").unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "name alice"
+        );
+
+        let id = Either::Right("bob".to_owned());
+        assert_eq!(TEST_RPC.find_path(&id), "/find/bob");
+        let result = TEST_RPC.find(&client, &id).await.unwrap();
+        assert_eq!(result, "name bob");
+        let result = TEST_RPC.find(&client, &Either::Left(7)).await.unwrap();
+        assert_eq!(result, "number 7");
+
+        let route = TEST_RPC
+            .routes()
+            .into_iter()
+            .find(|route| route.handler == "find")
+            .unwrap();
+        assert_eq!(route.args[0].ty, "u64 | String");
+    }
+
+    /// Test a route with a constant response, which is served without a
+    /// handler function.
+    #[tokio::test]
+    async fn test_router_macro_const_response() {
+        use super::test_rpc::{TestRpcRoute, PROTOCOL_VERSION};
+        use crate::ledger::queries::Client;
+
+        let client = TestClient::new(TEST_RPC);
+
+        let path = TEST_RPC.protocol_version_path();
+        assert_eq!(path, "/protocol_version");
+        let result = TEST_RPC.protocol_version(&client).await.unwrap();
+        assert_eq!(result, PROTOCOL_VERSION);
+
+        // The response is the borsh-encoded value, the same at any height
+        for height in [0, 1, 100] {
+            let response = client
+                .request(path.clone(), None, Some(BlockHeight(height)), false)
+                .await
+                .unwrap();
+            let decoded = String::try_from_slice(&response.data).unwrap();
+            assert_eq!(decoded, PROTOCOL_VERSION);
+        }
+
+        // A proof cannot be provided
+        let ctx = client.ctx();
+        let request = RequestQuery {
+            path: path.clone(),
+            prove: true,
+            ..RequestQuery::default()
+        };
+        assert!(TEST_RPC.handle(ctx, &request).is_err());
+
+        assert_eq!(
+            TEST_RPC.route_id(&path),
+            Some(TestRpcRoute::ProtocolVersion)
+        );
+        let route = TEST_RPC
+            .routes()
+            .into_iter()
+            .find(|route| route.handler == "protocol_version")
+            .unwrap();
+        assert_eq!(route.template, "/protocol_version");
+    }
+
+    /// Test handlers with their signatures generated by `#[router_handler]`.
+    #[tokio::test]
+    async fn test_router_macro_router_handler() {
+        let mut client = TestClient::new(TEST_RPC);
+        client.storage.last_height = BlockHeight(10);
+
+        let result = TEST_RPC.attr_height(&client, &5).await.unwrap();
+        assert_eq!(result, 15);
+        // The handler's error is propagated
+        assert!(handle_path(&client, &TEST_RPC.attr_height_path(&u64::MAX))
+            .is_err());
+
+        let response = TEST_RPC
+            .attr_request(&client, None, Some(BlockHeight(7)), false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, BlockHeight(7));
+    }
+
+    /// Test that the id of a request is propagated from the client to the
+    /// handler.
+    #[tokio::test]
+    async fn test_router_request_id() {
+        let mut client = TestClient::new(TEST_RPC);
+
+        // No id by default
+        let response = TEST_RPC
+            .request_id(&client, None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, "");
+
+        client.request_id = Some("req-1".to_owned());
+        let response = TEST_RPC
+            .request_id(&client, None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, "req-1");
+
+        // The id is set when the request is handled directly
+        let request = RequestQuery {
+            path: TEST_RPC.request_id_path(),
+            request_id: Some("req-2".to_owned()),
+            ..RequestQuery::default()
+        };
+        let ctx = client.ctx();
+        let response = TEST_RPC.handle(ctx, &request).unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "req-2");
+    }
+
+    /// Test a glob arg that matches the rest of the path at any depth.
+    #[tokio::test]
+    async fn test_router_macro_glob_arg() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+
+        for (path, rest) in [
+            ("/watch/a/b/c", "a/b/c"),
+            ("/watch/a", "a"),
+            ("/watch", ""),
+            ("/watch/a/shell/storage_value", "a/shell/storage_value"),
+        ] {
+            let response = handle_path(&client, path).unwrap();
+            let result = String::try_from_slice(&response.data).unwrap();
+            assert_eq!(result, format!("watch {rest}"), "{path}");
+            assert_eq!(TEST_RPC.route_id(path), Some(TestRpcRoute::Watch));
+        }
+
+        assert_eq!(TEST_RPC.watch_path("a/b/c"), "/watch/a/b/c");
+        assert_eq!(TEST_RPC.watch_path(""), "/watch");
+        let result = TEST_RPC.watch(&client, "x/y").await.unwrap();
+        assert_eq!(result, "watch x/y");
+
+        let route = TEST_RPC.route_params("watch").unwrap();
+        assert_eq!(route[0].to_string(), "rest: **");
+    }
+
+    /// Test that the entries of a prefix scan that returns chunks are decoded
+    /// one at a time, requesting the chunks only as they're needed.
+    #[tokio::test]
+    async fn test_router_stream_items() {
+        use futures::StreamExt;
+
+        use super::test_rpc::TEST_SCAN_RPC;
+        use super::test_rpc_handlers::scan_entries;
+        use crate::ledger::queries::clients::LoggingClient;
+        use crate::types::storage;
+
+        let requests = std::sync::Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let client =
+            LoggingClient::new(TestClient::new(TEST_SCAN_RPC), move |_log| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        let prefix = storage::Key::parse("scan").unwrap();
+        let expected = scan_entries(&prefix);
+        // The 16-byte chunks of the encoded entries, which start with the 4
+        // bytes of their length
+        let bytes = expected.try_to_vec().unwrap();
+        let first_len = 4 + expected[0].try_to_vec().unwrap().len();
+        let first_chunks = bytes[..first_len].chunks(16).count();
+        let total_chunks = bytes.chunks(16).count();
+
+        let stream = TEST_SCAN_RPC.scan_iter(&client, &prefix);
+        futures::pin_mut!(stream);
+        let first: (storage::Key, u64) = stream.next().await.unwrap().unwrap();
+        assert_eq!(first, expected[0]);
+        // Only the chunks with the first entry have been requested
+        assert_eq!(requests.load(Ordering::SeqCst), first_chunks);
+        assert!(first_chunks < total_chunks);
+
+        let mut entries = vec![first];
+        while let Some(entry) = stream.next().await {
+            entries.push(entry.unwrap());
+        }
+        assert_eq!(entries, expected);
+        assert_eq!(requests.load(Ordering::SeqCst), total_chunks);
+
+        // The same entries are reassembled by the client method
+        let result = TEST_SCAN_RPC.scan(&client, &prefix).await.unwrap();
+        assert_eq!(result, expected);
+    }
+
+    /// Test a handler that returns a value in chunks.
+    #[tokio::test]
+    async fn test_router_macro_stream() {
+        use crate::ledger::queries::ResponseChunk;
+
+        let client = TestClient::new(TEST_RPC);
+        let expected: Vec<u64> = (0..30).collect();
+
+        // The chunks are requested one by one
+        let mut stream = TEST_RPC.dump_stream(&client).await.unwrap();
+        let mut chunks = vec![];
+        while let Some(chunk) = stream.next_chunk().await.unwrap() {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), expected.try_to_vec().unwrap());
+        assert_eq!(stream.next_chunk().await.unwrap(), None);
+
+        // The client method reassembles the value
+        let result = TEST_RPC.dump(&client).await.unwrap();
+        assert_eq!(result, expected);
+
+        // A request without data gets the first chunk
+        let response = handle_path(&client, "/dump").unwrap();
+        let chunk = ResponseChunk::try_from_slice(&response.data).unwrap();
+        assert_eq!(chunk.data, chunks[0]);
+        assert_eq!(chunk.next, Some(1));
+
+        // There's no chunk after the last one
+        let request = RequestQuery {
+            path: "/dump".to_owned(),
+            data: 3_u32.try_to_vec().unwrap(),
+            ..RequestQuery::default()
+        };
+        let ctx = client.ctx();
+        let err = client.rpc.handle(ctx, &request).unwrap_err();
+        assert_eq!(err.to_string(), "There is no response chunk at index 3");
+    }
+
+    /// Test a base64url-encoded bytes arg.
+    #[tokio::test]
+    async fn test_router_macro_b64_arg() {
+        let client = TestClient::new(TEST_RPC);
+
+        // Non-ASCII bytes, including ones that would be a slash or invalid
+        // UTF-8 in the path if they weren't encoded
+        let tok: Vec<u8> = vec![0xff, 0x00, b'/', 0xe2, 0x82, 0xac, 0xfb, 0xbf];
+        let path = TEST_RPC.bytes_path(&tok);
+        assert_eq!(path, format!("/bytes/{}", encode_b64_segment(&tok)));
+        assert_eq!(path, "/bytes/_wAv4oKs-78");
+        let result = TEST_RPC.bytes(&client, &tok).await.unwrap();
+        assert_eq!(result, tok);
+
+        let route = TEST_RPC.route_params("bytes").unwrap();
+        assert_eq!(route[0].to_string(), "tok: b64");
+
+        // Invalid base64url is not matched
+        for segment in ["not+base64", "a", "a/b"] {
+            let path = format!("/bytes/{segment}");
+            assert!(handle_path(&client, &path).is_err(), "{path}");
+        }
+        let err = handle_path(&client, "/bytes/!").unwrap_err();
+        assert!(err.to_string().contains("base64url"), "{err}");
+    }
+
+    /// Test the integer args in a radix other than 10.
+    #[tokio::test]
+    async fn test_router_macro_radix_arg() {
+        use super::test_rpc::TEST_RADIX_RPC;
+        let client = TestClient::new(TEST_RADIX_RPC);
+
+        // A hex arg round-trips through its path
+        for id in [0, 255, u64::MAX] {
+            let path = TEST_RADIX_RPC.sub_n_path(&id);
+            assert_eq!(path, format!("/item/{id:#x}"));
+            let result = TEST_RADIX_RPC.sub_n(&client, &id).await.unwrap();
+            assert_eq!(result, format!("sub_n/{id}"));
+        }
+        assert_eq!(TEST_RADIX_RPC.sub_n_path(&255), "/item/0xff");
+        assert_eq!(TEST_RADIX_RPC.limited_path(&8), "/limit/0o10/x");
+        assert_eq!(TEST_RADIX_RPC.test_sub_rpc(&5).x_path(), "/mask/0b101/x");
+
+        // The prefix may be omitted
+        let response = handle_path(&client, "/item/ff").unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "sub_n/255"
+        );
+        let response = handle_path(&client, "/limit/0o17/x").unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "limited/15"
+        );
+
+        // Invalid digits for the radix are not matched
+        for path in ["/item/0xfg", "/item/0o7", "/limit/9/x", "/mask/0b2/x"] {
+            assert!(handle_path(&client, path).is_err(), "{path}");
+        }
+        let err = handle_path(&client, "/item/0xfg").unwrap_err();
+        assert!(err.to_string().contains("u64 radix 16"), "{err}");
+
+        let route = TEST_RADIX_RPC.route_params("sub_n").unwrap();
+        assert_eq!(route[0].to_string(), "id: u64 radix 16");
+
+        // The generated TypeScript client formats the arg in its radix too
+        let mut out = Vec::new();
+        TEST_RADIX_RPC.print_ts_client(&mut out).unwrap();
+        let client = String::from_utf8(out).unwrap();
+        assert!(
+            client.contains(
+                "export async function subN(client: QueryClient, id: bigint)"
+            ),
+            "{client}"
+        );
+        assert!(
+            client.contains("const path = `/item/0x${id.toString(16)}`;"),
+            "{client}"
+        );
+    }
+
+    /// Test an arg of a type that's converted with `TryFrom<&str>` and doesn't
+    /// implement `FromStr`.
+    #[tokio::test]
+    async fn test_router_macro_try_from_arg() {
+        use super::test_rpc::TEST_TRY_RPC;
+        use super::test_rpc_handlers::Color;
+        let client = TestClient::new(TEST_TRY_RPC);
+
+        let path = TEST_TRY_RPC.paint_path(&Color::Green);
+        assert_eq!(path, "/paint/green");
+        assert_eq!(
+            TEST_TRY_RPC.paint_validate_path(&path).unwrap(),
+            (Color::Green,)
+        );
+        let result = TEST_TRY_RPC.paint(&client, &Color::Green).await.unwrap();
+        assert_eq!(result, "paint Green");
+
+        // A failed conversion is not matched
+        let err = handle_path(&client, "/paint/blue").unwrap_err();
+        assert!(err.to_string().contains("\"blue\""), "{err}");
+        // The arg only matches a single segment
+        assert!(handle_path(&client, "/paint/red/x").is_err());
+
+        let route = TEST_TRY_RPC.route_params("paint").unwrap();
+        assert_eq!(route[0].to_string(), "color: Color");
+    }
+
+    /// Test that an arg with a custom `display` function round-trips through
+    /// its path, even though its `Display` isn't parsed back by `FromStr`.
+    #[tokio::test]
+    async fn test_router_macro_display_arg() {
+        use super::test_rpc::TEST_DISPLAY_RPC;
+        use super::test_rpc_handlers::Ratio;
+        let client = TestClient::new(TEST_DISPLAY_RPC);
+
+        let ratio = Ratio { num: 3, den: 4 };
+        assert_eq!(ratio.to_string(), "3/4");
+        let path = TEST_DISPLAY_RPC.ratio_value_path(&ratio);
+        assert_eq!(path, "/ratio/3:4/value");
+        assert_eq!(
+            TEST_DISPLAY_RPC.ratio_value_validate_path(&path).unwrap(),
+            (ratio,)
+        );
+        let result =
+            TEST_DISPLAY_RPC.ratio_value(&client, &ratio).await.unwrap();
+        assert_eq!(result, "ratio_value/3/4");
+
+        // The sub-router's prefix is formatted with it too
+        assert_eq!(TEST_DISPLAY_RPC.test_sub_rpc(&ratio).x_path(), "/of/3:4/x");
+
+        // The arg's `Display` isn't matched
+        assert!(handle_path(&client, "/ratio/3/4/value").is_err());
+
+        let route = TEST_DISPLAY_RPC.route_params("ratio_value").unwrap();
+        assert_eq!(route[0].to_string(), "ratio: Ratio");
+    }
+
+    /// Test that in debug builds, a `*_path` whose path isn't matched back
+    /// into the same route and args panics, here because of a broken custom
+    /// `display` function.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "The path `/ratio/3/4/value` constructed by \
+                               `ratio_value_path` must be matched back into \
+                               `ratio_value(ratio=3/4)`, but it matched no \
+                               route")]
+    fn test_router_path_matched_back() {
+        use super::test_rpc::TEST_BROKEN_DISPLAY_RPC;
+        use super::test_rpc_handlers::Ratio;
+
+        TEST_BROKEN_DISPLAY_RPC.ratio_value_path(&Ratio { num: 3, den: 4 });
+    }
+
+    /// Test that an address arg is only matched by an address of the same
+    /// network. The address' bech32m prefix is validated when it's parsed, so
+    /// an address of another network is not matched.
+    #[tokio::test]
+    async fn test_router_macro_foreign_network_address() {
+        use crate::types::address;
+
+        let client = TestClient::new(TEST_RPC);
+
+        let addr = address::nam();
+        let result = TEST_RPC.addr_raw(&client, &addr).await.unwrap();
+        assert_eq!(result, format!("addr_raw/{addr}/{addr}"));
+
+        // The same address encoded with the `a` prefix of another network
+        let foreign = "a1v4ehgw36x3prswzxggunzv6pxqmnvdj9xvcyzvpsggeyvs3cg9qnywf589qnwvfsg5erg3fkdl0pqe";
+        let err =
+            handle_path(&client, &format!("/addr/{foreign}")).unwrap_err();
+        assert!(err.to_string().contains(foreign), "{err}");
+    }
+
+    /// Test that an optional arg with a default value is set to the default
+    /// when it's absent.
+    #[tokio::test]
+    async fn test_router_macro_arg_default() {
+        let client = TestClient::new(TEST_RPC);
+
+        // An absent arg yields the default inside the handler
+        for path in ["/limit", "/limit/"] {
+            let response = handle_path(&client, path).unwrap();
+            assert_eq!(
+                String::try_from_slice(&response.data).unwrap(),
+                "limited/50"
+            );
+        }
+        assert!(handle_path(&client, "/limit/x").is_err());
+
+        // The default is omitted from the path
+        assert_eq!(TEST_RPC.limited_path(&50), "/limit");
+        assert_eq!(TEST_RPC.limited_path(&10), "/limit/10");
+        for limit in [0, 10, 50, u64::MAX] {
+            let result = TEST_RPC.limited(&client, &limit).await.unwrap();
+            assert_eq!(result, format!("limited/{limit}"));
+        }
+    }
+
+    /// Test that a `.json` or `.borsh` suffix of a path selects the encoding
+    /// of the response.
+    #[tokio::test]
+    async fn test_router_macro_encoding_suffix() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+
+        let json = handle_path(&client, "/a.json").unwrap();
+        assert_eq!(json.data, br#""a""#);
+        let borsh = handle_path(&client, "/a.borsh").unwrap();
+        assert_eq!(String::try_from_slice(&borsh.data).unwrap(), "a");
+        // Borsh is the default
+        let default = handle_path(&client, "/a").unwrap();
+        assert_eq!(default.data, borsh.data);
+
+        // The suffix is stripped before the last segment is matched
+        let json = handle_path(&client, "/e/-5.json").unwrap();
+        assert_eq!(json.data, br#""e/-5""#);
+        let json = handle_path(&client, "/search/10.json?limit=20").unwrap();
+        assert_eq!(json.data, br#""search/10""#);
+        let json = handle_path(&client, "/bytes/AQID.json").unwrap();
+        assert_eq!(json.data, b"[1,2,3]");
+        assert_eq!(TEST_RPC.route_id("/a.json"), Some(TestRpcRoute::A));
+        assert_eq!(TEST_RPC.e_validate_path("/e/-5.borsh").unwrap(), (-5,));
+        // Only the known suffixes are stripped
+        assert!(handle_path(&client, "/a.xml").is_err());
+
+        // A response whose type doesn't implement `serde::Serialize` and the
+        // responses encoded by the handler cannot be encoded with JSON
+        for path in ["/div/4/2.json", "/c.json", "/dump.json"] {
+            let err = handle_path(&client, path).unwrap_err();
+            assert!(err.to_string().contains("JSON"), "{path}: {err}");
+        }
+        for path in ["/div/4/2.borsh", "/c.borsh", "/dump.borsh"] {
+            assert!(handle_path(&client, path).is_ok(), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_split_encoding_suffix() {
+        use crate::ledger::queries::router::ResponseEncoding::{Borsh, Json};
+
+        assert_eq!(split_encoding_suffix("/a.json"), ("/a", Json));
+        assert_eq!(split_encoding_suffix("/a/b.borsh"), ("/a/b", Borsh));
+        assert_eq!(split_encoding_suffix("/a"), ("/a", Borsh));
+        assert_eq!(split_encoding_suffix("/a.b"), ("/a.b", Borsh));
+        assert_eq!(split_encoding_suffix("/a/.json"), ("/a/.json", Borsh));
+        assert_eq!(split_encoding_suffix("/.json"), ("/.json", Borsh));
+        assert_eq!(path_encoding("/a.json?b=1"), Json);
+        assert_eq!(path_encoding("/a?b=1.json"), Borsh);
+    }
+
+    /// Test that an optional arg that's absent from the path may be supplied
+    /// as a query param, while the path segment takes precedence.
+    #[tokio::test]
+    async fn test_router_macro_opt_arg_query_param() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+        let handle = |path: &str| {
+            let response = handle_path(&client, path).unwrap();
+            String::try_from_slice(&response.data).unwrap()
+        };
+
+        // Path form
+        assert_eq!(handle("/search/10"), "search/10");
+        assert_eq!(handle("/search"), "search");
+        // Query form
+        assert_eq!(handle("/search?limit=10"), "search/10");
+        assert_eq!(handle("/search/?limit=10"), "search/10");
+        assert_eq!(handle("/search?other=1&limit=10"), "search/10");
+        assert_eq!(handle("/search?other=10"), "search");
+        // An invalid query param is ignored like an absent one
+        assert_eq!(handle("/search?limit=x"), "search");
+        // Both present - the path segment wins
+        assert_eq!(handle("/search/10?limit=20"), "search/10");
+        // With a default value
+        assert_eq!(handle("/limit?limit=10"), "limited/10");
+        assert_eq!(handle("/limit/20?limit=10"), "limited/20");
+        assert_eq!(handle("/limit?limit=x"), "limited/50");
+
+        assert_eq!(
+            TEST_RPC.route_id("/search?limit=10"),
+            Some(TestRpcRoute::Search)
+        );
+        assert_eq!(
+            TEST_RPC.search_validate_path("/search?limit=10").unwrap(),
+            (Some(10),)
+        );
+        assert_eq!(
+            TEST_RPC
+                .search_validate_path("/search/10?limit=20")
+                .unwrap(),
+            (Some(10),)
+        );
+        // A required arg cannot be supplied as a query param
+        assert!(handle_path(&client, "/e?int=1").is_err());
+    }
+
+    /// Test that the optional args of a route can all be supplied by name as
+    /// query params in any order, omitting the literal segments between them.
+    #[test]
+    fn test_router_macro_opt_args_query_form() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+        let handle = |path: &str| {
+            handle_path(&client, path)
+                .map(|response| String::try_from_slice(&response.data).unwrap())
+        };
+
+        let expected = "b3iiii/1/2/5/10";
+        assert_eq!(handle("/b/3/1/2/iiii?a4=10&a3=5").unwrap(), expected);
+        assert_eq!(handle("/b/3/1/2/iiii?a3=5&a4=10").unwrap(), expected);
+        assert_eq!(handle("/b/3/1/2/iiii/?a4=10&a3=5").unwrap(), expected);
+        assert_eq!(handle("/b/3/1/2/iiii/xyz?a4=10&a3=5").unwrap(), expected);
+        // Mixed with the path form
+        assert_eq!(handle("/b/3/1/2/iiii/5?a4=10").unwrap(), expected);
+        assert_eq!(handle("/b/3/1/2/iiii/5/xyz?a4=10").unwrap(), expected);
+        assert_eq!(handle("/b/3/1/2/iiii?a4=10").unwrap(), "b3iiii/1/2/10");
+        assert_eq!(handle("/b/3/1/2/iiii?a3=5").unwrap(), "b3iiii/1/2/5");
+
+        // Without a query string, the literal segment is required
+        assert!(handle("/b/3/1/2/iiii").is_err());
+        assert!(handle("/b/3/1/2/iiii/5").is_err());
+        // The literal segment can only be omitted at the end of the path
+        assert!(handle("/b/3/1/2/iiii/5/10?a4=10").is_err());
+        // A trailing literal that doesn't separate optional args is required
+        assert!(handle("/b/3/1/2?a3=5").is_err());
+
+        // The route resolution agrees with the handling
+        assert_eq!(
+            TEST_RPC.route_id("/b/3/1/2/iiii?a4=10&a3=5"),
+            Some(TestRpcRoute::B3iiii)
+        );
+        assert_eq!(TEST_RPC.route_id("/b/3/1/2/iiii"), None);
+        assert_eq!(
+            TEST_RPC
+                .b3iiii_validate_path("/b/3/1/2/iiii?a4=10&a3=5")
+                .unwrap(),
+            (
+                token::Amount::from(1_000_000),
+                token::Amount::from(2_000_000),
+                Some(token::Amount::from(5_000_000)),
+                Some(Epoch(10))
+            )
+        );
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(split_query("/a/b"), ("/a/b", None));
+        assert_eq!(split_query("/a?b=1"), ("/a", Some("b=1")));
+        assert_eq!(split_query("/a?"), ("/a", Some("")));
+
+        let query = Some("a=1&b=&a=2&c");
+        assert_eq!(query_param(query, "a"), Some("1"));
+        assert_eq!(query_param(query, "b"), Some(""));
+        assert_eq!(query_param(query, "c"), None);
+        assert_eq!(query_param(query, "d"), None);
+        assert_eq!(query_param(None, "a"), None);
+    }
+
+    /// Test a route with an `async` handler.
+    #[tokio::test]
+    async fn test_router_macro_async_handler() {
+        let client = TestClient::new(TEST_RPC);
+
+        let result = TEST_RPC.async_add(&client, &2, &3).await.unwrap();
+        assert_eq!(result, 5);
+
+        let path = TEST_RPC.async_add_path(&u64::MAX, &1);
+        let err = handle_path_async(&client, &path).await.unwrap_err();
+        assert!(err.to_string().contains("overflows"), "{err}");
+
+        // The handlers of the other routes are called as usual
+        let response = handle_path_async(&client, "/a").await.unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "a");
+        let response = handle_path_async(&client, "/sub/x").await.unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "x");
+
+        // The future of an `async` handler cannot be awaited by the
+        // synchronous `handle`
+        let path = TEST_RPC.async_add_path(&2, &3);
+        match handle_path(&client, &path).unwrap_err() {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                let err = err.downcast::<Error>().unwrap();
+                assert!(
+                    matches!(&*err, Error::AsyncHandler { route } if *route == "async_add"),
+                    "{err}"
+                );
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+        assert!(handle_path_async(&client, &path).await.is_ok());
+    }
+
+    /// Test a versioned router that accepts an optional leading version
+    /// segment.
+    #[tokio::test]
+    async fn test_router_versioned() {
+        use super::test_rpc::{TestRpcRoute, TEST_VERSIONED_RPC};
+        use crate::ledger::queries::Version;
+
+        let client = TestClient::new(TEST_VERSIONED_RPC);
+        let handle = |path: &str| {
+            handle_path(&client, path)
+                .map(|response| String::try_from_slice(&response.data).unwrap())
+        };
+
+        // Both the unversioned and versioned forms dispatch to the same
+        // handler
+        assert_eq!(handle("/a").unwrap(), "a");
+        assert_eq!(handle("/v2/a").unwrap(), "a");
+        assert_eq!(handle("/v2/b/0/i").unwrap(), "b0i");
+        assert_eq!(handle("/v1/sub/x").unwrap(), "x");
+        assert_eq!(TEST_VERSIONED_RPC.route_id("/v2/a"), Some(TestRpcRoute::A));
+        assert_eq!(TEST_VERSIONED_RPC.route_id("/a"), Some(TestRpcRoute::A));
+
+        // The version is available to the handler
+        assert_eq!(handle("/api_version").unwrap(), "api_version");
+        assert_eq!(handle("/v2/api_version").unwrap(), "api_version/v2");
+        assert_eq!(handle("/v10/api_version").unwrap(), "api_version/v10");
+
+        // Only a single leading version is accepted
+        assert!(handle("/v2/v2/a").is_err());
+        assert!(handle("/a/v2").is_err());
+        assert!(handle("/v/a").is_err());
+
+        // The unversioned router doesn't accept the version
+        let client = TestClient::new(TEST_RPC);
+        assert!(handle_path(&client, "/v2/a").is_err());
+        assert_eq!(TEST_RPC.route_id("/v2/a"), None);
+
+        assert_eq!("v2".parse::<Version>().unwrap(), Version(2));
+        assert_eq!(Version(3).to_string(), "v3");
+        for invalid in ["2", "v", "v+2", "V2", "v2a"] {
+            assert!(invalid.parse::<Version>().is_err(), "{invalid}");
+        }
+    }
+
+    /// Test handling paths under a base path that is stripped before matching.
+    #[test]
+    fn test_router_handle_with_base() {
+        let client = TestClient::new(TEST_RPC);
+        let handle = |path: &str, base: &str| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            TEST_RPC
+                .handle_with_base(ctx, &request, base)
+                .map(|response| String::try_from_slice(&response.data).unwrap())
+        };
+
+        assert_eq!(handle("/gateway/a", "/gateway").unwrap(), "a");
+        assert_eq!(handle("/gateway/a", "/gateway/").unwrap(), "a");
+        assert_eq!(handle("/gw/v1/b/0/i", "/gw/v1").unwrap(), "b0i");
+        assert_eq!(handle("/gateway/sub/x", "/gateway").unwrap(), "x");
+        // An empty or root base strips nothing
+        assert_eq!(handle("/a", "").unwrap(), "a");
+        assert_eq!(handle("/a", "/").unwrap(), "a");
+
+        // The path must be under the base, matched by whole segments
+        assert!(handle("/a", "/gateway").is_err());
+        assert!(handle("/gatewayx/a", "/gateway").is_err());
+        assert!(handle("/gateway", "/gateway").is_err());
+
+        // Both the path and the base are normalized
+        assert_eq!(handle("gateway//a/", "/gateway").unwrap(), "a");
+        assert_eq!(handle("/gateway/a", "gateway//").unwrap(), "a");
+    }
+
+    /// Test that adversarial paths are rejected or matched without panicking
+    /// and that the route resolution agrees with the handling.
+    #[test]
+    fn test_router_adversarial_paths() {
+        use super::test_rpc::TEST_VERSIONED_RPC;
+
+        let client = TestClient::new(TEST_RPC);
+        let too_many_slashes = "/".repeat(MAX_PATH_LENGTH);
+        let many_empty_segments = "/a".to_owned() + &"/".repeat(1000);
+        let paths = [
+            "",
+            "a",
+            "a/",
+            "/",
+            "//",
+            "///a",
+            "/a//",
+            "/b",
+            "/b/",
+            "/b/3",
+            "/b/3/",
+            "/b/3//",
+            "/b/3/1/2/iiii///xyz/",
+            "/sub",
+            "/sub/",
+            "/ns",
+            "/ns/",
+            "/ns/::",
+            "/validator",
+            "/validator//name",
+            "/limit//",
+            "/bytes",
+            "/v1",
+            "/v1/",
+            too_many_slashes.as_str(),
+            many_empty_segments.as_str(),
+        ];
+        for rpc in [TEST_RPC, TEST_VERSIONED_RPC] {
+            for path in paths {
+                let request = RequestQuery {
+                    path: path.to_owned(),
+                    ..RequestQuery::default()
+                };
+                let ctx = client.ctx();
+                let result = rpc.handle(ctx, &request);
+                if result.is_ok() {
+                    assert!(rpc.route_id(path).is_some(), "path {path}");
+                }
+            }
+            // Matching from past the end of the segments
+            let ctx = client.ctx();
+            let request = RequestQuery {
+                path: "/a".to_owned(),
+                ..RequestQuery::default()
+            };
+            // At the end, the rest of the path is empty and matches nothing
+            let err = rpc
+                .internal_handle(ctx.clone(), &request, &["a"], 1)
+                .unwrap_err();
+            assert!(!err.to_string().contains("malformed"), "{err}");
+            assert!(rpc.internal_route_id(&["a"], 1, None).is_none());
+            // Past the end, the index is rejected with checked arithmetic
+            for start in [2, usize::MAX] {
+                let err = rpc
+                    .internal_handle(ctx.clone(), &request, &["a"], start)
+                    .unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    Error::MalformedPath("/a".to_owned()).to_string()
+                );
+                assert!(rpc.internal_route_id(&["a"], start, None).is_none());
+            }
+        }
+        assert!(handle_path(&client, "").is_err());
+        assert!(handle_path(&client, "/b/3").is_err());
+    }
+
+    /// Test a pre-dispatch authorization hook that rejects some routes.
+    #[test]
+    fn test_router_handle_authorized() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+        // Deny all the routes under `/b/3`
+        let authorize = |route: &TestRpcRoute, _ctx: &RequestCtx<'_, _, _>| {
+            if matches!(
+                route,
+                TestRpcRoute::B3sum
+                    | TestRpcRoute::B3i
+                    | TestRpcRoute::B3
+                    | TestRpcRoute::B3ii
+                    | TestRpcRoute::B3iii
+                    | TestRpcRoute::B3iiii
+            ) {
+                return Err(Error::Unauthorized(format!("{route:?}")));
+            }
+            Ok(())
+        };
+        let handle = |path: &str| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            TEST_RPC.handle_authorized(ctx, &request, authorize)
+        };
+
+        let response = handle("/a").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "a");
+        let response = handle("/b/2/i/1").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "b2i/1");
+
+        for path in ["/b/3/1/2/3", "/b/3/1/2/sum/3", "/b/3/1/2/iii"] {
+            match handle(path).unwrap_err() {
+                storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                    let err = err.downcast::<Error>().unwrap();
+                    assert!(matches!(*err, Error::Unauthorized(_)), "{path}");
+                }
+                err => panic!("Unexpected error {err}"),
+            }
+        }
+
+        // Unmatched paths are rejected by the router, not the hook
+        let err = handle("/unknown").unwrap_err();
+        assert!(!err.to_string().contains("Unauthorized"), "{err}");
+    }
+
+    /// Test that the response transformers are chained in order for every
+    /// matched route.
+    #[test]
+    fn test_router_handle_transformed() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+        let append_route: ResponseTransformer<TestRpcRoute> =
+            |route, mut response| {
+                response.info.push_str(&format!("[{route:?}]"));
+                response
+            };
+        let append_signed: ResponseTransformer<TestRpcRoute> =
+            |_route, mut response| {
+                response.info.push_str("[signed]");
+                response
+            };
+        let handle = |path: &str| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            TEST_RPC.handle_transformed(
+                ctx,
+                &request,
+                &[append_route, append_signed],
+            )
+        };
+
+        let response = handle("/a").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "a");
+        assert_eq!(response.info, "[A][signed]");
+        let response = handle("/b/2/i/1").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "b2i/1");
+        assert_eq!(response.info, "[B2i][signed]");
+
+        // A failed request is not transformed
+        assert!(handle("/unknown").is_err());
+    }
+
+    /// Test that a route with a capability is disabled unless the node has the
+    /// capability, while the other routes work either way.
+    #[test]
+    fn test_router_route_capability() {
+        use super::test_rpc::TEST_CAPABILITY_RPC;
+
+        let client = TestClient::new(TEST_CAPABILITY_RPC);
+        let handle = |path: &str, capabilities: &[&str]| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                ..RequestQuery::default()
+            };
+            let ctx = RequestCtx {
+                capabilities: capabilities
+                    .iter()
+                    .map(|capability| capability.to_string())
+                    .collect(),
+                ..client.ctx()
+            };
+            TEST_CAPABILITY_RPC.handle(ctx, &request)
+        };
+        let decode = |response: EncodedResponseQuery| {
+            String::try_from_slice(&response.data).unwrap()
+        };
+
+        // Without the capability
+        assert_eq!(decode(handle("/a", &[]).unwrap()), "a");
+        match handle("/archived", &["other"]).unwrap_err() {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                let err = err.downcast::<Error>().unwrap();
+                assert!(matches!(
+                    *err,
+                    Error::RouteDisabled {
+                        route: "b1",
+                        capability: "archive"
+                    }
+                ));
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+
+        // With the capability
+        assert_eq!(decode(handle("/a", &["archive"]).unwrap()), "a");
+        assert_eq!(decode(handle("/archived", &["archive"]).unwrap()), "b1");
+    }
+
+    /// Test the `/_routes` meta-route of a router constructed with meta-routes.
+    #[tokio::test]
+    async fn test_router_routes_meta_route() {
+        let client = TestClient::new(test_rpc::TestRpc::new_with_meta_routes());
+        let routes = request_routes(&client).await.unwrap();
+        let expected: Vec<RouteDescriptor> =
+            TEST_RPC.routes().into_iter().map(Into::into).collect();
+        assert_eq!(routes, expected);
+        let b3 = routes.iter().find(|route| route.handler == "b3").unwrap();
+        assert_eq!(b3.template, "/b/3/{a1}/{a2}/{a3}");
+        assert_eq!(b3.response_ty, "String");
+        assert_eq!(b3.args.len(), 3);
+        assert!(handle_path(&client, "/_routes/").is_ok());
+
+        // The other routes are still served
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        // A router without meta-routes doesn't serve it
+        let client = TestClient::new(TEST_RPC);
+        assert!(handle_path(&client, ROUTES_META_PATH).is_err());
+
+        assert!(is_reserved_segment("_routes"));
+        assert!(!is_reserved_segment("a_"));
+        assert!(!is_reserved_segment(""));
+        assert!(!is_routes_meta_path(&["_routes", "a"]));
+    }
+
+    /// Test that the responses of a configured route are cached by the
+    /// `handle_cached` until the cache is cleared.
+    #[test]
+    fn test_router_handle_cached() {
+        use super::test_rpc::{TestCachedRpcRoute, TEST_CACHED_RPC};
+        use super::test_rpc_handlers::COUNTED_CALLS;
+
+        let client = TestClient::new(TEST_CACHED_RPC);
+        let cache =
+            ResponseCache::new().with_route(TestCachedRpcRoute::Counted, None);
+        let handle = |path: &str| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            TEST_CACHED_RPC
+                .handle_cached(ctx, &request, &cache)
+                .unwrap()
+        };
+
+        let calls = COUNTED_CALLS.load(Ordering::SeqCst);
+        let path = TEST_CACHED_RPC.counted_path(&7);
+        let first = handle(&path);
+        let second = handle(&path);
+        assert_eq!(first.data, 14_u64.try_to_vec().unwrap());
+        assert_eq!(second.data, first.data);
+        assert_eq!(COUNTED_CALLS.load(Ordering::SeqCst), calls + 1);
+
+        // Other args are cached separately
+        let other = handle(&TEST_CACHED_RPC.counted_path(&8));
+        assert_eq!(other.data, 16_u64.try_to_vec().unwrap());
+        assert_eq!(COUNTED_CALLS.load(Ordering::SeqCst), calls + 2);
+
+        // A cleared cache invokes the handler again
+        cache.clear();
+        handle(&path);
+        assert_eq!(COUNTED_CALLS.load(Ordering::SeqCst), calls + 3);
+    }
+
+    /// Test that the responses for many distinct args and heights don't keep
+    /// growing a response cache.
+    #[test]
+    fn test_router_response_cache_bounded_entries() {
+        use super::test_rpc::TestCachedRpcRoute;
+
+        let entries_len = |cache: &ResponseCache<TestCachedRpcRoute>| {
+            cache.entries.lock().unwrap().responses.len()
+        };
+        let request = RequestQuery::default();
+        let cache_arg = |cache: &ResponseCache<TestCachedRpcRoute>,
+                         arg: u64,
+                         last_height: BlockHeight| {
+            cache
+                .get_or_handle(
+                    TestCachedRpcRoute::Counted,
+                    vec![arg.to_string()],
+                    &request,
+                    last_height,
+                    || Ok(EncodedResponseQuery::default()),
+                )
+                .unwrap();
+        };
+
+        // The responses until a new block are removed when a response is
+        // cached after a new block is committed
+        let cache =
+            ResponseCache::new().with_route(TestCachedRpcRoute::Counted, None);
+        for arg in 0..100 {
+            cache_arg(&cache, arg, BlockHeight(1));
+        }
+        assert_eq!(entries_len(&cache), 100);
+        cache_arg(&cache, 0, BlockHeight(2));
+        assert_eq!(entries_len(&cache), 1);
+
+        // The number of the responses is bounded, even if none has expired
+        let cache = ResponseCache::new()
+            .with_route(
+                TestCachedRpcRoute::Counted,
+                Some(Duration::from_secs(3600)),
+            )
+            .with_max_entries(10);
+        for arg in 0..100 {
+            cache_arg(&cache, arg, BlockHeight(arg));
+            assert!(entries_len(&cache) <= 10);
+        }
+    }
+
+    /// Test an aggregate route, whose results are combined into one response
+    /// and decoded together by the client.
+    #[tokio::test]
+    async fn test_router_combined_response() {
+        use super::test_rpc::TEST_AGGREGATE_RPC;
+
+        let client = TestClient::new(TEST_AGGREGATE_RPC);
+        let Combined((balance, stake, rewards)) =
+            TEST_AGGREGATE_RPC.portfolio(&client, &3).await.unwrap();
+        assert_eq!(balance, 300);
+        assert_eq!(stake, 30);
+        assert_eq!(rewards, "3 NAM");
+
+        // The results are encoded one after another
+        let response =
+            handle_path(&client, &TEST_AGGREGATE_RPC.portfolio_path(&3))
+                .unwrap();
+        let expected = [
+            300_u64.try_to_vec().unwrap(),
+            30_u64.try_to_vec().unwrap(),
+            "3 NAM".to_owned().try_to_vec().unwrap(),
+        ]
+        .concat();
+        assert_eq!(response.data, expected);
+    }
+
+    /// Test that a storage key arg is parsed from the rest of the path and
+    /// that a malformed key is rejected instead of trying the next pattern.
+    #[tokio::test]
+    async fn test_router_storage_key_arg() {
+        use super::test_rpc::TEST_KEY_RPC;
+        use crate::types::storage;
+
+        let client = TestClient::new(TEST_KEY_RPC);
+
+        let key = storage::Key::parse("#atest1/balance/tok_1").unwrap();
+        let path = TEST_KEY_RPC.key_segments_path(&key);
+        assert_eq!(path, "/key/#atest1/balance/tok_1");
+        let segments = TEST_KEY_RPC.key_segments(&client, &key).await.unwrap();
+        assert_eq!(segments, vec!["#atest1", "balance", "tok_1"]);
+        assert_eq!(
+            TEST_KEY_RPC.key_segments_validate_path(&path).unwrap(),
+            (key,)
+        );
+
+        // A disallowed character is rejected, even though the glob arg of the
+        // next pattern would match it
+        let err = handle_path(&client, "/key/a/b$c").unwrap_err();
+        let expected = Error::InvalidKey {
+            key: "a/b$c".to_owned(),
+            reason: InvalidKeyReason::InvalidChar('$'),
+        };
+        assert_eq!(err.to_string(), expected.to_string());
+        assert!(matches!(
+            TEST_KEY_RPC.key_segments_validate_path("/key/a/b$c"),
+            Err(Error::InvalidKey {
+                reason: InvalidKeyReason::InvalidChar('$'),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_storage_key("a//b"),
+            Err(Error::InvalidKey {
+                reason: InvalidKeyReason::EmptySegment(1),
+                ..
+            })
+        ));
+    }
+
+    /// Test a pre-dispatch rate limiter with a per-route limit.
+    #[test]
+    fn test_router_handle_rate_limited() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+        let limit = RateLimit {
+            max_requests: 2,
+            window: Duration::from_secs(3600),
+        };
+        let limiter = RateLimiter::new().with_limit(TestRpcRoute::A, limit);
+        let handle = |path: &str, caller: &str| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                caller: Some(caller.to_owned()),
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            TEST_RPC.handle_rate_limited(ctx, &request, |route, caller| {
+                limiter.check(route, caller)
+            })
+        };
+
+        for _ in 0..2 {
+            let response = handle("/a", "alice").unwrap();
+            assert_eq!(String::try_from_slice(&response.data).unwrap(), "a");
+        }
+        // The route's limit is exceeded
+        match handle("/a/", "alice").unwrap_err() {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                let err = err.downcast::<Error>().unwrap();
+                assert!(
+                    matches!(&*err, Error::RateLimited { route, .. } if *route == "a"),
+                    "{err}"
+                );
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+        // A different route and a different caller are unaffected
+        for _ in 0..3 {
+            let response = handle("/b/1", "alice").unwrap();
+            assert_eq!(String::try_from_slice(&response.data).unwrap(), "b1");
+        }
+        assert!(handle("/a", "bob").is_ok());
+
+        // The default limit applies to the routes without their own limit
+        let limiter = RateLimiter::new().with_default_limit(RateLimit {
+            max_requests: 0,
+            window: Duration::from_secs(3600),
+        });
+        let err = limiter.check(&TestRpcRoute::B1, None).unwrap_err();
+        assert!(matches!(err, Error::RateLimited { .. }));
+    }
+
+    /// Test that the windows of many distinct callers don't keep growing a
+    /// rate limiter.
+    #[test]
+    fn test_router_rate_limiter_bounded_windows() {
+        use super::test_rpc::TestRpcRoute;
+
+        let windows_len = |limiter: &RateLimiter<TestRpcRoute>| {
+            limiter.windows.lock().unwrap().counts.len()
+        };
+
+        // The expired windows are removed when a new one is added
+        let limiter = RateLimiter::new().with_default_limit(RateLimit {
+            max_requests: 1,
+            window: Duration::from_millis(10),
+        });
+        for caller in 0..100 {
+            let caller = caller.to_string();
+            limiter.check(&TestRpcRoute::A, Some(&caller)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        limiter.check(&TestRpcRoute::A, Some("new")).unwrap();
+        assert_eq!(windows_len(&limiter), 1);
+
+        // The number of the windows is bounded, even if none has expired
+        let limiter = RateLimiter::new()
+            .with_default_limit(RateLimit {
+                max_requests: 1,
+                window: Duration::from_secs(3600),
+            })
+            .with_max_windows(10);
+        for caller in 0..100 {
+            let caller = caller.to_string();
+            limiter.check(&TestRpcRoute::A, Some(&caller)).unwrap();
+            assert!(windows_len(&limiter) <= 10);
+        }
+        // The latest callers are still limited
+        let err = limiter.check(&TestRpcRoute::A, Some("99")).unwrap_err();
+        assert!(matches!(err, Error::RateLimited { .. }));
+    }
+
+    /// Test that each kind of an invalid request is rejected with its own
+    /// router error variant.
+    #[test]
+    fn test_router_errors() {
+        let client = TestClient::new(TEST_RPC);
+        let handle = |path: &str, prove: bool| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                prove,
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            match TEST_RPC.handle(ctx, &request).unwrap_err() {
+                storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                    *err.downcast::<Error>().unwrap()
+                }
+                err => panic!("Unexpected error {err}"),
+            }
+        };
+
+        let err = handle("/unknown", false);
+        assert!(matches!(err, Error::WrongPath(path) if path == "/unknown"));
+
+        // The request path is normalized, but the path must start with a
+        // slash when it's split without normalization
+        let err = split_request_path("a").unwrap_err();
+        assert!(matches!(err, Error::MalformedPath(path) if path == "a"));
+
+        let path = format!("/{}", "a".repeat(MAX_PATH_LENGTH));
+        let err = handle(&path, false);
+        assert!(matches!(
+            err,
+            Error::PathTooLong { length, max: MAX_PATH_LENGTH }
+                if length == MAX_PATH_LENGTH + 1
+        ));
+
+        // A typed arg in the middle of a pattern and one that consumes the
+        // rest of the path
+        let err = handle("/div/x/2", false);
+        assert!(matches!(
+            err,
+            Error::ParseFailed { segment, type_name, kind: ParseFailureKind::Invalid }
+                if segment == "x" && type_name == std::any::type_name::<u64>()
+        ));
+        let err = handle("/e/abc", false);
+        assert!(matches!(
+            err,
+            Error::ParseFailed { segment, type_name, kind: ParseFailureKind::Invalid }
+                if segment == "abc" && type_name == std::any::type_name::<i64>()
+        ));
+
+        // A valid number that doesn't fit into the arg's type is reported as
+        // an overflow
+        let err = handle("/e/99999999999999999999", false);
+        assert!(matches!(
+            err,
+            Error::ParseFailed {
+                kind: ParseFailureKind::Overflow,
+                ..
+            }
+        ));
+        let err = handle("/b/2/i/99999999999999999999999", false);
+        assert!(err.to_string().ends_with("the value is out of range"));
+        assert!(matches!(
+            err,
+            Error::ParseFailed { segment, type_name, kind: ParseFailureKind::Overflow }
+                if segment == "99999999999999999999999"
+                    && type_name == std::any::type_name::<token::Amount>()
+        ));
+
+        // A missing typed arg at the end of the path is a path mismatch,
+        // not a failure to parse an empty segment
+        for path in ["/e", "/b/2/i", "/div/6", "/b/3/1"] {
+            let err = handle(path, false);
+            assert!(
+                matches!(&err, Error::WrongPath(wrong) if wrong == path),
+                "path {path}: {err}"
+            );
+        }
+
+        // The route doesn't support proofs
+        let err = handle("/a", true);
+        assert!(matches!(err, Error::MethodNotAllowed(_)));
+    }
+
+    /// Test that a request for a block height above the chain height fails
+    /// before it's sent when the client checks the heights.
+    #[tokio::test]
+    async fn test_router_height_unavailable() {
+        use crate::ledger::queries::Client;
+
+        let mut client = TestClient::new(TEST_RPC);
+
+        // Without the check, the request is sent
+        let height = Some(BlockHeight(11));
+        let result = TEST_RPC.c(&client, None, height, false).await.unwrap();
+        assert_eq!(result.data, "c");
+
+        client.max_height = Some(BlockHeight(10));
+        assert_eq!(client.chain_height().await.unwrap(), BlockHeight(10));
+        for height in [None, Some(BlockHeight(0)), Some(BlockHeight(10))] {
+            let result =
+                TEST_RPC.c(&client, None, height, false).await.unwrap();
+            assert_eq!(result.data, "c");
+        }
+
+        let err = TEST_RPC
+            .c(&client, None, Some(BlockHeight(11)), false)
+            .await
+            .unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert!(matches!(
+            err,
+            Error::HeightUnavailable { requested, latest }
+                if *requested == BlockHeight(11) && *latest == BlockHeight(10)
+        ));
+        let result = TEST_RPC
+            .c_raw(&client, None, Some(BlockHeight(11)), false)
+            .await;
+        assert!(result.is_err());
+    }
+
+    /// Test that the queries fail fast once a client with a health gate finds
+    /// the node unhealthy.
+    #[tokio::test]
+    async fn test_router_node_unhealthy() {
+        use crate::ledger::queries::{Client, HealthGate, HealthStatus};
+
+        let mut client = TestClient::new(TEST_RPC);
+        client.storage.last_height = BlockHeight(10);
+        client.catching_up = true;
+        assert_eq!(
+            client.health().await.unwrap(),
+            HealthStatus {
+                latest_height: BlockHeight(10),
+                catching_up: true,
+            }
+        );
+
+        // Without a gate, the health is not checked
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+
+        client.health_gate = Some(HealthGate::new());
+        let err = TEST_RPC.a(&client).await.unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert!(matches!(
+            err,
+            Error::NodeUnhealthy(status) if status.catching_up
+        ));
+
+        // The first failed check short-circuits the following queries, even
+        // if the node has recovered in the meantime
+        client.catching_up = false;
+        assert!(TEST_RPC.a(&client).await.is_err());
+        assert!(TEST_RPC.c(&client, None, None, false).await.is_err());
+        assert!(TEST_RPC
+            .b3sum_raw(&client, &1.into(), &2.into(), &3.into())
+            .await
+            .is_err());
+
+        // Until the gate is reset
+        client.health_gate.as_ref().unwrap().reset();
+        assert_eq!(TEST_RPC.a(&client).await.unwrap(), "a");
+        let result = TEST_RPC.c(&client, None, None, false).await.unwrap();
+        assert_eq!(result.data, "c");
+    }
+
+    /// Test that the `try_` client methods classify the query failures.
+    #[tokio::test]
+    async fn test_router_query_error() {
+        use crate::ledger::queries::clients::{
+            ReplayClient, ReplayClientError, ReplayEntry, ReplayLog,
+        };
+        use crate::ledger::queries::{HealthGate, QueryError};
+
+        // The decoded value
+        let client = TestClient::new(TEST_RPC);
+        assert_eq!(TEST_RPC.try_a(&client).await.unwrap(), "a");
+        let response =
+            TEST_RPC.try_c(&client, None, None, false).await.unwrap();
+        assert_eq!(response.data, "c");
+
+        // Corrupt bytes that cannot be decoded
+        let entry = |path: String| ReplayEntry {
+            path,
+            data: None,
+            height: HeightSelector::Latest,
+            prove: false,
+            response: Ok((vec![0xff; 3], String::new())),
+        };
+        let client = ReplayClient::new(ReplayLog {
+            entries: vec![entry(TEST_RPC.a_path()), entry(TEST_RPC.c_path())],
+        });
+        let result = TEST_RPC.try_a(&client).await;
+        assert!(matches!(result, Err(QueryError::Decode(_))), "{result:?}");
+        let result = TEST_RPC.try_c(&client, None, None, false).await;
+        assert!(matches!(result, Err(QueryError::Decode(_))));
+        // The plain methods conflate it with the client's errors
+        let result = TEST_RPC.a(&client).await;
+        assert!(matches!(result, Err(ReplayClientError::Decoding(_))));
+
+        // The client's own failure
+        let result = TEST_RPC.try_b0i(&client).await;
+        assert!(matches!(
+            result,
+            Err(QueryError::Transport(ReplayClientError::NotRecorded(_)))
+        ));
+
+        // A router's error, e.g. of the client's health check
+        let mut client = TestClient::new(TEST_RPC);
+        client.catching_up = true;
+        client.health_gate = Some(HealthGate::new());
+        let result = TEST_RPC.try_a(&client).await;
+        assert!(matches!(
+            result,
+            Err(QueryError::Server(Error::NodeUnhealthy(_)))
+        ));
+
+        // An I/O timeout, also when it's wrapped in another error
+        let timeout = || io::Error::new(io::ErrorKind::TimedOut, "timeout");
+        let err = QueryError::from_client_error(timeout());
+        assert!(matches!(err, QueryError::Timeout));
+        let err = QueryError::from_client_error(ReplayClientError::Decoding(
+            timeout(),
+        ));
+        assert!(matches!(err, QueryError::Timeout));
+    }
+
+    /// Test that the context is only constructed for a path that's matched.
+    #[test]
+    fn test_router_dispatch_with_ctx_builder() {
+        use std::cell::Cell;
+
+        let client = TestClient::new(TEST_RPC);
+        let built = Cell::new(0);
+        let dispatch = |path: &str| {
+            let request = RequestQuery {
+                path: path.to_owned(),
+                ..RequestQuery::default()
+            };
+            TEST_RPC.dispatch_with_ctx_builder(&request, || {
+                built.set(built.get() + 1);
+                client.ctx()
+            })
+        };
+
+        for path in ["/x", "/b/3/a/2/sum/3", "/sub/x/y", "x//"] {
+            assert!(dispatch(path).is_err(), "{path}");
+            assert_eq!(built.get(), 0, "{path}");
+        }
+        let err = dispatch("/x").unwrap_err();
+        assert!(err.to_string().contains("/x"), "{err}");
+
+        let response = dispatch("/b/2/i/1").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "b2i/1");
+        assert_eq!(built.get(), 1);
+    }
+
+    /// Test a handler that proves multiple keys in a single response.
+    #[tokio::test]
+    async fn test_router_multi_key_proofs() {
+        use crate::tendermint::merkle::proof::{Proof, ProofOp};
+
+        let client = TestClient::new(TEST_RPC);
+
+        let (data, proofs) = TEST_RPC
+            .two_proofs_with_proofs(&client, None, None)
+            .await
+            .unwrap();
+        assert_eq!(data, "two_proofs");
+        let keys: Vec<String> =
+            proofs.iter().map(|proof| proof.key.to_string()).collect();
+        assert_eq!(keys, ["validator/state", "validator/stake"]);
+        for proof in &proofs {
+            assert_eq!(proof.proof.ops.len(), 2);
+            assert_eq!(
+                proof.proof.ops[0].key,
+                proof.key.to_string().as_bytes()
+            );
+        }
+
+        // The proofs are sent together in the response's `proof`
+        let response = TEST_RPC
+            .two_proofs(&client, None, None, true)
+            .await
+            .unwrap();
+        let proof = response.proof.unwrap();
+        assert_eq!(proof.ops.len(), 6);
+        assert_eq!(KeyedProof::split(&proof).unwrap(), response.proofs);
+        assert_eq!(KeyedProof::merge(response.proofs), proof);
+
+        // Without a proof requested, there are none
+        let response = TEST_RPC
+            .two_proofs(&client, None, None, false)
+            .await
+            .unwrap();
+        assert!(response.proof.is_none());
+        assert!(response.proofs.is_empty());
+
+        // A regular proof of a single key is not split
+        let single = Proof {
+            ops: vec![ProofOp {
+                field_type: "ics23_CommitmentProof".to_owned(),
+                key: b"a".to_vec(),
+                data: vec![],
+            }],
+        };
+        assert!(KeyedProof::split(&single).is_none());
+    }
+
+    /// Test selecting the format of the proofs with the trailing segment of
+    /// a path.
+    #[tokio::test]
+    async fn test_router_proof_format_selector() {
+        let client = TestClient::new(TEST_RPC);
+
+        let ics23 = TEST_RPC
+            .raw(&client, "/two_proofs/_proof=ics23", None, None, true)
+            .await
+            .unwrap();
+        let compact = TEST_RPC
+            .raw(&client, "/two_proofs/_proof=compact", None, None, true)
+            .await
+            .unwrap();
+        assert_eq!(ics23.data, compact.data);
+        let ics23 = ics23.proof.unwrap();
+        let compact = compact.proof.unwrap();
+        assert_ne!(ics23, compact);
+        assert_eq!(CompactProof::expand(&compact).unwrap(), ics23);
+
+        // The current format is the default
+        let default = TEST_RPC
+            .raw(&client, "/two_proofs", None, None, true)
+            .await
+            .unwrap();
+        assert_eq!(default.proof.unwrap(), ics23);
+
+        // The selector is not a part of the route's path
+        assert_eq!(
+            TEST_RPC.route_id("/two_proofs/_proof=compact"),
+            TEST_RPC.route_id("/two_proofs")
+        );
+        let response = handle_path(&client, "/two_proofs/_proof=unknown");
+        assert!(
+            response.unwrap_err().to_string().contains("unknown"),
+            "unknown proof format"
+        );
+    }
+
+    /// Test that a response carries the request's path from which it was
+    /// matched, before it's normalized.
+    #[tokio::test]
+    async fn test_router_matched_path() {
+        use super::test_rpc::TEST_REDIRECT_RPC;
+        use crate::ledger::queries::clients::RedirectClient;
+
+        let client = TestClient::new(TEST_RPC);
+
+        // A path rewritten by the caller, e.g. a gateway that strips its
+        // legacy prefix, is reflected as it was received
+        let rewrite = |path: &str| path.replacen("/legacy", "", 1);
+        let response = handle_path(&client, &rewrite("/legacy//a")).unwrap();
+        assert_eq!(response.matched_path.as_deref(), Some("//a"));
+        let response = TEST_RPC.c(&client, None, None, false).await.unwrap();
+        assert_eq!(response.matched_path.as_deref(), Some("/c"));
+
+        // A followed redirect is matched from the new path
+        let client = RedirectClient::new(TestClient::new(TEST_REDIRECT_RPC));
+        let response = TEST_REDIRECT_RPC
+            .moved_sub(&client, None, None, false, &7)
+            .await
+            .unwrap();
+        assert_eq!(response.matched_path.as_deref(), Some("/sub/7"));
+    }
+
+    /// Send a response to a client as a node does, i.e. only with the fields
+    /// of an ABCI query response and with the metadata in its `key`.
+    fn abci_round_trip(response: EncodedResponseQuery) -> EncodedResponseQuery {
+        let key = response.metadata().encode();
+        let sent = ResponseQuery {
+            data: response.data,
+            info: response.info,
+            log: response.log,
+            code: response.code,
+            codespace: response.codespace,
+            proof: response.proof,
+            ..ResponseQuery::default()
+        };
+        sent.with_metadata(ResponseMetadata::decode(&key).unwrap())
+    }
+
+    /// Test that the metadata set by the root router's `handle` is received
+    /// by a client of a node.
+    #[test]
+    fn test_router_metadata_round_trip() {
+        use crate::ledger::storage::merkle_tree::MerkleRoot;
+
+        let client = TestClient::new(TEST_RPC);
+        let ctx = || client.ctx();
+
+        // The ETag, content length and matched path of a head request
+        let request = RequestQuery {
+            path: "/c".to_owned(),
+            head: true,
+            ..RequestQuery::default()
+        };
+        let response = TEST_RPC.handle(ctx(), &request).unwrap();
+        assert!(response.etag.is_some());
+        assert!(response.content_length.is_some());
+        let received = abci_round_trip(response.clone());
+        assert_eq!(received.etag, response.etag);
+        assert_eq!(received.content_length, response.content_length);
+        assert_eq!(received.matched_path.as_deref(), Some("/c"));
+        assert!(!received.not_modified);
+
+        // A response that's not modified
+        let request = RequestQuery {
+            path: "/c".to_owned(),
+            if_none_match: response.etag.clone(),
+            ..RequestQuery::default()
+        };
+        let response = TEST_RPC.handle(ctx(), &request).unwrap();
+        let received = abci_round_trip(response.clone());
+        assert!(received.not_modified);
+        assert_eq!(received.etag, response.etag);
+
+        // The root of a response's proof
+        let response = EncodedResponseQuery::default()
+            .with_root(MerkleRoot(vec![1, 2, 3]));
+        let received = abci_round_trip(response);
+        assert_eq!(received.root, Some(MerkleRoot(vec![1, 2, 3])));
+    }
+
+    /// Test that a handler's `info` is kept alongside a redirect and a retry
+    /// hint, which are sent in the response metadata.
+    #[test]
+    fn test_router_metadata_with_info() {
+        use super::test_rpc::{TEST_REDIRECT_RPC, TEST_RETRY_RPC};
+
+        let client = TestClient::new(TEST_REDIRECT_RPC);
+        let path = TEST_REDIRECT_RPC.moved_sub_path(&7);
+        let response = abci_round_trip(handle_path(&client, &path).unwrap());
+        assert_eq!(response.info, "Moved to /sub/7");
+        assert_eq!(response.redirect_path(), Some("/sub/7"));
+
+        let client = TestClient::new(TEST_RETRY_RPC);
+        // The `flaky` handler fails on every other call
+        let response = (0..2)
+            .map(|_| {
+                abci_round_trip(
+                    handle_path(&client, &TEST_RETRY_RPC.flaky_path()).unwrap(),
+                )
+            })
+            .find(|response| response.retry_after_hint().is_some())
+            .unwrap();
+        assert_eq!(response.info, "Busy");
+        assert_eq!(
+            response.retry_after_hint(),
+            Some(std::time::Duration::from_millis(50))
+        );
+
+        // A response without metadata leaves the `key` empty
+        let response = EncodedResponseQuery::default();
+        assert!(response.metadata().encode().is_empty());
+    }
+
+    /// Test requesting the proofs in the compact format.
+    #[tokio::test]
+    async fn test_router_compact_proofs() {
+        let mut client = TestClient::new(TEST_RPC);
+        let full_proof = TEST_RPC
+            .two_proofs(&client, None, None, true)
+            .await
+            .unwrap()
+            .proof
+            .unwrap();
+        assert!(CompactProof::expand(&full_proof).is_none());
+
+        client.compact_proof = true;
+        // The repeated ops are only sent once
+        let response = TEST_RPC
+            .raw(&client, "/two_proofs", None, None, true)
+            .await
+            .unwrap();
+        let compact_proof = response.proof.unwrap();
+        assert_eq!(compact_proof.ops.len(), 5);
+        assert_eq!(
+            compact_proof.ops[0].field_type,
+            CompactProof::MARKER_FIELD_TYPE
+        );
+        assert_eq!(CompactProof::expand(&compact_proof).unwrap(), full_proof);
+
+        // The generated methods expand the proof
+        let response = TEST_RPC
+            .two_proofs(&client, None, None, true)
+            .await
+            .unwrap();
+        assert_eq!(response.proof.unwrap(), full_proof);
+        assert_eq!(response.proofs.len(), 2);
+    }
+
+    /// Test transforming a response's data while preserving the rest of it.
+    #[test]
+    fn test_response_query_map() {
+        use crate::ledger::storage::merkle_tree::MerkleRoot;
+        use crate::tendermint::merkle::proof::{Proof, ProofOp};
+
+        let proof = Proof {
+            ops: vec![ProofOp {
+                field_type: "test".to_owned(),
+                key: b"key".to_vec(),
+                data: vec![1, 2, 3],
+            }],
+        };
+        let response = ResponseQuery {
+            data: token::Amount::from(1_234_567),
+            info: "info".to_owned(),
+            info_message: Some(InfoMessage::new("key", vec!["arg".to_owned()])),
+            log: "log".to_owned(),
+            code: 2,
+            codespace: "codespace".to_owned(),
+            proof: Some(proof.clone()),
+            root: Some(MerkleRoot(vec![4, 5, 6])),
+            proofs: vec![],
+            etag: Some(Hash::sha256(b"data")),
+            not_modified: true,
+            value: None,
+            redirect: None,
+            retry_after: None,
+            content_length: None,
+            warnings: vec![Warning::new("code", "message")],
+            matched_path: Some("/a".to_owned()),
+        };
+
+        let mapped: ResponseQuery<u64> = response.clone().map(u64::from);
+        assert_eq!(mapped.data, 1_234_567);
+        assert_eq!(mapped.info, response.info);
+        assert_eq!(mapped.info_message, response.info_message);
+        assert_eq!(mapped.log, response.log);
+        assert_eq!(mapped.codespace, response.codespace);
+        assert_eq!(mapped.code, response.code);
+        assert_eq!(mapped.proof, Some(proof));
+        assert_eq!(mapped.root, response.root);
+        assert_eq!(mapped.etag, response.etag);
+        assert!(mapped.not_modified);
+        assert_eq!(mapped.warnings, response.warnings);
+        assert_eq!(mapped.matched_path, response.matched_path);
+
+        assert_eq!(response.into_inner(), token::Amount::from(1_234_567));
+    }
+
+    /// Test constructing a response with the builder methods.
+    #[test]
+    fn test_response_query_builder() {
+        use crate::tendermint::merkle::proof::{Proof, ProofOp};
+
+        let proof = Proof {
+            ops: vec![ProofOp {
+                field_type: "test".to_owned(),
+                key: b"key".to_vec(),
+                data: vec![1, 2, 3],
+            }],
+        };
+        let built = ResponseQuery::new(vec![1_u8, 2, 3])
+            .with_info("info")
+            .with_proof(proof.clone())
+            .with_log("log")
+            .with_code(3)
+            .with_codespace("namada");
+        let literal = ResponseQuery {
+            data: vec![1_u8, 2, 3],
+            info: "info".to_owned(),
+            proof: Some(proof),
+            log: "log".to_owned(),
+            code: 3,
+            codespace: "namada".to_owned(),
+            ..ResponseQuery::default()
+        };
+        // The response doesn't implement `PartialEq`, because of its `value`
+        assert_eq!(format!("{built:?}"), format!("{literal:?}"));
+
+        let empty: EncodedResponseQuery =
+            ResponseQuery::empty().with_proof(None);
+        assert_eq!(
+            format!("{empty:?}"),
+            format!("{:?}", EncodedResponseQuery::default())
+        );
+        assert_eq!(
+            format!("{:?}", ResponseQuery::new(Vec::<u8>::new())),
+            format!("{empty:?}")
+        );
+    }
+
+    /// Test the client methods that return the raw response bytes.
+    #[tokio::test]
+    async fn test_router_macro_raw() {
+        let client = TestClient::new(TEST_RPC);
+
+        let bytes = TEST_RPC.b1_raw(&client).await.unwrap();
+        assert_eq!(String::try_from_slice(&bytes).unwrap(), "b1");
+        let decoded = TEST_RPC.b1(&client).await.unwrap();
+        assert_eq!(bytes, decoded.try_to_vec().unwrap());
+
+        let balance = token::Amount::from(123_000_000);
+        let bytes = TEST_RPC.b2i_raw(&client, &balance).await.unwrap();
+        assert_eq!(
+            String::try_from_slice(&bytes).unwrap(),
+            format!("b2i/{balance}")
+        );
+
+        let response =
+            TEST_RPC.c_raw(&client, None, None, false).await.unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "c");
+        assert_eq!(response.info, "Served by c");
+    }
+
+    /// Test that a response is not sent again when the request has its ETag.
+    #[test]
+    fn test_router_etag() {
+        use crate::types::hash::Hash;
+
+        let client = TestClient::new(TEST_RPC);
+        let ctx = || client.ctx();
+        let request = |if_none_match: Option<Hash>| RequestQuery {
+            path: "/b/1".to_owned(),
+            if_none_match,
+            ..RequestQuery::default()
+        };
+
+        // The first request has no ETag
+        let response = TEST_RPC.handle(ctx(), &request(None)).unwrap();
+        assert!(!response.not_modified);
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "b1");
+        let etag = response.etag.unwrap();
+        assert_eq!(etag, Hash::sha256(&response.data));
+
+        // The second request with the matching ETag is not modified
+        let response = TEST_RPC
+            .handle(ctx(), &request(Some(etag.clone())))
+            .unwrap();
+        assert!(response.not_modified);
+        assert!(response.data.is_empty());
+        assert_eq!(response.etag, Some(etag.clone()));
+
+        // A request with an ETag that doesn't match gets the whole response
+        let stale = Hash::sha256(b"stale");
+        let response = TEST_RPC.handle(ctx(), &request(Some(stale))).unwrap();
+        assert!(!response.not_modified);
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "b1");
+        assert_eq!(response.etag, Some(etag));
+    }
+
+    /// Test a request for only the metadata of a response, which is handled
+    /// as usual, but without sending the response's data.
+    #[test]
+    fn test_router_head_request() {
+        let client = TestClient::new(TEST_RPC);
+        let ctx = || client.ctx();
+        let request = |head| RequestQuery {
+            path: "/c".to_owned(),
+            head,
+            ..RequestQuery::default()
+        };
+
+        let full = TEST_RPC.handle(ctx(), &request(false)).unwrap();
+        assert!(!full.data.is_empty());
+        assert_eq!(full.content_length, None);
+
+        let head = TEST_RPC.handle(ctx(), &request(true)).unwrap();
+        assert!(head.data.is_empty());
+        assert_eq!(head.content_length, Some(full.data.len()));
+        assert_eq!(head.etag, full.etag);
+        assert_eq!(head.info, "Served by c");
+        assert_eq!(head.info, full.info);
+
+        // A route that isn't matched is still an error
+        let request = RequestQuery {
+            path: "/unknown".to_owned(),
+            head: true,
+            ..RequestQuery::default()
+        };
+        assert!(TEST_RPC.handle(ctx(), &request).is_err());
+    }
+
+    /// Test that a request's options are carried in the path of an ABCI
+    /// query request and parsed back by `RequestQuery::try_from_tm`.
+    #[test]
+    fn test_request_query_try_from_tm() {
+        use crate::tendermint_proto::abci::RequestQuery as TmRequestQuery;
+        use crate::types::hash::Hash;
+        use crate::types::storage::BlockHeight;
+
+        let client = TestClient::new(TEST_RPC);
+        let to_tm = |request: &RequestQuery| TmRequestQuery {
+            data: request.data.clone(),
+            path: request.abci_path(),
+            height: 0,
+            prove: request.prove,
+        };
+
+        // A request without options is sent as is
+        let request = RequestQuery {
+            path: "/search?limit=10".to_owned(),
+            height: client.storage.last_height,
+            ..RequestQuery::default()
+        };
+        assert_eq!(request.abci_path(), request.path);
+        let parsed =
+            RequestQuery::try_from_tm(&client.storage, to_tm(&request))
+                .unwrap();
+        assert_eq!(parsed, request);
+
+        // All the options round-trip, while the route's own query params
+        // are kept in the path
+        let request = RequestQuery {
+            path: "/search?limit=10".to_owned(),
+            height: client.storage.last_height,
+            if_none_match: Some(Hash::sha256(b"cached")),
+            request_id: Some("req&id=1".to_owned()),
+            compact_proof: true,
+            if_height: Some(BlockHeight(5)),
+            head: true,
+            height_selector: HeightSelector::Finalized,
+            versioned: true,
+            ..RequestQuery::default()
+        };
+        let tm = to_tm(&request);
+        assert!(tm.path.starts_with("/search?limit=10&_"), "{}", tm.path);
+        let parsed = RequestQuery::try_from_tm(&client.storage, tm).unwrap();
+        assert_eq!(parsed, request);
+
+        // The options are stripped from a path without other params
+        let request = RequestQuery {
+            path: "/c".to_owned(),
+            height: client.storage.last_height,
+            head: true,
+            ..RequestQuery::default()
+        };
+        let parsed =
+            RequestQuery::try_from_tm(&client.storage, to_tm(&request))
+                .unwrap();
+        assert_eq!(parsed.path, "/c");
+        assert!(parsed.head);
+
+        // The caller is never sent, because only the node sets it
+        let request = RequestQuery {
+            path: "/c".to_owned(),
+            height: client.storage.last_height,
+            caller: Some("client".to_owned()),
+            ..RequestQuery::default()
+        };
+        assert_eq!(request.abci_path(), "/c");
+        let parsed =
+            RequestQuery::try_from_tm(&client.storage, to_tm(&request))
+                .unwrap();
+        assert_eq!(parsed.caller, None);
+
+        // An unknown or invalid option, or a caller set by the client, is
+        // rejected
+        for path in [
+            "/c?_caller=Y2xpZW50",
+            "/c?_unknown=1",
+            "/c?_head=yes",
+            "/c?_head",
+            "/c?_if_height=x",
+        ] {
+            let tm = TmRequestQuery {
+                path: path.to_owned(),
+                ..TmRequestQuery::default()
+            };
+            assert!(
+                RequestQuery::try_from_tm(&client.storage, tm).is_err(),
+                "{path}"
+            );
+        }
+    }
+
+    /// Test the protobuf service definition written by
+    /// `print_proto_service`.
+    #[test]
+    fn test_router_macro_print_proto_service() {
+        let mut buf = vec![];
+        TEST_RPC
+            .print_proto_service(&mut buf, "namada.test", "TestRpc")
+            .unwrap();
+        let proto = String::from_utf8(buf).unwrap();
+        let message = |name: &str| -> Vec<&str> {
+            let start = format!("message {name} {{");
+            proto
+                .lines()
+                .skip_while(|line| *line != start)
+                .skip(1)
+                .take_while(|line| *line != "}")
+                .map(str::trim)
+                .collect()
+        };
+
+        assert!(proto.starts_with("syntax = \"proto3\";\n"));
+        assert!(proto.contains("package namada.test;\n"));
+        assert!(proto.contains("service TestRpc {\n"));
+        assert!(proto.contains(
+            "  // /b/3/{a1}/{a2}/{a3?}/iii\n  rpc B3iii(B3iiiRequest) returns \
+             (B3iiiResponse);\n"
+        ));
+        assert_eq!(
+            message("B3iiiRequest"),
+            [
+                "string a1 = 1;",
+                "string a2 = 2;",
+                "optional string a3 = 3;"
+            ]
+        );
+        assert_eq!(message("B3iiiResponse"), ["string value = 1;"]);
+        assert_eq!(
+            message("B3iiiiRequest"),
+            [
+                "string a1 = 1;",
+                "string a2 = 2;",
+                "optional string a3 = 3;",
+                "optional uint64 a4 = 4;"
+            ]
+        );
+        assert_eq!(message("DivRequest"), ["uint64 a = 1;", "uint64 b = 2;"]);
+        assert_eq!(
+            message("DivResponse"),
+            [
+                "// The borsh-encoded `Result<u64,DivError>`",
+                "bytes value = 1;"
+            ]
+        );
+        assert_eq!(message("BytesRequest"), ["bytes tok = 1;"]);
+        assert_eq!(message("BytesResponse"), ["bytes value = 1;"]);
+        assert_eq!(message("DumpResponse"), ["repeated uint64 value = 1;"]);
+        assert_eq!(
+            message("LookupResponse"),
+            [
+                "// The borsh-encoded `Option<token::Amount>`",
+                "bytes value = 1;"
+            ]
+        );
+        assert_eq!(message("ARequest"), Vec::<&str>::new());
+        // Every route has an RPC
+        assert_eq!(proto.matches("  rpc ").count(), TEST_RPC.routes().len());
+    }
+
+    /// Test the route tree rendered by `to_dot`.
+    #[test]
+    fn test_router_macro_to_dot() {
+        let dot = TEST_RPC.to_dot();
+        assert!(dot.starts_with("digraph \"TEST_RPC\" {\n"));
+        assert!(dot.ends_with("}\n"));
+
+        // The literal segments and the args have distinct styles
+        assert!(dot.contains("  \"/b\" [label=\"b\", shape=ellipse];\n"));
+        assert!(dot.contains("  \"/b/3\" [label=\"3\", shape=ellipse];\n"));
+        assert!(dot.contains(
+            "  \"/b/3/{a1}\" [label=\"a1: token::Amount\", shape=box, \
+             style=dashed];\n"
+        ));
+        assert!(dot.contains("  \"/\" -> \"/b\";\n"));
+        assert!(dot.contains("  \"/b\" -> \"/b/3\";\n"));
+        assert!(dot.contains("  \"/b/3\" -> \"/b/3/{a1}\";\n"));
+        // Every node has a single incoming edge
+        assert_eq!(dot.matches(" -> \"/b/3\";").count(), 1);
+
+        // The routes of a sub-router are under its prefix
+        assert!(dot.contains("  \"/sub\" -> \"/sub/x\";\n"));
+        assert!(dot.contains("  \"/sub/x\" -> \"/sub/x#x\" [style=dotted];\n"));
+        assert!(dot
+            .contains("  \"/sub/x#x\" [label=\"x -> String\", shape=note];\n"));
+    }
+
+    /// Test the TypeScript client written by `print_ts_client`.
+    #[test]
+    fn test_router_macro_print_ts_client() {
+        let mut buf = vec![];
+        TEST_RPC.print_ts_client(&mut buf).unwrap();
+        let ts = String::from_utf8(buf).unwrap();
+        let function = |name: &str| -> Vec<&str> {
+            let start = format!("export async function {name}(");
+            ts.lines()
+                .skip_while(|line| !line.starts_with(&start))
+                .take_while(|line| *line != "}")
+                .collect()
+        };
+
+        assert!(ts.contains("import { deserialize } from \"borsh\";\n"));
+        assert!(ts.contains("export interface QueryClient {\n"));
+        assert_eq!(
+            function("b3iii"),
+            [
+                "export async function b3iii(client: QueryClient, a1: string, \
+                 a2: string, a3?: string): Promise<string> {",
+                "  const path = `/b/3/${a1}/${a2}${a3 === undefined ? \"\" : \
+                 `/${a3}`}/iii`;",
+                "  return deserialize(\"string\", await client.request(path)) \
+                 as string;",
+            ]
+        );
+        assert_eq!(
+            function("div"),
+            [
+                "export async function div(client: QueryClient, a: bigint, b: \
+                 bigint): Promise<Uint8Array> {",
+                "  const path = `/div/${a}/${b}`;",
+                "  return client.request(path);",
+            ]
+        );
+        assert_eq!(
+            function("bytes"),
+            [
+                "export async function bytes(client: QueryClient, tok: \
+                 Uint8Array): Promise<Array<number>> {",
+                "  const path = `/bytes/${encodeB64Segment(tok)}`;",
+                "  return deserialize({ array: { type: \"u8\" } }, await \
+                 client.request(path)) as Array<number>;",
+            ]
+        );
+        assert!(ts.contains(
+            "export async function dump(client: QueryClient): \
+             Promise<Array<bigint>> {\n"
+        ));
+        assert!(ts.contains(
+            "// Resolves to the borsh-encoded `Option<token::Amount>`\nexport \
+             async function lookup("
+        ));
+        // An optional arg followed by a required one
+        assert!(ts.contains(
+            "export async function wide(client: QueryClient, a1: string, a2: \
+             bigint | undefined, name: string): Promise<string> {\n"
+        ));
+        // Every route has a function
+        assert_eq!(
+            ts.matches("export async function ").count(),
+            TEST_RPC.routes().len()
+        );
+    }
+
+    /// Test the table of routes written by `print_routes`.
+    #[test]
+    fn test_router_macro_print_routes() {
+        let mut buf = vec![];
+        TEST_RPC.print_routes(&mut buf).unwrap();
+        let table = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), TEST_RPC.routes().len() + 1);
+
+        let find = |template: &str| {
+            lines
+                .iter()
+                .copied()
+                .find(|line| line.split_whitespace().next() == Some(template))
+                .unwrap()
+        };
+        let words = |line: &str| -> Vec<String> {
+            line.split_whitespace().map(ToOwned::to_owned).collect()
+        };
+        assert_eq!(words(lines[0]), ["ROUTE", "HANDLER", "ARGS"]);
+        assert_eq!(
+            words(find("/b/2/i/{balance}")),
+            ["/b/2/i/{balance}", "b2i", "balance:", "token::Amount"]
+        );
+        assert_eq!(words(find("/a")), ["/a", "a"]);
+        assert_eq!(
+            words(find("/limit/{limit?}")),
+            ["/limit/{limit?}", "limited", "limit:", "opt", "u64"]
+        );
+        // The args of a sub-router's prefix are included
+        assert_eq!(
+            words(find("/validator/{addr}/name")),
+            [
+                "/validator/{addr}/name",
+                "validator_name",
+                "addr:",
+                "Address"
+            ]
+        );
+        assert_eq!(
+            words(find("/sub/y/{untyped_arg}")),
+            ["/sub/y/{untyped_arg}", "y", "untyped_arg:", "str"]
+        );
+
+        // The handler column is aligned
+        let handler_column = lines[0].find("HANDLER").unwrap();
+        for line in &lines[1..] {
+            assert_eq!(&line[handler_column - 2..handler_column], "  ");
+            assert_ne!(line.as_bytes()[handler_column], b' ', "{line}");
+        }
+    }
+
+    /// Test that the routes with `#[cfg(...)]` attributes are only included
+    /// when the configuration predicate is satisfied. Run with and without
+    /// `--features tooling` to test both cases for the `/dump_state` route.
+    #[test]
+    fn test_router_macro_cfg() {
+        use super::test_rpc::{TestCfgRpcRoute, TEST_CFG_RPC};
+
+        let client = TestClient::new(TEST_CFG_RPC);
+        let dump_state_enabled = cfg!(feature = "tooling");
+
+        assert_eq!(TEST_CFG_RPC.route_id("/a"), Some(TestCfgRpcRoute::A));
+        assert_eq!(TEST_CFG_RPC.route_id("/b/1"), Some(TestCfgRpcRoute::B1));
+        assert!(handle_path(&client, "/b/1").is_ok());
+        // The routes that are never included
+        for path in ["/b/2", "/c/i", "/sub/x"] {
+            assert_eq!(TEST_CFG_RPC.route_id(path), None, "{path}");
+            assert!(handle_path(&client, path).is_err(), "{path}");
+        }
+        // The feature-gated route
+        assert_eq!(
+            TEST_CFG_RPC.route_id("/dump_state").is_some(),
+            dump_state_enabled
+        );
+        assert_eq!(
+            handle_path(&client, "/dump_state").is_ok(),
+            dump_state_enabled
+        );
+        #[cfg(feature = "tooling")]
+        assert_eq!(TEST_CFG_RPC.dump_state_path(), "/dump_state");
+
+        let handlers: Vec<&str> = TEST_CFG_RPC
+            .routes()
+            .into_iter()
+            .map(|route| route.handler)
+            .collect();
+        if dump_state_enabled {
+            assert_eq!(handlers, ["a", "dump_state", "b1"]);
+        } else {
+            assert_eq!(handlers, ["a", "b1"]);
+        }
+        assert_eq!(TEST_CFG_RPC.sample_paths().len(), handlers.len());
+        assert_eq!(TEST_CFG_RPC.response_schemas().len(), handlers.len());
+    }
+
+    /// Test describing the leaf routes of a router in JSON.
+    #[test]
+    fn test_router_macro_routes_json() {
+        let json = TEST_RPC.routes_json();
+        let routes = json.as_array().unwrap();
+        assert_eq!(routes.len(), TEST_RPC.routes().len());
+        let b2i = routes
+            .iter()
+            .find(|route| route["template"] == "/b/2/i/{balance}")
+            .unwrap();
+        assert_eq!(
+            *b2i,
+            serde_json::json!({
+                "template": "/b/2/i/{balance}",
+                "handler": "b2i",
+                "params": [
+                    {"name": "balance", "type": "token::Amount", "optional": false},
+                ],
+                "return_type": "String",
+                "provable": false,
+                "deprecated": false,
+            })
+        );
+        let c = routes.iter().find(|route| route["handler"] == "c").unwrap();
+        assert_eq!(c["provable"], true);
+
+        let json = super::test_rpc::TEST_REDIRECT_RPC.routes_json();
+        let deprecated: Vec<_> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|route| route["deprecated"] == true)
+            .map(|route| route["template"].as_str().unwrap())
+            .collect();
+        assert_eq!(deprecated, ["/old_sub/{n}"]);
+    }
+
+    /// Test the diff of a router's routes against a modified copy of them.
+    #[test]
+    fn test_diff_routes() {
+        let old = TEST_RPC.routes();
+        assert!(diff_routes(&old, &old).is_empty());
+
+        let mut new = old.clone();
+        let a = new.iter().position(|route| route.template == "/a").unwrap();
+        let removed = new.remove(a);
+        let added = RouteInfo {
+            handler: "z",
+            template: "/z/{n}".to_owned(),
+            args: vec![RouteArg {
+                name: "n",
+                ty: "u64",
+                optional: false,
+            }],
+            response_ty: "u64",
+            internal: false,
+            provable: false,
+            deprecated: false,
+        };
+        new.push(added.clone());
+        let c = new.iter_mut().find(|route| route.template == "/c").unwrap();
+        c.response_ty = "u64";
+        // Renaming an arg is not a change
+        let e = new
+            .iter_mut()
+            .find(|route| route.template == "/e/{int}")
+            .unwrap();
+        e.template = "/e/{i}".to_owned();
+        e.args[0].name = "i";
+
+        let diff = diff_routes(&old, &new);
+        assert_eq!(diff.added, vec![added]);
+        assert_eq!(diff.removed, vec![removed]);
+        let changed: Vec<_> = diff
+            .changed
+            .iter()
+            .map(|(old, new)| (old.response_ty, new.response_ty))
+            .collect();
+        assert_eq!(changed, [("String", "u64")]);
+        assert!(diff.is_breaking());
+        assert_eq!(
+            diff.to_string(),
+            "+ /z/{n} (n: u64) -> u64\n- /a -> String\n~ /c -> String => /c \
+             -> u64\n"
+        );
+
+        // Only adding a route is backward compatible
+        let diff = diff_routes(&old[1..], &old);
+        assert_eq!(diff.added, old[..1]);
+        assert!(!diff.is_breaking());
+    }
+
+    /// Test listing the leaf routes of a router.
+    #[test]
+    fn test_router_macro_routes() {
+        let routes = TEST_RPC.routes();
+        assert_eq!(
+            routes.len() + TEST_RPC.internal_routes().len(),
+            TEST_RPC.sample_paths().len()
+        );
+        let find = |handler: &str| {
+            routes
+                .iter()
+                .find(|route| route.handler == handler)
+                .unwrap()
+                .template
+                .clone()
+        };
+        assert_eq!(find("a"), "/a");
+        assert_eq!(find("b0ii"), "/b/0/ii");
+        assert_eq!(find("b3iii"), "/b/3/{a1}/{a2}/{a3?}/iii");
+        assert_eq!(find("c"), "/c");
+        assert_eq!(find("h"), "/h/{slug}");
+        assert_eq!(find("limited"), "/limit/{limit?}");
+        // Sub-routers' routes include their prefix
+        assert_eq!(find("y"), "/sub/y/{untyped_arg}");
+        assert_eq!(find("validator_name"), "/validator/{addr}/name");
+
+        let handlers = |prefix: &str| -> Vec<&str> {
+            TEST_RPC
+                .routes_under(prefix)
+                .into_iter()
+                .map(|route| route.handler)
+                .collect()
+        };
+        let b3_family = ["b3sum", "b3i", "b3", "b3ii", "b3iii", "b3iiii"];
+        assert_eq!(handlers("/b/3"), b3_family);
+        assert_eq!(handlers("/b/3/"), b3_family);
+        // Only whole segments are matched
+        assert!(handlers("/b/").len() > b3_family.len());
+        assert!(handlers("/b/30").is_empty());
+
+        let params = TEST_RPC.route_params("b2i").unwrap();
+        assert_eq!(
+            params,
+            vec![ParamSpec {
+                name: "balance",
+                ty: "token::Amount",
+                optional: false,
+            }]
+        );
+        let params: Vec<_> = TEST_RPC
+            .route_params("b3iiii")
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            params,
+            [
+                "a1: token::Amount",
+                "a2: token::Amount",
+                "a3: opt token::Amount",
+                "a4: opt Epoch"
+            ]
+        );
+        assert_eq!(TEST_RPC.route_params("unknown"), None);
+        assert_eq!(handlers("/validator"), ["validator_name"]);
+        assert_eq!(TEST_RPC.routes_under("/"), routes);
+    }
+
+    /// Test a handler that receives its args in a params struct.
+    #[tokio::test]
+    async fn test_router_macro_params_struct() {
+        let client = TestClient::new(TEST_RPC);
+
+        let a1 = token::Amount::from(1_000_000);
+        let result = TEST_RPC
+            .wide(&client, &a1, &Some(Epoch(2)), "abc")
+            .await
+            .unwrap();
+        assert_eq!(result, "wide/1/2/abc");
+        let result = TEST_RPC.wide(&client, &a1, &None, "abc").await.unwrap();
+        assert_eq!(result, "wide/1/abc");
+    }
+
+    /// Test a handler that receives its args in a map keyed by their names.
+    #[tokio::test]
+    async fn test_router_macro_args_map() {
+        use super::test_rpc::TEST_ARGS_MAP_RPC;
+        let client = TestClient::new(TEST_ARGS_MAP_RPC);
+
+        let result = TEST_ARGS_MAP_RPC
+            .proxy(&client, "namada", "pos", &Some(10))
+            .await
+            .unwrap();
+        assert_eq!(result, "chain=namada,height=10,module=pos");
+        // An absent optional arg is left out of the map
+        let result = TEST_ARGS_MAP_RPC
+            .proxy(&client, "namada", "pos", &None)
+            .await
+            .unwrap();
+        assert_eq!(result, "chain=namada,module=pos");
+    }
+
+    /// Test that a handler's keyed info message is sent in the response
+    /// metadata and resolved by the client into the chosen locale.
+    #[tokio::test]
+    async fn test_router_keyed_info() {
+        let client = TestClient::new(TEST_RPC);
+        let english: InfoCatalog =
+            [("items.found", "Found {0} items")].into_iter().collect();
+        let mut german = InfoCatalog::new();
+        german.insert("items.found", "{0} Einträge gefunden");
+
+        let response = TEST_RPC
+            .keyed_info(&client, None, None, false, &3)
+            .await
+            .unwrap();
+        assert_eq!(response.data, "keyed_info");
+        let message = InfoMessage::new("items.found", vec!["3".to_owned()]);
+        assert_eq!(response.info_message, Some(message.clone()));
+        assert_eq!(response.resolve_info(&english), "Found 3 items");
+        assert_eq!(response.resolve_info(&german), "3 Einträge gefunden");
+        // An unknown key is displayed with its args
+        assert_eq!(
+            response.resolve_info(&InfoCatalog::new()),
+            "items.found: 3"
+        );
+
+        // The message round-trips through the encoded response metadata
+        let raw = TEST_RPC
+            .keyed_info_raw(&client, None, None, false, &3)
+            .await
+            .unwrap();
+        let metadata =
+            ResponseMetadata::decode(&raw.metadata().encode()).unwrap();
+        assert_eq!(metadata.info_message, Some(message));
+        assert_eq!(ResponseMetadata::decode(&[]).unwrap(), Default::default());
+
+        // A plain info is returned as is
+        let response = TEST_RPC.c(&client, None, None, false).await.unwrap();
+        assert_eq!(response.info_message, None);
+        assert_eq!(response.resolve_info(&english), "Served by c");
+
+        // An arg that looks like a placeholder isn't replaced, nor is a
+        // placeholder without an arg
+        let catalog: InfoCatalog =
+            [("k", "{0} and {1} or {2}")].into_iter().collect();
+        let message = InfoMessage::new("k", vec!["{1}".to_owned(), "b".into()]);
+        assert_eq!(catalog.resolve(&message).unwrap(), "{1} and b or {2}");
+    }
+
+    /// Test that a route's aliases are dispatched to its handler, while the
+    /// generated methods use the first form of its literal.
+    #[tokio::test]
+    async fn test_router_macro_route_aliases() {
+        use super::test_rpc::TestRpcRoute;
+
+        let client = TestClient::new(TEST_RPC);
+
+        assert_eq!(TEST_RPC.balance_path(), "/balance");
+        assert_eq!(TEST_RPC.balance(&client).await.unwrap(), "balance");
+        for path in ["/balance", "/bal", "/bl/"] {
+            let response = handle_path(&client, path).unwrap();
+            let result = String::try_from_slice(&response.data).unwrap();
+            assert_eq!(result, "balance");
+            assert_eq!(TEST_RPC.route_id(path), Some(TestRpcRoute::Balance));
+        }
+        assert!(handle_path(&client, "/balances").is_err());
+
+        // An alias of a nested pattern
+        assert_eq!(TEST_RPC.b4_path(), "/b/4");
+        for path in ["/b/4", "/b/four"] {
+            let response = handle_path(&client, path).unwrap();
+            let result = String::try_from_slice(&response.data).unwrap();
+            assert_eq!(result, "b4");
+            assert_eq!(TEST_RPC.route_id(path), Some(TestRpcRoute::B4));
+        }
+
+        let templates: Vec<String> = TEST_RPC
+            .routes()
+            .into_iter()
+            .map(|route| route.template)
+            .collect();
+        assert!(templates.contains(&"/balance".to_owned()));
+        assert!(templates.contains(&"/b/4".to_owned()));
+        assert!(!templates.contains(&"/bal".to_owned()));
+    }
+
+    /// Test a router defined in an external spec file.
+    #[tokio::test]
+    async fn test_router_include_router() {
+        use super::test_rpc::{TestSpecRpcRoute, TEST_SPEC_RPC};
+
+        let client = TestClient::new(TEST_SPEC_RPC);
+
+        let result = TEST_SPEC_RPC.a(&client).await.unwrap();
+        assert_eq!(result, "a");
+        let balance = token::Amount::from(123_000_000);
+        let result = TEST_SPEC_RPC.b2i(&client, &balance).await.unwrap();
+        assert_eq!(result, format!("b2i/{balance}"));
+        let result = TEST_SPEC_RPC.b1(&client).await.unwrap();
+        assert_eq!(result, "b1");
+        let result = TEST_SPEC_RPC.test_sub_rpc().x(&client).await.unwrap();
+        assert_eq!(result, "x");
+
+        assert_eq!(TEST_SPEC_RPC.route_id("/b/1"), Some(TestSpecRpcRoute::B1));
+        assert_eq!(TEST_SPEC_RPC.sample_paths().len(), 6);
+    }
+
+    /// Test that an `#[internal]` route is handled, but it's only listed by
+    /// the `internal_routes`.
+    #[tokio::test]
+    async fn test_router_macro_internal_route() {
+        let client = TestClient::new(TEST_RPC);
+
+        let result = TEST_RPC.internal_stats(&client).await.unwrap();
+        assert_eq!(result, "internal_stats");
+
+        assert!(!TEST_RPC
+            .routes()
+            .iter()
+            .any(|route| route.handler == "internal_stats"));
+        assert!(TEST_RPC.routes_under("/internal_stats").is_empty());
+        assert_eq!(TEST_RPC.route_params("internal_stats"), None);
+        assert_eq!(
+            TEST_RPC.internal_routes(),
+            vec![RouteInfo {
+                handler: "internal_stats",
+                template: "/internal_stats".to_owned(),
+                args: vec![],
+                response_ty: "String",
+                internal: true,
+                provable: false,
+                deprecated: false,
+            }]
+        );
+        assert_eq!(TEST_RPC.all_routes().len(), TEST_RPC.routes().len() + 1);
+        assert!(!TEST_RPC
+            .response_schemas()
+            .iter()
+            .any(|(handler, _)| *handler == "internal_stats"));
+    }
+
+    /// Test that a handler call that takes longer than the threshold is logged.
+    #[test]
+    fn test_router_slow_query_log() {
+        use std::io;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        /// A writer for the captured logs
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+        impl io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut client = TestClient::new(TEST_RPC);
+        client.handler_config.slow_query_threshold = Duration::from_millis(20);
+        let logs = Logs::default();
+        let subscriber = {
+            let logs = logs.clone();
+            tracing_subscriber::fmt()
+                .with_writer(move || logs.clone())
+                .with_ansi(false)
+                .finish()
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            handle_path(&client, "/a").unwrap();
+            handle_path(&client, "/slow").unwrap();
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let warnings: Vec<&str> = logs.lines().collect();
+        assert_eq!(warnings.len(), 1, "{logs}");
+        assert!(warnings[0].contains("WARN"), "{logs}");
+        assert!(warnings[0].contains("Slow query"), "{logs}");
+        assert!(warnings[0].contains("route=\"slow\""), "{logs}");
+    }
+
+    /// Test that the time spent in each phase of handling a request is
+    /// measured, with the slow handler's call taking the most of it.
+    #[test]
+    fn test_router_handle_timed() {
+        let client = TestClient::new(TEST_RPC);
+        let request = RequestQuery {
+            path: "/slow".to_owned(),
+            ..RequestQuery::default()
+        };
+        let ctx = client.ctx();
+        let start = Instant::now();
+        let (response, timing) = TEST_RPC.handle_timed(ctx, &request).unwrap();
+        let total = start.elapsed();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "slow");
+
+        assert!(timing.match_ns > 0, "{timing:?}");
+        assert!(timing.encode_ns > 0, "{timing:?}");
+        // The handler sleeps for 50ms
+        assert!(timing.handler_ns >= 50_000_000, "{timing:?}");
+        assert!(timing.match_ns < timing.handler_ns, "{timing:?}");
+        assert!(timing.encode_ns < timing.handler_ns, "{timing:?}");
+        let sum = timing.match_ns + timing.handler_ns + timing.encode_ns;
+        assert!(u128::from(sum) <= total.as_nanos(), "{timing:?}");
+
+        // A route without a handler function is only matched
+        let request = RequestQuery {
+            path: "/protocol_version".to_owned(),
+            ..RequestQuery::default()
+        };
+        let ctx = client.ctx();
+        let (_response, timing) = TEST_RPC.handle_timed(ctx, &request).unwrap();
+        assert!(timing.match_ns > 0, "{timing:?}");
+        assert_eq!(timing.handler_ns, 0);
+        assert_eq!(timing.encode_ns, 0);
+    }
+
+    /// Test that the path of a route without args is a constant.
+    #[test]
+    fn test_router_macro_const_path() {
+        use super::test_rpc::TestRpc;
+
+        const A_PATH: &str = TestRpc::A_PATH;
+        assert_eq!(A_PATH, "/a");
+        assert_eq!(A_PATH, TEST_RPC.a_path());
+        // ... also in a sub-pattern and with the first form of an alias
+        assert_eq!(TestRpc::B0I_PATH, TEST_RPC.b0i_path());
+        assert_eq!(TestRpc::B4_PATH, "/b/4");
+        assert_eq!(TestRpc::C_PATH, TEST_RPC.c_path());
+        assert_eq!(
+            TestRpc::PROTOCOL_VERSION_PATH,
+            TEST_RPC.protocol_version_path()
+        );
+    }
+
+    /// Test the `where` predicates of typed args.
+    #[test]
+    fn test_router_where_predicate() {
+        let client = TestClient::new(test_rpc::TEST_WHERE_RPC);
+        let response = handle_path(&client, "/sub/1").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "sub_n/1");
+        let response = handle_path(&client, "/limit/100/x").unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "limited/100"
+        );
+
+        for (path, arg, value) in
+            [("/sub/0", "n", "0"), ("/limit/101/x", "limit", "101")]
+        {
+            match handle_path(&client, path).unwrap_err() {
+                storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                    let err = err.downcast::<Error>().unwrap();
+                    assert!(
+                        matches!(
+                            &*err,
+                            Error::ValidationFailed {
+                                arg: failed_arg,
+                                value: failed_value,
+                                ..
+                            } if *failed_arg == arg && failed_value == value
+                        ),
+                        "{err}"
+                    );
+                }
+                err => panic!("Unexpected error {err}"),
+            }
+            assert!(test_rpc::TEST_WHERE_RPC.route_id(path).is_none());
+        }
+        #[cfg(not(debug_assertions))]
+        assert_eq!(
+            test_rpc::TEST_WHERE_RPC.sub_n_path(&0),
+            "/sub/0",
+            "The paths are constructed without checking the predicates"
+        );
+        // In debug builds, a path that isn't matched back panics
+        #[cfg(debug_assertions)]
+        assert!(std::panic::catch_unwind(
+            || test_rpc::TEST_WHERE_RPC.sub_n_path(&0)
+        )
+        .is_err());
+    }
+
+    /// Test the `Display` of the `MatchInfo` of matched routes.
+    #[test]
+    fn test_router_match_info() {
+        let info = TEST_RPC.match_info("/b/3/345/123000/1000999").unwrap();
+        assert_eq!(info.handler, "b3");
+        assert_eq!(info.to_string(), "b3(a1=345, a2=123000, a3=1000999)");
+
+        let info = TEST_RPC.match_info("/a").unwrap();
+        assert_eq!(info.to_string(), "a");
+        // A sub-router's route with the args of its prefix
+        let info = TEST_RPC.match_info("/validator/atest1v/name").unwrap();
+        assert_eq!(info.to_string(), "validator_name(addr=atest1v)");
+        // An absent opt arg is formatted with `Debug`
+        let info = TEST_RPC.match_info("/search").unwrap();
+        assert_eq!(info.to_string(), "search(limit=None)");
+        let info = TEST_RPC.match_info("/protocol_version").unwrap();
+        assert_eq!(info.to_string(), "protocol_version");
+
+        assert!(TEST_RPC.match_info("/unknown").is_none());
+    }
+
+    /// Test that `match_all` finds every route that matches a path, including
+    /// the shadowed ones.
+    #[test]
+    fn test_router_match_all() {
+        use super::test_rpc::TEST_OVERLAP_RPC;
+
+        let display = |path: &str| -> Vec<String> {
+            TEST_OVERLAP_RPC
+                .match_all(path)
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        };
+
+        // Both routes match, in the order of declaration
+        assert_eq!(display("/item/7"), ["find(id=7)", "lookup(n=7)"]);
+        let info = TEST_OVERLAP_RPC.match_info("/item/7").unwrap();
+        assert_eq!(info, TEST_OVERLAP_RPC.match_all("/item/7")[0]);
+        // Only the first route parses the arg
+        assert_eq!(display("/item/seven"), ["find(id=seven)"]);
+        // A sub-router's route shadows a route of the parent
+        assert_eq!(display("/sub/x"), ["x", "a"]);
+        assert_eq!(display("/sub/y/1"), ["y(untyped_arg=1)"]);
+        assert!(display("/unknown").is_empty());
+
+        assert_eq!(TEST_RPC.match_all("/b/3/345/123000/1000999").len(), 1);
+        assert_eq!(
+            TEST_RPC.match_all("/validator/atest1v/name")[0].to_string(),
+            "validator_name(addr=atest1v)"
+        );
+    }
+
+    /// Pin the resolution of the overlapping sibling patterns under `/b/3`,
+    /// which are tried in the order of declaration. Each of these paths is
+    /// matched by exactly one route, so the order in which the literals and
+    /// the typed args are tried cannot change which handler is invoked.
+    #[test]
+    fn test_router_greedy_matching_resolution() {
+        let handlers = |path: &str| -> Vec<&'static str> {
+            TEST_RPC
+                .match_all(path)
+                .iter()
+                .map(|info| info.handler)
+                .collect()
+        };
+
+        let cases: &[(&str, &[&str])] = &[
+            // A literal before a typed arg at the same position
+            ("/b/3/1/2/sum/3", &["b3sum"]),
+            ("/b/3/1/2/i/3", &["b3i"]),
+            // The last typed arg consumes the rest of the path, so it's not
+            // matched by a path with more segments
+            ("/b/3/1/2/3", &["b3"]),
+            ("/b/3/1/2/3/ii", &["b3ii"]),
+            // An optional arg is skipped when its segment cannot be parsed
+            ("/b/3/1/2/3/iii", &["b3iii"]),
+            ("/b/3/1/2/iii", &["b3iii"]),
+            ("/b/3/1/2/iiii/xyz", &["b3iiii"]),
+            ("/b/3/1/2/iiii/3/xyz/4", &["b3iiii"]),
+            // A literal is not parsed as a typed arg, nor the other way
+            // around
+            ("/b/3/1/2/i", &[]),
+            ("/b/3/1/2/sum", &[]),
+            ("/b/3/1/2/ii", &[]),
+            ("/b/3/1/2/3/4", &[]),
+            ("/b/3/1/2", &[]),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(&handlers(path), expected, "{path}");
+            assert_eq!(
+                TEST_RPC.match_info(path).map(|info| info.handler),
+                expected.first().copied(),
+                "{path}"
+            );
+        }
+
+        // The sibling patterns are declared in this order, which is the order
+        // in which they're tried
+        let b3: Vec<&str> = TEST_RPC
+            .routes_under("/b/3")
+            .iter()
+            .map(|route| route.handler)
+            .collect();
+        assert_eq!(b3, ["b3sum", "b3i", "b3", "b3ii", "b3iii", "b3iiii"]);
+    }
+
+    /// Test that a request with an `If-Height` precondition is rejected by a
+    /// node whose latest height is lower.
+    #[test]
+    fn test_router_if_height_precondition() {
+        let mut client = TestClient::new(TEST_RPC);
+        client.storage.last_height = BlockHeight(5);
+        let handle = |client: &TestClient<_>, if_height| {
+            let request = RequestQuery {
+                path: "/a".to_owned(),
+                if_height,
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            TEST_RPC.handle(ctx, &request)
+        };
+
+        match handle(&client, Some(BlockHeight(6))).unwrap_err() {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                let err = err.downcast::<Error>().unwrap();
+                assert!(
+                    matches!(
+                        *err,
+                        Error::PreconditionFailed {
+                            required: BlockHeight(6),
+                            latest: BlockHeight(5),
+                        }
+                    ),
+                    "{err}"
+                );
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+        assert!(handle(&client, Some(BlockHeight(5))).is_ok());
+        assert!(handle(&client, None).is_ok());
+
+        // The precondition is met once the node catches up
+        client.storage.last_height = BlockHeight(6);
+        let response = handle(&client, Some(BlockHeight(6))).unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "a");
+    }
+
+    /// Test that a response longer than its route's `#[max_size = N]` is
+    /// rejected with the size and the limit, while shorter ones are returned.
+    #[test]
+    fn test_router_max_response_size() {
+        let client = TestClient::new(test_rpc::TEST_MAX_SIZE_RPC);
+
+        // "sub_n/10" is encoded with its 4 bytes long length to 12 bytes
+        let response = handle_path(&client, "/sub/10").unwrap();
+        assert_eq!(response.data.len(), 12);
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "sub_n/10");
+
+        match handle_path(&client, "/sub/100").unwrap_err() {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                let err = err.downcast::<Error>().unwrap();
+                assert!(
+                    matches!(
+                        *err,
+                        Error::ResponseTooLarge {
+                            size: 13,
+                            limit: 12
+                        }
+                    ),
+                    "{err}"
+                );
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+    }
+
+    /// Test that a handler of a route with `#[presence]` can tell a present
+    /// optional arg from an absent one and from one that couldn't be parsed.
+    #[tokio::test]
+    async fn test_router_opt_arg_presence() {
+        use super::test_rpc::TEST_PRESENCE_RPC;
+        let client = TestClient::new(TEST_PRESENCE_RPC);
+        let amount = token::Amount::from(5);
+        let present = format!("present/{amount}");
+
+        let result = TEST_PRESENCE_RPC
+            .amount_presence(&client, &Some(amount))
+            .await
+            .unwrap();
+        assert_eq!(result, present);
+        let result = TEST_PRESENCE_RPC
+            .amount_presence(&client, &None)
+            .await
+            .unwrap();
+        assert_eq!(result, "absent");
+
+        let decode = |path: &str| {
+            let response = handle_path(&client, path).unwrap();
+            String::try_from_slice(&response.data).unwrap()
+        };
+        // Present in the path or in the query param
+        assert_eq!(decode(&format!("/amount/{amount}")), present);
+        assert_eq!(decode(&format!("/amount?amount={amount}")), present);
+        // Absent, also with a trailing slash
+        assert_eq!(decode("/amount"), "absent");
+        assert_eq!(decode("/amount/"), "absent");
+        // Unparseable, which takes precedence over the query param
+        assert_eq!(decode("/amount/abc"), "unparseable/abc");
+        assert_eq!(
+            decode(&format!("/amount/abc?amount={amount}")),
+            "unparseable/abc"
+        );
+        // Only a single segment can be unparseable
+        assert!(handle_path(&client, "/amount/abc/def").is_err());
+
+        // The parsed args are returned as an `Option`
+        let (parsed,) = TEST_PRESENCE_RPC
+            .amount_presence_validate_path("/amount/abc")
+            .unwrap();
+        assert_eq!(parsed, None);
+    }
+
+    /// Test that a route with `#[empty_segment]` matches an empty last
+    /// segment after a trailing slash as an empty optional arg, distinct from
+    /// a missing segment.
+    #[tokio::test]
+    async fn test_router_empty_segment() {
+        use super::test_rpc::TEST_EMPTY_SEGMENT_RPC;
+        let client = TestClient::new(TEST_EMPTY_SEGMENT_RPC);
+        assert!(TEST_EMPTY_SEGMENT_RPC.keeps_trailing_slash());
+        assert!(!super::test_rpc::TEST_PRESENCE_RPC.keeps_trailing_slash());
+
+        let decode = |path: &str| {
+            let response = handle_path(&client, path).unwrap();
+            String::try_from_slice(&response.data).unwrap()
+        };
+        assert_eq!(decode("/search/"), r#"Some("")"#);
+        assert_eq!(decode("/search"), "None");
+        assert_eq!(decode("/search/abc"), r#"Some("abc")"#);
+        assert_eq!(decode("/search?q=abc"), r#"Some("abc")"#);
+        assert_eq!(decode("/search/?q=abc"), r#"Some("")"#);
+
+        // The client round-trips both forms
+        let result = TEST_EMPTY_SEGMENT_RPC
+            .search_query(&client, &Some(String::new()))
+            .await
+            .unwrap();
+        assert_eq!(result, r#"Some("")"#);
+        let result = TEST_EMPTY_SEGMENT_RPC
+            .search_query(&client, &None)
+            .await
+            .unwrap();
+        assert_eq!(result, "None");
+    }
+
+    /// Test that the warnings of a successful response are read by the client
+    /// separately from the value, also when they're sent in the `log` of an
+    /// ABCI query response.
+    #[tokio::test]
+    async fn test_router_response_warnings() {
+        use super::test_rpc::TEST_WARNING_RPC;
+        let client = TestClient::new(TEST_WARNING_RPC);
+        let expected = vec![Warning::new(
+            "stale_cache",
+            "The value is from a stale cache",
+        )];
+
+        let response = TEST_WARNING_RPC
+            .stale_value(&client, None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(response.data, 42);
+        assert_eq!(response.warnings, expected);
+        assert_eq!(response.log, "served from the cache");
+
+        // Only the ABCI query response's fields are sent to a remote client
+        let handled = handle_path(&client, "/stale").unwrap();
+        let sent = EncodedResponseQuery {
+            data: handled.data,
+            info: handled.info,
+            log: handled.log,
+            codespace: handled.codespace,
+            proof: handled.proof,
+            ..Default::default()
+        };
+        let received: ResponseQuery<u64> = decode_response(sent).unwrap();
+        assert_eq!(received.data, 42);
+        assert_eq!(received.warnings, expected);
+        assert_eq!(received.log, "served from the cache");
+        assert_eq!(
+            received.warnings[0].to_string(),
+            "stale_cache: The value is from a stale cache"
+        );
+
+        // A response without warnings keeps its log as is
+        let response =
+            ResponseQuery::new(7_u64.try_to_vec().unwrap()).with_log("log");
+        let received: ResponseQuery<u64> = decode_response(response).unwrap();
+        assert!(received.warnings.is_empty());
+        assert_eq!(received.log, "log");
+    }
+
+    /// Test that a panic of a handler is caught and returned as an error when
+    /// enabled, and that it unwinds otherwise.
+    #[test]
+    fn test_router_handler_panic() {
+        let mut client = TestClient::new(test_rpc::TEST_PANIC_RPC);
+
+        client.handler_config.catch_panics = true;
+        let result = handle_path(&client, "/panic");
+        let response = handle_path(&client, "/a");
+        client.handler_config.catch_panics = false;
+        match result.unwrap_err() {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                let err = err.downcast::<Error>().unwrap();
+                assert!(
+                    matches!(&*err, Error::HandlerPanicked { route } if *route == "panicking"),
+                    "{err}"
+                );
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+        // The other routes still work after a panic
+        let response = response.unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "a");
+
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                handle_path(&client, "/panic")
+            }));
+        assert!(result.is_err());
+    }
+
+    /// Test that a request of the compact protocol is handled by the same
+    /// handler as the path of its route.
+    #[test]
+    fn test_router_handle_compact() {
+        let client = TestClient::new(TEST_RPC);
+        let handle = |compact: &CompactRequest| {
+            let request = RequestQuery {
+                data: compact.try_to_vec().unwrap(),
+                ..RequestQuery::default()
+            };
+            let ctx = client.ctx();
+            TEST_RPC.handle_compact(ctx, &request)
+        };
+
+        let (a1, a2, a3) = (
+            token::Amount::whole(1),
+            token::Amount::whole(2),
+            token::Amount::whole(3),
+        );
+        let compact = CompactRequest::new(1, &(a1, a2, a3)).unwrap();
+        let response = handle(&compact).unwrap();
+        let expected = handle_path(&client, "/b/3/1/2/3").unwrap();
+        assert_eq!(response.data, expected.data);
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "b3/1/2/3");
+
+        // A union arg
+        let id: Either<u64, String> = Either::Right("abc".to_owned());
+        let response = handle(&CompactRequest::new(2, &id).unwrap());
+        let expected = handle_path(&client, "/find/abc").unwrap();
+        assert_eq!(response.unwrap().data, expected.data);
+
+        // A sub-router's route
+        let response = handle(&CompactRequest::new(3, &()).unwrap()).unwrap();
+        assert_eq!(
+            String::try_from_slice(&response.data).unwrap(),
+            "validator_name"
+        );
+
+        assert_eq!(
+            TEST_RPC.compact_routes(),
+            vec![(1, "b3"), (3, "validator_name"), (2, "find")]
+        );
+        assert_eq!(TEST_RPC.compact_handler(3), Some("validator_name"));
+
+        // Args that cannot be decoded
+        let compact = CompactRequest::new(1, &a1).unwrap();
+        assert!(handle(&compact).is_err());
+        match handle(&CompactRequest::new(4, &()).unwrap()).unwrap_err() {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                let err = err.downcast::<Error>().unwrap();
+                assert!(matches!(*err, Error::UnknownCompactRoute(4)), "{err}");
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+    }
+
+    /// Test the `curl` commands generated for routes.
+    #[test]
+    fn test_router_macro_curl() {
+        let balance = token::Amount::from(123_000_000);
+        let curl = TEST_RPC.b2i_curl("http://127.0.0.1:26657/", &balance);
+        assert_eq!(TEST_RPC.b2i_path(&balance), "/b/2/i/123");
+        assert_eq!(
+            curl,
+            "curl -s \
+             'http://127.0.0.1:26657/abci_query?path=%22%2Fb%2F2%2Fi%2F123%22'"
+        );
+        assert_eq!(
+            TEST_RPC.test_sub_rpc().y_curl("http://localhost:26657", "a b"),
+            "curl -s \
+             'http://localhost:26657/abci_query?path=%22%2Fsub%2Fy%2Fa%20b%22'"
+        );
+    }
+
+    /// Test the borsh schemas of the routes' responses.
+    #[test]
+    fn test_router_macro_response_schema() {
+        use borsh::schema::BorshSchemaContainer;
+        use borsh::BorshSchema;
+
+        use super::test_rpc_handlers::DivError;
+
+        let schema = TEST_RPC.b1_response_schema().unwrap();
+        assert_eq!(schema.declaration, "string");
+        assert!(schema.definitions.is_empty());
+        assert_eq!(schema, String::schema_container());
+
+        let schema = TEST_RPC.div_response_schema().unwrap();
+        assert_eq!(schema, Result::<u64, DivError>::schema_container());
+        assert_eq!(schema.declaration, "Result<u64, DivError>");
+
+        // Every route of the test router has a response schema
+        let schemas = TEST_RPC.response_schemas();
+        assert_eq!(schemas.len(), TEST_RPC.routes().len());
+        let (_, schema) = schemas
+            .iter()
+            .find(|(handler, _)| *handler == "validator_name")
+            .unwrap();
+        assert_eq!(schema, &String::schema_container());
+
+        // A type without `BorshSchema` has no schema
+        struct NoSchema;
+        #[allow(unused_imports)]
+        use super::{NoResponseSchema, ResponseSchema};
+        let schema: Option<BorshSchemaContainer> =
+            (&SchemaProbe::<NoSchema>::new()).response_schema();
+        assert!(schema.is_none());
+    }
+
+    /// Test that a handler doing arithmetic on amount args returns an error
+    /// on overflow instead of panicking.
+    #[tokio::test]
+    async fn test_router_macro_amount_overflow() -> storage_api::Result<()> {
+        use super::test_rpc_handlers::AmountOverflowError;
+
+        let client = TestClient::new(TEST_RPC);
+
+        let a1 = token::Amount::whole(1);
+        let a2 = token::Amount::whole(2);
+        let a3 = token::Amount::from(3);
+        let result = TEST_RPC.b3sum(&client, &a1, &a2, &a3).await.unwrap();
+        assert_eq!(result, token::Amount::from(3_000_003));
+
+        // The sum overflows
+        let max = token::Amount::max();
+        let path = TEST_RPC.b3sum_path(&a1, &max, &a3);
+        let err = handle_path(&client, &path).unwrap_err();
+        match err {
+            storage_api::Error::Custom(storage_api::CustomError(err)) => {
+                assert!(err.downcast_ref::<AmountOverflowError>().is_some())
+            }
+            err => panic!("Unexpected error {err}"),
+        }
+
+        Ok(())
+    }
+
+    /// Test that a route's `info` template is applied to its responses when
+    /// the handler doesn't set any `info`.
+    #[tokio::test]
+    async fn test_router_macro_default_info() -> storage_api::Result<()> {
+        let client = TestClient::new(TEST_RPC);
+
+        // A handler function with a default `info`
+        let response = handle_path(&client, "/f")?;
+        assert_eq!(response.info, "Served by f");
+
+        // A handler `with_options` with a default `info`
+        let response = TEST_RPC.c(&client, None, None, false).await.unwrap();
+        assert_eq!(response.info, "Served by c");
+
+        // A route without a default `info`
+        let response = handle_path(&client, "/a")?;
+        assert!(response.info.is_empty());
+
+        // The default doesn't override an `info` set by the handler
+        let mut response = ResponseQuery::<()> {
+            info: "custom".to_owned(),
+            ..ResponseQuery::default()
+        };
+        apply_default_info(&mut response, Some("Served by {route}"), "c");
+        assert_eq!(response.info, "custom");
+
+        Ok(())
+    }
+
+    /// Test that an arg is matched only when it's in its run-time set.
+    #[tokio::test]
+    async fn test_router_macro_oneof_arg() {
+        use super::test_rpc::{TEST_ONEOF_RPC, TOKEN_SET};
+        let client = TestClient::new(TEST_ONEOF_RPC);
+
+        assert_eq!(
+            TEST_ONEOF_RPC.symbol_value_path("NAM"),
+            "/symbol/NAM/value"
+        );
+        let result = TEST_ONEOF_RPC.symbol_value(&client, "NAM").await.unwrap();
+        assert_eq!(result, "symbol_value/NAM");
+
+        for sym in ["ETH", "nam", ""] {
+            let path = format!("/symbol/{sym}/value");
+            assert!(handle_path(&client, &path).is_err(), "path {path}");
+            assert!(TEST_ONEOF_RPC.route_id(&path).is_none(), "path {path}");
+        }
+
+        // A symbol added to the set at run-time is matched
+        TOKEN_SET.write().unwrap().insert("ETH".to_owned());
+        let result = TEST_ONEOF_RPC.symbol_value(&client, "ETH").await.unwrap();
+        assert_eq!(result, "symbol_value/ETH");
+    }
+
+    /// Test that an arg is matched only when it matches its regex.
+    #[tokio::test]
+    async fn test_router_macro_regex_arg() {
+        let client = TestClient::new(TEST_RPC);
+
+        let slug = "my-slug-123";
+        assert_eq!(TEST_RPC.h_path(slug), format!("/h/{slug}"));
+        let result = TEST_RPC.h(&client, slug).await.unwrap();
+        assert_eq!(result, format!("h/{slug}"));
+
+        for slug in ["My-Slug", "my_slug", "my slug", ""] {
+            let path = format!("/h/{slug}");
+            assert!(handle_path(&client, &path).is_err(), "path {path}");
+            assert!(TEST_RPC.route_id(&path).is_none(), "path {path}");
+        }
+    }
+
+    /// Test that a path that no pattern matches is reported with the
+    /// failures of the candidate patterns that get furthest in the path.
+    #[test]
+    fn test_router_peek() {
+        let candidates = |path: &str| match TEST_RPC.peek(path) {
+            Err(Error::NoMatch { candidates }) => candidates,
+            result => panic!("Unexpected result {result:?} for {path}"),
         };
+
+        // The amount `a2` cannot be parsed
+        assert_eq!(
+            candidates("/b/3/345/x/999"),
+            vec![CandidateFailure {
+                pattern: "/b/3/{a1}/{a2}".to_owned(),
+                segment: 3,
+                reason: CandidateFailureReason::ParseFailed {
+                    type_name: std::any::type_name::<token::Amount>(),
+                    kind: ParseFailureKind::Invalid,
+                },
+            }]
+        );
+        // A sub-router's routes are candidates too
+        assert_eq!(
+            candidates("/sub/w"),
+            vec![
+                CandidateFailure {
+                    pattern: "/sub/x".to_owned(),
+                    segment: 1,
+                    reason: CandidateFailureReason::Mismatch,
+                },
+                CandidateFailure {
+                    pattern: "/sub/y/{untyped_arg}".to_owned(),
+                    segment: 1,
+                    reason: CandidateFailureReason::Mismatch,
+                },
+                CandidateFailure {
+                    pattern: "/sub/z/{untyped_arg}".to_owned(),
+                    segment: 1,
+                    reason: CandidateFailureReason::Mismatch,
+                },
+                CandidateFailure {
+                    pattern: "/sub/{n}".to_owned(),
+                    segment: 1,
+                    reason: CandidateFailureReason::ParseFailed {
+                        type_name: "u64",
+                        kind: ParseFailureKind::Invalid,
+                    },
+                },
+            ]
+        );
+        // A matched pattern followed by more segments
+        assert_eq!(
+            candidates("/a/b"),
+            vec![CandidateFailure {
+                pattern: "/a".to_owned(),
+                segment: 1,
+                reason: CandidateFailureReason::TrailingSegments,
+            }]
+        );
+
+        // A matched path is resolved like with `match_info`
+        let path = TEST_RPC.b3_path(
+            &token::Amount::from(1),
+            &token::Amount::from(2),
+            &token::Amount::from(3),
+        );
+        assert_eq!(
+            TEST_RPC.peek(&path).unwrap(),
+            TEST_RPC.match_info(&path).unwrap()
+        );
     }
 
-    // Generate handler functions for the router below
-    handlers!(
-        a,
-        b0i,
-        b0ii,
-        b1,
-        b2i(balance: token::Amount),
-        b3(a1: token::Amount, a2: token::Amount, a3: token::Amount),
-        b3i(a1: token::Amount, a2: token::Amount, a3: token::Amount),
-        b3ii(a1: token::Amount, a2: token::Amount, a3: token::Amount),
-        x,
-        y(untyped_arg: &str),
-        z(untyped_arg: &str),
-    );
+    /// Test that the handler names of a router include its sub-routers'.
+    #[test]
+    fn test_router_handler_names() {
+        use super::test_rpc::{TestCfgRpc, TestRpc, TestSubRpc};
 
-    /// This handler is hand-written, because the test helper macro doesn't
-    /// support optional args.
-    pub fn b3iii<D, H>(
-        _ctx: RequestCtx<'_, D, H>,
-        a1: token::Amount,
-        a2: token::Amount,
-        a3: Option<token::Amount>,
-    ) -> storage_api::Result<String>
-    where
-        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
-        H: 'static + StorageHasher + Sync,
-    {
-        let data = "b3iii".to_owned();
-        let data = format!("{data}/{}", a1);
-        let data = format!("{data}/{}", a2);
-        let data = a3.map(|a3| format!("{data}/{}", a3)).unwrap_or(data);
-        Ok(data)
+        assert_eq!(TestSubRpc::HANDLER_NAMES, &["x", "y", "z"]);
+        for name in [
+            "sub_n",
+            "a",
+            "b0i",
+            "b0ii",
+            "b1",
+            "b2i",
+            "b3sum",
+            "b3iiii",
+            "c",
+            "div",
+            "dump",
+            "protocol_version",
+            "async_add",
+            // Sub-routers' handlers
+            "x",
+            "y",
+            "z",
+            "validator_name",
+            "ns",
+        ] {
+            assert!(
+                TestRpc::HANDLER_NAMES.contains(&name),
+                "Missing handler name {name}"
+            );
+        }
+        // Every route has a sample path
+        assert_eq!(TestRpc::HANDLER_NAMES.len(), TEST_RPC.sample_paths().len());
+        // The conditionally compiled routes are excluded
+        assert_eq!(TestCfgRpc::HANDLER_NAMES, &["a", "b1"]);
     }
 
-    /// This handler is hand-written, because the test helper macro doesn't
-    /// support optional args.
-    pub fn b3iiii<D, H>(
-        _ctx: RequestCtx<'_, D, H>,
-        a1: token::Amount,
-        a2: token::Amount,
-        a3: Option<token::Amount>,
-        a4: Option<Epoch>,
-    ) -> storage_api::Result<String>
-    where
-        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
-        H: 'static + StorageHasher + Sync,
-    {
-        let data = "b3iiii".to_owned();
-        let data = format!("{data}/{}", a1);
-        let data = format!("{data}/{}", a2);
-        let data = a3.map(|a3| format!("{data}/{}", a3)).unwrap_or(data);
-        let data = a4.map(|a4| format!("{data}/{}", a4)).unwrap_or(data);
-        Ok(data)
+    /// Test the cache policies declared with the routes' attributes.
+    #[test]
+    fn test_router_cache_policy() {
+        use super::test_rpc::TestRpcRoute;
+
+        assert_eq!(TEST_RPC.cache_policy("/b/1"), Some(CachePolicy::Immutable));
+        assert_eq!(TEST_RPC.cache_policy("/f"), Some(CachePolicy::NoStore));
+        // A sub-router's route
+        assert_eq!(
+            TEST_RPC.cache_policy("/sub/x"),
+            Some(CachePolicy::Immutable)
+        );
+        // No policy
+        assert_eq!(TEST_RPC.cache_policy("/a"), None);
+        assert_eq!(TEST_RPC.cache_policy("/sub/y/arg"), None);
+        assert_eq!(TEST_RPC.cache_policy("/unknown"), None);
+        assert_eq!(TestRpcRoute::A.cache_policy(), None);
+        assert_eq!(TestRpcRoute::F.cache_policy(), Some(CachePolicy::NoStore));
     }
 
-    /// This handler is hand-written, because the test helper macro doesn't
-    /// support handlers with `with_options`.
-    pub fn c<D, H>(
-        _ctx: RequestCtx<'_, D, H>,
-        _request: &RequestQuery,
-    ) -> storage_api::Result<EncodedResponseQuery>
-    where
-        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
-        H: 'static + StorageHasher + Sync,
-    {
-        let data = "c".to_owned().try_to_vec().into_storage_result()?;
-        Ok(ResponseQuery {
-            data,
-            ..ResponseQuery::default()
-        })
+    /// Test routes with the trailing slash tolerance on and off.
+    #[test]
+    fn test_router_macro_trailing_slash() {
+        let client = TestClient::new(TEST_RPC);
+
+        // Tolerance is on by default
+        for path in ["/a", "/a/"] {
+            let response = handle_path(&client, path).unwrap();
+            assert_eq!(String::try_from_slice(&response.data).unwrap(), "a");
+        }
+        assert!(TEST_RPC.route_id("/a/").is_some());
+
+        // Tolerance is off for `g`, which only applies to the segments that
+        // are matched without normalization
+        let response = handle_path(&client, "/g").unwrap();
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "g");
+        assert_eq!(TEST_RPC.g_path(), "/g");
+        for segments in [&["g", ""][..], &["g", "", ""][..]] {
+            let ctx = client.ctx();
+            let request = RequestQuery::default();
+            assert!(
+                TEST_RPC.handle_segments(ctx, &request, segments).is_err(),
+                "segments {segments:?}"
+            );
+            assert!(
+                TEST_RPC.internal_route_id(segments, 0, None).is_none(),
+                "segments {segments:?}"
+            );
+        }
+        // The request paths are normalized without the trailing slash
+        for path in ["/g/", "/g//"] {
+            let response = handle_path(&client, path).unwrap();
+            assert_eq!(String::try_from_slice(&response.data).unwrap(), "g");
+            assert!(TEST_RPC.route_id(path).is_some(), "path {path}");
+        }
+
+        assert!(is_path_end(&[], false));
+        assert!(!is_path_end(&[""], false));
+        assert!(is_path_end(&[""], true));
+        assert!(!is_path_end(&["", ""], true));
     }
-}
 
-/// You can expand the `router!` macro invocation with e.g.:
-/// ```shell
-/// cargo expand ledger::queries::router::test_rpc --features "ferveo-tpke, ibc-mocks, testing, wasm-runtime, tendermint-rpc" --tests --lib
-/// ```
-#[cfg(test)]
-mod test_rpc {
-    use super::test_rpc_handlers::*;
-    use crate::types::storage::Epoch;
-    use crate::types::token;
+    /// Test that routes are resolved from paths without being handled.
+    #[test]
+    fn test_router_macro_route_id() {
+        use std::collections::HashMap;
 
-    // Setup an RPC router for testing
-    router! {TEST_RPC,
-        ( "sub" ) = (sub TEST_SUB_RPC),
-        ( "a" ) -> String = a,
-        ( "b" ) = {
-            ( "0" ) = {
-                ( "i" ) -> String = b0i,
-                ( "ii" ) -> String = b0ii,
-            },
-            ( "1" ) -> String = b1,
-            ( "2" ) = {
-                ( "i" / [balance: token::Amount] ) -> String = b2i,
-            },
-            ( "3" / [a1: token::Amount] / [a2: token::Amount] ) = {
-                ( "i" / [a3: token:: Amount] ) -> String = b3i,
-                ( [a3: token:: Amount] ) -> String = b3,
-                ( [a3: token:: Amount] / "ii" ) -> String = b3ii,
-                ( [a3: opt token::Amount] / "iii" ) -> String = b3iii,
-                ( "iiii" / [a3: opt token::Amount] / "xyz" / [a4: opt Epoch] ) -> String = b3iiii,
-            },
-        },
-        ( "c" ) -> String = (with_options c),
+        use super::test_rpc::{TestRpcRoute, TestSubRpcRoute};
+
+        assert_eq!(TEST_RPC.route_id("/b/0/i"), Some(TestRpcRoute::B0i));
+        assert_eq!(TEST_RPC.route_id("/b/0/ii/"), Some(TestRpcRoute::B0ii));
+        assert_eq!(
+            TEST_RPC.route_id("/b/3/1/2/iii"),
+            Some(TestRpcRoute::B3iii)
+        );
+        assert_eq!(TEST_RPC.route_id("/c"), Some(TestRpcRoute::C));
+        assert_eq!(
+            TEST_RPC.route_id("/sub/y/arg"),
+            Some(TestRpcRoute::TestSubRpc(TestSubRpcRoute::Y))
+        );
+        assert_eq!(TEST_RPC.route_id("/b/0"), None);
+        assert_eq!(TEST_RPC.route_id("/b/2/i/not_an_amount"), None);
+        assert_eq!(TEST_RPC.route_id("/invalid"), None);
+
+        // Routes can be used as keys
+        let mut counts: HashMap<TestRpcRoute, u64> = HashMap::new();
+        for path in ["/a", "/b/1", "/a/", "/sub/x"] {
+            *counts.entry(TEST_RPC.route_id(path).unwrap()).or_default() += 1;
+        }
+        assert_eq!(counts[&TestRpcRoute::A], 2);
+        assert_eq!(counts[&TestRpcRoute::B1], 1);
+        assert_eq!(counts[&TestRpcRoute::TestSubRpc(TestSubRpcRoute::X)], 1);
     }
 
-    router! {TEST_SUB_RPC,
-        ( "x" ) -> String = x,
-        ( "y" / [untyped_arg] ) -> String = y,
-        ( "z" / [untyped_arg] ) -> String = z,
+    /// Test that handling already split path segments gives the same result
+    /// as handling the path.
+    #[test]
+    fn test_router_handle_segments() {
+        let client = TestClient::new(TEST_RPC);
+        let handle_segments = |segments: &[&str]| {
+            let ctx = client.ctx();
+            client
+                .rpc
+                .handle_segments(ctx, &RequestQuery::default(), segments)
+        };
+
+        let response = handle_segments(&["b", "2", "i", "123"]).unwrap();
+        let expected = handle_path(&client, "/b/2/i/123").unwrap();
+        assert_eq!(response.data, expected.data);
+        assert_eq!(String::try_from_slice(&response.data).unwrap(), "b2i/123");
+
+        for path in ["/a/", "/b/3/1/2/iiii/3/xyz/4", "/sub/y", "/h/slug"] {
+            let segments = split_path(path).unwrap();
+            let response = handle_segments(&segments).unwrap();
+            let expected = handle_path(&client, path).unwrap();
+            assert_eq!(response.data, expected.data, "path {path}");
+        }
+        assert!(handle_segments(&["b", "2", "i"]).is_err());
+        assert!(handle_segments(&[]).is_err());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::test_rpc::TEST_RPC;
-    use crate::ledger::queries::testing::TestClient;
-    use crate::ledger::queries::{RequestCtx, RequestQuery, Router};
-    use crate::ledger::storage_api;
-    use crate::types::storage::Epoch;
-    use crate::types::token;
+    #[test]
+    fn test_split_path() {
+        assert_eq!(split_path("/"), Some(vec![]));
+        assert_eq!(split_path(""), None);
+        assert_eq!(split_path("a/b"), None);
+        assert_eq!(split_path("/a"), Some(vec!["a"]));
+        assert_eq!(split_path("/a/"), Some(vec!["a", ""]));
+        assert_eq!(split_path("/a//c"), Some(vec!["a", "", "c"]));
+    }
 
-    /// Test all the possible paths in `TEST_RPC` router.
-    #[tokio::test]
-    async fn test_router_macro() -> storage_api::Result<()> {
-        let client = TestClient::new(TEST_RPC);
+    #[test]
+    fn test_normalize_path() {
+        for path in [
+            "/a/b", "a/b", "/a/b/", "a/b/", "//a/b", "/a//b", "/a/b//",
+            "a//b//",
+        ] {
+            assert_eq!(normalize_path(path), "/a/b", "path {path}");
+        }
+        for path in ["", "/", "//", "///"] {
+            assert_eq!(normalize_path(path), "/", "path {path:?}");
+        }
+        // A normalized path is borrowed
+        assert!(matches!(normalize_path("/a/b"), Cow::Borrowed("/a/b")));
+        assert!(matches!(normalize_path("/"), Cow::Borrowed("/")));
+        assert!(matches!(normalize_path("/a/"), Cow::Owned(_)));
 
-        // Test request with an invalid path
-        let request = RequestQuery {
-            path: "/invalid".to_owned(),
-            ..RequestQuery::default()
-        };
-        let ctx = RequestCtx {
-            event_log: &client.event_log,
-            storage: &client.storage,
-            vp_wasm_cache: client.vp_wasm_cache.clone(),
-            tx_wasm_cache: client.tx_wasm_cache.clone(),
-            storage_read_past_height_limit: None,
-        };
-        let result = TEST_RPC.handle(ctx, &request);
-        assert!(result.is_err());
+        // The malformed forms of a path are handled like the path
+        let client = TestClient::new(TEST_RPC);
+        for path in ["/b/2/i/1", "b/2/i/1", "/b/2/i/1/", "//b//2/i///1//"] {
+            let response = handle_path(&client, path).unwrap();
+            let result = String::try_from_slice(&response.data).unwrap();
+            assert_eq!(result, "b2i/1", "path {path}");
+        }
+    }
 
-        // Test requests to valid paths using the router's methods
+    /// Test that a literal segment is matched after it's percent-decoded,
+    /// while the generated paths have the unencoded literals.
+    #[test]
+    fn test_router_percent_encoded_literal() {
+        let client = TestClient::new(TEST_RPC);
+        assert_eq!(TEST_RPC.a_path(), "/a");
+        let expected = handle_path(&client, "/a").unwrap();
+        let response = handle_path(&client, "/%61").unwrap();
+        assert_eq!(response.data, expected.data);
 
-        let result = TEST_RPC.a(&client).await.unwrap();
-        assert_eq!(result, "a");
+        // A nested literal and a literal's alias are decoded too
+        let response = handle_path(&client, "/%62/%34").unwrap();
+        let result = String::try_from_slice(&response.data).unwrap();
+        assert_eq!(result, "b4");
+        let response = handle_path(&client, "/b/fo%75r").unwrap();
+        let result = String::try_from_slice(&response.data).unwrap();
+        assert_eq!(result, "b4");
 
-        let result = TEST_RPC.b0i(&client).await.unwrap();
-        assert_eq!(result, "b0i");
+        // A malformed escape doesn't match and a segment is only decoded once
+        for path in ["/%6", "/%6g", "/%2561"] {
+            assert!(handle_path(&client, path).is_err(), "path {path}");
+        }
 
-        let result = TEST_RPC.b0ii(&client).await.unwrap();
-        assert_eq!(result, "b0ii");
+        assert_eq!(percent_decode_segment("a%2Fb%20c").unwrap(), "a/b c");
+        assert!(matches!(
+            percent_decode_segment("a"),
+            Some(Cow::Borrowed("a"))
+        ));
+        assert_eq!(percent_decode_segment("%ff"), None);
+    }
 
-        let result = TEST_RPC.b1(&client).await.unwrap();
-        assert_eq!(result, "b1");
+    #[test]
+    fn test_segment_count() {
+        let count = |path| segment_count(&split_path(path).unwrap());
+        assert_eq!(count("/"), 0);
+        assert_eq!(count("/a"), 1);
+        assert_eq!(count("/a/"), 1);
+        assert_eq!(count("/a//"), 1);
+        assert_eq!(count("/a/b/c"), 3);
+        assert_eq!(count("/a//c"), 3);
+    }
 
-        let balance = token::Amount::from(123_000_000);
-        let result = TEST_RPC.b2i(&client, &balance).await.unwrap();
-        assert_eq!(result, format!("b2i/{balance}"));
+    #[test]
+    fn test_router_path_segment_count() {
+        assert_eq!(TEST_RPC.segment_count("/"), 0);
+        assert_eq!(TEST_RPC.segment_count("/a"), 1);
+        assert_eq!(TEST_RPC.segment_count("/a/"), 1);
+        assert_eq!(TEST_RPC.segment_count("/a/b/c"), 3);
+        assert_eq!(TEST_RPC.segment_count("a/b"), 2);
+        assert_eq!(TEST_RPC.segment_count(""), 0);
 
-        let a1 = token::Amount::from(345);
-        let a2 = token::Amount::from(123_000);
-        let a3 = token::Amount::from(1_000_999);
-        let result = TEST_RPC.b3(&client, &a1, &a2, &a3).await.unwrap();
-        assert_eq!(result, format!("b3/{a1}/{a2}/{a3}"));
+        // The segments under a sub-router's prefix are separated by its
+        // separator
+        let ns = TEST_RPC.test_ns_rpc();
+        assert_eq!(ns.segment_count("/ns"), 1);
+        assert_eq!(ns.segment_count("/ns/a:b:c"), 4);
+        assert_eq!(ns.segment_count("/ns/a:b:"), 3);
+        // A path that's not under the prefix is counted with slashes
+        assert_eq!(ns.segment_count("/x/a:b"), 2);
+        assert_eq!(path_segment_count("/x/a/b", "/ns", ':'), 3);
+    }
 
-        let result = TEST_RPC.b3i(&client, &a1, &a2, &a3).await.unwrap();
-        assert_eq!(result, format!("b3i/{a1}/{a2}/{a3}"));
+    #[test]
+    fn test_router_segment_bounds() {
+        let bounds = |min, max| SegmentBounds { min, max };
+        assert_eq!(segment_bounds!(("a"), a), bounds(1, Some(1)));
+        assert_eq!(
+            segment_bounds!(("sub"), (sub TEST_SUB_RPC)),
+            bounds(1, None)
+        );
+        assert_eq!(
+            segment_bounds!(("c"), (with_options c)),
+            bounds(1, Some(1))
+        );
+        // The last typed arg consumes the rest of the path
+        assert_eq!(segment_bounds!(("d" / [dec: Decimal]), d), bounds(1, None));
+        assert_eq!(
+            segment_bounds!(("x" / [a: opt Epoch] / "y" / [b]), x),
+            bounds(2, Some(4))
+        );
+        assert_eq!(
+            segment_bounds!(
+                ("b" / [a: token::Amount]),
+                {
+                    ("i") -> String = i,
+                    ("ii" / [c]) -> String = ii,
+                }
+            ),
+            bounds(2, Some(4))
+        );
+    }
 
-        let result = TEST_RPC.b3ii(&client, &a1, &a2, &a3).await.unwrap();
-        assert_eq!(result, format!("b3ii/{a1}/{a2}/{a3}"));
+    /// Test that the segment count fast-reject doesn't change the matcher's
+    /// behavior for any of the `TEST_RPC` paths, including edge cases.
+    #[test]
+    fn test_router_segment_count_fast_reject() {
+        let client = TestClient::new(TEST_RPC);
 
-        let result =
-            TEST_RPC.b3iii(&client, &a1, &a2, &Some(a3)).await.unwrap();
-        assert_eq!(result, format!("b3iii/{a1}/{a2}/{a3}"));
+        // Paths and the expected matched handler's result, if any
+        let cases: &[(&str, Option<&str>)] = &[
+            ("/a", Some("a")),
+            ("/a/", Some("a")),
+            ("/a//", Some("a")),
+            ("/a///", Some("a")),
+            ("/a/b", None),
+            ("/a/b/", None),
+            ("/b", None),
+            ("/b/9", None),
+            ("/b/0", None),
+            ("/b/0/i", Some("b0i")),
+            ("/b/0/ii/", Some("b0ii")),
+            ("/b/0/i/x", None),
+            ("/b/1", Some("b1")),
+            ("/b/2/i/123", Some("b2i/123")),
+            ("/b/2/i", None),
+            ("/b/3/1/2/3", Some("b3/1/2/3")),
+            ("/b/3/1/2/i/3", Some("b3i/1/2/3")),
+            ("/b/3/1/2/3/ii", Some("b3ii/1/2/3")),
+            ("/b/3/1/2/3/iii", Some("b3iii/1/2/3")),
+            ("/b/3/1/2/iii", Some("b3iii/1/2")),
+            ("/b/3/1/2/iiii/3/xyz/4", Some("b3iiii/1/2/3/4")),
+            ("/b/3/1/2/iiii/xyz", Some("b3iiii/1/2")),
+            ("/b/3/1/2/3/ii/x", None),
+            ("/d/1.5", Some("d/1.5")),
+            ("/e/-1", Some("e/-1")),
+            ("/g", Some("g")),
+            ("/g/", Some("g")),
+            ("/h/a-slug-1", Some("h/a-slug-1")),
+            ("/h/a-slug-1/", Some("h/a-slug-1")),
+            ("/h/Not_a_slug", None),
+            ("/h/a-slug/1", None),
+            ("/h", None),
+            ("/sub/x", Some("x")),
+            ("/sub/y/arg", Some("y/arg")),
+            ("/sub/y", Some("y/")),
+            ("/sub/z/arg/", Some("z/arg")),
+            ("/sub/z/arg/x", None),
+            ("/sub", None),
+            ("/sub/7", Some("sub_n/7")),
+            ("/sub/7/x", None),
+            ("/", None),
+            ("", None),
+        ];
+        for (path, expected) in cases {
+            let result = handle_path(&client, path).map(|response| {
+                String::try_from_slice(&response.data).unwrap()
+            });
+            match expected {
+                Some(expected) => {
+                    assert_eq!(&result.unwrap(), expected, "path {path}")
+                }
+                None => assert!(result.is_err(), "path {path}"),
+            }
+            // The route resolution must agree with the handling
+            assert_eq!(
+                TEST_RPC.route_id(path).is_some(),
+                expected.is_some(),
+                "route of path {path}"
+            );
+        }
+    }
 
-        let result = TEST_RPC.b3iii(&client, &a1, &a2, &None).await.unwrap();
-        assert_eq!(result, format!("b3iii/{a1}/{a2}"));
+    /// Test that the single literal segment routes, which are matched with a
+    /// fast path, are dispatched like any other route.
+    #[test]
+    fn test_router_literal_fast_path() {
+        use super::test_rpc::TestRpcRoute;
 
-        let result = TEST_RPC
-            .b3iiii(&client, &a1, &a2, &Some(a3), &None)
-            .await
-            .unwrap();
-        assert_eq!(result, format!("b3iiii/{a1}/{a2}/{a3}"));
+        let client = TestClient::new(TEST_RPC);
+        let handle = |path: &str| {
+            handle_path(&client, path)
+                .map(|response| String::try_from_slice(&response.data).unwrap())
+        };
 
-        let a4 = Epoch::from(10);
-        let result = TEST_RPC
-            .b3iiii(&client, &a1, &a2, &Some(a3), &Some(a4))
-            .await
-            .unwrap();
-        assert_eq!(result, format!("b3iiii/{a1}/{a2}/{a3}/{a4}"));
+        assert_eq!(handle("/a").unwrap(), "a");
+        assert_eq!(handle("/a/").unwrap(), "a");
+        assert!(handle("/a/x").is_err());
+        assert!(handle("/ab").is_err());
+        assert!(handle("/A").is_err());
+        // The route's attributes are applied
+        assert_eq!(handle_path(&client, "/f").unwrap().info, "Served by f");
+        assert_eq!(handle("/g").unwrap(), "g");
+        // A handler that uses the request
+        assert_eq!(handle("/c/").unwrap(), "c");
+        assert_eq!(TEST_RPC.route_id("/a/"), Some(TestRpcRoute::A));
+    }
 
-        let result = TEST_RPC
-            .b3iiii(&client, &a1, &a2, &None, &None)
-            .await
-            .unwrap();
-        assert_eq!(result, format!("b3iiii/{a1}/{a2}"));
+    /// A simple benchmark of the fast path of the single literal segment
+    /// routes compared with the general matcher of the routes with only
+    /// literal segments. Run with:
+    /// ```shell
+    /// cargo test --lib bench_router_literal_fast_path -- --ignored --nocapture
+    /// ```
+    #[test]
+    #[ignore]
+    fn bench_router_literal_fast_path() {
+        const ITERS: u32 = 100_000;
+        let client = TestClient::new(TEST_RPC);
+        // `/a` and `/f` are matched with the fast path, while `/b/1` and
+        // `/b/0/ii` go through the general matcher
+        for path in ["/a", "/a/", "/f", "/b/1", "/b/0/ii"] {
+            let start = std::time::Instant::now();
+            for _ in 0..ITERS {
+                let _ = std::hint::black_box(handle_path(&client, path));
+            }
+            println!("{path}: {:?} per request", start.elapsed() / ITERS);
+        }
+    }
 
-        let result = TEST_RPC.c(&client, None, None, false).await.unwrap();
-        assert_eq!(result.data, format!("c"));
+    /// A simple benchmark of the router's matching, including paths that get
+    /// rejected early by their segment count. Run with:
+    /// ```shell
+    /// cargo test --lib bench_router_matching -- --ignored --nocapture
+    /// ```
+    #[test]
+    #[ignore]
+    fn bench_router_matching() {
+        const ITERS: u32 = 100_000;
+        let client = TestClient::new(TEST_RPC);
+        for path in ["/a", "/sub/z/arg", "/b/3/1/2/iiii/3/xyz/4", "/a/b/c/d/e"]
+        {
+            let start = std::time::Instant::now();
+            for _ in 0..ITERS {
+                let _ = std::hint::black_box(handle_path(&client, path));
+            }
+            println!("{path}: {:?} per request", start.elapsed() / ITERS);
+        }
+    }
 
-        let result = TEST_RPC.test_sub_rpc().x(&client).await.unwrap();
-        assert_eq!(result, format!("x"));
+    /// Test that all the paths of `TEST_RPC` resolve to the same responses
+    /// and errors with or without the dispatch on the first segment's hash.
+    #[test]
+    fn test_router_first_segment_dispatch() {
+        let client = TestClient::new(TEST_RPC);
+        let mut paths = TEST_RPC.sample_paths();
+        paths.extend(
+            [
+                "/",
+                "/a/x",
+                "/ab",
+                "/b/3/1/2/sum/3",
+                "/b/3/1/2/iiii/3/xyz/4",
+                "/b/3/1/2",
+                "/sub/z/arg",
+                "/invalid",
+                "/a/b/c/d/e",
+            ]
+            .map(String::from),
+        );
+        let resolve = |path: &str| format!("{:?}", handle_path(&client, path));
 
-        let arg = "test123";
-        let result = TEST_RPC.test_sub_rpc().y(&client, arg).await.unwrap();
-        assert_eq!(result, format!("y/{arg}"));
+        assert!(first_segment_dispatch());
+        let dispatched: Vec<String> =
+            paths.iter().map(|path| resolve(path)).collect();
+        set_first_segment_dispatch(false);
+        let matched: Vec<String> =
+            paths.iter().map(|path| resolve(path)).collect();
+        set_first_segment_dispatch(true);
 
-        let arg = "test321";
-        let result = TEST_RPC.test_sub_rpc().z(&client, arg).await.unwrap();
-        assert_eq!(result, format!("z/{arg}"));
+        for ((path, dispatched), matched) in
+            paths.iter().zip(dispatched).zip(matched)
+        {
+            assert_eq!(dispatched, matched, "{path}");
+        }
+        assert_eq!(first_segment_hash(&["a"], 0), Some(segment_hash("a")));
+        assert_eq!(first_segment_hash(&["a"], 1), None);
+    }
 
-        Ok(())
+    /// A simple benchmark of the router's matching with and without the
+    /// dispatch on the first segment's hash. Run with:
+    /// ```shell
+    /// cargo test --lib bench_router_first_segment_dispatch -- --ignored --nocapture
+    /// ```
+    #[test]
+    #[ignore]
+    fn bench_router_first_segment_dispatch() {
+        const ITERS: u32 = 100_000;
+        let client = TestClient::new(TEST_RPC);
+        for enabled in [false, true] {
+            set_first_segment_dispatch(enabled);
+            for path in ["/a", "/z/arg", "/sub/z/arg", "/b/3/1/2/iiii/3/xyz/4"]
+            {
+                let start = std::time::Instant::now();
+                for _ in 0..ITERS {
+                    let _ = std::hint::black_box(handle_path(&client, path));
+                }
+                println!(
+                    "{path} (dispatch {enabled}): {:?} per request",
+                    start.elapsed() / ITERS
+                );
+            }
+        }
+        set_first_segment_dispatch(true);
     }
 }
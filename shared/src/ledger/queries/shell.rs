@@ -9,12 +9,17 @@ use namada_core::types::storage::BlockResults;
 use crate::ledger::events::log::dumb_queries;
 use crate::ledger::events::Event;
 use crate::ledger::queries::types::{RequestCtx, RequestQuery};
-use crate::ledger::queries::{require_latest_height, EncodedResponseQuery};
+use crate::ledger::queries::{
+    require_latest_height, require_no_proof, require_past_height_limit,
+    ContinuationToken, EncodedResponseQuery, NodeInfo, OrderedPrefixValues,
+    PrefixPage, StateDiff,
+};
+use crate::ledger::storage::merkle_tree::MerkleRoot;
 use crate::ledger::storage::traits::StorageHasher;
 use crate::ledger::storage::{DBIter, DB};
 use crate::ledger::storage_api::{self, ResultExt, StorageRead};
 use crate::tendermint::merkle::proof::Proof;
-use crate::types::storage::{self, Epoch, PrefixValue};
+use crate::types::storage::{self, BlockHeight, Epoch, PrefixValue};
 #[cfg(any(test, feature = "async-client"))]
 use crate::types::transaction::TxResult;
 
@@ -25,10 +30,23 @@ type Conversion = (
     MerklePath<Node>,
 );
 
+/// The maximum number of blocks between a diff's `from` height and the last
+/// committed height, whose changes are scanned for the keys that were present
+/// under the diff's prefix. It matches the node's default
+/// `storage_read_past_height_limit` of 1 hour of past blocks at 1 block/sec.
+pub const MAX_STORAGE_DIFF_BLOCKS: u64 = 3600;
+
+/// The maximum number of items in a page of a storage prefix iteration. A
+/// greater requested page limit is clamped to it.
+pub const MAX_PREFIX_PAGE_LIMIT: u64 = 1000;
+
 router! {SHELL,
     // Epoch of the last committed block
     ( "epoch" ) -> Epoch = epoch,
 
+    // Chain ID and the last committed block height
+    ( "node_info" ) -> NodeInfo = node_info,
+
     // Raw storage access - read value
     ( "value" / [storage_key: storage::Key] )
         -> Vec<u8> = (with_options storage_value),
@@ -36,9 +54,19 @@ router! {SHELL,
     // Dry run a transaction
     ( "dry_run_tx" ) -> TxResult = (with_options dry_run_tx),
 
-    // Raw storage access - prefix iterator
+    // Raw storage access - prefix iterator, ordered lexicographically by keys
     ( "prefix" / [storage_key: storage::Key] )
-        -> Vec<PrefixValue> = (with_options storage_prefix),
+        -> OrderedPrefixValues = (with_options storage_prefix),
+
+    // Raw storage access - prefix iterator in pages of the given size limit.
+    // The request `data` may contain a continuation token from a previous page.
+    ( "prefix_page" / [limit: u64] / [storage_key: storage::Key] )
+        -> PrefixPage<PrefixValue> = (with_options storage_prefix_page),
+
+    // Raw storage access - the keys under the prefix that were added, removed
+    // or modified between the two block heights
+    ( "diff" / [from: u64] / [to: u64] / [storage_key: storage::Key] )
+        -> StateDiff = storage_diff,
 
     // Raw storage access - is given storage key present?
     ( "has_key" / [storage_key: storage::Key] )
@@ -96,11 +124,7 @@ where
     )
     .into_storage_result()?;
     let data = data.try_to_vec().into_storage_result()?;
-    Ok(EncodedResponseQuery {
-        data,
-        proof: None,
-        info: Default::default(),
-    })
+    Ok(EncodedResponseQuery::new(data))
 }
 
 /// Query to read block results from storage
@@ -175,6 +199,17 @@ where
     Ok(data)
 }
 
+fn node_info<D, H>(ctx: RequestCtx<'_, D, H>) -> storage_api::Result<NodeInfo>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    Ok(NodeInfo {
+        chain_id: ctx.storage.chain_id.clone(),
+        latest_height: ctx.storage.last_height,
+    })
+}
+
 /// Returns data with `vec![]` when the storage key is not found. For all
 /// borsh-encoded types, it is safe to check `data.is_empty()` to see if the
 /// value was found, except for unit - see `fn query_storage_value` in
@@ -188,18 +223,7 @@ where
     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
     H: 'static + StorageHasher + Sync,
 {
-    if let Some(past_height_limit) = ctx.storage_read_past_height_limit {
-        if request.height.0 + past_height_limit < ctx.storage.last_height.0 {
-            return Err(storage_api::Error::new(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                format!(
-                    "Cannot query more than {past_height_limit} blocks in the \
-                     past (configured via \
-                     `shell.storage_read_past_height_limit`)."
-                ),
-            )));
-        }
-    }
+    require_past_height_limit(&ctx, request.height)?;
 
     match ctx
         .storage
@@ -207,40 +231,39 @@ where
         .into_storage_result()?
     {
         (Some(value), _gas) => {
-            let proof = if request.prove {
+            let (proof, root) = if request.prove {
                 let proof = ctx
                     .storage
                     .get_existence_proof(&storage_key, &value, request.height)
                     .into_storage_result()?;
-                Some(proof)
+                (Some(proof), Some(merkle_root(&ctx, request)?))
             } else {
-                None
+                (None, None)
             };
-            Ok(EncodedResponseQuery {
-                data: value,
-                proof,
-                info: Default::default(),
-            })
+            Ok(EncodedResponseQuery::new(value)
+                .with_proof(proof)
+                .with_root(root))
         }
         (None, _gas) => {
-            let proof = if request.prove {
+            let (proof, root) = if request.prove {
                 let proof = ctx
                     .storage
                     .get_non_existence_proof(&storage_key, request.height)
                     .into_storage_result()?;
-                Some(proof)
+                (Some(proof), Some(merkle_root(&ctx, request)?))
             } else {
-                None
+                (None, None)
             };
-            Ok(EncodedResponseQuery {
-                data: vec![],
-                proof,
-                info: format!("No value found for key: {}", storage_key),
-            })
+            Ok(EncodedResponseQuery::empty()
+                .with_proof(proof)
+                .with_root(root)
+                .with_info(format!("No value found for key: {}", storage_key)))
         }
     }
 }
 
+/// Returns all the values under the prefix, ordered lexicographically by
+/// their keys. With a proof requested, the proof ops are in the same order.
 fn storage_prefix<D, H>(
     ctx: RequestCtx<'_, D, H>,
     request: &RequestQuery,
@@ -259,10 +282,10 @@ where
             Ok(PrefixValue { key, value })
         })
         .collect();
-    let data = data?;
-    let proof = if request.prove {
+    let data = OrderedPrefixValues::new(data?);
+    let (proof, root) = if request.prove {
         let mut ops = vec![];
-        for PrefixValue { key, value } in &data {
+        for PrefixValue { key, value } in data.iter() {
             let mut proof: crate::tendermint::merkle::proof::Proof = ctx
                 .storage
                 .get_existence_proof(key, value, request.height)
@@ -271,16 +294,195 @@ where
         }
         // ops is not empty in this case
         let proof = Proof { ops };
-        Some(proof)
+        (Some(proof), Some(merkle_root(&ctx, request)?))
     } else {
-        None
+        (None, None)
     };
     let data = data.try_to_vec().into_storage_result()?;
-    Ok(EncodedResponseQuery {
-        data,
-        proof,
-        ..Default::default()
-    })
+    Ok(EncodedResponseQuery::new(data)
+        .with_proof(proof)
+        .with_root(root))
+}
+
+/// The root of the merkle tree at the request's height, against which the
+/// proofs of its response are verified
+fn merkle_root<D, H>(
+    ctx: &RequestCtx<'_, D, H>,
+    request: &RequestQuery,
+) -> storage_api::Result<MerkleRoot>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    ctx.storage
+        .get_merkle_root(request.height)
+        .into_storage_result()
+}
+
+/// Returns a page of at most `limit` items under the prefix, with the `limit`
+/// clamped to [`MAX_PREFIX_PAGE_LIMIT`]. If the request `data` is not empty, it
+/// must be a [`ContinuationToken`] and the iteration seeks directly past the
+/// token's last key.
+fn storage_prefix_page<D, H>(
+    ctx: RequestCtx<'_, D, H>,
+    request: &RequestQuery,
+    limit: u64,
+    storage_key: storage::Key,
+) -> storage_api::Result<EncodedResponseQuery>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    require_latest_height(&ctx, request)?;
+    require_no_proof(request)?;
+    if limit == 0 {
+        return Err(storage_api::Error::new_const(
+            "The page limit must be greater than 0",
+        ));
+    }
+    let limit = limit.min(MAX_PREFIX_PAGE_LIMIT);
+
+    let token = if request.data.is_empty() {
+        None
+    } else {
+        Some(ContinuationToken::from_bytes(request.data.clone()))
+    };
+    let last_key = match &token {
+        Some(token) => Some(token.last_key().ok_or_else(|| {
+            storage_api::Error::new_const("Invalid continuation token")
+        })?),
+        None => None,
+    };
+
+    let (iter, _gas) = match last_key {
+        Some(last_key) => ctx.storage.iter_prefix_after(&storage_key, last_key),
+        None => ctx.storage.iter_prefix(&storage_key),
+    };
+    let mut items = vec![];
+    let mut has_more = false;
+    for (key, value, _gas) in iter {
+        if items.len() as u64 == limit {
+            has_more = true;
+            break;
+        }
+        let key = storage::Key::parse(key).into_storage_result()?;
+        items.push(PrefixValue { key, value });
+    }
+    let continuation = if has_more {
+        items
+            .last()
+            .map(|PrefixValue { key, .. }| ContinuationToken::after_key(key))
+    } else {
+        None
+    };
+    let data = PrefixPage {
+        items,
+        continuation,
+    }
+    .try_to_vec()
+    .into_storage_result()?;
+    Ok(EncodedResponseQuery::new(data))
+}
+
+/// Returns the changes of the values under the prefix between the `from` and
+/// `to` block heights, which must be in order, not above the last committed
+/// height and the `from` height not more than [`MAX_STORAGE_DIFF_BLOCKS`]
+/// below it. The values at each height are read for the keys that were
+/// present under the prefix at that height.
+fn storage_diff<D, H>(
+    ctx: RequestCtx<'_, D, H>,
+    from: u64,
+    to: u64,
+    storage_key: storage::Key,
+) -> storage_api::Result<StateDiff>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    let (from, to) = (BlockHeight(from), BlockHeight(to));
+    if from > to {
+        return Err(storage_api::Error::new(format!(
+            "The diff's from height {from} is above its to height {to}"
+        )));
+    }
+    let last_height = ctx.storage.last_height;
+    if to > last_height {
+        return Err(storage_api::Error::new(format!(
+            "The diff's to height {to} is above the last committed height \
+             {last_height}"
+        )));
+    }
+    require_past_height_limit(&ctx, from)?;
+    if last_height.0 - from.0 > MAX_STORAGE_DIFF_BLOCKS {
+        return Err(storage_api::Error::new(format!(
+            "The diff's from height {from} is more than \
+             {MAX_STORAGE_DIFF_BLOCKS} blocks below the last committed height \
+             {last_height}"
+        )));
+    }
+
+    // The keys present at the later height are among the ones present at
+    // the earlier height and the ones changed after it
+    let keys = prefix_keys_since(&ctx, &storage_key, from)?;
+    let earlier = prefix_values_at_height(&ctx, &keys, from)?;
+    let later = prefix_values_at_height(&ctx, &keys, to)?;
+    Ok(StateDiff::between(earlier, later))
+}
+
+/// Collect the keys that may have been present under the prefix at the
+/// block height or at any later one: the keys present at the last committed
+/// height and the ones that were changed or deleted after the height, which
+/// are scanned in a single pass over the blocks' diffs.
+fn prefix_keys_since<D, H>(
+    ctx: &RequestCtx<'_, D, H>,
+    prefix: &storage::Key,
+    height: BlockHeight,
+) -> storage_api::Result<Vec<storage::Key>>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    let mut keys = storage_api::iter_prefix_bytes(ctx.storage, prefix)?
+        .map(|iter_result| iter_result.map(|(key, _value)| key))
+        .collect::<storage_api::Result<Vec<_>>>()?;
+    let last_height = ctx.storage.last_height;
+    for changed_at in (height.0 + 1)..=last_height.0 {
+        let (old_diffs, _gas) =
+            ctx.storage.iter_old_diffs(BlockHeight(changed_at), prefix);
+        for (key, _old_value, _gas) in old_diffs {
+            keys.push(storage::Key::parse(key).into_storage_result()?);
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+/// Read the values of the `keys` collected with [`prefix_keys_since`] at the
+/// block height, skipping the ones that were not present at it.
+fn prefix_values_at_height<D, H>(
+    ctx: &RequestCtx<'_, D, H>,
+    keys: &[storage::Key],
+    height: BlockHeight,
+) -> storage_api::Result<OrderedPrefixValues>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    let mut values = vec![];
+    for key in keys {
+        let (value, _gas) = ctx
+            .storage
+            .read_with_height(key, height)
+            .into_storage_result()?;
+        if let Some(value) = value {
+            values.push(PrefixValue {
+                key: key.clone(),
+                value,
+            });
+        }
+    }
+    Ok(OrderedPrefixValues::new(values))
 }
 
 fn storage_has_key<D, H>(
@@ -331,12 +533,21 @@ where
 
 #[cfg(test)]
 mod test {
-    use borsh::BorshDeserialize;
+    use std::str::FromStr;
 
+    use borsh::{BorshDeserialize, BorshSerialize};
+
+    use super::{MAX_PREFIX_PAGE_LIMIT, MAX_STORAGE_DIFF_BLOCKS};
     use crate::ledger::queries::testing::TestClient;
-    use crate::ledger::queries::RPC;
+    use crate::ledger::queries::{
+        handle_path, node_health, node_info, Client, CompactProof,
+        ContinuationToken, HealthStatus, OrderedPrefixValues, RequestQuery,
+        RPC, SCHEMA_VERSION,
+    };
     use crate::ledger::storage_api::{self, StorageWrite};
     use crate::proto::Tx;
+    use crate::types::chain::{ChainId, ChainIdPrefix};
+    use crate::types::storage::{self, BlockHash, BlockHeight, PrefixValue};
     use crate::types::{address, token};
 
     const TX_NO_OP_WASM: &str = "../wasm_for_tests/tx_no_op.wasm";
@@ -360,6 +571,129 @@ mod test {
 
         let path = RPC.shell().storage_has_key_path(&key);
         assert_eq!(format!("/shell/has_key/{}", key), path);
+
+        let path = RPC.shell().storage_diff_path(&1, &2, &key);
+        assert_eq!(format!("/shell/diff/1/2/{}", key), path);
+    }
+
+    /// Test that the node's query handler prefixes the data of a versioned
+    /// response with the node's schema version.
+    #[test]
+    fn test_shell_queries_schema_version() {
+        let client = TestClient::new(RPC);
+        let request = |versioned| RequestQuery {
+            path: RPC.shell().epoch_path(),
+            versioned,
+            ..RequestQuery::default()
+        };
+
+        let plain = handle_path(client.ctx(), &request(false)).unwrap();
+        let versioned = handle_path(client.ctx(), &request(true)).unwrap();
+        assert_eq!(versioned.data[0], SCHEMA_VERSION);
+        assert_eq!(versioned.data[1..], plain.data[..]);
+    }
+
+    /// Test the changes under a prefix between the committed block heights,
+    /// including the keys that are not present at the last height.
+    #[tokio::test]
+    async fn test_shell_queries_state_diff() -> storage_api::Result<()> {
+        let mut client = TestClient::new(RPC);
+        let token_addr = address::testing::established_address_1();
+        let owners = [
+            address::testing::established_address_2(),
+            address::testing::established_address_3(),
+            address::testing::established_address_4(),
+            address::testing::established_address_1(),
+        ];
+        let balance_key = |owner_ix: usize| {
+            token::balance_key(&token_addr, &owners[owner_ix])
+        };
+        let balance = |amount: u64| token::Amount::from(amount);
+        let prefix = token::balance_prefix(&token_addr);
+
+        // At height 1, the first three owners have a balance
+        client
+            .storage
+            .begin_block(BlockHash::default(), BlockHeight(1))
+            .unwrap();
+        for (owner_ix, amount) in [(0, 1), (1, 2), (2, 3)] {
+            StorageWrite::write(
+                &mut client.storage,
+                &balance_key(owner_ix),
+                balance(amount),
+            )?;
+        }
+        client.storage.commit().unwrap();
+
+        // At height 2, the first owner's balance is removed, the second's is
+        // unchanged, the third's is modified and the fourth's is added
+        client
+            .storage
+            .begin_block(BlockHash::default(), BlockHeight(2))
+            .unwrap();
+        StorageWrite::delete(&mut client.storage, &balance_key(0))?;
+        StorageWrite::write(&mut client.storage, &balance_key(2), balance(4))?;
+        StorageWrite::write(&mut client.storage, &balance_key(3), balance(5))?;
+        client.storage.commit().unwrap();
+
+        // At height 3, the third owner's balance is removed too
+        client
+            .storage
+            .begin_block(BlockHash::default(), BlockHeight(3))
+            .unwrap();
+        StorageWrite::delete(&mut client.storage, &balance_key(2))?;
+        client.storage.commit().unwrap();
+
+        let encoded = |amount: u64| balance(amount).try_to_vec().unwrap();
+        let keys = |values: &[PrefixValue]| -> Vec<storage::Key> {
+            values.iter().map(|value| value.key.clone()).collect()
+        };
+        let diff = RPC
+            .shell()
+            .storage_diff(&client, &1, &2, &prefix)
+            .await
+            .unwrap();
+        assert_eq!(keys(&diff.added), vec![balance_key(3)]);
+        assert_eq!(diff.added[0].value, encoded(5));
+        assert_eq!(diff.removed, vec![balance_key(0)]);
+        // The modified key is found even though it's been removed since
+        assert_eq!(keys(&diff.modified), vec![balance_key(2)]);
+        assert_eq!(diff.modified[0].value, encoded(4));
+        // The unchanged key is not reported
+        let mut changed: Vec<storage::Key> =
+            [0, 2, 3].into_iter().map(balance_key).collect();
+        changed.sort_by_cached_key(|key| key.to_string());
+        assert_eq!(diff.changed_keys(), changed.iter().collect::<Vec<_>>());
+
+        let diff = RPC
+            .shell()
+            .storage_diff(&client, &2, &3, &prefix)
+            .await
+            .unwrap();
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.removed, vec![balance_key(2)]);
+        assert!(diff.modified.is_empty());
+
+        // There are no changes at the same height
+        let diff = RPC
+            .shell()
+            .storage_diff(&client, &2, &2, &prefix)
+            .await
+            .unwrap();
+        assert!(diff.is_empty());
+
+        // The heights must be in order and not above the last height
+        let result = RPC.shell().storage_diff(&client, &2, &1, &prefix).await;
+        assert!(result.is_err());
+        let result = RPC.shell().storage_diff(&client, &1, &4, &prefix).await;
+        assert!(result.is_err());
+
+        // The from height must not be too far below the last height
+        client.storage.last_height = BlockHeight(MAX_STORAGE_DIFF_BLOCKS + 2);
+        let result = RPC.shell().storage_diff(&client, &1, &2, &prefix).await;
+        assert!(result.is_err());
+
+        Ok(())
     }
 
     #[tokio::test]
@@ -446,4 +780,340 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_shell_queries_storage_prefix_page() -> storage_api::Result<()>
+    {
+        let mut client = TestClient::new(RPC);
+
+        // Write balances for a few owners of the same token
+        let token_addr = address::testing::established_address_1();
+        let owners = [
+            address::testing::established_address_2(),
+            address::testing::established_address_3(),
+            address::testing::established_address_4(),
+        ];
+        for (ix, owner) in owners.iter().enumerate() {
+            let balance_key = token::balance_key(&token_addr, owner);
+            let balance = token::Amount::from(ix as u64);
+            StorageWrite::write(&mut client.storage, &balance_key, balance)?;
+        }
+        let balance_prefix = token::balance_prefix(&token_addr);
+        let all_balances = RPC
+            .shell()
+            .storage_prefix(&client, None, None, false, &balance_prefix)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(all_balances.len(), 3);
+
+        // The first page is full and has a continuation token
+        let first_page = RPC
+            .shell()
+            .storage_prefix_page(
+                &client,
+                None,
+                None,
+                false,
+                &2,
+                &balance_prefix,
+            )
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(first_page.items.len(), 2);
+        let continuation = first_page.continuation.unwrap();
+
+        // The second page continues after the first one and has no more
+        let second_page = RPC
+            .shell()
+            .storage_prefix_page(
+                &client,
+                Some(continuation.into_bytes()),
+                None,
+                false,
+                &2,
+                &balance_prefix,
+            )
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(second_page.items.len(), 1);
+        assert!(second_page.continuation.is_none());
+
+        // Together, the pages have all the items in the same order
+        let paged_keys: Vec<_> = first_page
+            .items
+            .iter()
+            .chain(second_page.items.iter())
+            .map(|item| &item.key)
+            .collect();
+        let all_keys: Vec<_> =
+            all_balances.iter().map(|item| &item.key).collect();
+        assert_eq!(paged_keys, all_keys);
+
+        // A continuation token of a key before the prefix doesn't iterate
+        // outside of it
+        let before_prefix = ContinuationToken::from_bytes(b"!".to_vec());
+        let page = RPC
+            .shell()
+            .storage_prefix_page(
+                &client,
+                Some(before_prefix.into_bytes()),
+                None,
+                false,
+                &3,
+                &balance_prefix,
+            )
+            .await
+            .unwrap()
+            .data;
+        let page_keys: Vec<_> =
+            page.items.iter().map(|item| &item.key).collect();
+        assert_eq!(page_keys, all_keys);
+
+        // A page limit above the maximum is clamped to it
+        for ix in 0..MAX_PREFIX_PAGE_LIMIT {
+            let owner = address::testing::gen_established_address();
+            let balance_key = token::balance_key(&token_addr, &owner);
+            let balance = token::Amount::from(ix);
+            StorageWrite::write(&mut client.storage, &balance_key, balance)?;
+        }
+        let page = RPC
+            .shell()
+            .storage_prefix_page(
+                &client,
+                None,
+                None,
+                false,
+                &u64::MAX,
+                &balance_prefix,
+            )
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(page.items.len() as u64, MAX_PREFIX_PAGE_LIMIT);
+        assert!(page.continuation.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shell_queries_storage_prefix_pairs() -> storage_api::Result<()>
+    {
+        let mut client = TestClient::new(RPC);
+
+        // Write balances for a few owners of the same token
+        let token_addr = address::testing::established_address_1();
+        let owners = [
+            address::testing::established_address_2(),
+            address::testing::established_address_3(),
+            address::testing::established_address_4(),
+        ];
+        let mut expected = vec![];
+        for (ix, owner) in owners.iter().enumerate() {
+            let balance_key = token::balance_key(&token_addr, owner);
+            let balance = token::Amount::from(ix as u64);
+            StorageWrite::write(&mut client.storage, &balance_key, balance)?;
+            expected.push((balance_key, balance));
+        }
+        expected.sort_by_cached_key(|(key, _)| key.to_string());
+        let balance_prefix = token::balance_prefix(&token_addr);
+
+        // The values are decoded into typed pairs in the keys' order
+        let pairs = RPC
+            .shell()
+            .storage_prefix_pairs::<_, token::Amount>(
+                &client,
+                None,
+                None,
+                false,
+                &balance_prefix,
+            )
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(pairs.len(), 3);
+        let pairs = pairs.collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(pairs, expected);
+
+        // A value of another type cannot be decoded
+        let mut pairs = RPC
+            .shell()
+            .storage_prefix_pairs::<_, String>(
+                &client,
+                None,
+                None,
+                false,
+                &balance_prefix,
+            )
+            .await
+            .unwrap()
+            .data;
+        assert!(pairs.next().unwrap().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shell_queries_storage_prefix_order() -> storage_api::Result<()>
+    {
+        let mut client = TestClient::new(RPC);
+
+        // Write balances for a few owners of the same token
+        let token_addr = address::testing::established_address_1();
+        let owners = [
+            address::testing::established_address_4(),
+            address::testing::established_address_2(),
+            address::testing::established_address_3(),
+        ];
+        for (ix, owner) in owners.iter().enumerate() {
+            let balance_key = token::balance_key(&token_addr, owner);
+            let balance = token::Amount::from(ix as u64);
+            StorageWrite::write(&mut client.storage, &balance_key, balance)?;
+        }
+        let balance_prefix = token::balance_prefix(&token_addr);
+
+        // Two scans of the same prefix return the values in the same order
+        let first_scan = RPC
+            .shell()
+            .storage_prefix(&client, None, None, false, &balance_prefix)
+            .await
+            .unwrap()
+            .data;
+        let second_scan = RPC
+            .shell()
+            .storage_prefix(&client, None, None, false, &balance_prefix)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(first_scan.len(), 3);
+        let first_keys: Vec<_> =
+            first_scan.iter().map(|item| item.key.to_string()).collect();
+        let second_keys: Vec<_> = second_scan
+            .iter()
+            .map(|item| item.key.to_string())
+            .collect();
+        assert_eq!(first_keys, second_keys);
+
+        // ... and the order is lexicographic by the keys
+        let mut sorted_keys = first_keys.clone();
+        sorted_keys.sort();
+        assert_eq!(first_keys, sorted_keys);
+
+        // Values that are out of order cannot be decoded
+        let mut unordered = first_scan.into_inner();
+        unordered.reverse();
+        let bytes = unordered.try_to_vec().unwrap();
+        assert!(OrderedPrefixValues::try_from_slice(&bytes).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shell_queries_compact_proof() -> storage_api::Result<()> {
+        let mut client = TestClient::new(RPC);
+
+        // Write a balance to prove
+        let token_addr = address::testing::established_address_1();
+        let owner = address::testing::established_address_2();
+        let balance_key = token::balance_key(&token_addr, &owner);
+        let balance = token::Amount::from(1000);
+        StorageWrite::write(&mut client.storage, &balance_key, balance)?;
+
+        let proof = RPC
+            .shell()
+            .storage_value(&client, None, None, true, &balance_key)
+            .await
+            .unwrap()
+            .proof
+            .unwrap();
+
+        // The proof is the same after a round-trip through the compact format
+        let compact_proof = CompactProof::compress(&proof);
+        assert_eq!(
+            compact_proof.ops[0].field_type,
+            CompactProof::MARKER_FIELD_TYPE
+        );
+        assert_eq!(CompactProof::expand(&compact_proof).unwrap(), proof);
+
+        // The compact proof is sent when requested and the client method
+        // expands it
+        client.compact_proof = true;
+        let response = RPC
+            .shell()
+            .raw(&client, &format!("/value/{balance_key}"), None, None, true)
+            .await
+            .unwrap();
+        assert_eq!(response.proof.unwrap(), compact_proof);
+        let expanded_proof = RPC
+            .shell()
+            .storage_value(&client, None, None, true, &balance_key)
+            .await
+            .unwrap()
+            .proof
+            .unwrap();
+        assert_eq!(expanded_proof, proof);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shell_queries_proof_root() -> storage_api::Result<()> {
+        let mut client = TestClient::new(RPC);
+
+        // Write a balance to prove
+        let token_addr = address::testing::established_address_1();
+        let owner = address::testing::established_address_2();
+        let balance_key = token::balance_key(&token_addr, &owner);
+        let balance = token::Amount::from(1000);
+        StorageWrite::write(&mut client.storage, &balance_key, balance)?;
+
+        // A provable response includes the root to verify its proof against
+        let response = RPC
+            .shell()
+            .storage_value(&client, None, None, true, &balance_key)
+            .await
+            .unwrap();
+        assert!(response.proof.is_some());
+        let root = response.root.unwrap();
+        assert!(!root.0.is_empty());
+        assert_eq!(root, client.storage.merkle_root());
+
+        // A response without a proof has no root
+        let response = RPC
+            .shell()
+            .storage_value(&client, None, None, false, &balance_key)
+            .await
+            .unwrap();
+        assert!(response.root.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shell_queries_node_info() {
+        // Initialize the `TestClient` with a custom chain ID
+        let mut client = TestClient::new(RPC);
+        let chain_id = ChainId::from_genesis(
+            ChainIdPrefix::from_str("test-chain").unwrap(),
+            b"genesis",
+        );
+        client.storage.chain_id = chain_id.clone();
+        client.storage.last_height = BlockHeight(10);
+
+        let info = node_info(&client).await.unwrap();
+        assert_eq!(info.chain_id, chain_id);
+        assert_eq!(info.latest_height, BlockHeight(10));
+
+        // The health is derived from the same info by default
+        let health = node_health(&client).await.unwrap();
+        assert_eq!(
+            health,
+            HealthStatus {
+                latest_height: BlockHeight(10),
+                catching_up: false,
+            }
+        );
+    }
 }
@@ -1,8 +1,27 @@
+use std::any::Any;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+use thiserror::Error;
+
+use super::router;
+#[cfg(any(test, feature = "async-handlers"))]
+use super::router::LocalBoxFuture;
 use crate::ledger::events::log::EventLog;
+use crate::ledger::storage::merkle_tree::MerkleRoot;
 use crate::ledger::storage::{DBIter, Storage, StorageHasher, DB};
-use crate::ledger::storage_api;
-use crate::tendermint::merkle::proof::Proof;
-use crate::types::storage::BlockHeight;
+use crate::ledger::storage_api::{self, ResultExt};
+use crate::tendermint::merkle::proof::{Proof, ProofOp};
+use crate::types::chain::ChainId;
+use crate::types::hash::Hash;
+use crate::types::storage::{self, BlockHeight, PrefixValue};
 #[cfg(feature = "wasm-runtime")]
 use crate::vm::wasm::{TxCache, VpCache};
 #[cfg(feature = "wasm-runtime")]
@@ -10,7 +29,7 @@ use crate::vm::WasmCacheRoAccess;
 
 /// A request context provides read-only access to storage and WASM compilation
 /// caches to request handlers.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct RequestCtx<'shell, D, H>
 where
     D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
@@ -30,13 +49,111 @@ where
     /// limit the how many block heights in the past can the storage be
     /// queried for reading values.
     pub storage_read_past_height_limit: Option<u64>,
+    /// The version of the queries API requested with a leading path segment
+    /// of a versioned root router, if any. This is set by the router.
+    pub version: Option<Version>,
+    /// The args matched by the prefixes of the sub-routers through which the
+    /// request is routed, keyed by the args' names, with the values formatted
+    /// with `Display` (see [`RequestCtx::prefix_arg`]). An absent optional arg
+    /// is left out. This is set by the router.
+    pub prefix_args: BTreeMap<String, String>,
+    /// The capabilities of the node, e.g. `archive` for a node that keeps
+    /// the historical state, which enable the routes with a
+    /// `#[capability = "..."]` attribute. The other such routes fail with
+    /// `router::Error::RouteDisabled`.
+    pub capabilities: BTreeSet<String>,
+    /// The height of the latest finalized block, if it's behind the last
+    /// committed block, to which a request with the
+    /// [`HeightSelector::Finalized`] is resolved. When it's not set, the last
+    /// committed block is final (see
+    /// [`RequestCtx::latest_finalized_height`]).
+    pub finalized_height: Option<BlockHeight>,
+    /// Taken from the node's config of the query handlers, e.g. the
+    /// threshold of a slow query.
+    pub handler_config: router::HandlerConfig,
+}
+
+// Implemented manually, because the derived implementation would require the
+// storage's `D` and `H` to be `Clone` too, even though it's borrowed.
+impl<'shell, D, H> Clone for RequestCtx<'shell, D, H>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage,
+            event_log: self.event_log,
+            #[cfg(feature = "wasm-runtime")]
+            vp_wasm_cache: self.vp_wasm_cache.clone(),
+            #[cfg(feature = "wasm-runtime")]
+            tx_wasm_cache: self.tx_wasm_cache.clone(),
+            storage_read_past_height_limit: self.storage_read_past_height_limit,
+            version: self.version,
+            prefix_args: self.prefix_args.clone(),
+            capabilities: self.capabilities.clone(),
+            finalized_height: self.finalized_height,
+            handler_config: self.handler_config,
+        }
+    }
+}
+
+impl<'shell, D, H> RequestCtx<'shell, D, H>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    /// Parse the arg with the given `name` matched by the prefix of a
+    /// sub-router through which the request is routed, or `None` if there's
+    /// no such arg, e.g. an absent optional arg, or if it cannot be parsed.
+    pub fn prefix_arg<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.prefix_args.get(name)?.parse().ok()
+    }
+
+    /// Get the height of the latest finalized block, which is the
+    /// `finalized_height`, if set, or otherwise the last committed block,
+    /// because a committed block is final.
+    pub fn latest_finalized_height(&self) -> BlockHeight {
+        self.finalized_height.unwrap_or(self.storage.last_height)
+    }
+}
+
+/// A version of the queries API, which a versioned root router accepts as an
+/// optional leading path segment, e.g. `v2` in `/v2/a`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(pub u32);
+
+/// An error of parsing a [`Version`] from a path segment.
+#[derive(Error, Debug)]
+#[error("Invalid version {0}, expected `v` followed by a number, e.g. `v2`")]
+pub struct VersionParseError(String);
+
+impl FromStr for Version {
+    type Err = VersionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix('v')
+            .filter(|num| num.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|num| num.parse().ok())
+            .map(Self)
+            .ok_or_else(|| VersionParseError(s.to_owned()))
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
 }
 
 /// A `Router` handles parsing read-only query requests and dispatching them to
 /// their handler functions. A valid query returns a borsh-encoded result.
 pub trait Router {
-    /// Handle a given request using the provided context. This must be invoked
-    /// on the root `Router` to be able to match the `request.path` fully.
+    /// Handle a given request using the provided context. The `request.path`
+    /// is normalized with [`router::normalize_path`] before it's matched,
+    /// except that a trailing slash is kept when the router
+    /// [`Router::keeps_trailing_slash`]. This must be invoked on the root
+    /// `Router` to be able to match the `request.path` fully.
     fn handle<D, H>(
         &self,
         ctx: RequestCtx<'_, D, H>,
@@ -46,24 +163,319 @@ pub trait Router {
         D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
         H: 'static + StorageHasher + Sync,
     {
-        self.internal_handle(ctx, request, 0)
+        let path = router::normalize_path(&request.path);
+        let mut segments =
+            router::split_request_path(&path).into_storage_result()?;
+        if self.keeps_trailing_slash() {
+            router::keep_trailing_slash(&request.path, &mut segments);
+        }
+        self.handle_segments(ctx, request, &segments)
+    }
+
+    /// Handle a given request using the provided context, after stripping the
+    /// given `base` path from the start of the `request.path` (e.g. when the
+    /// router is mounted behind a gateway at the `base`). The `base` is
+    /// matched by whole segments and it's not a part of the paths constructed
+    /// by the router. Both the `base` and the `request.path` are normalized
+    /// with [`router::normalize_path`] and a request whose path is not under
+    /// the `base` is rejected. This must be invoked on the root `Router`.
+    fn handle_with_base<D, H>(
+        &self,
+        ctx: RequestCtx<'_, D, H>,
+        request: &RequestQuery,
+        base: &str,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let base = router::normalize_path(base);
+        let base_segments = router::split_path(&base).unwrap_or_default();
+        let path = router::normalize_path(&request.path);
+        let mut segments =
+            router::split_request_path(&path).into_storage_result()?;
+        if self.keeps_trailing_slash() {
+            router::keep_trailing_slash(&request.path, &mut segments);
+        }
+        match segments.strip_prefix(base_segments.as_slice()) {
+            Some(segments) => self.handle_segments(ctx, request, segments),
+            None => Err(router::Error::WrongPath(request.path.clone()))
+                .into_storage_result(),
+        }
+    }
+
+    /// Handle a given request like [`Router::handle`] for a caller in the
+    /// same process. The response of a handler that doesn't use the request
+    /// is not encoded, instead its typed result is returned in the response's
+    /// `value` (see [`TypedValue`]) and its `data` is left empty, so that the
+    /// caller can take it without the borsh encoding and decoding. The
+    /// responses of the other routes and the responses with another encoding
+    /// selected by the path's suffix are encoded as usual.
+    fn handle_ref<D, H>(
+        &self,
+        ctx: RequestCtx<'_, D, H>,
+        request: &RequestQuery,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        if request.in_process {
+            return self.handle(ctx, request);
+        }
+        let request = RequestQuery {
+            in_process: true,
+            ..request.clone()
+        };
+        self.handle(ctx, &request)
     }
 
+    /// Handle a given request like [`Router::handle`], but prefix the
+    /// response's data with the given `schema_version` byte. A node that
+    /// serves the queries with a schema version allows the clients to detect
+    /// a response whose encoding they don't understand (e.g. after an upgrade
+    /// of the node) with the `clients::VersionedClient`, before it's decoded.
+    fn handle_with_schema_version<D, H>(
+        &self,
+        ctx: RequestCtx<'_, D, H>,
+        request: &RequestQuery,
+        schema_version: u8,
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let mut response = self.handle(ctx, request)?;
+        response.data.insert(0, schema_version);
+        Ok(response)
+    }
+
+    /// Handle a given request like [`Router::handle`] and measure the time
+    /// spent matching its path, in its handler and encoding its response
+    /// (see [`router::Timing`]), e.g. to find where the time goes when
+    /// debugging the performance of a query.
+    #[cfg(any(test, feature = "metrics"))]
+    fn handle_timed<D, H>(
+        &self,
+        ctx: RequestCtx<'_, D, H>,
+        request: &RequestQuery,
+    ) -> storage_api::Result<(EncodedResponseQuery, router::Timing)>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let (result, timing) =
+            router::time_request(|| self.handle(ctx, request));
+        result.map(|response| (response, timing))
+    }
+
+    /// Handle a given request using the provided context, matching the
+    /// already split path `segments` instead of the `request.path`. The
+    /// segments don't include the leading slash, e.g. a path `/a/b` is split
+    /// into `["a", "b"]`. This must be invoked on the root `Router` with all
+    /// the segments of a path.
+    fn handle_segments<D, H>(
+        &self,
+        ctx: RequestCtx<'_, D, H>,
+        request: &RequestQuery,
+        segments: &[&str],
+    ) -> storage_api::Result<EncodedResponseQuery>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let span = request_span(request);
+        let _entered = span.enter();
+        let (request, proof_format) = resolve_request(&ctx, request)?;
+        let response = self.internal_handle(ctx, &request, segments, 0)?;
+        Ok(finish_response(response, &request, proof_format))
+    }
+
+    /// Handle a given request like [`Router::handle`], but await the `async`
+    /// handlers of the routes with the `#[async_handler]` attribute, which
+    /// cannot be handled synchronously. The other handlers are called as in
+    /// [`Router::handle`].
+    #[cfg(any(test, feature = "async-handlers"))]
+    fn handle_async<'a, D, H>(
+        &'a self,
+        ctx: RequestCtx<'a, D, H>,
+        request: &'a RequestQuery,
+    ) -> LocalBoxFuture<'a, storage_api::Result<EncodedResponseQuery>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        let span = request_span(request);
+        let response = async move {
+            let path = router::normalize_path(&request.path);
+            let mut segments =
+                router::split_request_path(&path).into_storage_result()?;
+            if self.keeps_trailing_slash() {
+                router::keep_trailing_slash(&request.path, &mut segments);
+            }
+            let (request, proof_format) = resolve_request(&ctx, request)?;
+            let response = self
+                .internal_handle_async(ctx, &request, &segments, 0)
+                .await?;
+            Ok(finish_response(response, &request, proof_format))
+        };
+        Box::pin(tracing::Instrument::instrument(response, span))
+    }
+
+    /// Handle a given request like [`Router::handle_async`], but prefix the
+    /// response's data with the given `schema_version` byte, like
+    /// [`Router::handle_with_schema_version`].
+    #[cfg(any(test, feature = "async-handlers"))]
+    fn handle_async_with_schema_version<'a, D, H>(
+        &'a self,
+        ctx: RequestCtx<'a, D, H>,
+        request: &'a RequestQuery,
+        schema_version: u8,
+    ) -> LocalBoxFuture<'a, storage_api::Result<EncodedResponseQuery>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Box::pin(async move {
+            let mut response = self.handle_async(ctx, request).await?;
+            response.data.insert(0, schema_version);
+            Ok(response)
+        })
+    }
+
+    /// Find the [`router::CachePolicy`] of the route that matches the given
+    /// path, as declared with the route's `#[cache(...)]` attribute, if any.
+    /// This must be invoked on the root `Router`.
+    fn cache_policy(&self, path: &str) -> Option<router::CachePolicy>;
+
+    /// Check whether the router or any of its sub-routers has a route with
+    /// the `#[empty_segment]` attribute, for which [`Router::handle`] keeps
+    /// the trailing slash of a request path as an empty last segment.
+    fn keeps_trailing_slash(&self) -> bool;
+
+    /// Count the logical segments of a `path`, ignoring its leading slash
+    /// and an optional trailing slash. The segments under the router's prefix
+    /// are separated by the router's separator (see
+    /// [`router::path_segment_count`]).
+    fn segment_count(&self, path: &str) -> usize;
+
     /// Internal method which shouldn't be invoked directly. Instead, you may
     /// want to call `self.handle()`.
     ///
     /// Handle a given request using the provided context, starting to
-    /// try to match `request.path` against the `Router`'s patterns at the
-    /// given `start` offset.
+    /// try to match the path `segments` against the `Router`'s patterns at
+    /// the given `start` segment index.
     fn internal_handle<D, H>(
         &self,
         ctx: RequestCtx<'_, D, H>,
         request: &RequestQuery,
+        segments: &[&str],
         start: usize,
     ) -> storage_api::Result<EncodedResponseQuery>
     where
         D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
         H: 'static + StorageHasher + Sync;
+
+    /// Internal method which shouldn't be invoked directly. Instead, you may
+    /// want to call `self.handle_async()`.
+    ///
+    /// Handle a given request like [`Router::internal_handle`], but await the
+    /// `async` handlers. By default, the request is handled synchronously,
+    /// which is overridden by the routers generated with `router!`.
+    #[cfg(any(test, feature = "async-handlers"))]
+    fn internal_handle_async<'a, D, H>(
+        &'a self,
+        ctx: RequestCtx<'a, D, H>,
+        request: &'a RequestQuery,
+        segments: &'a [&'a str],
+        start: usize,
+    ) -> LocalBoxFuture<'a, storage_api::Result<EncodedResponseQuery>>
+    where
+        D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+        H: 'static + StorageHasher + Sync,
+    {
+        Box::pin(
+            async move { self.internal_handle(ctx, request, segments, start) },
+        )
+    }
+}
+
+/// The span in which the handling of a request with an id is traced.
+fn request_span(request: &RequestQuery) -> tracing::Span {
+    match &request.request_id {
+        Some(request_id) => tracing::info_span!(
+            "query",
+            request_id = request_id.as_str(),
+            path = request.path.as_str()
+        ),
+        None => tracing::Span::none(),
+    }
+}
+
+/// Resolve the block height selected explicitly for a request, which replaces
+/// the request's `height`, and check its `if_height` precondition. The proof
+/// format selected by the path's trailing segment takes precedence over the
+/// `compact_proof` flag.
+fn resolve_request<'a, D, H>(
+    ctx: &RequestCtx<'_, D, H>,
+    request: &'a RequestQuery,
+) -> storage_api::Result<(Cow<'a, RequestQuery>, ProofFormat)>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    let height = match request.height_selector {
+        HeightSelector::Latest => None,
+        HeightSelector::Finalized => Some(ctx.latest_finalized_height()),
+        HeightSelector::Exact(height) => Some(height),
+    };
+    let request = match height {
+        Some(height) if height != request.height => Cow::Owned(RequestQuery {
+            height,
+            ..request.clone()
+        }),
+        _ => Cow::Borrowed(request),
+    };
+    super::require_if_height(ctx, &request)?;
+    let proof_format = match router::path_proof_format(&request.path) {
+        Some(format) => format.parse().into_storage_result()?,
+        None if request.compact_proof => ProofFormat::Compact,
+        None => ProofFormat::default(),
+    };
+    Ok((request, proof_format))
+}
+
+/// Finish the response of a handler to a request: merge its proofs, move its
+/// warnings to its `log`, encode its proof in the `proof_format` and add the
+/// matched path, the ETag and, for a `head` request, the content length in
+/// place of the data.
+fn finish_response(
+    mut response: EncodedResponseQuery,
+    request: &RequestQuery,
+    proof_format: ProofFormat,
+) -> EncodedResponseQuery {
+    // The proofs of multiple keys are sent together in the `proof`
+    if !response.proofs.is_empty() {
+        let proofs = std::mem::take(&mut response.proofs);
+        response.proof = Some(KeyedProof::merge(proofs));
+    }
+    // The warnings are sent in the `log`, after the handler's log, if any
+    if !response.warnings.is_empty() {
+        Warning::encode_into_log(&response.warnings, &mut response.log);
+    }
+    response.proof = response.proof.map(|proof| proof_format.encode(proof));
+    response.matched_path = Some(request.path.clone());
+    let content_length = response.data.len();
+    // A typed value is not encoded, so it has no ETag
+    if response.value.is_none() {
+        router::apply_etag(&mut response, request.if_none_match.as_ref());
+    }
+    // Only the metadata is sent in response to a `head` request
+    if request.head {
+        response.data = vec![];
+        response.content_length = Some(content_length);
+    }
+    response
 }
 
 /// A client with async request dispatcher method, which can be used to invoke
@@ -81,11 +493,219 @@ pub trait Client {
         &self,
         path: String,
     ) -> Result<Vec<u8>, Self::Error> {
-        self.request(path, None, None, false)
+        self.request_with_id(path, None, None, false, self.next_request_id())
             .await
             .map(|response| response.data)
     }
 
+    /// Send a simple query request at the given path, like
+    /// [`Client::simple_request`], but return the whole response. A client
+    /// for a node in the same process may handle the request with
+    /// [`Router::handle_ref`], in which case the response carries the
+    /// handler's typed result in its `value` instead of the encoded `data`.
+    /// By default, the response is always encoded.
+    async fn simple_request_ref(
+        &self,
+        path: String,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        self.request_with_id(path, None, None, false, self.next_request_id())
+            .await
+    }
+
+    /// Get the height of the node's last committed block. By default, it's
+    /// the latest height of the [`Client::health`].
+    async fn chain_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.health().await.map(|status| status.latest_height)
+    }
+
+    /// Get the height of the latest finalized block, at which a request with
+    /// the [`HeightSelector::Finalized`] is sent by the default
+    /// [`Client::request_at`]. A committed block is final, so by default it's
+    /// the [`Client::chain_height`].
+    async fn finalized_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.chain_height().await
+    }
+
+    /// Fetch the height of the node's last committed block once to send
+    /// several queries at it, so that their results are mutually consistent
+    /// even if another block is committed in between. The
+    /// `clients::PinnedClient` sends all its requests at the pinned height.
+    async fn pin_latest_height(&self) -> Result<BlockHeight, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.chain_height().await
+    }
+
+    /// Query the [`HealthStatus`] of the node, e.g. to confirm that it's
+    /// synced before a burst of queries. By default, it's queried with the
+    /// [`node_health`](super::node_health) helper.
+    async fn health(&self) -> Result<HealthStatus, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        super::node_health(self).await
+    }
+
+    /// The readiness gate used by the [`Client::check_health`], if any. When
+    /// it's set, the `router!`'s generated methods check the node's health
+    /// before sending a request. There's no gate by default, because every
+    /// check costs an additional request.
+    fn health_gate(&self) -> Option<&HealthGate> {
+        None
+    }
+
+    /// If the client has a [`Client::health_gate`], check that the node is
+    /// healthy. The first failed check closes the gate, after which this
+    /// fails fast without querying the node again, until the gate is reset.
+    /// On failure, returns the [`router::Error::NodeUnhealthy`] wrapped in an
+    /// `std::io::Error`, because every client's error can be converted from
+    /// it.
+    async fn check_health(&self) -> Result<(), Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let gate = match self.health_gate() {
+            Some(gate) => gate,
+            None => return Ok(()),
+        };
+        let status = match gate.failure() {
+            Some(status) => status,
+            None => {
+                let status = self.health().await?;
+                if status.is_healthy() {
+                    return Ok(());
+                }
+                gate.close(status.clone());
+                status
+            }
+        };
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            router::Error::NodeUnhealthy(status),
+        )
+        .into())
+    }
+
+    /// Whether the `router!`'s generated methods that take a block height
+    /// should check it with [`Client::check_height`] before sending a
+    /// request. This is disabled by default, because every check costs an
+    /// additional request.
+    fn checks_height(&self) -> bool {
+        false
+    }
+
+    /// If the client [`Client::checks_height`], check that the `height` is
+    /// not above the [`Client::chain_height`], so that a query for a block
+    /// that the node doesn't have yet fails before it's sent. On failure,
+    /// returns the [`router::Error::HeightUnavailable`] wrapped in an
+    /// `std::io::Error`, because every client's error can be converted from
+    /// it. No height or the height `0` is the latest block and always passes.
+    async fn check_height(
+        &self,
+        height: Option<BlockHeight>,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        match height {
+            Some(requested)
+                if requested != BlockHeight(0) && self.checks_height() =>
+            {
+                let latest = self.chain_height().await?;
+                if requested > latest {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        router::Error::HeightUnavailable { requested, latest },
+                    )
+                    .into());
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The id to attach to the next request sent by the `router!`'s generated
+    /// methods, if the client propagates request ids for tracing. There's no
+    /// id by default.
+    fn next_request_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Send a query request at the given path with an optional `request_id`,
+    /// as given by the [`Client::next_request_id`]. A client that can deliver
+    /// the id to the node should set it in the [`RequestQuery`]. By default,
+    /// the id is dropped and the request is sent with the [`Client::request`],
+    /// because an ABCI query has no field to carry it.
+    async fn request_with_id(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: Option<BlockHeight>,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error> {
+        let _ = request_id;
+        self.request(path, data, height, prove).await
+    }
+
+    /// Send a query request at the given path at the block selected by the
+    /// `height`, with an optional `request_id`. A client that can deliver the
+    /// selector to the node should set it in the [`RequestQuery`]. By
+    /// default, a [`HeightSelector::Finalized`] is sent at the
+    /// [`Client::finalized_height`] and the other selectors at their height
+    /// with the [`Client::request_with_id`].
+    async fn request_at(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+        request_id: Option<String>,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        let height = match height {
+            HeightSelector::Latest => None,
+            HeightSelector::Finalized => Some(self.finalized_height().await?),
+            HeightSelector::Exact(height) => Some(height),
+        };
+        self.request_with_id(path, data, height, prove, request_id)
+            .await
+    }
+
+    /// Send a query request at an arbitrary path, e.g. for a route that
+    /// isn't modelled by the `router!`'s patterns, and return the response
+    /// without decoding its data. Like the generated methods, this checks the
+    /// node's health and the `height` and attaches the
+    /// [`Client::next_request_id`].
+    async fn raw_abci_query(
+        &self,
+        path: String,
+        data: Option<Vec<u8>>,
+        height: HeightSelector,
+        prove: bool,
+    ) -> Result<EncodedResponseQuery, Self::Error>
+    where
+        Self: Sized + Sync,
+    {
+        self.check_health().await?;
+        // A finalized block is never above the chain's height
+        if let HeightSelector::Exact(height) = height {
+            self.check_height(Some(height)).await?;
+        }
+        self.request_at(path, data, height, prove, self.next_request_id())
+            .await
+    }
+
     /// Send a query request at the given path.
     async fn request(
         &self,
@@ -94,6 +714,16 @@ pub trait Client {
         height: Option<BlockHeight>,
         prove: bool,
     ) -> Result<EncodedResponseQuery, Self::Error>;
+
+    /// Release the client's resources, e.g. the connections of a pooled or a
+    /// websocket client, once it's not needed anymore. A wrapper client
+    /// closes its inner client. This does nothing by default.
+    async fn close(self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
 }
 
 /// Temporary domain-type for `tendermint_proto::abci::RequestQuery`, copied
@@ -123,6 +753,89 @@ pub struct RequestQuery {
     pub height: BlockHeight,
     /// Whether to return a Merkle proof with the response, if possible.
     pub prove: bool,
+    /// The ETag of a response to this request that the client has cached, if
+    /// any. If the response's ETag matches, its data is not sent and the
+    /// response is marked as `not_modified`.
+    pub if_none_match: Option<Hash>,
+    /// An id of the request set by a client that propagates request ids, to
+    /// correlate the client's query with its handling in the logs. It's
+    /// recorded in the tracing span of the request's handling.
+    pub request_id: Option<String>,
+    /// Whether to return the Merkle proof, if any, in the compact format of
+    /// [`CompactProof`], e.g. for a bandwidth-constrained light client.
+    pub compact_proof: bool,
+    /// Whether the request is sent by a caller in the same process, which
+    /// takes the typed result of the handler from the response's `value`
+    /// instead of decoding its `data` (see [`Router::handle_ref`]). It's never
+    /// set for the requests received over ABCI.
+    pub in_process: bool,
+    /// The identity of the caller, if known (e.g. the client's address set
+    /// by the node's RPC frontend), which the routers' `handle_rate_limited`
+    /// passes to its rate-limiting hook. Only the node sets it, after
+    /// [`RequestQuery::try_from_tm`]: it's never sent in an ABCI request's
+    /// path, so that a client cannot pick its own identity.
+    pub caller: Option<String>,
+    /// An `If-Height` precondition: if set, the request is only handled if
+    /// the latest committed block height is at least this height, otherwise
+    /// it fails with `router::Error::PreconditionFailed`, so that a client
+    /// doesn't read a stale state from a lagging node.
+    pub if_height: Option<BlockHeight>,
+    /// Whether only the response's metadata is requested, like with an HTTP
+    /// `HEAD` request, e.g. for an existence check. The handler is invoked as
+    /// usual, but the response's `data` is left empty and its length is set
+    /// in the `content_length` instead.
+    pub head: bool,
+    /// The block at which the query should be executed, when it's selected
+    /// explicitly. The root router's `handle` resolves a
+    /// [`HeightSelector::Finalized`] or [`HeightSelector::Exact`] into the
+    /// `height`, while the [`HeightSelector::Latest`] keeps the `height`
+    /// as is.
+    pub height_selector: HeightSelector,
+    /// Whether the response's data should be prefixed with the node's schema
+    /// version byte (see [`Router::handle_with_schema_version`]), which is
+    /// requested by the `clients::VersionedClient` to check it.
+    pub versioned: bool,
+}
+
+/// A selector of the block at which a query is executed, which makes
+/// explicit whether the latest block means the newest committed block or the
+/// latest finalized block. A `None` block height is the [`Self::Latest`] and
+/// a `Some` block height is an [`Self::Exact`] height.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    BorshSerialize,
+    BorshDeserialize,
+)]
+pub enum HeightSelector {
+    /// The newest committed block
+    #[default]
+    Latest,
+    /// The latest finalized block, which may be behind the newest committed
+    /// block
+    Finalized,
+    /// The block at the given height
+    Exact(BlockHeight),
+}
+
+impl From<Option<BlockHeight>> for HeightSelector {
+    fn from(height: Option<BlockHeight>) -> Self {
+        match height {
+            Some(height) => Self::Exact(height),
+            None => Self::Latest,
+        }
+    }
+}
+
+impl From<BlockHeight> for HeightSelector {
+    fn from(height: BlockHeight) -> Self {
+        Self::Exact(height)
+    }
 }
 
 /// Generic response from a query
@@ -132,19 +845,1261 @@ pub struct ResponseQuery<T> {
     pub data: T,
     /// Non-deterministic log of the request execution
     pub info: String,
+    /// A keyed info message, set by handlers whose `info` should be localized
+    /// by the client. It's sent in the response's [`ResponseMetadata`]
+    /// alongside the `info`.
+    pub info_message: Option<InfoMessage>,
+    /// Output of the request execution, which maps onto the ABCI query
+    /// response's `log`
+    pub log: String,
+    /// The response's error code, which maps onto the ABCI query response's
+    /// `code`. It's `0` for a successful response. A handler may set another
+    /// code with [`ResponseQuery::with_code`], e.g. for an application-level
+    /// failure that still carries the response's `info` and `log`, which a
+    /// client receives as an error with the code.
+    pub code: u32,
+    /// Namespace of the response's error code, which maps onto the ABCI query
+    /// response's `codespace`
+    pub codespace: String,
     /// Optional proof - used for storage value reads which request `prove`
     pub proof: Option<Proof>,
+    /// The root of the merkle tree against which the `proof` should be
+    /// verified, set by the handlers of provable requests, so that a client
+    /// can check the proof's internal consistency before checking the root
+    /// against the app hash of the block header
+    pub root: Option<MerkleRoot>,
+    /// Proofs of multiple storage keys, set by handlers that prove several
+    /// keys at once. The root router's `handle` merges these into the `proof`
+    /// (see [`KeyedProof::merge`]) and the generated client methods split
+    /// them back, if any.
+    pub proofs: Vec<KeyedProof>,
+    /// The ETag of the response, which is a hash of its encoded data, set by
+    /// the root router's `handle`
+    pub etag: Option<Hash>,
+    /// Whether the response's ETag matches the request's `if_none_match`, in
+    /// which case the `data` and `proof` are left empty and the client should
+    /// use its cached response
+    pub not_modified: bool,
+    /// The typed result of the handler, which is set instead of the `data`
+    /// for the requests handled with [`Router::handle_ref`]
+    pub value: Option<TypedValue>,
+    /// A path to which the requested route has moved, set by a handler of a
+    /// deprecated route instead of the `data` (see
+    /// [`ResponseQuery::redirect`]). It's sent in the response's
+    /// [`ResponseMetadata`] alongside the `info`.
+    pub redirect: Option<String>,
+    /// A hint that the request failed transiently, e.g. because a resource
+    /// is busy, and that the client should retry it after the given duration,
+    /// set by a handler instead of the `data` (see
+    /// [`ResponseQuery::retry_after`]). It's sent in the response's
+    /// [`ResponseMetadata`] alongside the `info`.
+    pub retry_after: Option<Duration>,
+    /// The length of the encoded data of a response to a request for only
+    /// its metadata (see [`RequestQuery::head`]), whose `data` is left
+    /// empty, set by the root router's `handle`
+    pub content_length: Option<usize>,
+    /// The warnings of a successful response, e.g. that its data is from a
+    /// stale cache, which the client surfaces separately from the `data`.
+    /// The root router's `handle` encodes them into the `log` (see
+    /// [`Warning::encode_into_log`]) and the generated client methods decode
+    /// them back, if any.
+    pub warnings: Vec<Warning>,
+    /// The request's path from which the response was matched, as it was
+    /// received by the root router's `handle`, i.e. after it's been
+    /// rewritten by the caller (if at all), but before it's normalized. It's
+    /// set by the root router's `handle`, e.g. for an audit trail of the
+    /// queries.
+    pub matched_path: Option<String>,
+}
+
+/// The metadata of a response that the ABCI query response has no field
+/// for. A node sends it borsh-encoded in the ABCI query response's `key`,
+/// which is not used otherwise, because the queries' data are not keyed, and
+/// a client decodes it back into the response (see
+/// [`ResponseQuery::metadata`] and [`ResponseQuery::with_metadata`]).
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize,
+)]
+pub struct ResponseMetadata {
+    /// The response's [`ResponseQuery::info_message`]
+    pub info_message: Option<InfoMessage>,
+    /// The response's [`ResponseQuery::redirect`]
+    pub redirect: Option<String>,
+    /// The response's [`ResponseQuery::retry_after`] in milliseconds
+    pub retry_after_millis: Option<u64>,
+    /// The bytes of the response's [`ResponseQuery::root`]
+    pub root: Option<Vec<u8>>,
+    /// The response's [`ResponseQuery::etag`]
+    pub etag: Option<Hash>,
+    /// The response's [`ResponseQuery::not_modified`]
+    pub not_modified: bool,
+    /// The response's [`ResponseQuery::content_length`]
+    pub content_length: Option<u64>,
+    /// The response's [`ResponseQuery::matched_path`]
+    pub matched_path: Option<String>,
+}
+
+impl ResponseMetadata {
+    /// Encode the metadata with borsh. Metadata without any values is
+    /// encoded as no bytes, like the `key` of a response without metadata.
+    pub fn encode(&self) -> Vec<u8> {
+        if self == &Self::default() {
+            return vec![];
+        }
+        self.try_to_vec()
+            .expect("Encoding a response's metadata shouldn't fail")
+    }
+
+    /// Decode the metadata encoded with [`ResponseMetadata::encode`]. No
+    /// bytes are decoded into the metadata without any values.
+    pub fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        Self::try_from_slice(bytes)
+    }
+}
+
+/// A typed result of a handler, returned without encoding to a caller in the
+/// same process (see [`Router::handle_ref`]). The value can be taken only
+/// once, even from a clone of the response.
+#[derive(Clone)]
+pub struct TypedValue {
+    value: Arc<Mutex<Option<Box<dyn Any + Send>>>>,
+    encode: fn(&(dyn Any + Send)) -> std::io::Result<Vec<u8>>,
+}
+
+impl TypedValue {
+    /// Wrap the given typed value
+    pub fn new<T>(value: T) -> Self
+    where
+        T: Any + Send + BorshSerialize,
+    {
+        Self {
+            value: Arc::new(Mutex::new(Some(Box::new(value)))),
+            encode: |value| {
+                value
+                    .downcast_ref::<T>()
+                    .expect("The encoder must match the value's type")
+                    .try_to_vec()
+            },
+        }
+    }
+
+    /// Take the value, if it has the type `T` and it hasn't been taken yet.
+    /// A value of another type is left in place.
+    pub fn take<T: Any>(&self) -> Option<T> {
+        let mut value = self.value.lock().unwrap();
+        match value.take()?.downcast::<T>() {
+            Ok(typed) => Some(*typed),
+            Err(other) => {
+                *value = Some(other);
+                None
+            }
+        }
+    }
+
+    /// Encode the value with borsh, e.g. when the caller expects another type
+    /// with a compatible encoding. Fails if the value has been taken.
+    pub fn encode(&self) -> std::io::Result<Vec<u8>> {
+        match self.value.lock().unwrap().as_deref() {
+            Some(value) => (self.encode)(value),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "The typed value has already been taken",
+            )),
+        }
+    }
+}
+
+impl std::fmt::Debug for TypedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TypedValue").finish_non_exhaustive()
+    }
+}
+
+impl<T: Default> ResponseQuery<T> {
+    /// A response with the default, empty `data` and nothing else, e.g. for a
+    /// handler that only sets the `info` of its response.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// A response of a deprecated route that redirects the client to the
+    /// given `path` of its replacement, instead of any data. The
+    /// `clients::RedirectClient` follows it transparently.
+    pub fn redirect(path: impl Into<String>) -> Self {
+        Self {
+            redirect: Some(path.into()),
+            ..Self::default()
+        }
+    }
+
+    /// A response of a request that failed transiently, which the client
+    /// should retry after the given duration, instead of any data. The
+    /// `clients::RetryingClient` waits for it before retrying.
+    pub fn retry_after(retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::default()
+        }
+    }
+}
+
+impl<T> ResponseQuery<T> {
+    /// A response with the given `data` and nothing else, to which the
+    /// builder methods (e.g. [`ResponseQuery::with_proof`]) add the rest.
+    pub fn new(data: T) -> Self {
+        Self {
+            data,
+            info: String::default(),
+            info_message: None,
+            log: String::default(),
+            code: 0,
+            codespace: String::default(),
+            proof: None,
+            root: None,
+            proofs: Vec::default(),
+            etag: None,
+            not_modified: false,
+            value: None,
+            redirect: None,
+            retry_after: None,
+            content_length: None,
+            warnings: Vec::default(),
+            matched_path: None,
+        }
+    }
+
+    /// Set the response's `info`.
+    pub fn with_info(mut self, info: impl Into<String>) -> Self {
+        self.info = info.into();
+        self
+    }
+
+    /// Set the response's `proof`, if any.
+    pub fn with_proof(mut self, proof: impl Into<Option<Proof>>) -> Self {
+        self.proof = proof.into();
+        self
+    }
+
+    /// Set the merkle root against which the response's `proof` should be
+    /// verified, if any.
+    pub fn with_root(mut self, root: impl Into<Option<MerkleRoot>>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Set the response's `log`.
+    pub fn with_log(mut self, log: impl Into<String>) -> Self {
+        self.log = log.into();
+        self
+    }
+
+    /// Set the response's error `code`, which is `0` for a successful
+    /// response.
+    pub fn with_code(mut self, code: u32) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Set the namespace of the response's error code, i.e. its
+    /// `codespace`.
+    pub fn with_codespace(mut self, codespace: impl Into<String>) -> Self {
+        self.codespace = codespace.into();
+        self
+    }
+
+    /// Add a warning to the response's `warnings`.
+    pub fn with_warning(mut self, warning: Warning) -> Self {
+        self.warnings.push(warning);
+        self
+    }
+
+    /// Transform the response's `data` with the given function, preserving
+    /// the rest of the response.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> ResponseQuery<U> {
+        let ResponseQuery {
+            data,
+            info,
+            info_message,
+            log,
+            code,
+            codespace,
+            proof,
+            root,
+            proofs,
+            etag,
+            not_modified,
+            value,
+            redirect,
+            retry_after,
+            content_length,
+            warnings,
+            matched_path,
+        } = self;
+        ResponseQuery {
+            data: f(data),
+            info,
+            info_message,
+            log,
+            code,
+            codespace,
+            proof,
+            root,
+            proofs,
+            etag,
+            not_modified,
+            value,
+            redirect,
+            retry_after,
+            content_length,
+            warnings,
+            matched_path,
+        }
+    }
+
+    /// Get the path to which this response redirects, if any.
+    pub fn redirect_path(&self) -> Option<&str> {
+        self.redirect.as_deref()
+    }
+
+    /// Get the duration after which the request of this response should be
+    /// retried, if any.
+    pub fn retry_after_hint(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /// Get the response's metadata that the ABCI query response has no field
+    /// for, to be sent in its `key`.
+    pub fn metadata(&self) -> ResponseMetadata {
+        ResponseMetadata {
+            info_message: self.info_message.clone(),
+            redirect: self.redirect.clone(),
+            retry_after_millis: self.retry_after.map(|retry_after| {
+                u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX)
+            }),
+            root: self.root.as_ref().map(|root| root.0.clone()),
+            etag: self.etag.clone(),
+            not_modified: self.not_modified,
+            content_length: self.content_length.map(|length| length as u64),
+            matched_path: self.matched_path.clone(),
+        }
+    }
+
+    /// Set the response's metadata, e.g. as decoded by a client from the
+    /// ABCI query response's `key`.
+    pub fn with_metadata(mut self, metadata: ResponseMetadata) -> Self {
+        let ResponseMetadata {
+            info_message,
+            redirect,
+            retry_after_millis,
+            root,
+            etag,
+            not_modified,
+            content_length,
+            matched_path,
+        } = metadata;
+        self.info_message = info_message;
+        self.redirect = redirect;
+        self.retry_after = retry_after_millis.map(Duration::from_millis);
+        self.root = root.map(MerkleRoot);
+        self.etag = etag;
+        self.not_modified = not_modified;
+        self.content_length = content_length
+            .map(|length| usize::try_from(length).unwrap_or(usize::MAX));
+        self.matched_path = matched_path;
+        self
+    }
+
+    /// Take the response's `data`, discarding the rest of the response.
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    /// Get the response's `info` localized with the `resolver`. If the
+    /// response has an `info_message` that the `resolver` doesn't know, it's
+    /// displayed with its key and args. Without an `info_message`, the plain
+    /// `info` is returned.
+    pub fn resolve_info(&self, resolver: &impl InfoResolver) -> String {
+        match &self.info_message {
+            Some(message) => resolver
+                .resolve(message)
+                .unwrap_or_else(|| message.to_string()),
+            None => self.info.clone(),
+        }
+    }
 }
 
 /// [`ResponseQuery`] with borsh-encoded `data` field
 pub type EncodedResponseQuery = ResponseQuery<Vec<u8>>;
 
+/// A proof of a single storage key, one of the proofs of a response that
+/// proves multiple keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyedProof {
+    /// The proven storage key
+    pub key: storage::Key,
+    /// The key's existence or non-existence proof
+    pub proof: Proof,
+}
+
+impl KeyedProof {
+    /// The `field_type` of the op that precedes each key's ops in a merged
+    /// proof
+    pub const MARKER_FIELD_TYPE: &'static str = "namada_keyed_proof";
+
+    /// Merge the proofs of multiple keys into a single proof, so that they can
+    /// be sent in one response. Each key's ops are preceded by a marker op
+    /// with the key and the number of its ops.
+    pub fn merge(proofs: Vec<KeyedProof>) -> Proof {
+        let mut ops = vec![];
+        for KeyedProof { key, mut proof } in proofs {
+            ops.push(ProofOp {
+                field_type: Self::MARKER_FIELD_TYPE.to_owned(),
+                key: key.to_string().into_bytes(),
+                data: (proof.ops.len() as u64).to_le_bytes().to_vec(),
+            });
+            ops.append(&mut proof.ops);
+        }
+        Proof { ops }
+    }
+
+    /// Split a proof merged with [`KeyedProof::merge`] back into the proofs
+    /// of each key. Returns `None` if the proof is not a merged proof.
+    pub fn split(proof: &Proof) -> Option<Vec<KeyedProof>> {
+        let mut proofs = vec![];
+        let mut ops = proof.ops.iter();
+        while let Some(marker) = ops.next() {
+            if marker.field_type != Self::MARKER_FIELD_TYPE {
+                return None;
+            }
+            let key = std::str::from_utf8(&marker.key).ok()?;
+            let key = storage::Key::parse(key).ok()?;
+            let len =
+                u64::from_le_bytes(marker.data.as_slice().try_into().ok()?);
+            let key_ops: Vec<ProofOp> =
+                ops.by_ref().take(len.try_into().ok()?).cloned().collect();
+            if key_ops.len() as u64 != len {
+                return None;
+            }
+            proofs.push(KeyedProof {
+                key,
+                proof: Proof { ops: key_ops },
+            });
+        }
+        (!proofs.is_empty()).then_some(proofs)
+    }
+}
+
+/// A compact representation of a [`Proof`], in which every distinct op is
+/// sent only once (e.g. the inner nodes shared by the proofs of multiple
+/// keys). The first op is a marker whose `data` has the little-endian `u32`
+/// index of every op of the original proof into the distinct ops that follow
+/// the marker. It's returned for a request with the `compact_proof` flag and
+/// it must be expanded with [`CompactProof::expand`] before it's verified.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactProof;
+
+impl CompactProof {
+    /// The `field_type` of the marker op of a compact proof
+    pub const MARKER_FIELD_TYPE: &'static str = "namada_compact_proof";
+
+    /// Compress the proof into the compact format, deduplicating its ops.
+    pub fn compress(proof: &Proof) -> Proof {
+        let mut distinct: Vec<&ProofOp> = vec![];
+        let mut indices = Vec::with_capacity(proof.ops.len() * 4);
+        for op in &proof.ops {
+            let index = match distinct.iter().position(|known| *known == op) {
+                Some(index) => index,
+                None => {
+                    distinct.push(op);
+                    distinct.len() - 1
+                }
+            };
+            indices.extend_from_slice(&(index as u32).to_le_bytes());
+        }
+        let marker = ProofOp {
+            field_type: Self::MARKER_FIELD_TYPE.to_owned(),
+            key: vec![],
+            data: indices,
+        };
+        Proof {
+            ops: std::iter::once(marker)
+                .chain(distinct.into_iter().cloned())
+                .collect(),
+        }
+    }
+
+    /// Expand a proof compressed with [`CompactProof::compress`] back into
+    /// the original proof. Returns `None` if the proof is not a compact proof.
+    pub fn expand(proof: &Proof) -> Option<Proof> {
+        let (marker, distinct) = proof.ops.split_first()?;
+        if marker.field_type != Self::MARKER_FIELD_TYPE
+            || marker.data.len() % 4 != 0
+        {
+            return None;
+        }
+        let ops = marker
+            .data
+            .chunks_exact(4)
+            .map(|index| {
+                let index = u32::from_le_bytes(index.try_into().ok()?);
+                distinct.get(usize::try_from(index).ok()?).cloned()
+            })
+            .collect::<Option<Vec<ProofOp>>>()?;
+        Some(Proof { ops })
+    }
+}
+
+/// The format of a response's proof, selected by the trailing segment of the
+/// request's path, e.g. `/value/key/_proof=compact` (see
+/// [`router::split_proof_format`]), for the light clients on different
+/// verification backends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProofFormat {
+    /// The ICS23 proof ops, as generated by the storage
+    #[default]
+    Ics23,
+    /// The ICS23 proof ops in the compact format (see [`CompactProof`])
+    Compact,
+}
+
+/// An error of parsing a [`ProofFormat`] from a path segment.
+#[derive(Error, Debug)]
+#[error("Unknown proof format {0}, expected `ics23` or `compact`")]
+pub struct ProofFormatParseError(String);
+
+impl ProofFormat {
+    /// Encode the `proof` in this format.
+    pub fn encode(self, proof: Proof) -> Proof {
+        match self {
+            Self::Ics23 => proof,
+            Self::Compact => CompactProof::compress(&proof),
+        }
+    }
+}
+
+impl FromStr for ProofFormat {
+    type Err = ProofFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ics23" => Ok(Self::Ics23),
+            "compact" => Ok(Self::Compact),
+            _ => Err(ProofFormatParseError(s.to_owned())),
+        }
+    }
+}
+
+impl Display for ProofFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ics23 => write!(f, "ics23"),
+            Self::Compact => write!(f, "compact"),
+        }
+    }
+}
+
+/// A keyed `info` message of a response, which a client resolves into a
+/// localized string with an [`InfoResolver`], so that the node doesn't need to
+/// know about the client's locale.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct InfoMessage {
+    /// The key of the message, e.g. `"bond.unbonded"`
+    pub key: String,
+    /// The args to be substituted into the message's localized string
+    pub args: Vec<String>,
+}
+
+impl InfoMessage {
+    /// Create a message with the given key and args.
+    pub fn new(key: impl Into<String>, args: Vec<String>) -> Self {
+        Self {
+            key: key.into(),
+            args,
+        }
+    }
+}
+
+impl Display for InfoMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.key)?;
+        if !self.args.is_empty() {
+            write!(f, ": {}", self.args.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A warning of a successful response, e.g. that its data is from a stale
+/// cache, which doesn't turn the response into an error.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Warning {
+    /// The code of the warning for the client to match on, e.g.
+    /// `"stale_cache"`
+    pub code: String,
+    /// The description of the warning
+    pub message: String,
+}
+
+impl Warning {
+    /// The prefix of a line of a `log` with the encoded warnings
+    pub const LOG_PREFIX: &'static str = "namada_warnings:";
+
+    /// Create a warning with the given code and message.
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Encode the `warnings` into a line appended to the `log`, which is the
+    /// prefix followed by the warnings' borsh encoding in unpadded base64url.
+    pub fn encode_into_log(warnings: &[Warning], log: &mut String) {
+        let bytes = warnings
+            .try_to_vec()
+            .expect("Encoding warnings shouldn't fail");
+        if !log.is_empty() {
+            log.push('\n');
+        }
+        log.push_str(Self::LOG_PREFIX);
+        log.push_str(&router::encode_b64_segment(&bytes));
+    }
+
+    /// Decode the warnings from the last line of a `log` encoded with
+    /// [`Warning::encode_into_log`]. Returns the rest of the log together with
+    /// the warnings, or `None` if the log has no encoded warnings.
+    pub fn decode_from_log(log: &str) -> Option<(String, Vec<Warning>)> {
+        let (rest, line) = log.rsplit_once('\n').unwrap_or(("", log));
+        let encoded = line.strip_prefix(Self::LOG_PREFIX)?;
+        let bytes = router::decode_b64_segment(encoded)?;
+        let warnings = Vec::<Warning>::try_from_slice(&bytes).ok()?;
+        Some((rest.to_owned(), warnings))
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+/// A client-side resolver of the responses' [`InfoMessage`]s into localized
+/// strings.
+pub trait InfoResolver {
+    /// Resolve the message into a localized string, if its key is known.
+    fn resolve(&self, message: &InfoMessage) -> Option<String>;
+}
+
+/// An [`InfoResolver`] with the strings of a single locale, keyed by the
+/// messages' keys. Every `{0}`, `{1}`, etc. placeholder in a string is replaced
+/// with the message's arg at that index.
+#[derive(Clone, Debug, Default)]
+pub struct InfoCatalog {
+    strings: HashMap<String, String>,
+}
+
+impl InfoCatalog {
+    /// Create an empty catalog.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the localized `string` of the messages with the `key`, replacing
+    /// the previous one, if any.
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        string: impl Into<String>,
+    ) {
+        self.strings.insert(key.into(), string.into());
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for InfoCatalog
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self {
+            strings: iter
+                .into_iter()
+                .map(|(key, string)| (key.into(), string.into()))
+                .collect(),
+        }
+    }
+}
+
+impl InfoResolver for InfoCatalog {
+    fn resolve(&self, message: &InfoMessage) -> Option<String> {
+        let mut rest = self.strings.get(&message.key)?.as_str();
+        let mut resolved = String::with_capacity(rest.len());
+        // The placeholders are replaced in a single pass, so that an arg
+        // that looks like a placeholder isn't replaced again
+        while let Some(start) = rest.find('{') {
+            resolved.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let arg = rest.find('}').and_then(|end| {
+                let index: usize = rest[1..end].parse().ok()?;
+                Some((message.args.get(index)?, end))
+            });
+            match arg {
+                Some((arg, end)) => {
+                    resolved.push_str(arg);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    resolved.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        resolved.push_str(rest);
+        Some(resolved)
+    }
+}
+
+/// The values found under a storage prefix, ordered lexicographically by the
+/// string representation of their keys. This is the same order in which
+/// [`PrefixPage`]s are iterated, so clients can diff or verify the results of
+/// different scans item by item.
+///
+/// The borsh encoding is the same as that of a `Vec<PrefixValue>`, but
+/// decoding fails if the values are not in order.
+#[derive(Clone, Debug, Default, BorshSerialize, BorshSchema)]
+pub struct OrderedPrefixValues(Vec<PrefixValue>);
+
+impl OrderedPrefixValues {
+    /// Order the given values by their keys.
+    pub fn new(mut values: Vec<PrefixValue>) -> Self {
+        values.sort_by_cached_key(|PrefixValue { key, .. }| key.to_string());
+        Self(values)
+    }
+
+    /// Check that the values are ordered by their keys.
+    pub fn is_ordered(values: &[PrefixValue]) -> bool {
+        values.windows(2).all(|pair| {
+            pair[0].key.to_string().as_str() <= pair[1].key.to_string().as_str()
+        })
+    }
+
+    /// The number of values.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns `true` if there are no values.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate the values in order.
+    pub fn iter(&self) -> std::slice::Iter<'_, PrefixValue> {
+        self.0.iter()
+    }
+
+    /// Get the ordered values.
+    pub fn into_inner(self) -> Vec<PrefixValue> {
+        self.0
+    }
+}
+
+impl BorshDeserialize for OrderedPrefixValues {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let values: Vec<PrefixValue> = BorshDeserialize::deserialize(buf)?;
+        if !Self::is_ordered(&values) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The prefix values are not ordered by their keys",
+            ));
+        }
+        Ok(Self(values))
+    }
+}
+
+impl From<OrderedPrefixValues> for Vec<PrefixValue> {
+    fn from(values: OrderedPrefixValues) -> Self {
+        values.0
+    }
+}
+
+impl IntoIterator for OrderedPrefixValues {
+    type IntoIter = std::vec::IntoIter<PrefixValue>;
+    type Item = PrefixValue;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// An iterator of the `(storage::Key, V)` pairs of the values found under a
+/// storage prefix (e.g. by the shell's `storage_prefix`), which borsh-decodes
+/// each value only when it's reached. A value that cannot be decoded is
+/// yielded as an error with its key, without ending the iteration.
+#[derive(Debug)]
+pub struct PrefixIter<V> {
+    values: std::vec::IntoIter<PrefixValue>,
+    value_type: PhantomData<fn() -> V>,
+}
+
+impl<V> PrefixIter<V> {
+    /// Iterate the given values in order.
+    pub fn new(values: OrderedPrefixValues) -> Self {
+        Self {
+            values: values.into_iter(),
+            value_type: PhantomData,
+        }
+    }
+}
+
+impl<V> From<OrderedPrefixValues> for PrefixIter<V> {
+    fn from(values: OrderedPrefixValues) -> Self {
+        Self::new(values)
+    }
+}
+
+impl<V: BorshDeserialize> Iterator for PrefixIter<V> {
+    type Item = std::io::Result<(storage::Key, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let PrefixValue { key, value } = self.values.next()?;
+        match V::try_from_slice(&value) {
+            Ok(value) => Some(Ok((key, value))),
+            Err(err) => Some(Err(std::io::Error::new(
+                err.kind(),
+                format!("Failed to decode the value of key {key}: {err}"),
+            ))),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.values.size_hint()
+    }
+}
+
+impl<V: BorshDeserialize> ExactSizeIterator for PrefixIter<V> {}
+
+/// The changes of the values under a storage prefix between two block
+/// heights, for clients that track the state incrementally instead of
+/// querying full snapshots at each height. Each of the lists is ordered like
+/// [`OrderedPrefixValues`].
+#[derive(
+    Clone, Debug, Default, BorshSerialize, BorshDeserialize, BorshSchema,
+)]
+pub struct StateDiff {
+    /// The keys that are only present at the later height, with their values
+    pub added: Vec<PrefixValue>,
+    /// The keys that are only present at the earlier height
+    pub removed: Vec<storage::Key>,
+    /// The keys whose values changed, with their values at the later height
+    pub modified: Vec<PrefixValue>,
+}
+
+impl StateDiff {
+    /// Diff the values under a prefix at an earlier height with the values
+    /// under the same prefix at a later height.
+    pub fn between(
+        earlier: OrderedPrefixValues,
+        later: OrderedPrefixValues,
+    ) -> Self {
+        let mut diff = Self::default();
+        let mut earlier = earlier.into_iter().peekable();
+        let mut later = later.into_iter().peekable();
+        loop {
+            // Both of the values are ordered by their keys, so they're merged
+            // in a single pass
+            let ordering = match (earlier.peek(), later.peek()) {
+                (Some(old), Some(new)) => {
+                    old.key.to_string().cmp(&new.key.to_string())
+                }
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+            match ordering {
+                Ordering::Less => {
+                    diff.removed.extend(earlier.next().map(|old| old.key))
+                }
+                Ordering::Greater => diff.added.extend(later.next()),
+                Ordering::Equal => {
+                    if let (Some(old), Some(new)) =
+                        (earlier.next(), later.next())
+                    {
+                        if old.value != new.value {
+                            diff.modified.push(new);
+                        }
+                    }
+                }
+            }
+        }
+        diff
+    }
+
+    /// Check if no values changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+    }
+
+    /// Get the keys of all the changes, ordered like [`OrderedPrefixValues`].
+    pub fn changed_keys(&self) -> Vec<&storage::Key> {
+        let mut keys: Vec<&storage::Key> = self
+            .added
+            .iter()
+            .map(|PrefixValue { key, .. }| key)
+            .chain(&self.removed)
+            .chain(self.modified.iter().map(|PrefixValue { key, .. }| key))
+            .collect();
+        keys.sort_by_cached_key(|key| key.to_string());
+        keys
+    }
+}
+
+/// A page of the items found under a storage prefix. When there are more
+/// items than fit in a page, the `continuation` token can be sent in the
+/// request `data` of the next query to continue after the last item of this
+/// page.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema)]
+pub struct PrefixPage<T> {
+    /// Items of this page in the storage order
+    pub items: Vec<T>,
+    /// Token for the next page, if there are any more items
+    pub continuation: Option<ContinuationToken>,
+}
+
+/// An opaque token used to continue a storage prefix iteration after the last
+/// key seen in a previous [`PrefixPage`].
+#[derive(
+    Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize, BorshSchema,
+)]
+pub struct ContinuationToken(Vec<u8>);
+
+impl ContinuationToken {
+    /// Create a token to continue after the given key.
+    pub fn after_key(key: &storage::Key) -> Self {
+        Self(key.to_string().into_bytes())
+    }
+
+    /// Decode a token from the bytes, as sent in the request `data`.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the bytes of the token to be sent in the request `data`.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// Get the last key seen from the token. Returns `None` if the token
+    /// wasn't created with [`ContinuationToken::after_key`].
+    pub fn last_key(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+/// A chunk of a response that's too large to be sent at once, as returned by
+/// a route with a handler that returns chunks (`stream`). The chunks are
+/// requested one by one with the index of the chunk in the request `data` and
+/// together they make up the borsh-encoded response.
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    BorshSchema,
+)]
+pub struct ResponseChunk {
+    /// Bytes of this chunk
+    pub data: Vec<u8>,
+    /// Index of the next chunk, if there are any more chunks
+    pub next: Option<u32>,
+    /// Height of the block at which the chunks are produced, at which the
+    /// following chunks must be requested
+    pub height: BlockHeight,
+}
+
+/// Basic metadata of a node
+#[derive(
+    Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize, BorshSchema,
+)]
+pub struct NodeInfo {
+    /// ID of the chain that the node is running
+    pub chain_id: ChainId,
+    /// Height of the last committed block
+    pub latest_height: BlockHeight,
+}
+
+/// Health of a node, as reported by [`Client::health`]
+#[derive(
+    Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize, BorshSchema,
+)]
+pub struct HealthStatus {
+    /// Height of the last committed block
+    pub latest_height: BlockHeight,
+    /// Whether the node is still catching up with the chain
+    pub catching_up: bool,
+}
+
+impl HealthStatus {
+    /// A node is healthy when it's synced with the chain
+    pub fn is_healthy(&self) -> bool {
+        !self.catching_up
+    }
+}
+
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "latest block height {}", self.latest_height)?;
+        if self.catching_up {
+            write!(f, ", catching up")?;
+        }
+        Ok(())
+    }
+}
+
+/// A readiness gate of a [`Client`], which remembers the first failed health
+/// check (see [`Client::check_health`]). While it's closed, the queries fail
+/// fast with [`router::Error::NodeUnhealthy`].
+#[cfg(any(test, feature = "async-client"))]
+#[derive(Debug, Default)]
+pub struct HealthGate {
+    failure: std::sync::Mutex<Option<HealthStatus>>,
+}
+
+#[cfg(any(test, feature = "async-client"))]
+impl HealthGate {
+    /// Create an open gate
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The status of the failed health check that closed the gate, if any
+    pub fn failure(&self) -> Option<HealthStatus> {
+        self.failure
+            .lock()
+            .expect("The health gate lock shouldn't be poisoned")
+            .clone()
+    }
+
+    /// Close the gate with the status of a failed health check
+    fn close(&self, status: HealthStatus) {
+        *self
+            .failure
+            .lock()
+            .expect("The health gate lock shouldn't be poisoned") =
+            Some(status);
+    }
+
+    /// Re-open the gate, so that the node's health is checked again
+    pub fn reset(&self) {
+        *self
+            .failure
+            .lock()
+            .expect("The health gate lock shouldn't be poisoned") = None;
+    }
+}
+
+/// The chunks of a response from a route with a handler that returns chunks
+/// (`stream`), which are requested one by one as they're consumed. It's
+/// created by the `router!`'s generated `*_stream` and `*_iter` methods.
+#[cfg(any(test, feature = "async-client"))]
+pub struct ResponseStream<'client, CLIENT> {
+    client: &'client CLIENT,
+    path: String,
+    /// Index of the next chunk to request, if there are any more chunks
+    next: Option<u32>,
+    /// Height of the block at which the chunks are produced, which is only
+    /// known after the first chunk is received
+    height: Option<BlockHeight>,
+    /// Whether the node's health has been checked with the
+    /// [`Client::check_health`] before the first chunk is requested
+    health_checked: bool,
+}
+
+/// A type that's borsh-encoded as a sequence of `ITEM`s, i.e. the number of
+/// the items followed by the items, like a `Vec<ITEM>`, whose items can be
+/// decoded one by one with [`ResponseStream::items`].
+pub trait BorshSeq<ITEM> {}
+
+impl<ITEM> BorshSeq<ITEM> for Vec<ITEM> {}
+
+impl BorshSeq<PrefixValue> for OrderedPrefixValues {}
+
+#[cfg(any(test, feature = "async-client"))]
+impl<'client, CLIENT> ResponseStream<'client, CLIENT>
+where
+    CLIENT: Client + Sync,
+{
+    /// Create a stream of the chunks of a response at the given path, which
+    /// starts with the first chunk
+    pub fn new(client: &'client CLIENT, path: String) -> Self {
+        Self {
+            client,
+            path,
+            next: Some(0),
+            height: None,
+            health_checked: true,
+        }
+    }
+
+    /// Create a stream like [`ResponseStream::new`], which checks the node's
+    /// health with the [`Client::check_health`] before the first chunk is
+    /// requested.
+    pub fn with_health_check(client: &'client CLIENT, path: String) -> Self {
+        Self {
+            health_checked: false,
+            ..Self::new(client, path)
+        }
+    }
+
+    /// Request the next chunk. Returns `None` once all the chunks have been
+    /// received.
+    pub async fn next_chunk(
+        &mut self,
+    ) -> Result<Option<Vec<u8>>, CLIENT::Error> {
+        let index = match self.next {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        if !self.health_checked {
+            self.client.check_health().await?;
+            self.health_checked = true;
+        }
+        let data = index.try_to_vec()?;
+        let response = self
+            .client
+            .request_with_id(
+                self.path.clone(),
+                Some(data),
+                self.height,
+                false,
+                self.client.next_request_id(),
+            )
+            .await?;
+        let chunk = ResponseChunk::try_from_slice(&response.data[..])?;
+        self.next = chunk.next;
+        self.height = Some(chunk.height);
+        Ok(Some(chunk.data))
+    }
+
+    /// Request all the remaining chunks and reassemble them into the
+    /// borsh-encoded response.
+    pub async fn reassemble(mut self) -> Result<Vec<u8>, CLIENT::Error> {
+        let mut data = vec![];
+        while let Some(mut chunk) = self.next_chunk().await? {
+            data.append(&mut chunk);
+        }
+        Ok(data)
+    }
+
+    /// Decode the items of a response that's a borsh-encoded sequence (see
+    /// [`BorshSeq`]) one by one, requesting the chunks only as they're needed
+    /// to decode the next item, so that the whole response is never buffered.
+    /// The stream ends after the first error.
+    pub fn items<ITEM>(
+        self,
+    ) -> impl futures::Stream<Item = Result<ITEM, CLIENT::Error>> + 'client
+    where
+        ITEM: BorshDeserialize + 'client,
+    {
+        let state = SeqDecoder {
+            stream: self,
+            buffer: vec![],
+            remaining: None,
+            done: false,
+        };
+        futures::stream::unfold(state, |mut state| async move {
+            let item = state.next_item().await.transpose()?;
+            state.done = item.is_err();
+            Some((item, state))
+        })
+    }
+}
+
+/// The state of the decoding of the items of a [`ResponseStream`].
+#[cfg(any(test, feature = "async-client"))]
+struct SeqDecoder<'client, CLIENT> {
+    stream: ResponseStream<'client, CLIENT>,
+    /// The received bytes that haven't been decoded yet
+    buffer: Vec<u8>,
+    /// The number of items that are yet to be decoded, which is only known
+    /// once the sequence's length is received
+    remaining: Option<u32>,
+    /// Whether the stream has ended with an error
+    done: bool,
+}
+
+#[cfg(any(test, feature = "async-client"))]
+impl<'client, CLIENT> SeqDecoder<'client, CLIENT>
+where
+    CLIENT: Client + Sync,
+{
+    /// Decode the next item, if any, requesting more chunks while it's
+    /// incomplete.
+    async fn next_item<ITEM>(&mut self) -> Result<Option<ITEM>, CLIENT::Error>
+    where
+        ITEM: BorshDeserialize,
+    {
+        if self.done {
+            return Ok(None);
+        }
+        loop {
+            let mut rest = &self.buffer[..];
+            match self.remaining {
+                Some(0) => return Ok(None),
+                Some(remaining) => match ITEM::deserialize(&mut rest) {
+                    Ok(item) => {
+                        let decoded = self.buffer.len() - rest.len();
+                        self.buffer.drain(..decoded);
+                        self.remaining = Some(remaining - 1);
+                        return Ok(Some(item));
+                    }
+                    // The item may be incomplete
+                    Err(_) if self.stream.next.is_some() => {}
+                    Err(err) => return Err(err.into()),
+                },
+                None => {
+                    if let Ok(len) = u32::deserialize(&mut rest) {
+                        self.buffer.drain(..std::mem::size_of::<u32>());
+                        self.remaining = Some(len);
+                        continue;
+                    }
+                }
+            }
+            match self.stream.next_chunk().await? {
+                Some(mut chunk) => self.buffer.append(&mut chunk),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "The response ended before all its items",
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+}
+
 impl RequestQuery {
+    /// The prefix of the names of the query params of an ABCI request's path
+    /// that carry the request's options, which the ABCI query request has no
+    /// field for (see [`RequestQuery::abci_path`])
+    pub const OPTION_PARAM_PREFIX: &'static str = "_";
+
     /// Try to convert tendermint RequestQuery into our [`RequestQuery`]
     /// domain type. This tries to convert the block height into our
     /// [`BlockHeight`] type, where `0` is treated as a special value to signal
     /// to use the latest committed block height as per tendermint ABCI Query
-    /// spec. A negative block height will cause an error.
+    /// spec. A negative block height will cause an error. The request's
+    /// options are parsed from the reserved query params of the `path` (see
+    /// [`RequestQuery::abci_path`]), which are then stripped from it.
     pub fn try_from_tm<D, H>(
         storage: &Storage<D, H>,
         crate::tendermint_proto::abci::RequestQuery {
@@ -167,11 +2122,142 @@ impl RequestQuery {
                 format!("Query height cannot be negative, got: {}", height)
             })?),
         };
-        Ok(Self {
+        let mut request = Self {
             data,
-            path,
+            path: String::new(),
             height,
             prove,
-        })
+            if_none_match: None,
+            request_id: None,
+            compact_proof: false,
+            in_process: false,
+            caller: None,
+            if_height: None,
+            head: false,
+            height_selector: HeightSelector::Latest,
+            versioned: false,
+        };
+        request.path = request.parse_option_params(path)?;
+        Ok(request)
+    }
+
+    /// Get the request's `path` with its options, if any, appended as the
+    /// reserved query params, to be sent in an ABCI query request, from which
+    /// [`RequestQuery::try_from_tm`] parses them back. The string values are
+    /// encoded in unpadded base64url.
+    pub fn abci_path(&self) -> String {
+        let mut params: Vec<(&str, String)> = vec![];
+        if let Some(etag) = &self.if_none_match {
+            params.push(("if_none_match", router::encode_b64_segment(&etag.0)));
+        }
+        if let Some(request_id) = &self.request_id {
+            params.push((
+                "request_id",
+                router::encode_b64_segment(request_id.as_bytes()),
+            ));
+        }
+        if self.compact_proof {
+            params.push(("compact_proof", "1".to_owned()));
+        }
+        if let Some(if_height) = self.if_height {
+            params.push(("if_height", if_height.to_string()));
+        }
+        if self.head {
+            params.push(("head", "1".to_owned()));
+        }
+        // An exact height is sent in the ABCI request's `height`
+        if self.height_selector == HeightSelector::Finalized {
+            params.push(("height", "finalized".to_owned()));
+        }
+        if self.versioned {
+            params.push(("versioned", "1".to_owned()));
+        }
+        let mut path = self.path.clone();
+        for (name, value) in params {
+            let separator = if path.contains('?') { '&' } else { '?' };
+            path.push(separator);
+            path.push_str(Self::OPTION_PARAM_PREFIX);
+            path.push_str(name);
+            path.push('=');
+            path.push_str(&value);
+        }
+        path
+    }
+
+    /// Set the request's options from the reserved query params of the
+    /// `path`, if any, and return the `path` without them. An unknown or
+    /// invalid option is an error, as is the request's `caller`, which can
+    /// only be set by the node.
+    pub(crate) fn parse_option_params(
+        &mut self,
+        path: String,
+    ) -> Result<String, String> {
+        let (rest, query) = router::split_query(&path);
+        let query = match query {
+            Some(query)
+                if query.split('&').any(|param| {
+                    param.starts_with(Self::OPTION_PARAM_PREFIX)
+                }) =>
+            {
+                query
+            }
+            _ => return Ok(path),
+        };
+        let mut kept = vec![];
+        for param in query.split('&') {
+            let name = match param.strip_prefix(Self::OPTION_PARAM_PREFIX) {
+                Some(name) => name,
+                None => {
+                    kept.push(param);
+                    continue;
+                }
+            };
+            let (name, value) = name.split_once('=').ok_or_else(|| {
+                format!("Request option without a value: {param}")
+            })?;
+            let invalid = || format!("Invalid request option: {param}");
+            let decode_string = |value: &str| {
+                router::decode_b64_segment(value)
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .ok_or_else(invalid)
+            };
+            let flag = |value: &str| match value {
+                "1" => Ok(true),
+                _ => Err(invalid()),
+            };
+            match name {
+                "if_none_match" => {
+                    let bytes = router::decode_b64_segment(value)
+                        .ok_or_else(invalid)?;
+                    let etag =
+                        bytes.as_slice().try_into().map_err(|_| invalid())?;
+                    self.if_none_match = Some(Hash(etag));
+                }
+                "request_id" => self.request_id = Some(decode_string(value)?),
+                "compact_proof" => self.compact_proof = flag(value)?,
+                "caller" => {
+                    return Err(format!(
+                        "The caller of a request cannot be set by a client: \
+                         {param}"
+                    ));
+                }
+                "if_height" => {
+                    self.if_height = Some(
+                        BlockHeight::from_str(value).map_err(|_| invalid())?,
+                    )
+                }
+                "head" => self.head = flag(value)?,
+                "height" if value == "finalized" => {
+                    self.height_selector = HeightSelector::Finalized
+                }
+                "versioned" => self.versioned = flag(value)?,
+                _ => return Err(format!("Unknown request option: {param}")),
+            }
+        }
+        if kept.is_empty() {
+            Ok(rest.to_owned())
+        } else {
+            Ok(format!("{rest}?{}", kept.join("&")))
+        }
     }
 }
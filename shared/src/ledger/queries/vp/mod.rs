@@ -1,6 +1,6 @@
 // Re-export to show in rustdoc!
-pub use pos::Pos;
 use pos::POS;
+pub use pos::{Pos, PosRoute};
 mod pos;
 
 // Validity predicate queries
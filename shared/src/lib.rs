@@ -5,6 +5,8 @@
 #![warn(missing_docs)]
 #![deny(rustdoc::broken_intra_doc_links)]
 #![deny(rustdoc::private_intra_doc_links)]
+// The `router!` macro recurses for each of a router's routes
+#![recursion_limit = "256"]
 
 #[cfg(feature = "tendermint-rpc")]
 pub use tendermint_rpc;
@@ -19,6 +21,8 @@ pub use {
     tendermint_proto_abcipp as tendermint_proto,
 };
 pub use {namada_core as core, namada_proof_of_stake as proof_of_stake};
+// Makes the `::namada` paths generated by `namada_macros` resolve in here too
+extern crate self as namada;
 pub mod ledger;
 pub use namada_core::proto;
 pub mod types;
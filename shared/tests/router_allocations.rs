@@ -0,0 +1,155 @@
+//! The allocations made by the code generated by the `router!` macro,
+//! counted by a global allocator, which is installed in this test binary so
+//! that it doesn't replace the allocator of the library's other tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::borrow::Cow;
+use std::cell::Cell;
+
+use namada::ledger::queries::matcher::{display_segment, format_path};
+use namada::ledger::queries::RequestCtx;
+use namada::ledger::storage::{DBIter, StorageHasher, DB};
+use namada::ledger::storage_api;
+
+thread_local! {
+    /// The number of allocations made by the current thread
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The system allocator that counts the allocations of each thread, so that
+/// the tests running in parallel don't affect each other's counts.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Call the given function and count the allocations it makes.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(|count| count.get());
+    let result = f();
+    let after = ALLOCATIONS.with(|count| count.get());
+    (result, after - before)
+}
+
+// A sub-router of `TEST_ALLOC_RPC`
+namada::router! {TEST_ALLOC_SUB_RPC,
+    ( "y" ) -> String = y,
+}
+
+// A router with a route with three typed args, whose path is built in a
+// single buffer
+namada::router! {TEST_ALLOC_RPC,
+    ( "triple" / [a: u64] / [b: u64] / [c: u64] ) -> String = triple,
+    ( "sub" ) = (sub TEST_ALLOC_SUB_RPC),
+}
+
+fn triple<D, H>(
+    _ctx: RequestCtx<'_, D, H>,
+    a: u64,
+    b: u64,
+    c: u64,
+) -> storage_api::Result<String>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    Ok(format!("{a}/{b}/{c}"))
+}
+
+fn y<D, H>(_ctx: RequestCtx<'_, D, H>) -> storage_api::Result<String>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    Ok("y".to_owned())
+}
+
+/// Test that the typed args of a `*_path` are written into the path's
+/// buffer, instead of being turned into strings of their own first.
+#[test]
+fn test_router_path_allocations() {
+    let rpc = TEST_ALLOC_RPC;
+    let (a, b, c) = (1_u64, 22_u64, 333_u64);
+
+    // Before, each typed arg was turned into a string before the segments
+    // were joined
+    let (before, before_count) = count_allocations(|| {
+        let segments: Vec<Cow<'_, str>> = vec![
+            Cow::from("triple"),
+            Cow::from(a.to_string()),
+            Cow::from(b.to_string()),
+            Cow::from(c.to_string()),
+        ];
+        format!("/{}", segments.join("/"))
+    });
+    #[cfg(not(debug_assertions))]
+    let (after, after_count) =
+        count_allocations(|| rpc.triple_path(&a, &b, &c));
+    // In debug builds, the path is matched back after it's constructed,
+    // which allocates, so only its construction is counted
+    #[cfg(debug_assertions)]
+    let (after, after_count) = count_allocations(|| {
+        format_path(
+            "",
+            '/',
+            [
+                display_segment(&Some("triple")),
+                display_segment(&Some(a)),
+                display_segment(&Some(b)),
+                display_segment(&Some(c)),
+            ],
+        )
+    });
+    assert_eq!(after, rpc.triple_path(&a, &b, &c));
+    assert_eq!(after, "/triple/1/22/333");
+    assert_eq!(after, before);
+    // A short path fits in its initial buffer
+    assert_eq!(after_count, 1);
+    assert!(
+        after_count < before_count,
+        "{after_count} allocations after, {before_count} before"
+    );
+}
+
+/// Test that a route's name is accessed from its handler's name without
+/// allocating.
+#[test]
+fn test_router_route_name_allocations() {
+    use namada::ledger::queries::RouteName;
+
+    let route = TEST_ALLOC_RPC.route_id("/sub/y").unwrap();
+    assert_eq!(
+        route,
+        TestAllocRpcRoute::TestAllocSubRpc(TestAllocSubRpcRoute::Y)
+    );
+    let (name, count) = count_allocations(|| route.handler_name());
+    assert_eq!(name, "y");
+    assert_eq!(count, 0);
+    assert!(TestAllocRpc::HANDLER_NAMES.contains(&name));
+    let (name, count) = count_allocations(|| {
+        RouteName::handler_name(&TestAllocRpcRoute::Triple)
+    });
+    assert_eq!(name, "triple");
+    assert_eq!(count, 0);
+}
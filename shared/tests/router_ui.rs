@@ -0,0 +1,9 @@
+//! The compile time errors of the `router!` macro for the mistakes in a
+//! router's declaration, with the expected errors in `tests/ui/*.stderr`.
+//! Run with `TRYBUILD=overwrite` to update them after a change of the errors.
+
+#[test]
+fn test_router_compile_errors() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
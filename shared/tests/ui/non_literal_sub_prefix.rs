@@ -0,0 +1,22 @@
+use namada::ledger::queries::RequestCtx;
+use namada::ledger::storage::{DBIter, StorageHasher, DB};
+use namada::ledger::storage_api;
+
+namada::router! {INNER,
+    ( "x" ) -> String = x,
+}
+
+// A sub-router's prefix can only have literal and typed arg segments
+namada::router! {OUTER,
+    ( "inner" / [name] ) = (sub INNER),
+}
+
+fn x<D, H>(_ctx: RequestCtx<'_, D, H>) -> storage_api::Result<String>
+where
+    D: 'static + DB + for<'iter> DBIter<'iter> + Sync,
+    H: 'static + StorageHasher + Sync,
+{
+    Ok("x".to_owned())
+}
+
+fn main() {}